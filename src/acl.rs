@@ -0,0 +1,658 @@
+use anyhow::{anyhow, bail};
+use log::{info, warn};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// An address range in CIDR notation, a plain address is a /32 or /128
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let bits_eq = |a: &[u8], b: &[u8]| {
+            let full = (self.prefix / 8) as usize;
+            let rest = self.prefix % 8;
+            a[..full] == b[..full] && (rest == 0 || (a[full] ^ b[full]) >> (8 - rest) == 0)
+        };
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => bits_eq(&net.octets(), &ip.octets()),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => bits_eq(&net.octets(), &ip.octets()),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("invalid address in '{}'", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => bail!("invalid prefix length in '{}', must be 0-{}", s, max),
+            },
+            None => max,
+        };
+        match canonical(addr) {
+            // ::ffff:a.b.c.d/120 is the same range as a.b.c.d/24
+            IpAddr::V4(v4) if addr.is_ipv6() => {
+                if prefix < 96 {
+                    bail!("prefix of IPv4-mapped range '{}' must be at least 96", s);
+                }
+                Ok(Cidr {
+                    addr: v4.into(),
+                    prefix: prefix - 96,
+                })
+            }
+            addr => Ok(Cidr { addr, prefix }),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// IPv4-mapped IPv6 peers (::ffff:a.b.c.d, seen on dual stack listeners)
+/// are matched as the IPv4 address they carry
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        v4 => v4,
+    }
+}
+
+/// Binary trie over address bits for longest prefix lookups
+#[derive(Default)]
+struct PrefixTrie {
+    // Node 0 is the root, children are indexes into this vector
+    nodes: Vec<([Option<u32>; 2], Option<usize>)>,
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, bits: &[u8], prefix: u8, rule: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push(([None, None], None));
+        }
+        let mut node = 0;
+        for i in 0..prefix as usize {
+            let bit = ((bits[i / 8] >> (7 - i % 8)) & 1) as usize;
+            node = match self.nodes[node].0[bit] {
+                Some(next) => next as usize,
+                None => {
+                    self.nodes.push(([None, None], None));
+                    let next = self.nodes.len() - 1;
+                    self.nodes[node].0[bit] = Some(next as u32);
+                    next
+                }
+            };
+        }
+        // Keep the first rule for duplicates so counts go to one place
+        self.nodes[node].1.get_or_insert(rule);
+    }
+
+    /// Index of the most specific rule covering `bits`
+    fn lookup(&self, bits: &[u8]) -> Option<usize> {
+        let mut node = 0;
+        let mut found = self.nodes.first()?.1;
+        for i in 0..bits.len() * 8 {
+            let bit = ((bits[i / 8] >> (7 - i % 8)) & 1) as usize;
+            match self.nodes[node].0[bit] {
+                Some(next) => node = next as usize,
+                None => break,
+            }
+            found = self.nodes[node].1.or(found);
+        }
+        found
+    }
+}
+
+/// One family-split set of rules
+#[derive(Default)]
+struct RuleSet {
+    rules: Vec<Cidr>,
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+}
+
+impl RuleSet {
+    fn new(rules: Vec<Cidr>) -> Self {
+        let mut set = RuleSet::default();
+        for (i, rule) in rules.iter().enumerate() {
+            match rule.addr {
+                IpAddr::V4(a) => set.v4.insert(&a.octets(), rule.prefix, i),
+                IpAddr::V6(a) => set.v6.insert(&a.octets(), rule.prefix, i),
+            }
+        }
+        set.rules = rules;
+        set
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<usize> {
+        match canonical(ip) {
+            IpAddr::V4(a) => self.v4.lookup(&a.octets()),
+            IpAddr::V6(a) => self.v6.lookup(&a.octets()),
+        }
+    }
+}
+
+/// Outcome of checking a peer against the access rules
+pub enum Verdict {
+    Allowed,
+    /// Matched the deny rule with this index
+    Denied(usize),
+    /// There are allow rules and none of them matched
+    NotAllowed,
+}
+
+struct Rules {
+    allow: RuleSet,
+    deny: RuleSet,
+    deny_hits: Vec<AtomicU64>,
+    not_allowed_hits: AtomicU64,
+}
+
+/// Source address filter for accepted local connections.
+///
+/// Rules come from `--allow-from`/`--deny-from` and optionally a file with
+/// one rule per line, the file part is re-read on SIGHUP. A deny rule always
+/// wins over an allow rule, and when there are no allow rules at all every
+/// address that isn't denied is accepted.
+pub struct AccessList {
+    static_allow: Vec<Cidr>,
+    static_deny: Vec<Cidr>,
+    file: Option<PathBuf>,
+    rules: RwLock<Rules>,
+    last_reject_log: Mutex<(Option<Instant>, u64)>,
+}
+
+const REJECT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+impl AccessList {
+    pub fn new(
+        static_allow: Vec<Cidr>,
+        static_deny: Vec<Cidr>,
+        file: Option<PathBuf>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let acl = AccessList {
+            static_allow,
+            static_deny,
+            file,
+            rules: RwLock::new(Rules {
+                allow: RuleSet::default(),
+                deny: RuleSet::default(),
+                deny_hits: vec![],
+                not_allowed_hits: AtomicU64::new(0),
+            }),
+            last_reject_log: Mutex::new((None, 0)),
+        };
+        acl.reload()?;
+        Ok(Arc::new(acl))
+    }
+
+    /// Rebuild the rule set from the command line rules and the rules file,
+    /// rejection counters start over
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let mut allow = self.static_allow.clone();
+        let mut deny = self.static_deny.clone();
+        if let Some(path) = &self.file {
+            let (a, d) = read_rules_file(path)?;
+            allow.extend(a);
+            deny.extend(d);
+        }
+        info!(
+            "Client access list has {} allow and {} deny rule(s)",
+            allow.len(),
+            deny.len()
+        );
+        let deny_hits = deny.iter().map(|_| AtomicU64::new(0)).collect();
+        *self.rules.write().unwrap() = Rules {
+            allow: RuleSet::new(allow),
+            deny: RuleSet::new(deny),
+            deny_hits,
+            not_allowed_hits: AtomicU64::new(0),
+        };
+        Ok(())
+    }
+
+    /// Check whether a connection from `ip` may be forwarded, counting
+    /// rejections against the rule responsible
+    pub fn check(&self, ip: IpAddr) -> Verdict {
+        let rules = self.rules.read().unwrap();
+        if let Some(i) = rules.deny.lookup(ip) {
+            rules.deny_hits[i].fetch_add(1, Ordering::Relaxed);
+            return Verdict::Denied(i);
+        }
+        if rules.allow.rules.is_empty() || rules.allow.lookup(ip).is_some() {
+            return Verdict::Allowed;
+        }
+        rules.not_allowed_hits.fetch_add(1, Ordering::Relaxed);
+        Verdict::NotAllowed
+    }
+
+    /// Log a rejected peer, at most once every few seconds so a scanner
+    /// can't flood the log
+    pub fn log_rejected(&self, ip: IpAddr, verdict: &Verdict) {
+        let mut last = self.last_reject_log.lock().unwrap();
+        let (when, suppressed) = &mut *last;
+        match when {
+            Some(t) if t.elapsed() < REJECT_LOG_INTERVAL => *suppressed += 1,
+            _ => {
+                let reason = match verdict {
+                    Verdict::Denied(i) => {
+                        format!("denied by {}", self.rules.read().unwrap().deny.rules[*i])
+                    }
+                    _ => "not in the allowlist".to_string(),
+                };
+                warn!(
+                    "Closed connection from {} {} ({} more rejected since last report)",
+                    ip, reason, suppressed
+                );
+                *when = Some(Instant::now());
+                *suppressed = 0;
+            }
+        }
+    }
+
+    pub fn allow_rules(&self) -> Vec<Cidr> {
+        self.rules.read().unwrap().allow.rules.clone()
+    }
+
+    pub fn deny_rules(&self) -> Vec<Cidr> {
+        self.rules.read().unwrap().deny.rules.clone()
+    }
+
+    /// Rejected connection counts per deny rule, plus the count of peers
+    /// that matched no allow rule
+    pub fn stats(&self) -> (Vec<(Cidr, u64)>, u64) {
+        let rules = self.rules.read().unwrap();
+        let per_rule = rules
+            .deny
+            .rules
+            .iter()
+            .zip(&rules.deny_hits)
+            .map(|(c, n)| (*c, n.load(Ordering::Relaxed)))
+            .collect();
+        (per_rule, rules.not_allowed_hits.load(Ordering::Relaxed))
+    }
+}
+
+/// Lines are `allow <cidr>`, `deny <cidr>` or a bare `<cidr>` meaning allow
+fn read_rules_file(path: &Path) -> anyhow::Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("unable to read rules file {}: {}", path.display(), e))?;
+    let mut allow = vec![];
+    let mut deny = vec![];
+    for (n, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let (list, rule) = match line.split_once(char::is_whitespace) {
+            Some(("allow", rule)) => (&mut allow, rule.trim()),
+            Some(("deny", rule)) => (&mut deny, rule.trim()),
+            Some(_) => bail!(
+                "{}:{}: expected 'allow <cidr>' or 'deny <cidr>'",
+                path.display(),
+                n + 1
+            ),
+            None if line.is_empty() => continue,
+            None => (&mut allow, line),
+        };
+        list.push(
+            rule.parse()
+                .map_err(|e| anyhow!("{}:{}: {}", path.display(), n + 1, e))?,
+        );
+    }
+    Ok((allow, deny))
+}
+
+/// Destination requested by a dynamic forwarding client
+pub enum Destination<'a> {
+    Ip(IpAddr),
+    Name(&'a str),
+}
+
+#[derive(Clone)]
+enum DestHost {
+    Cidr(Cidr),
+    /// Case-insensitive pattern with `*` and `?` wildcards
+    Glob(String),
+}
+
+/// `<host-glob|CIDR>[:port|port-range]` rule for dynamic forwarding targets
+#[derive(Clone)]
+pub struct DestRule {
+    host: DestHost,
+    ports: (u16, u16),
+}
+
+impl DestRule {
+    /// Host globs only match names as the client sent them and CIDRs only
+    /// match literal addresses, nothing is resolved locally
+    fn matches(&self, dest: &Destination, port: u16) -> bool {
+        if port < self.ports.0 || port > self.ports.1 {
+            return false;
+        }
+        match (&self.host, dest) {
+            (DestHost::Cidr(c), Destination::Ip(ip)) => c.contains(*ip),
+            (DestHost::Glob(g), Destination::Name(n)) => {
+                glob_match(g.as_bytes(), n.to_ascii_lowercase().as_bytes())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for DestRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (host, ports) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, after) = rest
+                .split_once(']')
+                .ok_or_else(|| anyhow!("missing ']' in destination rule '{}'", s))?;
+            match after.strip_prefix(':') {
+                Some(ports) => (host, Some(ports)),
+                None if after.is_empty() => (host, None),
+                None => bail!(
+                    "unexpected '{}' after ']' in destination rule '{}'",
+                    after,
+                    s
+                ),
+            }
+        } else if s.matches(':').count() > 1 {
+            (s, None)
+        } else {
+            match s.rsplit_once(':') {
+                Some((host, ports)) => (host, Some(ports)),
+                None => (s, None),
+            }
+        };
+        let ports = match ports {
+            None => (0, u16::MAX),
+            Some(p) => {
+                let parse = |v: &str| {
+                    v.parse::<u16>()
+                        .map_err(|_| anyhow!("invalid port '{}' in destination rule '{}'", v, s))
+                };
+                match p.split_once('-') {
+                    Some((lo, hi)) => (parse(lo)?, parse(hi)?),
+                    None => (parse(p)?, parse(p)?),
+                }
+            }
+        };
+        if ports.0 > ports.1 {
+            bail!("empty port range in destination rule '{}'", s);
+        }
+        if host.is_empty() {
+            bail!("missing host in destination rule '{}'", s);
+        }
+        let host = match host.parse::<Cidr>() {
+            Ok(c) => DestHost::Cidr(c),
+            Err(_)
+                if host.chars().next().is_some_and(|c| c.is_ascii_digit())
+                    && host.contains('/') =>
+            {
+                bail!("invalid CIDR in destination rule '{}'", s)
+            }
+            Err(_) => DestHost::Glob(host.to_ascii_lowercase()),
+        };
+        Ok(DestRule { host, ports })
+    }
+}
+
+impl fmt::Display for DestRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.host {
+            DestHost::Cidr(c) if c.addr.is_ipv6() => write!(f, "[{}]", c)?,
+            DestHost::Cidr(c) => write!(f, "{}", c)?,
+            DestHost::Glob(g) => write!(f, "{}", g)?,
+        }
+        match self.ports {
+            (0, u16::MAX) => Ok(()),
+            (lo, hi) if lo == hi => write!(f, ":{}", lo),
+            (lo, hi) => write!(f, ":{}-{}", lo, hi),
+        }
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], text) || (!text.is_empty() && glob_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p == t => glob_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+/// Which destinations dynamic forwarding clients may ask for, from
+/// `--dest-allow` plus an optional file re-read on SIGHUP. Without any rule
+/// every destination is allowed.
+pub struct DestAccessList {
+    static_rules: Vec<DestRule>,
+    file: Option<PathBuf>,
+    rules: RwLock<Vec<DestRule>>,
+}
+
+impl DestAccessList {
+    pub fn new(static_rules: Vec<DestRule>, file: Option<PathBuf>) -> anyhow::Result<Arc<Self>> {
+        let acl = DestAccessList {
+            static_rules,
+            file,
+            rules: RwLock::new(vec![]),
+        };
+        acl.reload()?;
+        Ok(Arc::new(acl))
+    }
+
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let mut rules = self.static_rules.clone();
+        if let Some(path) = &self.file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("unable to read rules file {}: {}", path.display(), e))?;
+            for (n, line) in content.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or("").trim();
+                if line.is_empty() {
+                    continue;
+                }
+                rules.push(
+                    line.parse()
+                        .map_err(|e| anyhow!("{}:{}: {}", path.display(), n + 1, e))?,
+                );
+            }
+        }
+        info!("Destination allowlist has {} rule(s)", rules.len());
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    pub fn is_allowed(&self, dest: &Destination, port: u16) -> bool {
+        let rules = self.rules.read().unwrap();
+        rules.is_empty() || rules.iter().any(|r| r.matches(dest, port))
+    }
+
+    pub fn rules(&self) -> Vec<String> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .map(|r| r.to_string())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(rules: &[&str]) -> Vec<Cidr> {
+        rules.iter().map(|r| r.parse().unwrap()).collect()
+    }
+
+    fn acl(allow: &[&str], deny: &[&str]) -> Arc<AccessList> {
+        AccessList::new(cidrs(allow), cidrs(deny), None).unwrap()
+    }
+
+    fn verdict(acl: &AccessList, ip: &str) -> &'static str {
+        match acl.check(ip.parse().unwrap()) {
+            Verdict::Allowed => "allowed",
+            Verdict::Denied(_) => "denied",
+            Verdict::NotAllowed => "not allowed",
+        }
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.255.1".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        let odd: Cidr = "192.168.1.128/25".parse().unwrap();
+        assert!(odd.contains("192.168.1.200".parse().unwrap()));
+        assert!(!odd.contains("192.168.1.127".parse().unwrap()));
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("203.0.113.9".parse().unwrap()));
+        assert!(!all.contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_ipv6() {
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+        let host: Cidr = "fe80::1".parse().unwrap();
+        assert_eq!(host.to_string(), "fe80::1/128");
+        assert!(!host.contains("fe80::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_ipv4_mapped() {
+        let mapped: Cidr = "::ffff:10.0.0.0/120".parse().unwrap();
+        assert_eq!(mapped.to_string(), "10.0.0.0/24");
+        let v4: Cidr = "10.0.0.0/24".parse().unwrap();
+        assert!(v4.contains("::ffff:10.0.0.7".parse().unwrap()));
+        assert!("::ffff:10.0.0.0/64".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn cidr_rejects_garbage() {
+        for s in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "host/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "accepted '{}'", s);
+        }
+    }
+
+    #[test]
+    fn no_rules_allow_everything() {
+        let acl = acl(&[], &[]);
+        assert_eq!(verdict(&acl, "192.0.2.1"), "allowed");
+        assert_eq!(verdict(&acl, "2001:db8::1"), "allowed");
+    }
+
+    #[test]
+    fn allowlist_only() {
+        let acl = acl(&["10.0.0.0/8", "2001:db8::/32"], &[]);
+        assert_eq!(verdict(&acl, "10.9.9.9"), "allowed");
+        assert_eq!(verdict(&acl, "2001:db8::5"), "allowed");
+        assert_eq!(verdict(&acl, "11.0.0.1"), "not allowed");
+        assert_eq!(verdict(&acl, "2001:db9::5"), "not allowed");
+    }
+
+    #[test]
+    fn deny_wins_over_a_more_specific_allow() {
+        let acl = acl(&["10.1.2.3/32"], &["10.0.0.0/8"]);
+        assert_eq!(verdict(&acl, "10.1.2.3"), "denied");
+    }
+
+    #[test]
+    fn deny_carves_out_of_an_allow() {
+        let acl = acl(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert_eq!(verdict(&acl, "10.2.0.1"), "allowed");
+        assert_eq!(verdict(&acl, "10.1.0.1"), "denied");
+        assert_eq!(verdict(&acl, "192.0.2.1"), "not allowed");
+    }
+
+    #[test]
+    fn deny_only_allows_the_rest() {
+        let acl = acl(&[], &["192.0.2.0/24", "2001:db8::/48"]);
+        assert_eq!(verdict(&acl, "192.0.2.77"), "denied");
+        assert_eq!(verdict(&acl, "198.51.100.1"), "allowed");
+        assert_eq!(verdict(&acl, "2001:db8:0:1::1"), "denied");
+        assert_eq!(verdict(&acl, "2001:db8:1::1"), "allowed");
+    }
+
+    #[test]
+    fn families_dont_mix() {
+        let v4 = acl(&["0.0.0.0/0"], &[]);
+        assert_eq!(verdict(&v4, "2001:db8::1"), "not allowed");
+        let v6 = acl(&["::/0"], &[]);
+        assert_eq!(verdict(&v6, "2001:db8::1"), "allowed");
+        assert_eq!(verdict(&v6, "10.0.0.1"), "not allowed");
+    }
+
+    #[test]
+    fn mapped_peers_match_ipv4_rules() {
+        let acl = acl(&["10.0.0.0/8"], &["10.66.0.0/16"]);
+        assert_eq!(verdict(&acl, "::ffff:10.1.1.1"), "allowed");
+        assert_eq!(verdict(&acl, "::ffff:10.66.1.1"), "denied");
+    }
+
+    #[test]
+    fn overlapping_deny_rules_count_on_the_most_specific() {
+        let acl = acl(&[], &["10.0.0.0/8", "10.1.0.0/16", "10.1.0.0/16"]);
+        for ip in ["10.1.0.1", "10.1.0.2", "10.2.0.1"] {
+            acl.check(ip.parse().unwrap());
+        }
+        acl.check("192.0.2.1".parse().unwrap());
+        let (per_rule, not_allowed) = acl.stats();
+        let hits: Vec<u64> = per_rule.iter().map(|(_, n)| *n).collect();
+        assert_eq!(hits, [1, 2, 0]);
+        assert_eq!(not_allowed, 0);
+    }
+
+    #[test]
+    fn dest_rules() {
+        let rule = |s: &str| s.parse::<DestRule>().unwrap();
+        let name = |n| Destination::Name(n);
+        let ip = |s: &str| Destination::Ip(s.parse().unwrap());
+        assert!(rule("*.internal:443").matches(&name("DB.internal"), 443));
+        assert!(!rule("*.internal:443").matches(&name("db.internal"), 80));
+        assert!(rule("10.0.0.0/8:1000-2000").matches(&ip("10.3.3.3"), 1500));
+        assert!(!rule("10.0.0.0/8:1000-2000").matches(&ip("10.3.3.3"), 2001));
+        assert!(rule("[2001:db8::/32]:22").matches(&ip("2001:db8::9"), 22));
+        assert!(rule("2001:db8::/32").matches(&ip("2001:db8::9"), 8080));
+        // Names are not resolved, a CIDR never matches one
+        assert!(!rule("10.0.0.0/8").matches(&name("10.0.0.1.example"), 80));
+        assert_eq!(rule("[2001:db8::/32]:22").to_string(), "[2001:db8::/32]:22");
+        for s in ["", ":80", "host:80-70", "host:x", "10.0.0.0/99", "[::1"] {
+            assert!(s.parse::<DestRule>().is_err(), "accepted '{}'", s);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+#[cfg(unix)]
+use std::sync::Weak;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Reads returning data close together count as one wake-up for the idle
+/// readers, a bulk transfer would wake them for every buffer otherwise
+const COALESCE: Duration = Duration::from_millis(5);
+
+/// Pause of the socket watcher after waking the readers, doubled while the
+/// data stays unread
+const MIN_PAUSE: Duration = Duration::from_millis(1);
+const MAX_PAUSE: Duration = Duration::from_millis(100);
+
+/// Longest a reader sleeps where the socket can't be watched, data that
+/// comes meanwhile waits that long
+#[cfg(not(unix))]
+const UNWATCHED_WAIT: Duration = Duration::from_millis(10);
+
+/// How long the watcher polls or stays parked before it looks whether the
+/// session is gone
+#[cfg(unix)]
+const WATCH_CHECK: Duration = Duration::from_secs(1);
+
+/// Tells channel readers with nothing to read when the SSH connection may
+/// have something for them, so idle connections sleep instead of polling.
+///
+/// A thread watches the connection socket and advances a counter when it is
+/// readable. Reads that return data advance it as well: libssh2 pulls in
+/// the packets of every channel while reading for one, and the socket then
+/// shows nothing for the others. The watcher parks while no reader waits.
+pub struct Activity {
+    /// A duplicate of the connection socket, so the watcher never polls a
+    /// descriptor number the session closed and something else reuses
+    #[cfg(unix)]
+    fd: Arc<OwnedFd>,
+    state: Mutex<State>,
+    /// The counter moved
+    cond: Condvar,
+    /// A reader started waiting, for the parked watcher
+    #[cfg(unix)]
+    wanted: Condvar,
+}
+
+struct State {
+    generation: u64,
+    last_bump: Instant,
+    #[cfg(unix)]
+    watching: bool,
+    waiters: usize,
+}
+
+impl Activity {
+    #[cfg(unix)]
+    pub fn new(fd: BorrowedFd) -> std::io::Result<Arc<Self>> {
+        Ok(Arc::new(Activity {
+            fd: Arc::new(fd.try_clone_to_owned()?),
+            state: State::new(),
+            cond: Condvar::new(),
+            wanted: Condvar::new(),
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Activity {
+            state: State::new(),
+            cond: Condvar::new(),
+        })
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    /// A read returned data
+    pub fn note(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.last_bump.elapsed() >= COALESCE {
+            self.bump(&mut state);
+        }
+    }
+
+    /// Sleep until the counter moves past `seen` or `timeout` passes. Where
+    /// the socket can't be watched only reads that return data move it, and
+    /// the sleep is kept short.
+    pub fn wait(self: &Arc<Self>, seen: u64, timeout: Duration) {
+        #[cfg(not(unix))]
+        let timeout = timeout.min(UNWATCHED_WAIT);
+        let mut state = self.state.lock().unwrap();
+        #[cfg(unix)]
+        if !state.watching {
+            state.watching = true;
+            let (fd, activity) = (self.fd.clone(), Arc::downgrade(self));
+            std::thread::Builder::new()
+                .name("ssh-watch".to_string())
+                .spawn(move || watch(fd, activity))
+                .expect("starting the SSH socket watcher");
+        }
+        state.waiters += 1;
+        #[cfg(unix)]
+        self.wanted.notify_one();
+        let (mut state, _) = self
+            .cond
+            .wait_timeout_while(state, timeout, |s| s.generation == seen)
+            .unwrap();
+        state.waiters -= 1;
+    }
+
+    fn bump(&self, state: &mut State) {
+        state.generation += 1;
+        state.last_bump = Instant::now();
+        self.cond.notify_all();
+    }
+
+    /// Park the watcher until a reader waits, false when nobody came
+    /// within `timeout`
+    #[cfg(unix)]
+    fn wait_for_waiters(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .wanted
+            .wait_timeout_while(state, timeout, |s| s.waiters == 0)
+            .unwrap();
+        state.waiters > 0
+    }
+}
+
+impl State {
+    fn new() -> Mutex<Self> {
+        Mutex::new(State {
+            generation: 0,
+            last_bump: Instant::now(),
+            #[cfg(unix)]
+            watching: false,
+            waiters: 0,
+        })
+    }
+}
+
+/// Wake the readers whenever the socket has data, until the session is gone
+#[cfg(unix)]
+fn watch(fd: Arc<OwnedFd>, activity: Weak<Activity>) {
+    #[cfg(target_os = "linux")]
+    crate::sandbox::enforce_current_thread();
+    let mut pause = MIN_PAUSE;
+    loop {
+        // With nobody waiting, a readable socket would only be noted over
+        // and over
+        match activity.upgrade() {
+            Some(activity) if activity.wait_for_waiters(WATCH_CHECK) => {}
+            Some(_) => continue,
+            None => return,
+        }
+        let mut pfd = libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let polled = Instant::now();
+        let ready = unsafe { libc::poll(&mut pfd, 1, WATCH_CHECK.as_millis() as i32) };
+        let activity = match activity.upgrade() {
+            Some(activity) => activity,
+            None => return,
+        };
+        if ready < 0 {
+            if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+        if ready == 0 {
+            continue;
+        }
+        activity.bump(&mut activity.state.lock().unwrap());
+        if pfd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0 {
+            // The readers find out from their next read
+            return;
+        }
+        drop(activity);
+        // The data stays readable until a reader got the session lock and
+        // pulled it in, maybe never when no channel is open. Don't spin
+        // meanwhile, but look again soon once it was taken.
+        pause = if polled.elapsed() >= MIN_PAUSE {
+            MIN_PAUSE
+        } else {
+            (pause * 2).min(MAX_PAUSE)
+        };
+        std::thread::sleep(pause);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::fd::AsFd;
+    use std::os::unix::net::UnixStream;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn data_on_the_socket_wakes_a_waiter() {
+        let (ours, mut server) = UnixStream::pair().unwrap();
+        let activity = Activity::new(ours.as_fd()).unwrap();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            server.write_all(b"x").unwrap();
+            server
+        });
+        let started = Instant::now();
+        activity.wait(0, WAIT);
+        assert!(started.elapsed() < WAIT);
+        assert_ne!(activity.generation(), 0);
+        drop(writer.join());
+    }
+
+    #[test]
+    fn the_watcher_parks_without_waiters() {
+        let (ours, mut server) = UnixStream::pair().unwrap();
+        let activity = Activity::new(ours.as_fd()).unwrap();
+        activity.wait(0, Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(50));
+        server.write_all(b"x").unwrap();
+        // A poll still running when the last reader left may note the
+        // data once, nothing after that while it stays unread
+        std::thread::sleep(Duration::from_millis(300));
+        let seen = activity.generation();
+        assert!(seen <= 1, "noted {} times with nobody waiting", seen);
+        activity.wait(seen, WAIT);
+        assert_eq!(activity.generation(), seen + 1);
+    }
+
+    #[test]
+    fn the_watcher_keeps_its_own_descriptor() {
+        let (ours, mut server) = UnixStream::pair().unwrap();
+        let activity = Activity::new(ours.as_fd()).unwrap();
+        drop(ours);
+        // Takes the number the socket had
+        let (_reused, _) = UnixStream::pair().unwrap();
+        server.write_all(b"x").unwrap();
+        activity.wait(0, WAIT);
+        assert_eq!(activity.generation(), 1);
+    }
+}
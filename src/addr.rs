@@ -0,0 +1,150 @@
+use anyhow::{anyhow, bail};
+
+/// Split `host[:port]` into its parts. IPv6 literals must be written in
+/// brackets when a port follows, e.g. `[2001:db8::1]:22`; the returned host
+/// never contains the brackets.
+pub fn split_host_port(s: &str) -> anyhow::Result<(String, Option<u16>)> {
+    let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+        let (host, after) = rest
+            .split_once(']')
+            .ok_or_else(|| anyhow!("missing ']' in address '{}'", s))?;
+        match after {
+            "" => (host, None),
+            _ => match after.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => bail!("unexpected '{}' after ']' in address '{}'", after, s),
+            },
+        }
+    } else if s.matches(':').count() > 1 {
+        // A bare IPv6 literal, there is no way to tell a port apart from it
+        (s, None)
+    } else {
+        match s.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        }
+    };
+    if host.is_empty() {
+        bail!("missing host in address '{}'", s);
+    }
+    let port = match port {
+        Some(p) => Some(
+            p.parse::<u16>()
+                .map_err(|_| anyhow!("invalid port '{}' in address '{}'", p, s))?,
+        ),
+        None => None,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// Join host and port back into a string that can be resolved, adding
+/// brackets around IPv6 literals
+pub fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Whether `host` can be looked up at all: an IP literal, an IPv6 literal
+/// with a zone, or a name made of letters, digits, '-', '_' and dots
+pub fn valid_host(host: &str) -> bool {
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return true;
+    }
+    if let Some((ip, zone)) = host.split_once('%') {
+        return ip.parse::<std::net::Ipv6Addr>().is_ok() && !zone.is_empty();
+    }
+    // A trailing dot makes a name absolute
+    let name = host.strip_suffix('.').unwrap_or(host);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(s: &str) -> (String, Option<u16>) {
+        split_host_port(s).unwrap()
+    }
+
+    #[test]
+    fn every_address_form_splits() {
+        let host = |h: &str| h.to_string();
+        assert_eq!(
+            split("[2001:db8::10]:2222"),
+            (host("2001:db8::10"), Some(2222))
+        );
+        assert_eq!(split("[2001:db8::10]"), (host("2001:db8::10"), None));
+        assert_eq!(split("2001:db8::10"), (host("2001:db8::10"), None));
+        assert_eq!(split("::1"), (host("::1"), None));
+        assert_eq!(split("[fe80::1%eth0]:22"), (host("fe80::1%eth0"), Some(22)));
+        assert_eq!(split("192.0.2.7"), (host("192.0.2.7"), None));
+        assert_eq!(split("192.0.2.7:22"), (host("192.0.2.7"), Some(22)));
+        assert_eq!(
+            split("bastion.example.com"),
+            (host("bastion.example.com"), None)
+        );
+        assert_eq!(split("bastion:2200"), (host("bastion"), Some(2200)));
+    }
+
+    #[test]
+    fn malformed_addresses_say_what_is_wrong() {
+        for (addr, problem) in [
+            ("[2001:db8::10:22", "missing ']'"),
+            ("[2001:db8::10]22", "unexpected '22' after ']'"),
+            ("[::1]:", "invalid port ''"),
+            ("host:65536", "invalid port '65536'"),
+            ("host:ssh", "invalid port 'ssh'"),
+            (":22", "missing host"),
+            ("[]:22", "missing host"),
+            ("", "missing host"),
+        ] {
+            let e = split_host_port(addr).unwrap_err().to_string();
+            assert!(e.contains(problem), "{}: {}", addr, e);
+            assert!(e.contains(&format!("'{}'", addr)), "{}: {}", addr, e);
+        }
+    }
+
+    #[test]
+    fn joined_addresses_split_back() {
+        for (host, port) in [("2001:db8::10", 22), ("192.0.2.7", 2222), ("bastion", 80)] {
+            let joined = join_host_port(host, port);
+            assert_eq!(split(&joined), (host.to_string(), Some(port)));
+        }
+        assert_eq!(join_host_port("::1", 22), "[::1]:22");
+    }
+
+    #[test]
+    fn hosts_that_cannot_be_looked_up_are_invalid() {
+        for host in [
+            "192.0.2.7",
+            "::1",
+            "fe80::1%eth0",
+            "bastion.example.com.",
+            "a_b-c",
+        ] {
+            assert!(valid_host(host), "{}", host);
+        }
+        for host in [
+            "",
+            ".",
+            "a..b",
+            "fe80::1%",
+            "bad host",
+            "x/y",
+            &"a".repeat(64),
+        ] {
+            assert!(!valid_host(host), "{}", host);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use crate::audit::json_string;
+use crate::conn_state::{ConnectionState, SharedState};
+use crate::latency::LatencyTracker;
+use crate::local::PeerAddr;
+use crate::secret::Secret;
+use crate::token::constant_time_eq;
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::time::{timeout, Duration};
+
+/// Largest request head accepted, the API has no use for request bodies
+const MAX_REQUEST: usize = 8192;
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A forwarded connection as the API shows it, and the handle to close it
+pub struct LiveConnection {
+    id: u64,
+    client: String,
+    started: Instant,
+    state: SharedState,
+    bytes_out: Arc<AtomicU64>,
+    bytes_in: Arc<AtomicU64>,
+    target: Mutex<Option<String>>,
+    identity: Mutex<Option<String>>,
+    killed: AtomicBool,
+    notify: Notify,
+}
+
+impl LiveConnection {
+    pub fn set_target(&self, target: &str) {
+        *self.target.lock().unwrap() = Some(target.to_string());
+    }
+
+    pub fn set_identity(&self, identity: &str) {
+        *self.identity.lock().unwrap() = Some(identity.to_string());
+    }
+
+    /// Whether the connection was closed through the API
+    pub fn killed(&self) -> bool {
+        self.killed.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the connection is closed through the API, for a copy
+    /// task blocked on a read
+    pub async fn closed(&self) {
+        if !self.killed() {
+            self.notify.notified().await;
+        }
+    }
+
+    fn kill(&self) {
+        if !self.killed.swap(true, Ordering::AcqRel) {
+            self.notify.notify_one();
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let opt = |v: &Mutex<Option<String>>| match &*v.lock().unwrap() {
+            Some(s) => json_string(s),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"id\":{},\"client\":{},\"state\":{},\"target\":{},\"identity\":{},\"bytes_out\":{},\"bytes_in\":{},\"duration_ms\":{}}}",
+            self.id,
+            json_string(&self.client),
+            json_string(ConnectionState::load(&self.state).name()),
+            opt(&self.target),
+            opt(&self.identity),
+            self.bytes_out.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.started.elapsed().as_millis()
+        )
+    }
+}
+
+/// Connections currently being forwarded, by id
+#[derive(Default)]
+pub struct LiveConnections {
+    conns: Mutex<BTreeMap<u64, Arc<LiveConnection>>>,
+}
+
+/// Keeps a connection listed until it is dropped with the connection task
+pub struct Registration {
+    table: Arc<LiveConnections>,
+    pub conn: Arc<LiveConnection>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.table.conns.lock().unwrap().remove(&self.conn.id);
+    }
+}
+
+impl LiveConnections {
+    pub fn register(
+        self: &Arc<Self>,
+        id: u64,
+        client: PeerAddr,
+        state: SharedState,
+        bytes_out: Arc<AtomicU64>,
+        bytes_in: Arc<AtomicU64>,
+    ) -> Registration {
+        let conn = Arc::new(LiveConnection {
+            id,
+            client: client.to_string(),
+            started: Instant::now(),
+            state,
+            bytes_out,
+            bytes_in,
+            target: Mutex::new(None),
+            identity: Mutex::new(None),
+            killed: AtomicBool::new(false),
+            notify: Notify::new(),
+        });
+        self.conns.lock().unwrap().insert(id, conn.clone());
+        Registration {
+            table: self.clone(),
+            conn,
+        }
+    }
+
+    /// One line per connection, for listing them on the terminal
+    pub fn describe(&self) -> Vec<String> {
+        self.conns
+            .lock()
+            .unwrap()
+            .values()
+            .map(|c| {
+                format!(
+                    "#{} {} -> {} {} ({} bytes out, {} in, {}s)",
+                    c.id,
+                    c.client,
+                    c.target.lock().unwrap().as_deref().unwrap_or("?"),
+                    ConnectionState::load(&c.state),
+                    c.bytes_out.load(Ordering::Relaxed),
+                    c.bytes_in.load(Ordering::Relaxed),
+                    c.started.elapsed().as_secs()
+                )
+            })
+            .collect()
+    }
+}
+
+/// What the API serves besides the connections, fixed at startup
+pub struct ApiState {
+    pub connections: Arc<LiveConnections>,
+    /// JSON object for /api/v1/session
+    pub session: String,
+    /// JSON array for /api/v1/tunnels
+    pub tunnels: String,
+    pub latency: Option<Arc<LatencyTracker>>,
+    pub token: Option<Secret>,
+}
+
+/// Serve the API on `addr` in the background. The listener is bound before
+/// returning so a bad address fails at startup.
+pub async fn serve(addr: SocketAddr, state: ApiState) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("API listening on {}", listener.local_addr()?);
+    let state = Arc::new(state);
+    tokio::spawn(async move {
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) if crate::local::is_transient_accept_error(&e) => {
+                    warn!("API accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+                Err(e) => {
+                    error!("API listener failed, no longer serving the API: {}", e);
+                    return;
+                }
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle(socket, &state).await {
+                    debug!("API request from {} failed: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle(mut socket: TcpStream, state: &ApiState) -> anyhow::Result<()> {
+    let head = match timeout(REQUEST_TIMEOUT, read_head(&mut socket)).await {
+        Ok(head) => head?,
+        Err(_) => anyhow::bail!("timed out reading the request"),
+    };
+    let (status, body) = match head {
+        Some(head) => respond(&head, state),
+        None => (400, error_body("bad request")),
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Read up to the empty line ending the request head, None when it is too
+/// long or not UTF-8
+async fn read_head(socket: &mut TcpStream) -> anyhow::Result<Option<String>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the end of the request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(String::from_utf8(buf).ok())
+}
+
+fn respond(head: &str, state: &ApiState) -> (u16, String) {
+    let mut lines = head.split("\r\n");
+    let mut request = lines.next().unwrap_or("").split(' ');
+    let (method, path) = match (request.next(), request.next()) {
+        (Some(method), Some(path)) => (method, path),
+        _ => return (400, error_body("bad request")),
+    };
+    if let Some(token) = &state.token {
+        let presented = lines
+            .filter_map(|l| l.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+            .and_then(|(_, value)| value.trim().strip_prefix("Bearer "))
+            .unwrap_or("");
+        if !constant_time_eq(presented.as_bytes(), token.expose().as_bytes()) {
+            return (401, error_body("missing or wrong bearer token"));
+        }
+    }
+    let path = path.split('?').next().unwrap_or(path);
+    match (method, path) {
+        ("GET", "/api/v1/connections") => {
+            let conns: Vec<String> = state
+                .connections
+                .conns
+                .lock()
+                .unwrap()
+                .values()
+                .map(|c| c.to_json())
+                .collect();
+            (200, format!("[{}]", conns.join(",")))
+        }
+        ("GET", "/api/v1/session") => (200, state.session.clone()),
+        ("GET", "/api/v1/tunnels") => (200, state.tunnels.clone()),
+        ("GET", "/api/v1/latency") => match &state.latency {
+            Some(latency) => (200, latency.to_json()),
+            None => (404, error_body("not found")),
+        },
+        ("DELETE", _) if path.starts_with("/api/v1/connections/") => {
+            let id = &path["/api/v1/connections/".len()..];
+            let conn = id
+                .parse::<u64>()
+                .ok()
+                .and_then(|id| state.connections.conns.lock().unwrap().get(&id).cloned());
+            match conn {
+                Some(conn) => {
+                    info!("Closing connection #{} on API request", conn.id);
+                    conn.kill();
+                    (200, conn.to_json())
+                }
+                None => (404, error_body("no such connection")),
+            }
+        }
+        (_, "/api/v1/connections" | "/api/v1/session" | "/api/v1/tunnels" | "/api/v1/latency") => {
+            (405, error_body("method not allowed"))
+        }
+        _ => (404, error_body("not found")),
+    }
+}
+
+fn error_body(message: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(message))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    }
+}
@@ -0,0 +1,301 @@
+use crate::limits::ByteQuotas;
+use crate::local::PeerAddr;
+use anyhow::{bail, Context};
+use log::error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Rotated files kept next to the audit log, path.1 being the newest
+const ROTATED_FILES: u32 = 5;
+
+/// --audit-log-max-size value, bytes with an optional K, M or G suffix
+#[derive(Clone, Copy, Debug)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (num, mult) = match s.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+            Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+            Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+            _ => (s, 1),
+        };
+        let n: u64 = num.parse().with_context(|| format!("invalid size {}", s))?;
+        if n == 0 {
+            bail!("size must not be 0");
+        }
+        Ok(ByteSize(n * mult))
+    }
+}
+
+/// A JSON value in an audit record
+pub enum Value<'a> {
+    Str(&'a str),
+    Num(u64),
+}
+
+/// Append-only JSON lines file recording sessions and forwarded connections,
+/// kept apart from the normal log
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: Option<u64>,
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, max_size: Option<ByteSize>) -> anyhow::Result<Arc<Self>> {
+        let file = open_private(path)?;
+        let size = file.metadata()?.len();
+        Ok(Arc::new(AuditLog {
+            path: path.to_path_buf(),
+            max_size: max_size.map(|s| s.0),
+            file: Mutex::new((file, size)),
+        }))
+    }
+
+    /// Append one record. The line is written in one piece and reaches the
+    /// file before this returns. Failures are logged, they never stop the
+    /// tunnel.
+    pub fn record(&self, event: &str, fields: &[(&str, Value)]) {
+        let mut line = format!(
+            "{{\"ts\":\"{}\",\"event\":{}",
+            timestamp(),
+            json_string(event)
+        );
+        for (key, value) in fields {
+            line += &format!(",{}:", json_string(key));
+            match value {
+                Value::Str(s) => line += &json_string(s),
+                Value::Num(n) => line += &n.to_string(),
+            }
+        }
+        line += "}\n";
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = self.write(&mut file, line.as_bytes()) {
+            error!("Unable to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn write(&self, file: &mut (File, u64), line: &[u8]) -> anyhow::Result<()> {
+        if let Some(max) = self.max_size {
+            if file.1 > 0 && file.1 + line.len() as u64 > max {
+                self.rotate()?;
+                *file = (open_private(&self.path)?, 0);
+            }
+        }
+        file.0.write_all(line)?;
+        file.0.sync_data()?;
+        file.1 += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self) -> anyhow::Result<()> {
+        let rotated = |n: u32| {
+            let mut p = self.path.clone().into_os_string();
+            p.push(format!(".{}", n));
+            PathBuf::from(p)
+        };
+        for n in (1..ROTATED_FILES).rev() {
+            let from = rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated(1))?;
+        Ok(())
+    }
+}
+
+/// Only the owner may read the audit trail
+fn open_private(path: &Path) -> anyhow::Result<File> {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options
+            .open(path)
+            .with_context(|| format!("opening audit log {}", path.display()))?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(options
+            .open(path)
+            .with_context(|| format!("opening audit log {}", path.display()))?)
+    }
+}
+
+/// Details of one forwarded connection, written as a record when dropped so
+/// every way a connection task can end is covered
+pub struct ConnectionRecord {
+    log: Option<Arc<AuditLog>>,
+    id: u64,
+    client: PeerAddr,
+    started: Instant,
+    pub identity: Option<String>,
+    pub target: Option<String>,
+    pub bytes_out: Arc<AtomicU64>,
+    pub bytes_in: Arc<AtomicU64>,
+    /// Why the connection ended, the first reason set wins
+    pub reason: Arc<OnceLock<&'static str>>,
+    /// Where the bytes are counted towards the client's quota
+    pub quotas: Option<Arc<ByteQuotas>>,
+}
+
+impl ConnectionRecord {
+    pub fn new(log: Option<Arc<AuditLog>>, id: u64, client: PeerAddr) -> Self {
+        ConnectionRecord {
+            log,
+            id,
+            client,
+            started: Instant::now(),
+            identity: None,
+            target: None,
+            bytes_out: Arc::new(AtomicU64::new(0)),
+            bytes_in: Arc::new(AtomicU64::new(0)),
+            reason: Arc::new(OnceLock::new()),
+            quotas: None,
+        }
+    }
+
+    pub fn close(&self, reason: &'static str) {
+        let _ = self.reason.set(reason);
+    }
+}
+
+impl Drop for ConnectionRecord {
+    fn drop(&mut self) {
+        let bytes = self.bytes_out.load(Ordering::Relaxed) + self.bytes_in.load(Ordering::Relaxed);
+        if let (Some(quotas), Some(ip)) = (&self.quotas, self.client.ip()) {
+            quotas.record(ip, bytes, self.identity.as_deref());
+        }
+        let log = match &self.log {
+            Some(log) => log,
+            None => return,
+        };
+        let client = self.client.to_string();
+        log.record(
+            "connection",
+            &[
+                ("id", Value::Num(self.id)),
+                ("client", Value::Str(&client)),
+                (
+                    "identity",
+                    Value::Str(self.identity.as_deref().unwrap_or("none")),
+                ),
+                ("target", Value::Str(self.target.as_deref().unwrap_or("-"))),
+                (
+                    "bytes_out",
+                    Value::Num(self.bytes_out.load(Ordering::Relaxed)),
+                ),
+                (
+                    "bytes_in",
+                    Value::Num(self.bytes_in.load(Ordering::Relaxed)),
+                ),
+                (
+                    "duration_ms",
+                    Value::Num(self.started.elapsed().as_millis() as u64),
+                ),
+                (
+                    "close_reason",
+                    Value::Str(self.reason.get().copied().unwrap_or("closed")),
+                ),
+            ],
+        );
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Value of a string or number field anywhere in a flat JSON object, for
+/// the answers of the etcd gateway (which quotes its 64 bit numbers) and the
+/// control socket
+#[cfg(any(unix, feature = "etcd"))]
+pub fn json_field(body: &str, name: &str) -> Option<String> {
+    let key = format!("\"{}\"", name);
+    let rest = body[body.find(&key)? + key.len()..].trim_start();
+    let rest = rest.strip_prefix(':')?.trim_start();
+    let quoted = match rest.strip_prefix('"') {
+        Some(quoted) => quoted,
+        None => {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '-')
+                .unwrap_or(rest.len());
+            return Some(rest[..end].to_string());
+        }
+    };
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+/// Current UTC time as RFC 3339 with milliseconds
+pub fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
@@ -0,0 +1,305 @@
+use clap::{Arg, Command, ValueEnum, ValueHint};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+/// Ports people usually want to reach through a tunnel
+const COMMON_PORTS: &[&str] = &["22", "80", "443", "3306", "5432", "6379", "27017"];
+
+/// Host aliases from ~/.ssh/config, skipping wildcard patterns
+const SH_SSH_CONFIG_HOSTS: &str = r#"awk 'tolower($1)=="host"{for(i=2;i<=NF;i++) if ($i !~ /[*?!]/) print $i}' ~/.ssh/config 2>/dev/null"#;
+/// The current user plus every User from ~/.ssh/config
+const SH_SSH_CONFIG_USERS: &str =
+    r#"{ echo "$USER"; awk 'tolower($1)=="user"{print $2}' ~/.ssh/config 2>/dev/null; } | sort -u"#;
+
+enum Values {
+    None,
+    Hosts,
+    Users,
+    Ports,
+    Files,
+    List(Vec<String>),
+    Any,
+}
+
+struct Opt {
+    short: Option<char>,
+    long: Option<String>,
+    help: String,
+    values: Values,
+}
+
+impl Opt {
+    fn from_arg(arg: &Arg) -> Opt {
+        let takes_value = arg.get_action().takes_values();
+        let possible: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .map(|v| v.get_name().to_string())
+            .collect();
+        let values = if !takes_value {
+            Values::None
+        } else if !possible.is_empty() {
+            Values::List(possible)
+        } else if arg.get_id().as_str().ends_with("port") {
+            Values::Ports
+        } else {
+            match arg.get_value_hint() {
+                ValueHint::Hostname => Values::Hosts,
+                ValueHint::Username => Values::Users,
+                ValueHint::FilePath | ValueHint::AnyPath | ValueHint::DirPath => Values::Files,
+                _ => Values::Any,
+            }
+        };
+        // Only the first line of the help, completion UIs have little room
+        let help = arg
+            .get_help()
+            .map(|h| h.to_string())
+            .unwrap_or_default()
+            .lines()
+            .next()
+            .unwrap_or("")
+            .to_string();
+        Opt {
+            short: arg.get_short(),
+            long: arg.get_long().map(|l| l.to_string()),
+            help,
+            values,
+        }
+    }
+
+    fn flags(&self) -> Vec<String> {
+        let mut flags = vec![];
+        if let Some(s) = self.short {
+            flags.push(format!("-{}", s));
+        }
+        if let Some(l) = &self.long {
+            flags.push(format!("--{}", l));
+        }
+        flags
+    }
+}
+
+/// Write the completion script for `shell` to stdout
+pub fn generate(shell: Shell, mut cmd: Command) {
+    cmd.build();
+    let name = cmd.get_name().to_string();
+    let opts: Vec<Opt> = cmd
+        .get_arguments()
+        .filter(|a| !a.is_positional())
+        .map(Opt::from_arg)
+        .collect();
+    let script = match shell {
+        Shell::Bash => bash(&name, &opts),
+        Shell::Zsh => zsh(&name, &opts),
+        Shell::Fish => fish(&name, &opts),
+        Shell::Powershell => powershell(&name, &opts),
+    };
+    print!("{}", script);
+}
+
+fn bash(name: &str, opts: &[Opt]) -> String {
+    let fname = format!("_{}", name.replace('-', "_"));
+    let mut cases = String::new();
+    for o in opts {
+        let words = match &o.values {
+            Values::None => continue,
+            Values::Hosts => format!("$({}; echo localhost)", SH_SSH_CONFIG_HOSTS),
+            Values::Users => format!("$({})", SH_SSH_CONFIG_USERS),
+            Values::Ports => COMMON_PORTS.join(" "),
+            Values::List(l) => l.join(" "),
+            Values::Files => {
+                cases += &format!(
+                    "        {})\n            COMPREPLY=($(compgen -f -- \"$cur\"))\n            return;;\n",
+                    o.flags().join("|")
+                );
+                continue;
+            }
+            Values::Any => {
+                cases += &format!("        {})\n            return;;\n", o.flags().join("|"));
+                continue;
+            }
+        };
+        cases += &format!(
+            "        {})\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return;;\n",
+            o.flags().join("|"),
+            words
+        );
+    }
+    let all: Vec<String> = opts.iter().flat_map(|o| o.flags()).collect();
+    format!(
+        r#"{fname}() {{
+    local cur prev
+    cur="${{COMP_WORDS[COMP_CWORD]}}"
+    prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$prev" in
+{cases}    esac
+    COMPREPLY=($(compgen -W "{all}" -- "$cur"))
+}}
+complete -F {fname} {name}
+"#,
+        all = all.join(" ")
+    )
+}
+
+fn zsh_escape(s: &str) -> String {
+    s.replace('\'', r"'\''")
+        .replace('[', r"\[")
+        .replace(']', r"\]")
+        .replace(':', r"\:")
+}
+
+fn zsh(name: &str, opts: &[Opt]) -> String {
+    let fname = format!("_{}", name.replace('-', "_"));
+    let mut specs = vec![];
+    for o in opts {
+        let action = match &o.values {
+            Values::None => String::new(),
+            Values::Hosts => format!(":host:{}_hosts", fname),
+            Values::Users => format!(":user:{}_users", fname),
+            Values::Ports => format!(":port:({})", COMMON_PORTS.join(" ")),
+            Values::List(l) => format!(":value:({})", l.join(" ")),
+            Values::Files => ":file:_files".to_string(),
+            Values::Any => ":value: ".to_string(),
+        };
+        let eq = if matches!(o.values, Values::None) {
+            ""
+        } else {
+            "+"
+        };
+        let flags = o.flags();
+        let names = if flags.len() > 1 {
+            format!(
+                "'({})'{{{}}}",
+                flags.join(" "),
+                flags
+                    .iter()
+                    .map(|f| format!("{}{}", f, eq))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )
+        } else {
+            format!("'{}{}'", flags[0], eq)
+        };
+        specs.push(format!(
+            "        {}'[{}]{}'",
+            names,
+            zsh_escape(&o.help),
+            action
+        ));
+    }
+    format!(
+        r#"#compdef {name}
+
+{fname}_hosts() {{
+    local -a hosts
+    hosts=(localhost ${{(f)"$({hosts})"}})
+    _describe 'host' hosts
+}}
+
+{fname}_users() {{
+    local -a users
+    users=(${{(f)"$({users})"}})
+    _describe 'user' users
+}}
+
+{fname}() {{
+    _arguments -s \
+{specs}
+}}
+
+compdef {fname} {name}
+"#,
+        hosts = SH_SSH_CONFIG_HOSTS,
+        users = SH_SSH_CONFIG_USERS,
+        specs = specs.join(" \\\n")
+    )
+}
+
+fn fish(name: &str, opts: &[Opt]) -> String {
+    let fname = format!("__{}", name.replace('-', "_"));
+    let mut out = format!(
+        "function {fname}_hosts\n    echo localhost\n    {hosts}\nend\n\nfunction {fname}_users\n    {users}\nend\n\n",
+        hosts = SH_SSH_CONFIG_HOSTS,
+        users = r#"begin; echo $USER; awk 'tolower($1)=="user"{print $2}' ~/.ssh/config 2>/dev/null; end | sort -u"#
+    );
+    for o in opts {
+        let mut line = format!("complete -c {}", name);
+        if let Some(s) = o.short {
+            line += &format!(" -s {}", s);
+        }
+        if let Some(l) = &o.long {
+            line += &format!(" -l {}", l);
+        }
+        line += &match &o.values {
+            Values::None => String::new(),
+            Values::Hosts => format!(" -r -f -a '({}_hosts)'", fname),
+            Values::Users => format!(" -r -f -a '({}_users)'", fname),
+            Values::Ports => format!(" -r -f -a '{}'", COMMON_PORTS.join(" ")),
+            Values::List(l) => format!(" -r -f -a '{}'", l.join(" ")),
+            Values::Files => " -r -F".to_string(),
+            Values::Any => " -r -f".to_string(),
+        };
+        line += &format!(" -d '{}'\n", o.help.replace('\'', r"\'"));
+        out += &line;
+    }
+    out
+}
+
+fn powershell(name: &str, opts: &[Opt]) -> String {
+    let mut cases = String::new();
+    for o in opts {
+        let values = match &o.values {
+            Values::None | Values::Any => continue,
+            Values::Hosts => "@('localhost') + (Get-SshConfigValues 'Host')".to_string(),
+            Values::Users => "@($env:USERNAME) + (Get-SshConfigValues 'User')".to_string(),
+            Values::Ports => format!("@('{}')", COMMON_PORTS.join("','")),
+            Values::List(l) => format!("@('{}')", l.join("','")),
+            Values::Files => "(Get-ChildItem -Name -Path \"$wordToComplete*\")".to_string(),
+        };
+        let labels: Vec<String> = o.flags().iter().map(|f| format!("'{}'", f)).collect();
+        cases += &format!(
+            "        {{ $_ -in @({}) }} {{ {} }}\n",
+            labels.join(", "),
+            values
+        );
+    }
+    let all: Vec<String> = opts
+        .iter()
+        .flat_map(|o| o.flags())
+        .map(|f| format!("'{}'", f))
+        .collect();
+    format!(
+        r#"Register-ArgumentCompleter -Native -CommandName '{name}' -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+
+    function Get-SshConfigValues($keyword) {{
+        Get-Content "$HOME/.ssh/config" -ErrorAction SilentlyContinue | ForEach-Object {{
+            if ($_ -match "^\s*$keyword\s+(.+)$") {{
+                $Matches[1] -split '\s+' | Where-Object {{ $_ -notmatch '[*?!]' }}
+            }}
+        }}
+    }}
+
+    $elements = $commandAst.CommandElements | ForEach-Object {{ $_.ToString() }}
+    if ($wordToComplete) {{
+        $prev = $elements[-2]
+    }} else {{
+        $prev = $elements[-1]
+    }}
+    $values = switch ($prev) {{
+{cases}        default {{ @({all}) }}
+    }}
+    $values | Where-Object {{ $_ -like "$wordToComplete*" }} | Sort-Object -Unique | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+"#,
+        all = all.join(", ")
+    )
+}
@@ -0,0 +1,476 @@
+use crate::acl::{AccessList, Cidr, DestAccessList, DestRule};
+use crate::audit::ByteSize;
+use crate::connstr::ConnectionString;
+use crate::known_hosts::StrictHostKeyChecking;
+use crate::limits::{ClientByteLimit, RateLimit};
+use crate::preflight::Preflight;
+use crate::proxy::Proxy;
+use crate::secret::Secret;
+use crate::sni::SniRoute;
+use crate::{AuthMethod, HalfDuplex, Opts, SandboxMode};
+use anyhow::{bail, Context};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+/// Effective settings after merging the command line with everything that
+/// can provide defaults for it
+pub struct AppConfig {
+    pub sshaddress: Option<String>,
+    pub mdns_service_name: Option<String>,
+    pub sshuser: String,
+    pub agent_socket: Option<PathBuf>,
+    pub identity: Option<PathBuf>,
+    pub auth_order: Vec<AuthMethod>,
+    pub port_knock: Vec<u16>,
+    pub port_knock_delay_ms: u64,
+    pub port_knock_tcp: bool,
+    pub port_knock_wait_ms: u64,
+    pub known_hosts: Option<PathBuf>,
+    pub strict_host_key_checking: StrictHostKeyChecking,
+    pub source_addresses: Vec<IpAddr>,
+    pub proxy_command: Option<String>,
+    pub proxy: Option<Proxy>,
+    pub no_proxy: bool,
+    pub handshake_timeout: u64,
+    pub channel_open_timeout_ms: u64,
+    pub compression: bool,
+    pub kex: Option<String>,
+    pub hostkey_algorithms: Option<String>,
+    pub cipher: Option<String>,
+    pub mac: Option<String>,
+    pub ip_tos: Option<u8>,
+    pub propagate_tos: bool,
+    pub control: bool,
+    pub remote_srv: String,
+    pub remote_port: u16,
+    pub remote_check_command: Option<String>,
+    pub preflight: Option<Preflight>,
+    pub dns_forward: Option<SocketAddr>,
+    pub dns_server: Option<String>,
+    pub local_srv_address: String,
+    pub allow_external: bool,
+    pub unix_mode: Option<u32>,
+    pub unix_owner: Option<String>,
+    pub allow_from: Vec<Cidr>,
+    pub deny_from: Vec<Cidr>,
+    pub allow_from_file: Option<PathBuf>,
+    pub dynamic: bool,
+    pub sni_passthrough: Vec<SniRoute>,
+    pub dest_allow: Vec<DestRule>,
+    pub dest_allow_file: Option<PathBuf>,
+    pub socks_user: Option<String>,
+    pub socks_password: Option<Secret>,
+    pub socks_users_file: Option<PathBuf>,
+    pub socks_enable_bind: bool,
+    pub connection_limit_per_source_ip: Option<u32>,
+    pub rate_limit: Option<RateLimit>,
+    pub rate_limit_exempt_loopback: bool,
+    pub max_bytes_per_conn: Option<ByteSize>,
+    pub max_bytes_per_client: Option<ClientByteLimit>,
+    pub half_duplex: Option<HalfDuplex>,
+    pub receive_window_size: Option<ByteSize>,
+    pub write_timeout: u64,
+    pub reuse_channel: bool,
+    pub channel_burst_size: Option<u16>,
+    pub mysql_logging: bool,
+    pub require_tunnel_token: bool,
+    pub client_token: Option<Secret>,
+    pub client_token_file: Option<PathBuf>,
+    pub client_token_on_loopback: bool,
+    pub allow_env_auth: bool,
+    pub audit_log: Option<PathBuf>,
+    pub audit_log_max_size: Option<ByteSize>,
+    pub latency_sla_ms: Option<u64>,
+    pub api_addr: Option<SocketAddr>,
+    pub api_token: Option<Secret>,
+    #[cfg(feature = "consul")]
+    pub consul_url: Option<String>,
+    #[cfg(feature = "etcd")]
+    pub etcd_url: Option<String>,
+    #[cfg(feature = "etcd")]
+    pub etcd_ttl_secs: u64,
+    #[cfg(any(feature = "consul", feature = "etcd"))]
+    pub service_name: Option<String>,
+    pub forwarder_pool: Option<u16>,
+    pub sandbox: Option<SandboxMode>,
+    pub no_core_dumps: bool,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub allow_root: bool,
+    pub refuse_root: bool,
+    pub quiet: bool,
+    pub print_config: bool,
+}
+
+impl AppConfig {
+    pub fn from_args(opts: Opts, matches: &ArgMatches) -> anyhow::Result<Self> {
+        let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        let mut config = AppConfig {
+            sshaddress: opts.sshaddress,
+            mdns_service_name: opts.mdns_service_name,
+            sshuser: opts.sshuser,
+            agent_socket: opts.agent_socket,
+            identity: opts.identity,
+            auth_order: opts.auth_order,
+            port_knock: opts.port_knock,
+            port_knock_delay_ms: opts.port_knock_delay_ms,
+            port_knock_tcp: opts.port_knock_tcp,
+            port_knock_wait_ms: opts.port_knock_wait_ms,
+            known_hosts: opts.known_hosts,
+            strict_host_key_checking: if opts.accept_new {
+                StrictHostKeyChecking::AcceptNew
+            } else {
+                opts.strict_host_key_checking
+            },
+            source_addresses: opts.source_addresses,
+            proxy_command: opts.proxy_command,
+            proxy: opts
+                .proxy
+                .as_deref()
+                .map(Proxy::parse)
+                .transpose()
+                .context("--proxy")?,
+            no_proxy: opts.no_proxy,
+            handshake_timeout: opts.handshake_timeout,
+            channel_open_timeout_ms: opts.channel_open_timeout_ms,
+            compression: opts.compression && !opts.no_compression,
+            kex: opts.kex,
+            hostkey_algorithms: opts.hostkey_algorithms,
+            cipher: opts.cipher,
+            mac: opts.mac,
+            ip_tos: opts.ip_tos,
+            propagate_tos: opts.propagate_tos,
+            control: opts.control,
+            remote_srv: opts.remote_srv,
+            remote_port: opts.remote_port,
+            remote_check_command: opts.remote_check_command,
+            preflight: opts.preflight,
+            dns_forward: opts.dns_forward,
+            dns_server: opts.dns_server,
+            local_srv_address: opts.local_srv_address,
+            allow_external: opts.allow_external,
+            unix_mode: opts.unix_mode,
+            unix_owner: opts.unix_owner,
+            allow_from: opts.allow_from,
+            deny_from: opts.deny_from,
+            allow_from_file: opts.allow_from_file,
+            dynamic: opts.dynamic,
+            sni_passthrough: opts.sni_passthrough,
+            dest_allow: opts.dest_allow,
+            dest_allow_file: opts.dest_allow_file,
+            socks_user: opts.socks_user,
+            socks_password: opts.socks_password.map(Secret::new),
+            socks_users_file: opts.socks_users_file,
+            socks_enable_bind: opts.socks_enable_bind,
+            connection_limit_per_source_ip: opts.connection_limit_per_source_ip,
+            rate_limit: opts.rate_limit,
+            rate_limit_exempt_loopback: opts.rate_limit_exempt_loopback,
+            max_bytes_per_conn: opts.max_bytes_per_conn,
+            max_bytes_per_client: opts.max_bytes_per_client,
+            half_duplex: opts.half_duplex,
+            receive_window_size: opts.receive_window_size,
+            write_timeout: opts.write_timeout,
+            reuse_channel: opts.reuse_channel,
+            channel_burst_size: opts.channel_burst_size,
+            mysql_logging: opts.mysql_logging,
+            require_tunnel_token: opts.require_tunnel_token,
+            client_token: opts.client_token.map(Secret::new),
+            client_token_file: opts.client_token_file,
+            client_token_on_loopback: opts.client_token_on_loopback,
+            allow_env_auth: opts.allow_env_auth,
+            audit_log: opts.audit_log,
+            audit_log_max_size: opts.audit_log_max_size,
+            latency_sla_ms: opts.latency_sla_ms,
+            api_addr: opts.api_addr,
+            api_token: opts.api_token.map(Secret::new),
+            #[cfg(feature = "consul")]
+            consul_url: opts.consul_url,
+            #[cfg(feature = "etcd")]
+            etcd_url: opts.etcd_url,
+            #[cfg(feature = "etcd")]
+            etcd_ttl_secs: opts.etcd_ttl_secs,
+            #[cfg(any(feature = "consul", feature = "etcd"))]
+            service_name: opts.service_name,
+            forwarder_pool: opts.forwarder_pool,
+            sandbox: opts.sandbox,
+            no_core_dumps: opts.no_core_dumps,
+            user: opts.user,
+            group: opts.group,
+            allow_root: opts.allow_root,
+            refuse_root: opts.refuse_root,
+            quiet: opts.quiet,
+            print_config: opts.print_config,
+        };
+
+        // Individual flags given on the command line win over the connection string
+        if let Some(s) = opts.connection_string {
+            let spec = ConnectionString::parse(&s)?;
+            if config.sshaddress.is_none() && config.mdns_service_name.is_none() {
+                config.sshaddress = Some(spec.sshaddress);
+            }
+            if let (Some(user), false) = (spec.sshuser, explicit("sshuser")) {
+                config.sshuser = user;
+            }
+            if let (Some(srv), false) = (spec.remote_srv, explicit("remote_srv")) {
+                config.remote_srv = srv;
+            }
+            if let (Some(port), false) = (spec.remote_port, explicit("remote_port")) {
+                config.remote_port = port;
+            }
+            if let (Some(local), false) = (spec.local_srv_address, explicit("local_srv_address")) {
+                config.local_srv_address = local;
+            }
+        }
+        // Environment variables set at deployment win over everything else
+        if let Some(var) = &opts.sshaddress_from_env {
+            match from_env(var) {
+                Some(addr) => config.sshaddress = Some(addr),
+                None if config.sshaddress.is_some() => {}
+                None => bail!(
+                    "{} is not set and there is no --sshaddress to fall back to",
+                    var
+                ),
+            }
+        }
+        if let Some(var) = &opts.remote_port_from_env {
+            if let Some(port) = from_env(var) {
+                config.remote_port = port
+                    .parse()
+                    .with_context(|| format!("{} '{}' is not a port", var, port))?;
+            }
+        }
+        #[cfg(windows)]
+        if let Some(pipe) = opts.named_pipe {
+            config.local_srv_address = format!("{}{}", crate::local::PIPE_PREFIX, pipe);
+        }
+        Ok(config)
+    }
+
+    /// Dump the settings in effect, used by --print-config
+    pub fn print(&self, acl: &AccessList, dest_acl: Option<&DestAccessList>) {
+        let opt = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+        println!("sshaddress = {}", opt(&self.sshaddress));
+        println!("mdns-service-name = {}", opt(&self.mdns_service_name));
+        println!("sshuser = {}", self.sshuser);
+        if let Some(path) = &self.agent_socket {
+            println!("agent-socket = {}", path.display());
+        }
+        if let Some(path) = &self.identity {
+            println!("identity = {}", path.display());
+        }
+        let order: Vec<String> = self
+            .auth_order
+            .iter()
+            .filter_map(|m| m.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect();
+        println!("auth-order = {}", order.join(","));
+        if let Some(path) = &self.known_hosts {
+            println!("known-hosts = {}", path.display());
+        }
+        if let Some(v) = self.strict_host_key_checking.to_possible_value() {
+            println!("strict-host-key-checking = {}", v.get_name());
+        }
+        if !self.source_addresses.is_empty() {
+            let addrs: Vec<String> = self
+                .source_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect();
+            println!("source-addresses = {}", addrs.join(","));
+        }
+        if let Some(command) = &self.proxy_command {
+            println!("proxy-command = {}", command);
+        }
+        if let Some(proxy) = &self.proxy {
+            println!("proxy = {}", proxy);
+        }
+        if self.no_proxy {
+            println!("no-proxy = true");
+        }
+        println!("handshake-timeout = {}", self.handshake_timeout);
+        println!("channel-open-timeout-ms = {}", self.channel_open_timeout_ms);
+        println!("compression = {}", self.compression);
+        for (name, algs) in [
+            ("kex", &self.kex),
+            ("hostkey-algorithms", &self.hostkey_algorithms),
+            ("cipher", &self.cipher),
+            ("mac", &self.mac),
+        ] {
+            if let Some(algs) = algs {
+                println!("{} = {}", name, algs);
+            }
+        }
+        if let Some(tos) = self.ip_tos {
+            println!("ip-tos = {:#04x}", tos);
+            println!("propagate-tos = {}", self.propagate_tos);
+        }
+        println!("control = {}", self.control);
+        if !self.port_knock.is_empty() {
+            let ports: Vec<String> = self.port_knock.iter().map(|p| p.to_string()).collect();
+            println!("port-knock = {}", ports.join(","));
+            println!("port-knock-delay-ms = {}", self.port_knock_delay_ms);
+            println!("port-knock-tcp = {}", self.port_knock_tcp);
+            println!("port-knock-wait-ms = {}", self.port_knock_wait_ms);
+        }
+        if let Some(dest_acl) = dest_acl {
+            println!("dynamic = true");
+            let rules = dest_acl.rules();
+            println!(
+                "dest-allow = {}",
+                if rules.is_empty() {
+                    "any".to_string()
+                } else {
+                    rules.join(", ")
+                }
+            );
+            if let Some(user) = &self.socks_user {
+                println!("socks-user = {}", user);
+                println!("socks-password = <redacted>");
+            }
+            if let Some(path) = &self.socks_users_file {
+                println!("socks-users-file = {}", path.display());
+            }
+            if self.socks_enable_bind {
+                println!("socks-enable-bind = true");
+            }
+        } else {
+            println!("remote-srv = {}", self.remote_srv);
+            println!("remote-port = {}", self.remote_port);
+            if !self.sni_passthrough.is_empty() {
+                let routes: Vec<String> =
+                    self.sni_passthrough.iter().map(|r| r.to_string()).collect();
+                println!("sni-passthrough = {}", routes.join(", "));
+            }
+        }
+        if let Some(command) = &self.remote_check_command {
+            println!("remote-check-command = {}", command);
+        }
+        if let Some(v) = self.preflight.and_then(|p| p.to_possible_value()) {
+            println!("preflight = {}", v.get_name());
+        }
+        if let (Some(addr), Some(server)) = (self.dns_forward, &self.dns_server) {
+            println!("dns-forward = {}", addr);
+            println!("dns-server = {}", server);
+        }
+        println!("local-srv-address = {}", self.local_srv_address);
+        println!("allow-external = {}", self.allow_external);
+        if let Some(mode) = self.unix_mode {
+            println!("unix-mode = {:04o}", mode);
+        }
+        if let Some(owner) = &self.unix_owner {
+            println!("unix-owner = {}", owner);
+        }
+        if let Some(n) = self.connection_limit_per_source_ip {
+            println!("connection-limit-per-source-ip = {}", n);
+        }
+        if let Some(limit) = self.rate_limit {
+            println!("rate-limit = {}", limit);
+            println!(
+                "rate-limit-exempt-loopback = {}",
+                self.rate_limit_exempt_loopback
+            );
+        }
+        if let Some(size) = self.max_bytes_per_conn {
+            println!("max-bytes-per-conn = {}", size.0);
+        }
+        if let Some(limit) = self.max_bytes_per_client {
+            println!("max-bytes-per-client = {}", limit);
+        }
+        if let Some(direction) = self.half_duplex {
+            if let Some(v) = direction.to_possible_value() {
+                println!("half-duplex = {}", v.get_name());
+            }
+        }
+        if let Some(size) = self.receive_window_size {
+            println!("receive-window-size = {}", size.0);
+        }
+        println!("write-timeout = {}", self.write_timeout);
+        println!("reuse-channel = {}", self.reuse_channel);
+        if let Some(n) = self.channel_burst_size {
+            println!("channel-burst-size = {}", n);
+        }
+        println!("mysql-logging = {}", self.mysql_logging);
+        let rules: Vec<String> = acl.allow_rules().iter().map(|c| c.to_string()).collect();
+        println!(
+            "allow-from = {}",
+            if rules.is_empty() {
+                "any".to_string()
+            } else {
+                rules.join(", ")
+            }
+        );
+        let rules: Vec<String> = acl.deny_rules().iter().map(|c| c.to_string()).collect();
+        println!(
+            "deny-from = {}",
+            if rules.is_empty() {
+                "none".to_string()
+            } else {
+                rules.join(", ")
+            }
+        );
+        if let Some(path) = &self.allow_from_file {
+            println!("allow-from-file = {}", path.display());
+        }
+        println!("require-tunnel-token = {}", self.require_tunnel_token);
+        if self.client_token.is_some() {
+            println!("client-token = <redacted>");
+        }
+        if let Some(path) = &self.client_token_file {
+            println!("client-token-file = {}", path.display());
+        }
+        println!("allow-env-auth = {}", self.allow_env_auth);
+        if let Some(path) = &self.audit_log {
+            println!("audit-log = {}", path.display());
+        }
+        if let Some(size) = self.audit_log_max_size {
+            println!("audit-log-max-size = {}", size.0);
+        }
+        if let Some(ms) = self.latency_sla_ms {
+            println!("latency-sla-ms = {}", ms);
+        }
+        if let Some(addr) = self.api_addr {
+            println!("api-addr = {}", addr);
+        }
+        if self.api_token.is_some() {
+            println!("api-token = <redacted>");
+        }
+        #[cfg(feature = "consul")]
+        if let Some(url) = &self.consul_url {
+            println!("consul-url = {}", url);
+        }
+        #[cfg(feature = "etcd")]
+        if let Some(url) = &self.etcd_url {
+            println!("etcd-url = {}", url);
+            println!("etcd-ttl-secs = {}", self.etcd_ttl_secs);
+        }
+        #[cfg(any(feature = "consul", feature = "etcd"))]
+        if let Some(name) = &self.service_name {
+            println!("service-name = {}", name);
+        }
+        if let Some(n) = self.forwarder_pool {
+            println!("forwarder-pool = {}", n);
+        }
+        if let Some(v) = self.sandbox.and_then(|m| m.to_possible_value()) {
+            println!("sandbox = {}", v.get_name());
+        }
+        println!("no-core-dumps = {}", self.no_core_dumps);
+        if let Some(user) = &self.user {
+            println!("user = {}", user);
+        }
+        if let Some(group) = &self.group {
+            println!("group = {}", group);
+        }
+        println!("allow-root = {}", self.allow_root);
+        println!("refuse-root = {}", self.refuse_root);
+        println!("quiet = {}", self.quiet);
+    }
+}
+
+/// The value of an environment variable, an empty one counts as unset
+fn from_env(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|v| !v.is_empty())
+}
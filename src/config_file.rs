@@ -0,0 +1,734 @@
+use anyhow::{anyhow, bail, Context};
+use clap::{ArgMatches, Command};
+use log::debug;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Names of the config file in each directory searched, in order
+const FILE_NAMES: [&str; 3] = ["config.toml", "config.yaml", "config.yml"];
+
+/// Options that carry a secret, with where to give it instead. Config
+/// files are plain text, so they may not set these.
+const SECRET_OPTIONS: [(&str, &str); 3] = [
+    ("socks_password", "--socks-users-file"),
+    ("client_token", "--client-token-file"),
+    ("api_token", "the command line"),
+];
+
+/// Where a config file is looked for without --config, the first one found
+/// is used
+fn search_path() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        dirs.push(PathBuf::from(dir).join("ssh2fwd"));
+    }
+    if let Some(home) = std::env::var_os("HOME") {
+        dirs.push(PathBuf::from(home).join(".config").join("ssh2fwd"));
+    }
+    #[cfg(windows)]
+    if let Some(appdata) = std::env::var_os("APPDATA") {
+        dirs.push(PathBuf::from(appdata).join("ssh2fwd"));
+    }
+    #[cfg(unix)]
+    dirs.push(PathBuf::from("/etc/ssh2fwd"));
+    dirs.iter()
+        .flat_map(|dir| FILE_NAMES.iter().map(move |name| dir.join(name)))
+        .collect()
+}
+
+/// Syntax of a config file, told by its extension
+#[derive(Clone, Copy)]
+enum Format {
+    /// Keys are the long option names, with '-' or '_'
+    Toml,
+    /// Keys are the long option names in camelCase
+    Yaml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Toml,
+        }
+    }
+
+    fn parse(self, text: &str) -> anyhow::Result<Vec<(String, Value)>> {
+        match self {
+            Format::Toml => parse(text),
+            Format::Yaml => parse_yaml(text),
+        }
+    }
+
+    /// Clap id of the option a key stands for
+    fn option_id(self, key: &str) -> String {
+        match self {
+            Format::Toml => key.replace('-', "_"),
+            Format::Yaml => {
+                let mut id = String::with_capacity(key.len() + 4);
+                for c in key.chars() {
+                    if c.is_ascii_uppercase() {
+                        id.push('_');
+                    }
+                    id.push(c.to_ascii_lowercase());
+                }
+                id
+            }
+        }
+    }
+}
+
+/// Parse the command line with the settings of the config file filled in
+/// for the options it doesn't give
+pub fn get_matches(cmd: Command) -> anyhow::Result<ArgMatches> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    // Only to find --config, the file may provide required options
+    let given = match cmd.clone().ignore_errors(true).try_get_matches_from(&args) {
+        Ok(given) => given,
+        Err(_) => return Ok(cmd.get_matches_from(args)),
+    };
+    let path = match given.get_one::<PathBuf>("config") {
+        Some(path) => Some(path.clone()),
+        None if given.get_flag("no_system_config") => None,
+        None => search_path().into_iter().find(|p| p.is_file()),
+    };
+    let path = match path {
+        Some(path) => path,
+        None => {
+            debug!("No config file found");
+            return Ok(cmd.get_matches_from(args));
+        }
+    };
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("reading config file {}", path.display()))?;
+    let format = Format::of(&path);
+    let settings = format
+        .parse(&text)
+        .with_context(|| format!("in config file {}", path.display()))?;
+    let from_file = to_args(&cmd, &given, format, settings)
+        .with_context(|| format!("in config file {}", path.display()))?;
+    debug!("Loaded config file {}", path.display());
+    // Right after the program name, so a subcommand keeps its arguments
+    let args: Vec<OsString> = args
+        .iter()
+        .take(1)
+        .cloned()
+        .chain(from_file)
+        .chain(args.iter().skip(1).cloned())
+        .collect();
+    match cmd.try_get_matches_from(args) {
+        Ok(matches) => Ok(matches),
+        Err(e) => {
+            eprintln!(
+                "The settings of {} were added to the command line.",
+                path.display()
+            );
+            e.exit()
+        }
+    }
+}
+
+/// Turn `key = value` settings into command line arguments. Options given
+/// on the command line are left as they are.
+fn to_args(
+    cmd: &Command,
+    given: &ArgMatches,
+    format: Format,
+    settings: Vec<(String, Value)>,
+) -> anyhow::Result<Vec<OsString>> {
+    let mut args = vec![];
+    for (key, value) in settings {
+        let id = format.option_id(&key);
+        let arg = cmd
+            .get_arguments()
+            .find(|a| a.get_id() == id.as_str() && a.get_long().is_some())
+            .ok_or_else(|| anyhow!("unknown setting '{}'", key))?;
+        let long = arg.get_long().unwrap_or_default();
+        if matches!(id.as_str(), "config" | "no_system_config") {
+            bail!("'{}' can only be given on the command line", key);
+        }
+        if let Some((_, instead)) = SECRET_OPTIONS.iter().find(|(secret, _)| *secret == id) {
+            bail!(
+                "'{}' is a secret and config files aren't encrypted, use {} instead",
+                key,
+                instead
+            );
+        }
+        if given.value_source(&id) == Some(clap::parser::ValueSource::CommandLine) {
+            debug!("--{} from the command line overrides the config file", long);
+            continue;
+        }
+        if !arg.get_action().takes_values() {
+            match value {
+                Value::Bool(true) => args.push(format!("--{}", long).into()),
+                Value::Bool(false) => {}
+                _ => bail!("'{}' must be true or false", key),
+            }
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            args.push(format!("--{}={}", long, value.to_arg(&key)?).into());
+        }
+    }
+    Ok(args)
+}
+
+/// The values the config file may hold, a subset of TOML
+enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn to_arg(&self, key: &str) -> anyhow::Result<String> {
+        Ok(match self {
+            Value::Str(s) => s.clone(),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Array(_) => bail!("'{}' can't hold nested arrays", key),
+        })
+    }
+}
+
+/// Top level `key = value` lines of a TOML file, with strings, numbers,
+/// booleans and arrays of them. Tables aren't needed, every setting is an
+/// option of the command line.
+fn parse(text: &str) -> anyhow::Result<Vec<(String, Value)>> {
+    let mut parser = Parser { text, pos: 0 };
+    parser
+        .settings()
+        .map_err(|e| anyhow!("line {}: {}", parser.line(), e))
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.text[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn line(&self) -> usize {
+        self.text[..self.pos].matches('\n').count() + 1
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Spaces and tabs, plus newlines and comments with `newlines`
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\r' | '\n' if newlines => self.pos += 1,
+                '#' if newlines => {
+                    self.pos += self.rest().find('\n').unwrap_or(self.rest().len());
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn settings(&mut self) -> anyhow::Result<Vec<(String, Value)>> {
+        let mut settings = vec![];
+        loop {
+            self.skip_blank(true);
+            let key = match self.peek() {
+                None => return Ok(settings),
+                Some('[') => bail!("tables aren't supported, settings go at the top level"),
+                Some('"') => self.basic_string()?,
+                Some(_) => {
+                    let len = self
+                        .rest()
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+                        .unwrap_or(self.rest().len());
+                    if len == 0 {
+                        bail!("expected a setting name");
+                    }
+                    let key = self.rest()[..len].to_string();
+                    self.pos += len;
+                    key
+                }
+            };
+            self.skip_blank(false);
+            if !self.eat('=') {
+                bail!("expected '=' after '{}'", key);
+            }
+            self.skip_blank(false);
+            let value = self.value()?;
+            self.skip_blank(false);
+            if self.peek() == Some('#') {
+                self.skip_blank(true);
+            } else if !(self.eat('\n') || self.rest().starts_with("\r\n") || self.peek().is_none())
+            {
+                bail!("unexpected text after the value of '{}'", key);
+            }
+            if settings.iter().any(|(k, _)| *k == key) {
+                bail!("'{}' is set twice", key);
+            }
+            settings.push((key, value));
+        }
+    }
+
+    fn value(&mut self) -> anyhow::Result<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::Str(self.basic_string()?)),
+            Some('\'') => {
+                self.pos += 1;
+                let end = self
+                    .rest()
+                    .find(['\'', '\n'])
+                    .filter(|&i| self.rest()[i..].starts_with('\''))
+                    .ok_or_else(|| anyhow!("unterminated string"))?;
+                let s = self.rest()[..end].to_string();
+                self.pos += end + 1;
+                Ok(Value::Str(s))
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut values = vec![];
+                loop {
+                    self.skip_blank(true);
+                    if self.eat(']') {
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.skip_blank(true);
+                    if !self.eat(',') && self.peek() != Some(']') {
+                        bail!("expected ',' or ']' in array");
+                    }
+                }
+            }
+            _ => {
+                let len = self
+                    .rest()
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || "+-._".contains(c)))
+                    .unwrap_or(self.rest().len());
+                let word = &self.rest()[..len];
+                let value = match word {
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => {
+                        let number = word.replace('_', "");
+                        if let Ok(i) = number.parse() {
+                            Value::Int(i)
+                        } else if let Ok(f) = number.parse() {
+                            Value::Float(f)
+                        } else {
+                            bail!("invalid value '{}', strings need quotes", word)
+                        }
+                    }
+                };
+                self.pos += len;
+                Ok(value)
+            }
+        }
+    }
+
+    /// A "..." string with the usual escapes
+    fn basic_string(&mut self) -> anyhow::Result<String> {
+        self.pos += 1;
+        let mut s = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(s);
+                }
+                '\n' => break,
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| anyhow!("invalid escape \\u{}", hex))?;
+                        s.push(c);
+                    }
+                    other => bail!("invalid escape \\{}", other.unwrap_or(' ')),
+                },
+                c => s.push(c),
+            }
+        }
+        bail!("unterminated string")
+    }
+}
+
+/// Top level `key: value` lines of a YAML file, with scalars and lists of
+/// them, as `- item` lines or `[a, b]`. Anchors, multi-line strings and
+/// nested mappings aren't needed for options of the command line, and are
+/// an error instead of being read as something else.
+fn parse_yaml(text: &str) -> anyhow::Result<Vec<(String, Value)>> {
+    let mut settings: Vec<(String, Value)> = vec![];
+    // A key without a value on its line, the items of its list may follow
+    let mut open: Option<(usize, String, Vec<Value>)> = None;
+    let set = |settings: &mut Vec<(String, Value)>, line, key: String, value: Option<Value>| {
+        if settings.iter().any(|(k, _)| *k == key) {
+            bail!("line {}: '{}' is set twice", line, key);
+        }
+        // null leaves the option unset
+        if let Some(value) = value {
+            settings.push((key, value));
+        }
+        Ok(())
+    };
+    let mut documents = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line_error = |e: anyhow::Error| anyhow!("line {}: {}", i + 1, e);
+        let line = strip_yaml_comment(line).trim_end();
+        if line.trim_start().is_empty() {
+            continue;
+        }
+        if line == "---" || line.starts_with("--- ") {
+            documents += 1;
+            if documents > 1 || !settings.is_empty() || open.is_some() {
+                bail!("line {}: only one document is supported", i + 1);
+            }
+            if line != "---" {
+                bail!("line {}: the settings go on the lines after '---'", i + 1);
+            }
+            continue;
+        }
+        if line.starts_with('%') {
+            bail!("line {}: directives aren't supported", i + 1);
+        }
+        if line == "..." {
+            break;
+        }
+        let item = line
+            .trim_start()
+            .strip_prefix('-')
+            .filter(|rest| rest.is_empty() || rest.starts_with(' '));
+        if let Some(item) = item {
+            match &mut open {
+                Some((_, _, items)) => items.push(
+                    yaml_scalar(item.trim())
+                        .map_err(line_error)?
+                        .ok_or_else(|| anyhow!("line {}: empty list item", i + 1))?,
+                ),
+                None => bail!("line {}: list item without a setting", i + 1),
+            }
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            if line.contains(": ") || line.ends_with(':') {
+                bail!(
+                    "line {}: nested settings aren't supported, settings go at the top level",
+                    i + 1
+                );
+            }
+            bail!(
+                "line {}: multi-line values aren't supported, put the value on one line",
+                i + 1
+            );
+        }
+        if let Some((line, key, items)) = open.take() {
+            let value = (!items.is_empty()).then_some(Value::Array(items));
+            set(&mut settings, line, key, value)?;
+        }
+        let (key, value) = line
+            .split_once(": ")
+            .or_else(|| line.strip_suffix(':').map(|key| (key, "")))
+            .ok_or_else(|| anyhow!("line {}: expected 'key: value'", i + 1))?;
+        let key = key.trim().to_string();
+        if key.starts_with(['"', '\'']) {
+            bail!("line {}: quoted keys aren't supported", i + 1);
+        }
+        if !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!(
+                "line {}: '{}' isn't a setting name, anchors, tags and merge keys aren't supported",
+                i + 1,
+                key
+            );
+        }
+        let value = value.trim();
+        if value.is_empty() {
+            open = Some((i + 1, key, vec![]));
+            continue;
+        }
+        let value = match value.strip_prefix('[') {
+            Some(list) => {
+                let list = list
+                    .strip_suffix(']')
+                    .ok_or_else(|| anyhow!("line {}: unterminated list", i + 1))?;
+                let mut items = vec![];
+                for item in split_flow_list(list).map_err(line_error)? {
+                    items.extend(yaml_scalar(item).map_err(line_error)?);
+                }
+                Some(Value::Array(items))
+            }
+            None => yaml_scalar(value).map_err(line_error)?,
+        };
+        set(&mut settings, i + 1, key, value)?;
+    }
+    if let Some((line, key, items)) = open {
+        let value = (!items.is_empty()).then_some(Value::Array(items));
+        set(&mut settings, line, key, value)?;
+    }
+    Ok(settings)
+}
+
+/// The line without a `#` comment, which starts the line or follows a
+/// space outside of quotes
+fn strip_yaml_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') if prev == ' ' || prev == '\t' => return &line[..i],
+            (None, '"' | '\'') => quote = Some(c),
+            (Some('"'), '\\') => {}
+            (Some(q), c) if c == q && prev != '\\' => quote = None,
+            _ => {}
+        }
+        prev = c;
+    }
+    line
+}
+
+/// Items of a `[a, b]` list, commas inside quotes don't count
+fn split_flow_list(list: &str) -> anyhow::Result<Vec<&str>> {
+    let mut items = vec![];
+    let (mut quote, mut start, mut prev) = (None, 0, ' ');
+    for (i, c) in list.char_indices() {
+        match (quote, c) {
+            (None, ',') => {
+                items.push(list[start..i].trim());
+                start = i + 1;
+            }
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[' | '{') => bail!("nested lists and mappings aren't supported"),
+            (Some(q), c) if c == q && prev != '\\' => quote = None,
+            _ => {}
+        }
+        prev = c;
+    }
+    // After a trailing comma there is nothing left
+    let last = list[start..].trim();
+    if !last.is_empty() {
+        items.push(last);
+    }
+    Ok(items)
+}
+
+/// A plain, single or double quoted scalar, None for null
+fn yaml_scalar(s: &str) -> anyhow::Result<Option<Value>> {
+    if s.starts_with('"') {
+        let mut parser = Parser { text: s, pos: 0 };
+        let value = parser.basic_string()?;
+        if !parser.rest().is_empty() {
+            bail!("unexpected text after the string");
+        }
+        return Ok(Some(Value::Str(value)));
+    }
+    if let Some(quoted) = s.strip_prefix('\'') {
+        let value = quoted
+            .strip_suffix('\'')
+            .filter(|v| !v.replace("''", "").contains('\''))
+            .ok_or_else(|| anyhow!("unterminated string"))?;
+        return Ok(Some(Value::Str(value.replace("''", "'"))));
+    }
+    // Flow collections, block scalars, anchors, aliases, tags and the
+    // reserved indicators
+    if s.starts_with(['[', '{', '|', '>', '&', '*', '!', '%', '@', '`', '?']) {
+        bail!("'{}' isn't supported, use a plain or quoted value", s);
+    }
+    if s.contains(": ") || s.ends_with(':') {
+        bail!("'{}' isn't supported, quote a value with ': ' in it", s);
+    }
+    Ok(Some(match s {
+        "~" | "null" | "Null" | "NULL" => return Ok(None),
+        "true" | "True" | "TRUE" => Value::Bool(true),
+        "false" | "False" | "FALSE" => Value::Bool(false),
+        _ => {
+            if let Ok(i) = s.parse() {
+                Value::Int(i)
+            } else if let Some(f) = s.parse().ok().filter(|_| s.contains(['.', 'e', 'E'])) {
+                Value::Float(f)
+            } else {
+                Value::Str(s.to_string())
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// Arguments the settings of a config file in `format` turn into
+    fn args_of(format: Format, text: &str) -> anyhow::Result<Vec<String>> {
+        let cmd = crate::Opts::command();
+        let given = cmd
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(["ssh2fwd"])
+            .unwrap();
+        let args = to_args(&cmd, &given, format, format.parse(text)?)?;
+        Ok(args.into_iter().map(|a| a.into_string().unwrap()).collect())
+    }
+
+    #[test]
+    fn secrets_are_rejected() {
+        for (format, text) in [
+            (Format::Toml, "socks-password = \"hunter2\""),
+            (Format::Toml, "socks5-pass = \"hunter2\""),
+            (Format::Toml, "client_token = \"hunter2\""),
+            (Format::Toml, "api-token = \"hunter2\""),
+            (Format::Yaml, "socksPassword: hunter2"),
+            (Format::Yaml, "clientToken: hunter2"),
+            (Format::Yaml, "apiToken: hunter2"),
+        ] {
+            let e = match args_of(format, text) {
+                Ok(args) => panic!("'{}' was accepted as {:?}", text, args),
+                Err(e) => e.to_string(),
+            };
+            assert!(!e.contains("hunter2"), "{}", e);
+        }
+        let e = args_of(Format::Toml, "client-token = \"x\"").unwrap_err();
+        assert!(e.to_string().contains("--client-token-file"), "{}", e);
+    }
+
+    #[test]
+    fn files_holding_secrets_are_fine() {
+        assert_eq!(
+            args_of(Format::Toml, "client-token-file = \"/run/token\"").unwrap(),
+            ["--client-token-file=/run/token"]
+        );
+    }
+
+    #[test]
+    fn both_formats_give_the_same_arguments() {
+        let toml = r#"
+# The SSH server
+sshaddress = "example.com:22"
+sshuser = 'deploy'
+remote-port = 5432
+port_knock = [1234, 5678]
+accept-new = true
+port-knock-tcp = false
+remote-check-command = "nc -z localhost 5432 # inline"
+"#;
+        let yaml = r#"
+---
+# The SSH server
+sshaddress: example.com:22
+sshuser: 'deploy'
+remotePort: 5432
+portKnock:
+  - 1234
+  - 5678
+acceptNew: true
+portKnockTcp: false
+remoteCheckCommand: "nc -z localhost 5432 # inline"
+knownHosts: ~
+"#;
+        let expected = [
+            "--sshaddress=example.com:22",
+            "--sshuser=deploy",
+            "--remote-port=5432",
+            "--port-knock=1234",
+            "--port-knock=5678",
+            "--accept-new",
+            "--remote-check-command=nc -z localhost 5432 # inline",
+        ];
+        assert_eq!(args_of(Format::Toml, toml).unwrap(), expected);
+        assert_eq!(args_of(Format::Yaml, yaml).unwrap(), expected);
+        assert_eq!(
+            args_of(Format::Yaml, "portKnock: [1234, 5678]").unwrap(),
+            ["--port-knock=1234", "--port-knock=5678"]
+        );
+    }
+
+    #[test]
+    fn both_formats_reject_the_same_settings() {
+        for (toml, yaml) in [
+            ("no-such-option = 1", "noSuchOption: 1"),
+            ("config = \"other.toml\"", "config: other.yaml"),
+            ("accept-new = \"yes\"", "acceptNew: \"yes\""),
+            ("sshuser = \"a\"\nsshuser = \"b\"", "sshuser: a\nsshuser: b"),
+        ] {
+            assert!(args_of(Format::Toml, toml).is_err(), "{}", toml);
+            assert!(args_of(Format::Yaml, yaml).is_err(), "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn unsupported_toml_fails() {
+        for text in [
+            "[server]\nsshuser = \"deploy\"",
+            "sshuser = deploy",
+            "sshuser = \"deploy",
+            "sshuser = \"deploy\" extra",
+            "sshuser",
+            "port-knock = [1234 5678]",
+        ] {
+            assert!(parse(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+
+    /// YAML this parser doesn't read fails instead of turning into other
+    /// settings
+    #[test]
+    fn unsupported_yaml_fails() {
+        for text in [
+            // Anchors, aliases and merge keys
+            "sshuser: &user deploy",
+            "sshuser: *user",
+            "<<: *defaults",
+            "&user sshuser: deploy",
+            "portKnock:\n  - *knock",
+            // Flow sequences and mappings where a scalar goes
+            "portKnock:\n  - [1234, 5678]",
+            "portKnock: [[1234], 5678]",
+            "portKnock: [1234,\n  5678]",
+            "sshuser: {name: deploy}",
+            // Multi-line scalars
+            "remoteCheckCommand: |\n  nc -z localhost 5432",
+            "remoteCheckCommand: >\n  nc -z\n  localhost 5432",
+            "remoteCheckCommand: nc -z\n  localhost 5432",
+            "remoteCheckCommand: \"nc -z\n  localhost 5432\"",
+            // Quoted keys
+            "\"sshuser\": deploy",
+            "'sshuser': deploy",
+            // Nested mappings, tags, several documents
+            "server:\n  sshuser: deploy",
+            "sshuser: !!str deploy",
+            "sshuser: a: b",
+            "sshuser: deploy\n---\nsshuser: other",
+            "%YAML 1.2\n---\nsshuser: deploy",
+        ] {
+            assert!(parse_yaml(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use log::debug;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Where a forwarded connection is in its life
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ConnectionState {
+    /// Accepted, tokens and SOCKS negotiation still to come
+    Accepted,
+    /// Waiting for the SSH server to open the channel
+    ChannelOpening,
+    /// Copying data both ways
+    Forwarding,
+    /// The copy loops are done, the channel is being closed or kept
+    Closing,
+    Closed,
+}
+
+impl ConnectionState {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectionState::Accepted => "accepted",
+            ConnectionState::ChannelOpening => "channel_opening",
+            ConnectionState::Forwarding => "forwarding",
+            ConnectionState::Closing => "closing",
+            ConnectionState::Closed => "closed",
+        }
+    }
+
+    /// The state stored in a `SharedState`
+    pub fn load(shared: &AtomicU8) -> Self {
+        match shared.load(Ordering::Acquire) {
+            0 => ConnectionState::Accepted,
+            1 => ConnectionState::ChannelOpening,
+            2 => ConnectionState::Forwarding,
+            3 => ConnectionState::Closing,
+            _ => ConnectionState::Closed,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The current state of a connection, for the API to look at
+pub type SharedState = Arc<AtomicU8>;
+
+/// The state of one connection, owned by its task. Every change is logged at
+/// debug level with the time since the connection was accepted; dropping it
+/// with the task is the change to closed.
+pub struct StateMachine {
+    conn_id: u64,
+    accepted: Instant,
+    state: SharedState,
+}
+
+impl StateMachine {
+    pub fn new(conn_id: u64) -> Self {
+        debug!("Connection #{}: {}", conn_id, ConnectionState::Accepted);
+        StateMachine {
+            conn_id,
+            accepted: Instant::now(),
+            state: Arc::new(AtomicU8::new(ConnectionState::Accepted as u8)),
+        }
+    }
+
+    pub fn shared(&self) -> SharedState {
+        self.state.clone()
+    }
+
+    pub fn set(&self, state: ConnectionState) {
+        let old = ConnectionState::load(&self.state);
+        if old == state {
+            return;
+        }
+        self.state.store(state as u8, Ordering::Release);
+        debug!(
+            "Connection #{}: {} -> {} after {:?}",
+            self.conn_id,
+            old,
+            state,
+            self.accepted.elapsed()
+        );
+    }
+}
+
+impl Drop for StateMachine {
+    fn drop(&mut self) {
+        self.set(ConnectionState::Closed);
+    }
+}
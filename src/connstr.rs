@@ -0,0 +1,178 @@
+use crate::addr::{join_host_port, split_host_port};
+use anyhow::{anyhow, bail};
+
+/// A full tunnel description parsed from a connection string
+#[derive(Debug, PartialEq)]
+pub struct TunnelSpec {
+    pub sshuser: Option<String>,
+    /// SSH server as host:port, the port defaults to 22
+    pub sshaddress: String,
+    pub remote_srv: Option<String>,
+    pub remote_port: Option<u16>,
+    pub local_srv_address: Option<String>,
+}
+
+/// Parser for `[user@]sshhost[:port][/remote-host:remote-port[/local-host:local-port]]`
+pub struct ConnectionString;
+
+impl ConnectionString {
+    pub fn parse(s: &str) -> anyhow::Result<TunnelSpec> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.len() > 3 {
+            bail!(
+                "connection string '{}' has more than 3 '/' separated parts",
+                s
+            );
+        }
+
+        let (sshuser, ssh) = match parts[0].rsplit_once('@') {
+            Some(("", _)) => bail!("empty user name in connection string '{}'", s),
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, parts[0]),
+        };
+        let (ssh_host, ssh_port) =
+            split_host_port(ssh).map_err(|e| anyhow!("SSH server part: {}", e))?;
+
+        let (remote_srv, remote_port) = match parts.get(1) {
+            Some(remote) => {
+                let (host, port) =
+                    split_host_port(remote).map_err(|e| anyhow!("remote part: {}", e))?;
+                let port = port.ok_or_else(|| anyhow!("remote part '{}' has no port", remote))?;
+                (Some(host), Some(port))
+            }
+            None => (None, None),
+        };
+
+        let local_srv_address = match parts.get(2) {
+            Some(local) => {
+                let (host, port) =
+                    split_host_port(local).map_err(|e| anyhow!("local part: {}", e))?;
+                let port = port.ok_or_else(|| anyhow!("local part '{}' has no port", local))?;
+                Some(join_host_port(&host, port))
+            }
+            None => None,
+        };
+
+        Ok(TunnelSpec {
+            sshuser,
+            sshaddress: join_host_port(&ssh_host, ssh_port.unwrap_or(22)),
+            remote_srv,
+            remote_port,
+            local_srv_address,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(
+        sshuser: Option<&str>,
+        sshaddress: &str,
+        remote: Option<(&str, u16)>,
+        local: Option<&str>,
+    ) -> TunnelSpec {
+        TunnelSpec {
+            sshuser: sshuser.map(str::to_string),
+            sshaddress: sshaddress.to_string(),
+            remote_srv: remote.map(|(host, _)| host.to_string()),
+            remote_port: remote.map(|(_, port)| port),
+            local_srv_address: local.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn host_only_defaults_to_port_22() {
+        assert_eq!(
+            ConnectionString::parse("jump").unwrap(),
+            spec(None, "jump:22", None, None)
+        );
+    }
+
+    #[test]
+    fn user_and_port() {
+        assert_eq!(
+            ConnectionString::parse("me@jump:2222").unwrap(),
+            spec(Some("me"), "jump:2222", None, None)
+        );
+    }
+
+    #[test]
+    fn user_may_contain_at() {
+        assert_eq!(
+            ConnectionString::parse("me@corp@jump").unwrap(),
+            spec(Some("me@corp"), "jump:22", None, None)
+        );
+    }
+
+    #[test]
+    fn all_three_parts() {
+        assert_eq!(
+            ConnectionString::parse("me@jump:2222/db.internal:5432/127.0.0.1:15432").unwrap(),
+            spec(
+                Some("me"),
+                "jump:2222",
+                Some(("db.internal", 5432)),
+                Some("127.0.0.1:15432")
+            )
+        );
+    }
+
+    #[test]
+    fn ipv6_in_brackets() {
+        assert_eq!(
+            ConnectionString::parse("me@[2001:db8::1]:2222/[fd00::5]:80/[::1]:8080").unwrap(),
+            spec(
+                Some("me"),
+                "[2001:db8::1]:2222",
+                Some(("fd00::5", 80)),
+                Some("[::1]:8080")
+            )
+        );
+        assert_eq!(
+            ConnectionString::parse("[2001:db8::1]").unwrap(),
+            spec(None, "[2001:db8::1]:22", None, None)
+        );
+    }
+
+    #[test]
+    fn bare_ipv6_takes_no_port() {
+        assert_eq!(
+            ConnectionString::parse("2001:db8::1").unwrap(),
+            spec(None, "[2001:db8::1]:22", None, None)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_strings() {
+        for s in [
+            "",
+            "@jump",
+            "me@",
+            "jump:",
+            "jump:port",
+            "jump:65536",
+            "jump/db",
+            "jump/db:5432/localhost",
+            "jump/db:5432/localhost:1/extra",
+            "[2001:db8::1",
+            "[2001:db8::1]2222",
+            // There are no query options, they don't pass as a port
+            "jump:22?compression=yes",
+            "jump/db:5432?timeout=5",
+        ] {
+            assert!(ConnectionString::parse(s).is_err(), "accepted '{}'", s);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_part() {
+        let e = ConnectionString::parse("jump/db").unwrap_err().to_string();
+        assert!(e.contains("remote part 'db' has no port"), "{}", e);
+        let e = ConnectionString::parse("jump/db:1/lo:x")
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("local part:"), "{}", e);
+    }
+}
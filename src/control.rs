@@ -0,0 +1,330 @@
+use crate::acl::{AccessList, Verdict};
+use crate::audit::{json_field, json_string};
+use crate::local::LocalListener;
+use crate::multi_path::MultiPath;
+use crate::transport::SshTransport;
+use crate::{
+    classify_io_error, close_channel, get_channels_for_remote_server, report_io_error,
+    write_channel, Direction, ErrorAction, ACCEPT_RETRY_DELAY, IDLE_WAIT, NEXT_CONNECTION_ID,
+};
+use anyhow::{bail, Context};
+use futures::executor::block_on;
+use log::{debug, error, info, warn};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+
+/// Longest message on the control socket, requests and replies are a few
+/// dozen bytes
+const MAX_MESSAGE: u32 = 4096;
+
+/// Control socket of the master for `target` (user@host:port). Root's go to
+/// /run, everybody else's to their runtime directory.
+pub fn socket_path(target: &str) -> PathBuf {
+    let dir = if crate::privdrop::running_as_root() {
+        PathBuf::from("/run")
+    } else {
+        std::env::var_os("XDG_RUNTIME_DIR")
+            .filter(|d| !d.is_empty())
+            .map_or_else(std::env::temp_dir, PathBuf::from)
+    };
+    let hash: String = crate::fingerprint::sha256(target.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    dir.join(format!("ssh2fwd-ctrl-{}.sock", hash))
+}
+
+/// One message: its length as 4 bytes big endian, then a JSON object
+async fn write_message<S: AsyncWrite + Unpin>(stream: &mut S, json: &str) -> io::Result<()> {
+    stream.write_all(&(json.len() as u32).to_be_bytes()).await?;
+    stream.write_all(json.as_bytes()).await?;
+    stream.flush().await
+}
+
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<String> {
+    let len = stream.read_u32().await?;
+    if len > MAX_MESSAGE {
+        bail!("control message of {} bytes", len);
+    }
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Holds the SSH session for the other ssh2fwd processes to the same
+/// server. Each of their connections comes in on its own connection to the
+/// control socket, asks for a channel with `{"op":"open","host":..,"port":..}`,
+/// gets `{"ok":true}` or `{"ok":false,"error":..}` back and then carries the
+/// data of the channel.
+pub struct ControlMaster {
+    path: PathBuf,
+}
+
+impl ControlMaster {
+    /// Listen on the control socket in the background. A socket file nobody
+    /// answers on was left behind by a master that is gone and is replaced.
+    pub fn spawn<T: SshTransport>(
+        path: &Path,
+        sessions: Arc<MultiPath<T>>,
+    ) -> anyhow::Result<ControlMaster> {
+        if std::os::unix::net::UnixStream::connect(path).is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("binding control socket {}", path.display()))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        info!(
+            "Control master, other processes to this server share the session through {}",
+            path.display()
+        );
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Accepting a control connection failed, retrying: {}", e);
+                        sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                tokio::spawn(serve(stream, sessions.clone()));
+            }
+        });
+        Ok(ControlMaster {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlMaster {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Open the channel a slave asks for and copy between it and the control
+/// connection until either side is done
+async fn serve<T: SshTransport>(mut stream: UnixStream, sessions: Arc<MultiPath<T>>) {
+    let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let request = match read_message(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("Connection #{}: bad control request: {}", conn_id, e);
+            return;
+        }
+    };
+    let target = match json_field(&request, "op").as_deref() {
+        Some("open") => json_field(&request, "host")
+            .zip(json_field(&request, "port").and_then(|p| p.parse::<u16>().ok())),
+        _ => None,
+    };
+    let Some((host, port)) = target else {
+        let _ = write_message(&mut stream, "{\"ok\":false,\"error\":\"unknown request\"}").await;
+        return;
+    };
+    info!("Connection #{}: from a control slave", conn_id);
+    let (_, session) = sessions
+        .pick()
+        .expect("the first SSH session is never taken out");
+    let (mut channel, mut rxchan, mut txchan) =
+        match get_channels_for_remote_server(&host, port, &session, conn_id).await {
+            Ok(channels) => channels,
+            Err(e) => {
+                error!(
+                    "Connection #{}: no channel to {}:{} for a control slave: {}",
+                    conn_id, host, port, e
+                );
+                sessions.report_failure(&session);
+                let reply = format!(
+                    "{{\"ok\":false,\"error\":{}}}",
+                    json_string(&format!("{:#}", e))
+                );
+                let _ = write_message(&mut stream, &reply).await;
+                return;
+            }
+        };
+    if write_message(&mut stream, "{\"ok\":true}").await.is_err() {
+        close_channel(session, channel, conn_id).await;
+        return;
+    }
+
+    let (mut local_rd, mut local_wr) = stream.into_split();
+    let local_eof = Arc::new(AtomicBool::new(false));
+    let local_eof_rd = local_eof.clone();
+    let session_rd = session.clone();
+    let t1 = spawn_blocking(move || {
+        #[cfg(target_os = "linux")]
+        crate::sandbox::enforce_current_thread();
+        let mut buf = vec![0; 1024];
+        loop {
+            match block_on(local_rd.read(&mut buf)) {
+                Ok(0) => {
+                    local_eof_rd.store(true, Ordering::Release);
+                    break;
+                }
+                Ok(n) => {
+                    if let Err(e) = write_channel(&session_rd, &mut txchan, &buf[..n], None, None) {
+                        report_io_error(conn_id, Direction::ChannelWrite(n), &e);
+                        break;
+                    }
+                }
+                Err(e) => match classify_io_error(&e) {
+                    ErrorAction::Continue => continue,
+                    _ => {
+                        report_io_error(conn_id, Direction::LocalRead, &e);
+                        break;
+                    }
+                },
+            }
+        }
+    });
+    let session_wr = session.clone();
+    let t2 = spawn_blocking(move || {
+        #[cfg(target_os = "linux")]
+        crate::sandbox::enforce_current_thread();
+        let mut buf = vec![0; 1024];
+        let mut eof_sent = false;
+        loop {
+            // The slave is done sending, the answer still comes back
+            if !eof_sent && local_eof.load(Ordering::Acquire) {
+                if let Err(e) = session_wr.send_eof(&mut channel) {
+                    debug!("Connection #{}: sending EOF on the channel: {}", conn_id, e);
+                }
+                eof_sent = true;
+            }
+            if session_wr.lost().is_lost() {
+                let _ = block_on(local_wr.shutdown());
+                break;
+            }
+            let seen = session_wr.data_generation();
+            match rxchan.read(&mut buf) {
+                Ok(0) if !session_wr.channel_eof(&channel) => {
+                    session_wr.wait_for_data(seen, IDLE_WAIT)
+                }
+                Ok(0) => {
+                    let _ = block_on(async {
+                        local_wr.flush().await?;
+                        local_wr.shutdown().await
+                    });
+                    break;
+                }
+                Ok(n) => {
+                    session_wr.data_read();
+                    if let Err(e) = block_on(local_wr.write_all(&buf[..n])) {
+                        report_io_error(conn_id, Direction::LocalWrite(n), &e);
+                        break;
+                    }
+                }
+                Err(e) => match classify_io_error(&e) {
+                    ErrorAction::Continue => {
+                        if session_wr.data_generation() != seen {
+                            session_wr.data_read();
+                        }
+                        session_wr.wait_for_data(seen, IDLE_WAIT);
+                    }
+                    _ => {
+                        report_io_error(conn_id, Direction::ChannelRead, &e);
+                        break;
+                    }
+                },
+            }
+        }
+        channel
+    });
+    let (_, channel) = tokio::join!(t1, t2);
+    if let Ok(channel) = channel {
+        close_channel(session, channel, conn_id).await;
+    }
+}
+
+/// A process that found a master for its server and opens its channels
+/// through it instead of logging in itself
+pub struct ControlSlave {
+    path: PathBuf,
+}
+
+impl ControlSlave {
+    /// The slave of the master listening on `path`, if there is one
+    pub async fn connect(path: &Path) -> Option<ControlSlave> {
+        UnixStream::connect(path).await.ok()?;
+        Some(ControlSlave {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// A connection to the master carrying a new channel to `host`:`port`
+    pub async fn open(&self, host: &str, port: u16) -> anyhow::Result<UnixStream> {
+        let mut stream = UnixStream::connect(&self.path)
+            .await
+            .with_context(|| format!("connecting to control socket {}", self.path.display()))?;
+        let request = format!(
+            "{{\"op\":\"open\",\"host\":{},\"port\":{}}}",
+            json_string(host),
+            port
+        );
+        write_message(&mut stream, &request).await?;
+        let reply = read_message(&mut stream).await?;
+        if let Some(e) = json_field(&reply, "error") {
+            bail!("the control master opened no channel: {}", e);
+        }
+        if !reply.contains("\"ok\":true") {
+            bail!("bad reply from the control master: {}", reply);
+        }
+        Ok(stream)
+    }
+
+    /// Forward the connections of `listener` to `host`:`port` through the
+    /// master, checking clients against `acl`
+    pub async fn run(
+        self,
+        listener: LocalListener,
+        acl: Arc<AccessList>,
+        host: String,
+        port: u16,
+    ) -> anyhow::Result<()> {
+        info!(
+            "Forwarding through the control master at {}",
+            self.path.display()
+        );
+        let slave = Arc::new(self);
+        loop {
+            let (mut socket, info) = listener.accept_retrying().await?;
+            if let Some(ip) = info.ip() {
+                let verdict = acl.check(ip);
+                if !matches!(verdict, Verdict::Allowed) {
+                    acl.log_rejected(ip, &verdict);
+                    continue;
+                }
+            }
+            let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+            info!("Connection #{} from {}", conn_id, info);
+            let (slave, host) = (slave.clone(), host.clone());
+            tokio::spawn(async move {
+                let mut stream = match slave.open(&host, port).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!(
+                            "Closing connection #{} from {}, no channel to {}:{}: {:#}",
+                            conn_id, info, host, port, e
+                        );
+                        return;
+                    }
+                };
+                match io::copy_bidirectional(&mut socket, &mut stream).await {
+                    Ok((out, back)) => info!(
+                        "Connection #{} closed, {} bytes out, {} bytes in",
+                        conn_id, out, back
+                    ),
+                    Err(e) => info!("Connection #{} closed: {}", conn_id, e),
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use crate::transport::{FileAttrs, SshTransport};
+use anyhow::{bail, Context};
+use clap::{Args, ValueHint};
+use log::info;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::spawn_blocking;
+
+/// Marks the path of `ssh2fwd cp` that is on the SSH server
+const REMOTE_PREFIX: char = ':';
+/// How often the progress line is redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Options of `ssh2fwd cp`
+#[derive(Args)]
+pub struct CpOpts {
+    /// File to copy, a path on the SSH server starts with ':', e.g.
+    /// :/var/log/syslog or :notes.txt in the home directory
+    #[clap(value_hint = ValueHint::AnyPath)]
+    pub source: String,
+    /// Where to copy it, a path on the SSH server starts with ':'. Into an
+    /// existing directory the file keeps its name
+    #[clap(value_hint = ValueHint::AnyPath)]
+    pub target: String,
+    /// Keep the permissions and the modification and access times
+    #[clap(short = 'p', long)]
+    pub preserve: bool,
+}
+
+impl CpOpts {
+    /// The source and target, one on the server and one local
+    pub fn paths(&self) -> anyhow::Result<(Location, Location)> {
+        match (Location::parse(&self.source), Location::parse(&self.target)) {
+            (Location::Local(_), Location::Local(_))
+            | (Location::Remote(_), Location::Remote(_)) => {
+                bail!(
+                    "one of the two paths has to be on the SSH server and the other one local, \
+                     mark the one on the server with a leading ':'"
+                )
+            }
+            paths => Ok(paths),
+        }
+    }
+}
+
+pub enum Location {
+    Local(PathBuf),
+    Remote(PathBuf),
+}
+
+impl Location {
+    fn parse(s: &str) -> Self {
+        match s.strip_prefix(REMOTE_PREFIX) {
+            Some(path) => Location::Remote(PathBuf::from(path)),
+            None => Location::Local(PathBuf::from(s)),
+        }
+    }
+}
+
+/// Copy one file to or from the SSH server through SFTP
+pub async fn run<T: SshTransport>(session: T, opts: CpOpts) -> anyhow::Result<()> {
+    spawn_blocking(move || match opts.paths()? {
+        (Location::Remote(from), Location::Local(to)) => {
+            download(&session, &from, &to, opts.preserve)
+        }
+        (Location::Local(from), Location::Remote(to)) => {
+            upload(&session, &from, &to, opts.preserve)
+        }
+        _ => unreachable!("paths() returns one of each"),
+    })
+    .await?
+}
+
+fn download<T: SshTransport>(
+    session: &T,
+    from: &Path,
+    to: &Path,
+    preserve: bool,
+) -> anyhow::Result<()> {
+    let attrs = session
+        .sftp_stat(from)
+        .with_context(|| format!(":{}", from.display()))?;
+    if attrs.is_dir() {
+        bail!(":{} is a directory, only files are copied", from.display());
+    }
+    let to = match (to.is_dir(), from.file_name()) {
+        (true, Some(name)) => to.join(name),
+        _ => to.to_path_buf(),
+    };
+    let mut source = session
+        .sftp_open(from, None)
+        .with_context(|| format!(":{}", from.display()))?;
+    let mut target = fs::File::create(&to).with_context(|| to.display().to_string())?;
+    let copied = copy(&mut source, &mut target, attrs.size, &to)?;
+    if preserve {
+        #[cfg(unix)]
+        if let Some(mode) = attrs.mode {
+            use std::os::unix::fs::PermissionsExt;
+            target.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        let mut times = fs::FileTimes::new();
+        if let Some(atime) = attrs.atime {
+            times = times.set_accessed(SystemTime::UNIX_EPOCH + Duration::from_secs(atime));
+        }
+        if let Some(mtime) = attrs.mtime {
+            times = times.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+        }
+        target.set_times(times)?;
+    }
+    info!(
+        "Copied {} bytes from :{} to {}",
+        copied,
+        from.display(),
+        to.display()
+    );
+    Ok(())
+}
+
+fn upload<T: SshTransport>(
+    session: &T,
+    from: &Path,
+    to: &Path,
+    preserve: bool,
+) -> anyhow::Result<()> {
+    let mut source = fs::File::open(from).with_context(|| from.display().to_string())?;
+    let meta = source.metadata()?;
+    if meta.is_dir() {
+        bail!("{} is a directory, only files are copied", from.display());
+    }
+    // A path on the server that doesn't exist yet is the name of the new file
+    let to = match (session.sftp_stat(to), from.file_name()) {
+        (Ok(attrs), Some(name)) if attrs.is_dir() => to.join(name),
+        _ => to.to_path_buf(),
+    };
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o7777
+    };
+    #[cfg(not(unix))]
+    let mode = 0o644;
+    let mut target = session
+        .sftp_open(&to, Some(if preserve { mode } else { 0o644 }))
+        .with_context(|| format!(":{}", to.display()))?;
+    let copied = copy(&mut source, &mut target, Some(meta.len()), &to)?;
+    drop(target);
+    if preserve {
+        let secs = |t: io::Result<SystemTime>| {
+            t.ok()
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+        };
+        // The mode given at open only applies to a file that is new
+        let attrs = FileAttrs {
+            size: None,
+            mode: Some(mode),
+            atime: secs(meta.accessed()),
+            mtime: secs(meta.modified()),
+        };
+        session
+            .sftp_setstat(&to, attrs)
+            .with_context(|| format!("keeping the permissions and times of :{}", to.display()))?;
+    }
+    info!(
+        "Copied {} bytes from {} to :{}",
+        copied,
+        from.display(),
+        to.display()
+    );
+    Ok(())
+}
+
+/// Copy everything from `source` to `target`, with a progress line on a
+/// terminal. Returns the number of bytes copied.
+fn copy(
+    source: &mut impl Read,
+    target: &mut impl Write,
+    size: Option<u64>,
+    name: &Path,
+) -> anyhow::Result<u64> {
+    let show = io::stderr().is_terminal();
+    let started = Instant::now();
+    let mut drawn = started;
+    let mut buf = vec![0; 32 * 1024];
+    let mut copied = 0u64;
+    loop {
+        let n = match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("reading"),
+        };
+        target.write_all(&buf[..n]).context("writing")?;
+        copied += n as u64;
+        if show && drawn.elapsed() >= PROGRESS_INTERVAL {
+            draw_progress(name, copied, size, started.elapsed());
+            drawn = Instant::now();
+        }
+    }
+    target.flush().context("writing")?;
+    if show {
+        draw_progress(name, copied, size, started.elapsed());
+        eprintln!();
+    }
+    Ok(copied)
+}
+
+fn draw_progress(name: &Path, copied: u64, size: Option<u64>, elapsed: Duration) {
+    let name = name
+        .file_name()
+        .unwrap_or(name.as_os_str())
+        .to_string_lossy();
+    let rate = copied as f64 / elapsed.as_secs_f64().max(0.001) / 1024.0;
+    match size {
+        Some(size) if size > 0 => eprint!(
+            "\r{}  {} of {} bytes  {:3}%  {:.0} KiB/s ",
+            name,
+            copied,
+            size,
+            (copied * 100 / size).min(100),
+            rate
+        ),
+        _ => eprint!("\r{}  {} bytes  {:.0} KiB/s ", name, copied, rate),
+    }
+}
@@ -0,0 +1,141 @@
+use crate::multi_path::MultiPath;
+use crate::transport::SshTransport;
+use crate::{classify_io_error, write_channel, ErrorAction, ACCEPT_RETRY_DELAY, IDLE_WAIT};
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+
+/// Largest DNS message, UDP or TCP
+const MAX_MESSAGE: usize = 65535;
+/// How long one query may take through the tunnel, clients retry by then
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries relayed at the same time, more are dropped for the client to
+/// retry. Each takes a channel of its own.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Answer DNS queries arriving on UDP at `addr` by asking `server`:`port`,
+/// a DNS server as seen from the SSH server, over TCP through a channel
+pub async fn spawn<T: SshTransport>(
+    addr: SocketAddr,
+    server: String,
+    port: u16,
+    sessions: Arc<MultiPath<T>>,
+) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(addr)
+        .await
+        .with_context(|| format!("binding the DNS relay to {}", addr))?;
+    let socket = Arc::new(socket);
+    info!(
+        "Relaying DNS queries to {} to {}:{} through the tunnel",
+        addr, server, port
+    );
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let server: Arc<str> = server.into();
+    tokio::spawn(async move {
+        let mut buf = vec![0; MAX_MESSAGE];
+        loop {
+            let (n, client) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // ICMP errors for earlier answers show up here on some
+                    // systems, they are no reason to stop
+                    debug!("DNS relay: receiving failed: {}", e);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+                warn!(
+                    "DNS relay: too many queries at once, dropping one from {}",
+                    client
+                );
+                continue;
+            };
+            let Some((_, session)) = sessions.pick() else {
+                continue;
+            };
+            let (query, socket, server) = (buf[..n].to_vec(), socket.clone(), server.clone());
+            tokio::spawn(async move {
+                let _permit = permit;
+                let answer = spawn_blocking(move || exchange(&session, &server, port, &query))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| r);
+                match answer {
+                    Ok(answer) => {
+                        if let Err(e) = socket.send_to(&answer, client).await {
+                            debug!("DNS relay: answering {} failed: {}", client, e);
+                        }
+                    }
+                    Err(e) => warn!("DNS relay: query from {} failed: {}", client, e),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Send one query to the DNS server over a new channel with the 2 byte
+/// length prefix of DNS over TCP, and return the answer without it
+pub fn exchange<T: SshTransport>(
+    session: &T,
+    server: &str,
+    port: u16,
+    query: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let (channel, mut rx, mut tx) = session.open_direct_tcpip(server, port)?;
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let mut framed = Vec::with_capacity(2 + query.len());
+    framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    framed.extend_from_slice(query);
+    let answer = write_channel(session, &mut tx, &framed, None, Some(QUERY_TIMEOUT))
+        .map_err(anyhow::Error::from)
+        .and_then(|()| {
+            let mut len = [0; 2];
+            read_exact(session, &channel, &mut rx, &mut len, deadline)?;
+            let mut answer = vec![0; u16::from_be_bytes(len) as usize];
+            read_exact(session, &channel, &mut rx, &mut answer, deadline)?;
+            Ok(answer)
+        });
+    if let Err(e) = session.close_channel(channel) {
+        debug!("DNS relay: closing the channel: {}", e);
+    }
+    answer
+}
+
+/// Fill `buf` from the channel, waiting for data up to `deadline`
+fn read_exact<T: SshTransport>(
+    session: &T,
+    channel: &T::Channel,
+    rx: &mut T::Stream,
+    mut buf: &mut [u8],
+    deadline: Instant,
+) -> anyhow::Result<()> {
+    while !buf.is_empty() {
+        if Instant::now() >= deadline {
+            bail!("no answer within {:?}", QUERY_TIMEOUT);
+        }
+        let seen = session.data_generation();
+        match rx.read(buf) {
+            Ok(0) if session.channel_eof(channel) => bail!("the DNS server closed the connection"),
+            Ok(0) => session.wait_for_data(seen, IDLE_WAIT),
+            Ok(n) => {
+                session.data_read();
+                buf = &mut buf[n..];
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => match classify_io_error(&e) {
+                ErrorAction::Continue => session.wait_for_data(seen, IDLE_WAIT),
+                _ => return Err(e.into()),
+            },
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use crate::api::LiveConnections;
+use log::{debug, info};
+use std::io::Read;
+use std::sync::Arc;
+
+const HELP: &str = "Supported escape sequences:
+ ~.   - terminate the tunnel
+ ~#   - list forwarded connections
+ ~B   - send a BREAK (not available for forwarded connections)
+ ~?   - this message
+(Note that escapes are only recognized immediately after newline.)";
+
+/// What an escape sequence asks for
+pub enum Command {
+    Terminate,
+    List,
+    Break,
+    Help,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    /// At the start of a line, a `~` begins an escape
+    LineStart,
+    /// Right after a `~` at the start of a line
+    Tilde,
+    /// Anywhere else in a line
+    InLine,
+}
+
+/// Finds OpenSSH style escape sequences in what is typed on the terminal:
+/// `~` right after a newline (or at the very start) followed by the command
+/// character. In the usual line mode of the terminal they take effect once
+/// Enter is pressed.
+pub struct EscapeParser {
+    state: State,
+}
+
+impl EscapeParser {
+    pub fn new() -> Self {
+        EscapeParser {
+            state: State::LineStart,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<Command> {
+        let (state, command) = match (self.state, byte) {
+            (_, b'\r' | b'\n') => (State::LineStart, None),
+            (State::LineStart, b'~') => (State::Tilde, None),
+            (State::Tilde, b'.') => (State::InLine, Some(Command::Terminate)),
+            (State::Tilde, b'#') => (State::InLine, Some(Command::List)),
+            (State::Tilde, b'B' | b'b') => (State::InLine, Some(Command::Break)),
+            (State::Tilde, b'?') => (State::InLine, Some(Command::Help)),
+            // Anything else is ordinary text, there is no session to pass
+            // it on to
+            _ => (State::InLine, None),
+        };
+        self.state = state;
+        command
+    }
+}
+
+/// Watch stdin for escape sequences in the background. Only for an
+/// interactive terminal, ends with stdin.
+pub fn spawn(live: Arc<LiveConnections>) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .name("escapes".to_string())
+        .spawn(move || {
+            #[cfg(target_os = "linux")]
+            crate::sandbox::enforce_current_thread();
+            let mut parser = EscapeParser::new();
+            let mut buf = [0; 256];
+            let mut stdin = std::io::stdin().lock();
+            loop {
+                let n = match stdin.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        debug!("Not reading escape sequences any more: {}", e);
+                        return;
+                    }
+                };
+                for command in buf[..n].iter().filter_map(|b| parser.feed(*b)) {
+                    run(command, &live);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn run(command: Command, live: &LiveConnections) {
+    match command {
+        Command::Terminate => {
+            info!("Terminating on ~.");
+            crate::shutdown::exit(0);
+        }
+        Command::List => {
+            let conns = live.describe();
+            if conns.is_empty() {
+                eprintln!("No forwarded connections are open.");
+            } else {
+                eprintln!("The following connections are open:");
+                for conn in conns {
+                    eprintln!("  {}", conn);
+                }
+            }
+        }
+        // RFC 4335 breaks go to session channels with a terminal, the
+        // channels here are forwarded TCP connections
+        Command::Break => {
+            eprintln!("BREAK is not supported, the tunnel has no remote terminal to send it to.")
+        }
+        Command::Help => eprintln!("{}", HELP),
+    }
+}
@@ -0,0 +1,394 @@
+use crate::fingerprint::{base64, base64_decode, key_type, sha256, sha256_fingerprint};
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use rand::RngCore;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// What to do about a server host key that isn't in known_hosts yet or
+/// differs from the one there, named after the OpenSSH option
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum StrictHostKeyChecking {
+    /// Only connect to hosts already in known_hosts
+    Yes,
+    /// Show the fingerprint of new hosts and ask before adding them
+    Ask,
+    /// Add new hosts without asking, refuse changed keys
+    AcceptNew,
+    /// Add new hosts and connect even when the key changed
+    No,
+}
+
+/// Result of looking up a server in known_hosts
+pub enum HostKeyStatus {
+    Known,
+    New,
+    /// A different key of the same type is recorded for the host
+    Changed {
+        line: usize,
+        fingerprint: String,
+    },
+    /// The key is marked @revoked
+    Revoked {
+        line: usize,
+    },
+}
+
+/// Name a server is recorded under, `[host]:port` unless the port is 22.
+/// Lower case like ssh, hashed names have to match exactly.
+pub fn host_entry_name(host: &str, port: u16) -> String {
+    let host = host.to_lowercase();
+    if port == 22 {
+        host
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+/// Check the server key against known_hosts and decide whether to go on,
+/// adding new hosts as `mode` allows. `can_prompt` is false when nobody may
+/// be asked, an Ask for a new host then fails.
+pub fn verify(
+    path: &Path,
+    host: &str,
+    key: &[u8],
+    mode: StrictHostKeyChecking,
+    can_prompt: bool,
+) -> anyhow::Result<()> {
+    let fingerprint = sha256_fingerprint(&sha256(key));
+    match check(path, host, key)? {
+        HostKeyStatus::Known => {
+            debug!("Host key of {} found in {}", host, path.display());
+            Ok(())
+        }
+        HostKeyStatus::Revoked { line } => bail!(
+            "host key {} of {} is marked as revoked in {}:{}",
+            fingerprint,
+            host,
+            path.display(),
+            line
+        ),
+        HostKeyStatus::Changed {
+            line,
+            fingerprint: old,
+        } => {
+            warn_changed(path, host, key, line, &old);
+            if mode != StrictHostKeyChecking::No {
+                bail!(
+                    "host key of {} has changed, remove the old entry from {}:{} \
+                     if the change is expected",
+                    host,
+                    path.display(),
+                    line
+                );
+            }
+            warn!(
+                "Connecting to {} despite the changed host key, \
+                 --strict-host-key-checking is no",
+                host
+            );
+            Ok(())
+        }
+        HostKeyStatus::New => {
+            let trusted = match mode {
+                StrictHostKeyChecking::Yes => bail!(
+                    "host key {} of {} is not in {} and --strict-host-key-checking is yes",
+                    fingerprint,
+                    host,
+                    path.display()
+                ),
+                StrictHostKeyChecking::Ask if !can_prompt => bail!(
+                    "host key {} of {} is not in {}, pass --accept-new to add it \
+                     without asking",
+                    fingerprint,
+                    host,
+                    path.display()
+                ),
+                StrictHostKeyChecking::Ask => confirm_new_host(host, key)?,
+                StrictHostKeyChecking::AcceptNew | StrictHostKeyChecking::No => true,
+            };
+            if !trusted {
+                bail!("host key of {} not accepted", host);
+            }
+            add(path, host, key)?;
+            info!(
+                "Added {} with host key {} to {}",
+                host,
+                fingerprint,
+                path.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Look `host` (as returned by `host_entry_name`) up in the known_hosts file
+/// at `path`, a missing file knows no hosts. Hashed names, wildcards and
+/// negated patterns are understood, @cert-authority lines are skipped.
+pub fn check(path: &Path, host: &str, key: &[u8]) -> anyhow::Result<HostKeyStatus> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HostKeyStatus::New),
+        Err(e) => return Err(e).context(format!("reading {}", path.display())),
+    };
+    let key_type = key_type(key).unwrap_or_default();
+    let mut status = HostKeyStatus::New;
+    for (n, line) in content.lines().enumerate() {
+        let mut fields = line.split_whitespace();
+        let mut first = match fields.next() {
+            Some(f) if !f.starts_with('#') => f,
+            _ => continue,
+        };
+        let marker = first.strip_prefix('@');
+        if marker.is_some() {
+            first = match fields.next() {
+                Some(f) => f,
+                None => continue,
+            };
+        }
+        let entry_type = fields.next();
+        let (entry_type, entry_key) = match (entry_type, fields.next().and_then(base64_decode)) {
+            (Some(t), Some(k)) => (t, k),
+            _ => continue,
+        };
+        if marker == Some("cert-authority") || !host_matches(first, host) {
+            continue;
+        }
+        if marker == Some("revoked") {
+            if entry_key == key {
+                return Ok(HostKeyStatus::Revoked { line: n + 1 });
+            }
+            continue;
+        }
+        if entry_key == key {
+            status = HostKeyStatus::Known;
+        } else if entry_type == key_type && !matches!(status, HostKeyStatus::Known) {
+            status = HostKeyStatus::Changed {
+                line: n + 1,
+                fingerprint: sha256_fingerprint(&sha256(&entry_key)),
+            };
+        }
+    }
+    Ok(status)
+}
+
+/// Add `host` with `key` to the file, with the name hashed like
+/// `HashKnownHosts yes` does. The file is rewritten through a temporary file
+/// and a rename, so it is never seen half written; existing lines are kept
+/// as they are.
+pub fn add(path: &Path, host: &str, key: &[u8]) -> anyhow::Result<()> {
+    let key_type = key_type(key).context("malformed host key")?;
+    let mut salt = [0; 20];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let line = format!(
+        "|1|{}|{} {} {}\n",
+        base64(&salt),
+        base64(&hmac_sha1(&salt, host.as_bytes())),
+        key_type,
+        base64(key)
+    );
+
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+    let dir = dir.unwrap_or(Path::new("."));
+    if !dir.exists() {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(dir)
+            .with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let mut content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e).context(format!("reading {}", path.display())),
+    };
+    if !content.is_empty() && !content.ends_with(b"\n") {
+        content.push(b'\n');
+    }
+    content.extend_from_slice(line.as_bytes());
+
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp = dir.join(tmp_name);
+    let result = (|| -> anyhow::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o644);
+        let mut file = options.open(&tmp)?;
+        if let Ok(meta) = fs::metadata(path) {
+            file.set_permissions(meta.permissions())?;
+        }
+        file.write_all(&content)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result.with_context(|| format!("writing {}", path.display()))
+}
+
+/// Ask on the terminal whether to trust a new host, like ssh does. Typing
+/// the fingerprint itself also counts as yes.
+pub fn confirm_new_host(host: &str, key: &[u8]) -> anyhow::Result<bool> {
+    if !io::stdin().is_terminal() {
+        bail!("no terminal to ask whether to trust {}", host);
+    }
+    let fingerprint = sha256_fingerprint(&sha256(key));
+    eprintln!("The authenticity of host '{}' can't be established.", host);
+    eprintln!(
+        "{} key fingerprint is {}.",
+        display_type(key_type(key).unwrap_or("unknown")),
+        fingerprint
+    );
+    eprint!("Are you sure you want to continue connecting (yes/no/[fingerprint])? ");
+    let stdin = io::stdin();
+    for answer in stdin.lock().lines() {
+        let answer = answer?;
+        match answer.trim() {
+            "yes" => return Ok(true),
+            "no" => return Ok(false),
+            a if a == fingerprint => return Ok(true),
+            _ => eprint!("Please type 'yes', 'no' or the fingerprint: "),
+        }
+    }
+    Ok(false)
+}
+
+/// The warning ssh prints for a changed host key, it has to stand out from
+/// the normal log output
+pub fn warn_changed(path: &Path, host: &str, key: &[u8], line: usize, old: &str) {
+    let rule = "@".repeat(59);
+    eprintln!("{}", rule);
+    eprintln!("@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @");
+    eprintln!("{}", rule);
+    eprintln!("IT IS POSSIBLE THAT SOMEONE IS DOING SOMETHING NASTY!");
+    eprintln!("Someone could be eavesdropping on you right now (man-in-the-middle attack)!");
+    eprintln!("It is also possible that the host key has just been changed.");
+    eprintln!(
+        "The {} key sent by {} has fingerprint {},",
+        display_type(key_type(key).unwrap_or("unknown")),
+        host,
+        sha256_fingerprint(&sha256(key))
+    );
+    eprintln!("the key recorded for it has fingerprint {}.", old);
+    eprintln!("Offending key in {}:{}", path.display(), line);
+    eprintln!("{}", rule);
+}
+
+/// Key type as ssh shows it in messages, e.g. ED25519
+fn display_type(key_type: &str) -> String {
+    match key_type {
+        "ssh-ed25519" => "ED25519".to_string(),
+        "ssh-rsa" => "RSA".to_string(),
+        "ssh-dss" => "DSA".to_string(),
+        t if t.starts_with("ecdsa-") => "ECDSA".to_string(),
+        t => t.to_string(),
+    }
+}
+
+/// Comma separated patterns with * and ?, a matching !pattern rules the host
+/// out. `|1|salt|hash` is a hashed name.
+fn host_matches(patterns: &str, host: &str) -> bool {
+    if let Some(hashed) = patterns.strip_prefix("|1|") {
+        return match hashed.split_once('|') {
+            Some((salt, hash)) => match (base64_decode(salt), base64_decode(hash)) {
+                (Some(salt), Some(hash)) => hmac_sha1(&salt, host.as_bytes())[..] == hash[..],
+                _ => false,
+            },
+            None => false,
+        };
+    }
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(negated, host) => return false,
+            Some(_) => {}
+            None => matched |= wildcard_match(pattern, host),
+        }
+    }
+    matched
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    fn matches(p: &[u8], n: &[u8]) -> bool {
+        match (p.first(), n.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => matches(&p[1..], n) || (!n.is_empty() && matches(p, &n[1..])),
+            (Some(b'?'), Some(_)) => matches(&p[1..], &n[1..]),
+            (Some(a), Some(b)) if a.eq_ignore_ascii_case(b) => matches(&p[1..], &n[1..]),
+            _ => false,
+        }
+    }
+    matches(pattern.as_bytes(), name.as_bytes())
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    // Keys are the 20 byte salts, never longer than the block
+    let mut block = [0u8; 64];
+    block[..key.len()].copy_from_slice(key);
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha1(&inner));
+    sha1(&outer)
+}
+
+/// SHA-1 (FIPS 180-4), only for the HMAC of hashed known_hosts names
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (x, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(v);
+        }
+    }
+    let mut out = [0; 20];
+    for (i, x) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
@@ -0,0 +1,68 @@
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::Duration;
+
+/// Latencies kept for the percentiles, the most recent connections
+const MAX_SAMPLES: usize = 1024;
+
+/// Time from accepting a local connection to the first byte coming back
+/// from the remote side. That covers the channel open and the remote
+/// service's answer, what a client notices as a slow tunnel.
+pub struct LatencyTracker {
+    sla: Option<Duration>,
+    samples: Mutex<VecDeque<u64>>,
+    violations: AtomicU64,
+}
+
+impl LatencyTracker {
+    pub fn new(sla: Option<Duration>) -> Arc<Self> {
+        Arc::new(LatencyTracker {
+            sla,
+            samples: Mutex::new(VecDeque::with_capacity(MAX_SAMPLES)),
+            violations: AtomicU64::new(0),
+        })
+    }
+
+    pub fn record(&self, conn_id: u64, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        if let Some(sla) = self.sla.filter(|sla| latency > *sla) {
+            self.violations.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Connection #{}: SLA violation: connection latency {}ms > {}ms sla",
+                conn_id,
+                ms,
+                sla.as_millis()
+            );
+        }
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(ms);
+    }
+
+    /// JSON object for /api/v1/latency
+    pub fn to_json(&self) -> String {
+        let mut sorted: Vec<u64> = self.samples.lock().unwrap().iter().copied().collect();
+        sorted.sort_unstable();
+        let percentile = |p: usize| match sorted.len() {
+            0 => "null".to_string(),
+            n => sorted[(n * p).div_ceil(100).max(1) - 1].to_string(),
+        };
+        let sla = match self.sla {
+            Some(sla) => sla.as_millis().to_string(),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"samples\":{},\"p50_ms\":{},\"p95_ms\":{},\"p99_ms\":{},\"sla_ms\":{},\"sla_violations\":{}}}",
+            sorted.len(),
+            percentile(50),
+            percentile(95),
+            percentile(99),
+            sla,
+            self.violations.load(Ordering::Relaxed)
+        )
+    }
+}
@@ -0,0 +1,457 @@
+use crate::acl::canonical;
+use crate::audit::ByteSize;
+use anyhow::{anyhow, bail, Context};
+use dashmap::DashMap;
+use log::warn;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Caps the number of concurrently forwarded connections per client address
+pub struct ConnectionLimiter {
+    max_per_ip: u32,
+    active: DashMap<IpAddr, AtomicU32>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: u32) -> Arc<Self> {
+        Arc::new(ConnectionLimiter {
+            max_per_ip,
+            active: DashMap::new(),
+        })
+    }
+
+    /// Count a new connection from `ip`, or refuse it when the address is
+    /// already at the limit. The slot is given back when the guard drops, so
+    /// it is released even if the connection task panics.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let ip = canonical(ip);
+        let entry = self.active.entry(ip).or_insert_with(|| AtomicU32::new(0));
+        let admitted = entry
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.max_per_ip).then_some(n + 1)
+            })
+            .is_ok();
+        drop(entry);
+        admitted.then(|| ConnectionGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn active(&self, ip: IpAddr) -> u32 {
+        self.active
+            .get(&canonical(ip))
+            .map(|n| n.load(Ordering::Acquire))
+            .unwrap_or(0)
+    }
+}
+
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(n) = self.limiter.active.get(&self.ip) {
+            n.fetch_sub(1, Ordering::AcqRel);
+        }
+        // Forget addresses without connections so the map doesn't grow with
+        // every client ever seen
+        self.limiter
+            .active
+            .remove_if(&self.ip, |_, n| n.load(Ordering::Acquire) == 0);
+    }
+}
+
+/// Clients whose rate limit state is remembered, the least recently seen
+/// are forgotten first
+const MAX_RATE_BUCKETS: usize = 10_000;
+/// Minimum time between two warnings about the same client
+const RATE_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// --rate-limit value, `<conns/sec>[:burst]`. The burst defaults to the rate.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let per_sec: f64 = rate.parse()?;
+        let burst = match burst {
+            Some(b) => b.parse::<u32>()? as f64,
+            None => per_sec.max(1.0),
+        };
+        if per_sec <= 0.0 || !per_sec.is_finite() || burst < 1.0 {
+            bail!(
+                "rate limit {} must be a positive rate and a burst of at least 1",
+                s
+            );
+        }
+        Ok(RateLimit { per_sec, burst })
+    }
+}
+
+impl std::fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.per_sec, self.burst)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Position in the LRU order
+    tick: u64,
+    last_warn: Option<Instant>,
+    suppressed: u64,
+}
+
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    lru: BTreeMap<u64, IpAddr>,
+    tick: u64,
+}
+
+/// Token bucket per client address for new connections
+pub struct RateLimiter {
+    limit: RateLimit,
+    exempt_loopback: bool,
+    buckets: Mutex<Buckets>,
+    rejected: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, exempt_loopback: bool) -> Arc<Self> {
+        Arc::new(RateLimiter {
+            limit,
+            exempt_loopback,
+            buckets: Mutex::new(Buckets {
+                by_ip: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            }),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    /// Take a token for a new connection from `ip`. False means the client is
+    /// over its rate and the connection should be closed.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        if self.exempt_loopback && ip.is_loopback() {
+            return true;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let b = &mut *buckets;
+        b.tick += 1;
+        let tick = b.tick;
+        let bucket = b.by_ip.entry(ip).or_insert_with(|| Bucket {
+            tokens: self.limit.burst,
+            updated: now,
+            tick,
+            last_warn: None,
+            suppressed: 0,
+        });
+        b.lru.remove(&bucket.tick);
+        b.lru.insert(tick, ip);
+        bucket.tick = tick;
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limit.per_sec).min(self.limit.burst);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            match bucket.last_warn {
+                Some(t) if now.duration_since(t) < RATE_WARN_INTERVAL => bucket.suppressed += 1,
+                _ => {
+                    warn!(
+                        "Closed connection from {}, over the rate limit of {}/s ({} more rejected since last report)",
+                        ip, self.limit.per_sec, bucket.suppressed
+                    );
+                    bucket.last_warn = Some(now);
+                    bucket.suppressed = 0;
+                }
+            }
+        }
+
+        while b.by_ip.len() > MAX_RATE_BUCKETS {
+            match b.lru.pop_first() {
+                Some((_, oldest)) => {
+                    b.by_ip.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        allowed
+    }
+
+    /// Connections closed for being over the rate so far
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// Clients whose data usage is remembered, the least recently seen are
+/// forgotten first
+const MAX_QUOTA_CLIENTS: usize = 10_000;
+/// The per client window is tracked in this many slots, usage leaves the
+/// window one slot at a time
+const WINDOW_SLOTS: u64 = 16;
+
+/// --max-bytes-per-client value, `<bytes>/<window>` such as 10G/24h
+#[derive(Clone, Copy, Debug)]
+pub struct ClientByteLimit {
+    pub bytes: u64,
+    pub window: Duration,
+}
+
+impl FromStr for ClientByteLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (bytes, window) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("expected <bytes>/<window>, e.g. 10G/24h"))?;
+        let bytes: ByteSize = bytes.parse()?;
+        let window = parse_window(window)?;
+        Ok(ClientByteLimit {
+            bytes: bytes.0,
+            window,
+        })
+    }
+}
+
+impl std::fmt::Display for ClientByteLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}s", self.bytes, self.window.as_secs())
+    }
+}
+
+/// Seconds with an optional s, m, h or d suffix
+fn parse_window(s: &str) -> anyhow::Result<Duration> {
+    let (num, mult) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 3600),
+        Some((i, 'd')) => (&s[..i], 86400),
+        _ => (s, 1),
+    };
+    let n: u64 = num
+        .parse()
+        .with_context(|| format!("invalid window {}, e.g. 90s, 15m, 24h or 7d", s))?;
+    if n == 0 {
+        bail!("window must not be 0");
+    }
+    Ok(Duration::from_secs(n * mult))
+}
+
+/// Byte cap of a single connection, shared by both copy directions
+pub struct ConnQuota {
+    max: u64,
+    used: AtomicU64,
+    exceeded: AtomicBool,
+    notify: Notify,
+}
+
+impl ConnQuota {
+    /// Count `n` bytes about to be forwarded and return how many of them
+    /// still fit. Once the cap is reached the connection has to be closed,
+    /// a task waiting in `closed` is woken up.
+    pub fn take(&self, n: usize) -> usize {
+        let prev = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |u| {
+                Some((u + n as u64).min(self.max))
+            })
+            .unwrap_or(self.max);
+        if prev + n as u64 >= self.max && !self.exceeded.swap(true, Ordering::AcqRel) {
+            self.notify.notify_one();
+        }
+        (self.max - prev).min(n as u64) as usize
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the cap is reached, for a copy task blocked on a read
+    pub async fn closed(&self) {
+        if !self.exceeded() {
+            self.notify.notified().await;
+        }
+    }
+}
+
+struct ClientUsage {
+    /// (slot number, bytes) of the slots still in the window, oldest first
+    slots: VecDeque<(u64, u64)>,
+    /// Position in the LRU order
+    tick: u64,
+    /// Who the client last authenticated as, for the log
+    identity: Option<String>,
+}
+
+struct UsageTable {
+    by_ip: HashMap<IpAddr, ClientUsage>,
+    lru: BTreeMap<u64, IpAddr>,
+    tick: u64,
+}
+
+/// --max-bytes-per-conn and --max-bytes-per-client. A connection's bytes
+/// count towards its client once it is closed.
+pub struct ByteQuotas {
+    per_conn: Option<u64>,
+    per_client: Option<ClientByteLimit>,
+    started: Instant,
+    usage: Mutex<UsageTable>,
+    conns_cut: AtomicU64,
+    clients_rejected: AtomicU64,
+}
+
+impl ByteQuotas {
+    pub fn new(per_conn: Option<ByteSize>, per_client: Option<ClientByteLimit>) -> Arc<Self> {
+        Arc::new(ByteQuotas {
+            per_conn: per_conn.map(|s| s.0),
+            per_client,
+            started: Instant::now(),
+            usage: Mutex::new(UsageTable {
+                by_ip: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            }),
+            conns_cut: AtomicU64::new(0),
+            clients_rejected: AtomicU64::new(0),
+        })
+    }
+
+    /// Cap for a new connection, None when connections are unlimited
+    pub fn conn_quota(&self) -> Option<Arc<ConnQuota>> {
+        self.per_conn.map(|max| {
+            Arc::new(ConnQuota {
+                max,
+                used: AtomicU64::new(0),
+                exceeded: AtomicBool::new(false),
+                notify: Notify::new(),
+            })
+        })
+    }
+
+    /// Note a connection closed for reaching --max-bytes-per-conn
+    pub fn conn_cut(&self, id: u64, client: &dyn std::fmt::Display, identity: Option<&str>) {
+        self.conns_cut.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Closed connection #{} from {} ({}) after {} bytes, its --max-bytes-per-conn",
+            id,
+            client,
+            identity.unwrap_or("no identity"),
+            self.per_conn.unwrap_or_default()
+        );
+    }
+
+    fn current_slot(&self, limit: &ClientByteLimit) -> u64 {
+        let slot_len = (limit.window.as_millis() as u64 / WINDOW_SLOTS).max(1);
+        self.started.elapsed().as_millis() as u64 / slot_len
+    }
+
+    /// Whether `ip` may open another connection. False means it used up its
+    /// --max-bytes-per-client within the window and the connection should be
+    /// closed.
+    pub fn check_client(&self, ip: IpAddr) -> bool {
+        let limit = match &self.per_client {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let ip = canonical(ip);
+        let slot = self.current_slot(limit);
+        let mut table = self.usage.lock().unwrap();
+        let usage = match table.by_ip.get_mut(&ip) {
+            Some(usage) => usage,
+            None => return true,
+        };
+        while matches!(usage.slots.front(), Some((s, _)) if s + WINDOW_SLOTS <= slot) {
+            usage.slots.pop_front();
+        }
+        let used: u64 = usage.slots.iter().map(|(_, n)| n).sum();
+        if used < limit.bytes {
+            return true;
+        }
+        self.clients_rejected.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Closed connection from {} ({}), it transferred {} of its {} bytes in the last {}s",
+            ip,
+            usage.identity.as_deref().unwrap_or("no identity"),
+            used,
+            limit.bytes,
+            limit.window.as_secs()
+        );
+        false
+    }
+
+    /// Add the bytes of a closed connection to its client's usage
+    pub fn record(&self, ip: IpAddr, bytes: u64, identity: Option<&str>) {
+        let limit = match &self.per_client {
+            Some(limit) => limit,
+            None => return,
+        };
+        let ip = canonical(ip);
+        let slot = self.current_slot(limit);
+        let mut table = self.usage.lock().unwrap();
+        let t = &mut *table;
+        t.tick += 1;
+        let tick = t.tick;
+        let usage = t.by_ip.entry(ip).or_insert_with(|| ClientUsage {
+            slots: VecDeque::new(),
+            tick,
+            identity: None,
+        });
+        t.lru.remove(&usage.tick);
+        t.lru.insert(tick, ip);
+        usage.tick = tick;
+        if let Some(identity) = identity {
+            usage.identity = Some(identity.to_string());
+        }
+        match usage.slots.back_mut() {
+            Some((s, n)) if *s == slot => *n += bytes,
+            _ => usage.slots.push_back((slot, bytes)),
+        }
+        while usage.slots.len() as u64 > WINDOW_SLOTS {
+            usage.slots.pop_front();
+        }
+
+        while t.by_ip.len() > MAX_QUOTA_CLIENTS {
+            match t.lru.pop_first() {
+                Some((_, oldest)) => {
+                    t.by_ip.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Connections closed at their byte cap and connections refused for a
+    /// client over its quota so far
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.conns_cut.load(Ordering::Relaxed),
+            self.clients_rejected.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,746 @@
+use anyhow::{bail, Context};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::pin::Pin;
+use std::task::{self, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+#[cfg(unix)]
+use {
+    std::path::{Path, PathBuf},
+    tokio::net::{UnixListener, UnixStream},
+};
+
+/// Prefix of --local-srv-address for listening on a Unix socket
+const UNIX_PREFIX: &str = "unix:";
+/// Prefix of --local-srv-address for listening on a Windows named pipe, what
+/// --named-pipe turns into
+pub const PIPE_PREFIX: &str = "pipe:";
+
+/// Permissions for a Unix socket listener, from --unix-mode/--unix-owner
+#[derive(Clone, Default)]
+pub struct UnixSocketOptions {
+    pub mode: Option<u32>,
+    /// `user`, `user:group` or `:group`
+    pub owner: Option<String>,
+}
+
+/// Where local clients connect, a TCP address or a Unix socket
+pub enum LocalListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    /// The pipe name and the instance waiting for the next client
+    #[cfg(windows)]
+    Pipe(String, tokio::sync::Mutex<NamedPipeServer>),
+}
+
+/// A local client connection from either kind of listener
+pub enum LocalStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(windows)]
+    Pipe(NamedPipeServer),
+}
+
+/// Who connected. Unix socket and named pipe peers have no address, access
+/// to them is controlled by the permissions of the socket file or pipe.
+#[derive(Clone, Copy, Debug)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+    #[cfg(windows)]
+    Pipe,
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            PeerAddr::Unix => None,
+            #[cfg(windows)]
+            PeerAddr::Pipe => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix => f.write_str("unix socket peer"),
+            #[cfg(windows)]
+            PeerAddr::Pipe => f.write_str("named pipe client"),
+        }
+    }
+}
+
+impl LocalListener {
+    /// Bind `addr`, either host:port or unix:/path/to/socket
+    /// With `reuse_port` several processes can listen on the same TCP address
+    /// and the kernel spreads the connections over them
+    pub async fn bind(
+        addr: &str,
+        unix: &UnixSocketOptions,
+        reuse_port: bool,
+    ) -> anyhow::Result<Self> {
+        if reuse_port {
+            return bind_reuse_port(addr).await.map(LocalListener::Tcp);
+        }
+        if let Some(name) = addr.strip_prefix(PIPE_PREFIX) {
+            #[cfg(windows)]
+            return bind_pipe(name);
+            #[cfg(not(windows))]
+            bail!("named pipe {} can only be served on Windows", name);
+        }
+        match addr.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => bind_unix(Path::new(path), unix),
+            #[cfg(not(unix))]
+            Some(_) => bail!("Unix socket listeners are only supported on Unix"),
+            None => {
+                if unix.mode.is_some() || unix.owner.is_some() {
+                    bail!("--unix-mode and --unix-owner need a unix: local address");
+                }
+                match parse_scoped(addr)? {
+                    Some(scoped) => Ok(LocalListener::Tcp(bind_scoped(scoped)?)),
+                    None => {
+                        let resolved = resolve(addr).await?;
+                        let listener = TcpListener::bind(&resolved[..])
+                            .await
+                            .with_context(|| format!("binding {}", addr))?;
+                        Ok(LocalListener::Tcp(listener))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accept the next connection, riding out errors that leave the listener
+    /// usable. Out of file descriptors the pending connection stays queued
+    /// and accept fails again right away, so connections get time to close
+    /// instead of the loop spinning. Only errors that end the listener are
+    /// returned.
+    pub async fn accept_retrying(&self) -> io::Result<(LocalStream, PeerAddr)> {
+        loop {
+            match self.accept().await {
+                Err(e) if is_transient_accept_error(&e) => {
+                    log::warn!("Accepting a local connection failed, retrying: {}", e);
+                    tokio::time::sleep(crate::ACCEPT_RETRY_DELAY).await;
+                }
+                accepted => return accepted,
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(LocalStream, PeerAddr)> {
+        match self {
+            LocalListener::Tcp(l) => {
+                let (socket, addr) = l.accept().await?;
+                Ok((LocalStream::Tcp(socket), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            LocalListener::Unix(l, _) => {
+                let (socket, _) = l.accept().await?;
+                Ok((LocalStream::Unix(socket), PeerAddr::Unix))
+            }
+            // A pipe instance serves one client, a new one takes over
+            // waiting for the next
+            #[cfg(windows)]
+            LocalListener::Pipe(name, waiting) => {
+                let mut waiting = waiting.lock().await;
+                waiting.connect().await?;
+                let next = ServerOptions::new().create(name)?;
+                let client = std::mem::replace(&mut *waiting, next);
+                Ok((LocalStream::Pipe(client), PeerAddr::Pipe))
+            }
+        }
+    }
+
+    /// Loopback TCP addresses and Unix sockets can only be reached from
+    /// this machine
+    pub fn is_local_only(&self) -> io::Result<bool> {
+        match self {
+            LocalListener::Tcp(l) => Ok(l.local_addr()?.ip().is_loopback()),
+            #[cfg(unix)]
+            LocalListener::Unix(..) => Ok(true),
+            #[cfg(windows)]
+            LocalListener::Pipe(..) => Ok(true),
+        }
+    }
+
+    /// Address of a TCP listener, None for a Unix socket
+    #[cfg(any(feature = "consul", feature = "etcd"))]
+    pub fn tcp_addr(&self) -> io::Result<Option<SocketAddr>> {
+        match self {
+            LocalListener::Tcp(l) => l.local_addr().map(Some),
+            #[cfg(unix)]
+            LocalListener::Unix(..) => Ok(None),
+            #[cfg(windows)]
+            LocalListener::Pipe(..) => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for LocalListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LocalListener::Tcp(l) => match l.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => f.write_str("?"),
+            },
+            #[cfg(unix)]
+            LocalListener::Unix(_, path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            #[cfg(windows)]
+            LocalListener::Pipe(name, _) => f.write_str(name),
+        }
+    }
+}
+
+/// Refuse a local address other machines can reach unless the user asked
+/// for it. With `protected` (client address or token rules) it is allowed,
+/// with only `allow_external` it is allowed with a warning naming the
+/// interfaces the port is reachable on.
+pub async fn check_exposure(
+    addr: &str,
+    protected: bool,
+    allow_external: bool,
+) -> anyhow::Result<()> {
+    if addr.starts_with(UNIX_PREFIX) || addr.starts_with(PIPE_PREFIX) {
+        return Ok(());
+    }
+    let resolved = resolve(addr).await?;
+    let exposed: Vec<SocketAddr> = resolved
+        .into_iter()
+        .filter(|a| !a.ip().is_loopback())
+        .collect();
+    if exposed.is_empty() || protected {
+        return Ok(());
+    }
+    if !allow_external {
+        bail!(
+            "refusing to listen on {}, it is reachable from other machines. Bind to a \
+             loopback address, restrict clients with --allow-from, --require-tunnel-token or \
+             --client-token-file, or pass --allow-external",
+            addr
+        );
+    }
+    let reachable: Vec<String> = exposed
+        .iter()
+        .flat_map(|a| reachable_on(a.ip()))
+        .map(|(name, ip)| format!("{} ({})", ip, name))
+        .collect();
+    log::warn!(
+        "Listening on {} WITHOUT ANY ACCESS CONTROL, every machine that can reach {} can use the tunnel",
+        addr,
+        if reachable.is_empty() {
+            "this host".to_string()
+        } else {
+            reachable.join(", ")
+        }
+    );
+    Ok(())
+}
+
+/// Interface addresses a socket bound to `ip` accepts connections on
+fn reachable_on(ip: IpAddr) -> Vec<(String, IpAddr)> {
+    let ifaces = interface_addrs();
+    let matches = |a: &IpAddr| match ip {
+        IpAddr::V4(v4) if v4.is_unspecified() => a.is_ipv4(),
+        // A :: socket is dual stack unless IPV6_V6ONLY is set, which it isn't
+        IpAddr::V6(v6) if v6.is_unspecified() => true,
+        ip => *a == ip,
+    };
+    ifaces
+        .into_iter()
+        .filter(|(_, a)| !a.is_loopback() && matches(a))
+        .collect()
+}
+
+#[cfg(unix)]
+fn interface_addrs() -> Vec<(String, IpAddr)> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut addrs = vec![];
+    let mut ifap = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return addrs;
+    }
+    let mut cur = ifap;
+    while let Some(ifa) = unsafe { cur.as_ref() } {
+        cur = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let ip = match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        addrs.push((name, ip));
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    addrs
+}
+
+#[cfg(not(unix))]
+fn interface_addrs() -> Vec<(String, IpAddr)> {
+    vec![]
+}
+
+/// An IPv6 address with a zone, `[fe80::1%eth0]:8080` or `fe80::1%eth0:8080`.
+/// Addresses without `%` are left to the normal resolver.
+/// Resolve a local TCP address: `host:port`, `[ipv6]:port` or a scoped
+/// link-local IPv6 address
+async fn resolve(addr: &str) -> anyhow::Result<Vec<SocketAddr>> {
+    if let Some(scoped) = parse_scoped(addr)? {
+        return Ok(vec![scoped.into()]);
+    }
+    let (host, port) = match crate::addr::split_host_port(addr)? {
+        (host, Some(port)) => (host, port),
+        (_, None) => bail!(
+            "missing port in local address {}, IPv6 addresses take the form [::1]:8181",
+            addr
+        ),
+    };
+    let resolved: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .with_context(|| format!("resolving local address {}", addr))?
+        .collect();
+    if resolved.is_empty() {
+        bail!("{} did not resolve to any address", addr);
+    }
+    Ok(resolved)
+}
+
+fn parse_scoped(addr: &str) -> anyhow::Result<Option<SocketAddrV6>> {
+    if !addr.contains('%') {
+        return Ok(None);
+    }
+    let (host, port) = if addr.starts_with('[') {
+        match crate::addr::split_host_port(addr)? {
+            (host, Some(port)) => (host, port),
+            (_, None) => bail!("missing port in local address {}", addr),
+        }
+    } else {
+        let (host, port) = addr
+            .rsplit_once(':')
+            .filter(|(host, _)| host.contains('%'))
+            .ok_or_else(|| anyhow::anyhow!("missing port in local address {}", addr))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port in local address {}", addr))?;
+        (host.to_string(), port)
+    };
+    let (ip, zone) = host.split_once('%').unwrap_or((&host, ""));
+    let ip: Ipv6Addr = ip
+        .parse()
+        .with_context(|| format!("{} is not an IPv6 address", ip))?;
+    let scope_id = match zone.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => interface_index(zone)?,
+    };
+    Ok(Some(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> anyhow::Result<u32> {
+    let cname = std::ffi::CString::new(name)?;
+    match unsafe { libc::if_nametoindex(cname.as_ptr()) } {
+        0 => bail!("no network interface named {}", name),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(name: &str) -> anyhow::Result<u32> {
+    bail!(
+        "use the numeric interface index instead of {} as the zone",
+        name
+    )
+}
+
+/// Bind through socket2, which keeps the scope id that picks the interface
+/// a link-local address belongs to
+fn bind_scoped(addr: SocketAddrV6) -> anyhow::Result<TcpListener> {
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&SockAddr::from(addr))
+        .with_context(|| format!("binding {}", addr))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+async fn bind_reuse_port(addr: &str) -> anyhow::Result<TcpListener> {
+    if addr.starts_with(UNIX_PREFIX) || addr.starts_with(PIPE_PREFIX) {
+        bail!("only a TCP local address can be shared by several processes");
+    }
+    let resolved = resolve(addr).await?[0];
+    let socket = match resolved {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket
+        .bind(resolved)
+        .with_context(|| format!("binding {}", addr))?;
+    Ok(socket.listen(1024)?)
+}
+
+/// Create the socket under a private temporary directory, give it its final
+/// mode and owner there and only then rename it into place, so it is never
+/// reachable with the default permissions.
+/// Create the first instance of the pipe. Failing when the name is taken
+/// keeps another process from serving the same pipe unnoticed.
+#[cfg(windows)]
+fn bind_pipe(name: &str) -> anyhow::Result<LocalListener> {
+    let first = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(name)
+        .with_context(|| format!("creating named pipe {}", name))?;
+    Ok(LocalListener::Pipe(
+        name.to_string(),
+        tokio::sync::Mutex::new(first),
+    ))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, opts: &UnixSocketOptions) -> anyhow::Result<LocalListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    remove_stale_socket(path)?;
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+    let dir = dir.unwrap_or(Path::new("."));
+    let tmp_dir = dir.join(format!(".ssh2fwd-{}.tmp", std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&tmp_dir)
+        .with_context(|| format!("creating {}", tmp_dir.display()))?;
+
+    let result: anyhow::Result<UnixListener> = (|| {
+        let tmp = tmp_dir.join("sock");
+        let listener = UnixListener::bind(&tmp)?;
+        if let Some(mode) = opts.mode {
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("setting mode {:o} on {}", mode, path.display()))?;
+        }
+        if let Some(owner) = &opts.owner {
+            let (uid, gid) = parse_owner(owner)?;
+            std::os::unix::fs::chown(&tmp, uid, gid)
+                .with_context(|| format!("changing owner of {} to {}", path.display(), owner))?;
+        }
+        std::fs::rename(&tmp, path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_file(tmp_dir.join("sock"));
+    let _ = std::fs::remove_dir(&tmp_dir);
+    Ok(LocalListener::Unix(result?, path.to_path_buf()))
+}
+
+/// A socket file left behind by an earlier run is removed, but only when
+/// nobody accepts connections on it anymore
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !meta.file_type().is_socket() {
+        bail!("{} exists and is not a socket", path.display());
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => bail!("{} is in use by another process", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            log::info!("Removing stale socket {}", path.display());
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("checking {}", path.display())),
+    }
+}
+
+/// --unix-mode value, octal with or without a leading 0
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{} is not an octal file mode", s)),
+    }
+}
+
+#[cfg(unix)]
+fn parse_owner(owner: &str) -> anyhow::Result<(Option<u32>, Option<u32>)> {
+    use crate::privdrop::{lookup_group, lookup_user};
+
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+    let uid = match user {
+        "" => None,
+        user => Some(lookup_user(user)?.0),
+    };
+    let gid = match group {
+        None | Some("") => None,
+        Some(group) => Some(lookup_group(group)?),
+    };
+    Ok((uid, gid))
+}
+
+/// Accept errors that leave the listener usable: the client gave up before
+/// it was accepted, or the process or system ran out of descriptors or
+/// memory for the moment
+pub fn is_transient_accept_error(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(
+        libc::EMFILE
+        | libc::ENFILE
+        | libc::ENOBUFS
+        | libc::ENOMEM
+        | libc::ECONNABORTED
+        | libc::EPROTO
+        | libc::EINTR,
+    ) = e.raw_os_error()
+    {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+impl LocalStream {
+    /// Limit how much data the kernel queues for the client. Writes block
+    /// once that much is waiting, which holds back the copy task and, through
+    /// the SSH window, the remote side.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        match self {
+            LocalStream::Tcp(s) => socket2::SockRef::from(s).set_send_buffer_size(size),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => socket2::SockRef::from(s).set_send_buffer_size(size),
+            // The pipe buffer size is fixed when the instance is created
+            #[cfg(windows)]
+            LocalStream::Pipe(_) => Ok(()),
+        }
+    }
+
+    /// Address the client connected to, where a SOCKS5 UDP relay listens
+    /// for it. Unix socket and pipe clients are on this machine.
+    pub fn local_ip(&self) -> IpAddr {
+        match self {
+            LocalStream::Tcp(s) => s
+                .local_addr()
+                .map_or(IpAddr::from(Ipv4Addr::LOCALHOST), |a| a.ip()),
+            _ => IpAddr::from(Ipv4Addr::LOCALHOST),
+        }
+    }
+
+    /// Mark the packets to the client, only TCP has a TOS
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        match self {
+            LocalStream::Tcp(s) => crate::tos::set(socket2::SockRef::from(s), tos),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for LocalStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LocalStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(windows)]
+            LocalStream::Pipe(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LocalStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            LocalStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(windows)]
+            LocalStream::Pipe(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LocalStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(windows)]
+            LocalStream::Pipe(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LocalStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            LocalStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(windows)]
+            LocalStream::Pipe(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Whether `check_exposure` lets `addr` be bound, for each combination
+    /// of protected and --allow-external
+    async fn verdicts(addr: &str) -> [bool; 4] {
+        let mut verdicts = [false; 4];
+        for (i, (protected, allow_external)) in
+            [(false, false), (true, false), (false, true), (true, true)]
+                .into_iter()
+                .enumerate()
+        {
+            verdicts[i] = check_exposure(addr, protected, allow_external)
+                .await
+                .is_ok();
+        }
+        verdicts
+    }
+
+    #[tokio::test]
+    async fn loopback_is_always_fine() {
+        for addr in [
+            "127.0.0.1:8080",
+            "127.0.0.2:8080",
+            "[::1]:8080",
+            "localhost:8080",
+        ] {
+            assert_eq!(verdicts(addr).await, [true; 4], "{}", addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn exposed_needs_protection_or_allow_external() {
+        for addr in [
+            "0.0.0.0:8080",
+            "[::]:8080",
+            "192.0.2.10:8080",
+            "[2001:db8::10]:8080",
+        ] {
+            assert_eq!(verdicts(addr).await, [false, true, true, true], "{}", addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn sockets_and_pipes_are_local() {
+        for addr in [
+            format!("{}/tmp/ssh2fwd.sock", UNIX_PREFIX),
+            format!(r"{}\\.\pipe\ssh2fwd", PIPE_PREFIX),
+        ] {
+            assert_eq!(verdicts(&addr).await, [true; 4], "{}", addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn refusal_names_the_ways_out() {
+        let e = check_exposure("0.0.0.0:8080", false, false)
+            .await
+            .unwrap_err();
+        let e = e.to_string();
+        for way in [
+            "--allow-from",
+            "--require-tunnel-token",
+            "--client-token-file",
+            "--allow-external",
+        ] {
+            assert!(e.contains(way), "{}", e);
+        }
+    }
+
+    #[tokio::test]
+    async fn bad_addresses_are_errors_even_when_allowed() {
+        for addr in ["0.0.0.0", "[::1", "host:port"] {
+            assert!(check_exposure(addr, true, true).await.is_err(), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn reachable_on_filters_by_family() {
+        for (_, ip) in reachable_on(IpAddr::from([0, 0, 0, 0])) {
+            assert!(ip.is_ipv4() && !ip.is_loopback(), "{}", ip);
+        }
+        assert!(reachable_on(IpAddr::from([127, 0, 0, 1])).is_empty());
+    }
+
+    /// Out of file descriptors the listener keeps going, and accepts the
+    /// waiting client once there are descriptors again
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn accept_outlasts_running_out_of_descriptors() {
+        if !crate::tests::in_child("local::tests::accept_outlasts_running_out_of_descriptors") {
+            return;
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = LocalListener::Tcp(listener);
+        let limit = libc::rlimit {
+            rlim_cur: 256,
+            rlim_max: 256,
+        };
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+        let mut hogs = Vec::new();
+        loop {
+            match std::fs::File::open("/dev/null") {
+                Ok(file) => hogs.push(file),
+                Err(e) if e.raw_os_error() == Some(libc::EMFILE) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        // The client takes the last descriptor, none is left for accepting it
+        hogs.pop();
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        let acceptor = tokio::spawn(async move { listener.accept_retrying().await });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!acceptor.is_finished(), "accept gave up");
+        drop(hogs);
+        let accepted = tokio::time::timeout(Duration::from_secs(5), acceptor).await;
+        assert!(accepted.unwrap().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,408 @@
+use crate::session_lost::SessionLost;
+use crate::transport::{
+    AgentIdentity, AnswerPrompts, ChannelOpenError, DataWait, ExecOutput, FileAttrs,
+    HandshakeOptions, ProbeOutcome, SessionInfo, SshTransport,
+};
+use anyhow::bail;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long an idle reader sleeps in `wait_for_data`, reads block for the
+/// session timeout themselves
+const WAIT_POLL: Duration = Duration::from_millis(1);
+/// How often `forward_accept` looks for a connection
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+/// A session without an SSH server: a channel is a TCP connection made from
+/// this machine to the target, as if the SSH server ran here. The forwarding
+/// code runs over it unchanged, for --test-mode and as the second backend of
+/// the tests.
+#[derive(Clone)]
+pub struct LoopbackTransport {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    timeout_ms: AtomicU32,
+    channel_open_timeout: Duration,
+    generation: AtomicU64,
+    lost: Arc<SessionLost>,
+    latency: Duration,
+    empty_reads: bool,
+    forwarding_prohibited: bool,
+    panic_on_read: Arc<AtomicBool>,
+    closed_channels: AtomicU64,
+}
+
+/// A channel, the connection to the target
+pub struct LoopbackChannel {
+    socket: Arc<TcpStream>,
+    eof: Arc<AtomicBool>,
+}
+
+/// One direction of a channel. Reads that run into the session timeout fail
+/// with TimedOut like libssh2's, a read of nothing marks the channel EOF.
+pub struct LoopbackStream {
+    socket: Arc<TcpStream>,
+    eof: Arc<AtomicBool>,
+    latency: Duration,
+    empty_reads: bool,
+    panic_on_read: Arc<AtomicBool>,
+}
+
+impl LoopbackTransport {
+    pub fn new(channel_open_timeout: Duration) -> Self {
+        LoopbackTransport {
+            inner: Arc::new(Inner {
+                timeout_ms: AtomicU32::new(0),
+                channel_open_timeout,
+                generation: AtomicU64::new(0),
+                lost: SessionLost::new(),
+                latency: Duration::ZERO,
+                empty_reads: false,
+                forwarding_prohibited: false,
+                panic_on_read: Arc::default(),
+                closed_channels: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Delay each channel open and each read by `latency`, like a link to a
+    /// server far away
+    #[cfg(test)]
+    pub fn latency(mut self, latency: Duration) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("latency is set before the session is shared")
+            .latency = latency;
+        self
+    }
+
+    /// Reads that find nothing within the session timeout return nothing
+    /// instead of failing with TimedOut, as libssh2's may; only the channel
+    /// EOF tells them from the end of the data
+    #[cfg(test)]
+    pub fn empty_reads(mut self) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("empty reads are set before the session is shared")
+            .empty_reads = true;
+        self
+    }
+
+    /// Refuse every channel open as administratively prohibited, like a
+    /// server with `AllowTcpForwarding no`
+    #[cfg(test)]
+    pub fn prohibit_forwarding(mut self) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("forwarding is prohibited before the session is shared")
+            .forwarding_prohibited = true;
+        self
+    }
+
+    /// Have the next read of a channel that returns data panic, to see the
+    /// rest of the connection and the process survive it
+    #[cfg(test)]
+    pub fn panic_on_next_read(&self) {
+        self.inner.panic_on_read.store(true, Ordering::Release);
+    }
+
+    /// The session timeout channels opened now get
+    #[cfg(test)]
+    pub fn timeout_ms(&self) -> u32 {
+        self.inner.timeout_ms.load(Ordering::Acquire)
+    }
+
+    /// How many channels `close_channel` closed
+    #[cfg(test)]
+    pub fn closed_channels(&self) -> u64 {
+        self.inner.closed_channels.load(Ordering::Acquire)
+    }
+
+    /// The streams of a connected socket, under the session timeout
+    fn channel(
+        &self,
+        socket: TcpStream,
+    ) -> io::Result<(LoopbackChannel, LoopbackStream, LoopbackStream)> {
+        let timeout = match self.inner.timeout_ms.load(Ordering::Acquire) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms.into())),
+        };
+        socket.set_read_timeout(timeout)?;
+        socket.set_write_timeout(timeout)?;
+        socket.set_nodelay(true)?;
+        let (socket, eof) = (Arc::new(socket), Arc::new(AtomicBool::new(false)));
+        let stream = || LoopbackStream {
+            socket: socket.clone(),
+            eof: eof.clone(),
+            latency: self.inner.latency,
+            empty_reads: self.inner.empty_reads,
+            panic_on_read: self.inner.panic_on_read.clone(),
+        };
+        let (reader, writer) = (stream(), stream());
+        Ok((LoopbackChannel { socket, eof }, reader, writer))
+    }
+
+    fn connect(&self, host: &str, port: u16) -> Result<TcpStream, ChannelOpenError> {
+        std::thread::sleep(self.inner.latency);
+        if self.inner.forwarding_prohibited {
+            return Err(ChannelOpenError::Prohibited);
+        }
+        let addrs: Vec<SocketAddr> = (host, port)
+            .to_socket_addrs()
+            .map_err(|_| ChannelOpenError::ConnectFailed)?
+            .collect();
+        let mut last = ChannelOpenError::ConnectFailed;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, self.inner.channel_open_timeout) {
+                Ok(socket) => return Ok(socket),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => last = ChannelOpenError::TimedOut,
+                Err(_) => last = ChannelOpenError::ConnectFailed,
+            }
+        }
+        Err(last)
+    }
+}
+
+impl Read for LoopbackStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        std::thread::sleep(self.latency);
+        match (&*self.socket).read(buf) {
+            Ok(0) if !buf.is_empty() => {
+                self.eof.store(true, Ordering::Release);
+                Ok(0)
+            }
+            Ok(_) if self.panic_on_read.swap(false, Ordering::AcqRel) => {
+                panic!("injected panic in a channel read")
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && self.empty_reads => Ok(0),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, e))
+            }
+            read => read,
+        }
+    }
+}
+
+impl Write for LoopbackStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match (&*self.socket).write(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, e))
+            }
+            written => written,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DataWait for LoopbackTransport {
+    fn data_generation(&self) -> u64 {
+        self.inner.generation.load(Ordering::Acquire)
+    }
+
+    fn wait_for_data(&self, seen: u64, timeout: Duration) {
+        if self.data_generation() == seen {
+            std::thread::sleep(timeout.min(WAIT_POLL));
+        }
+    }
+
+    fn data_read(&self) {
+        self.inner.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+impl SshTransport for LoopbackTransport {
+    type Stream = LoopbackStream;
+    type Channel = LoopbackChannel;
+    type RemoteFile = std::fs::File;
+    type Listener = TcpListener;
+
+    fn handshake(_tcp: tokio::net::TcpStream, _options: &HandshakeOptions) -> anyhow::Result<Self> {
+        bail!("the loopback transport has no SSH server to shake hands with")
+    }
+
+    #[cfg(unix)]
+    fn handshake_socket(_socket: UnixStream, _options: &HandshakeOptions) -> anyhow::Result<Self> {
+        bail!("the loopback transport has no SSH server to shake hands with")
+    }
+
+    fn userauth_agent(&self, _user: &str, _socket: Option<&Path>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn agent_identities(_socket: Option<&Path>) -> anyhow::Result<Vec<AgentIdentity>> {
+        Ok(vec![])
+    }
+
+    fn userauth_password(&self, _user: &str, _password: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn userauth_key_file(
+        &self,
+        _user: &str,
+        _key: &Path,
+        _passphrase: Option<&str>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn userauth_keyboard_interactive(
+        &self,
+        _user: &str,
+        _answer: &mut AnswerPrompts,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn authenticated(&self) -> bool {
+        true
+    }
+
+    fn open_direct_tcpip(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> anyhow::Result<(LoopbackChannel, LoopbackStream, LoopbackStream)> {
+        let socket = self.connect(host, port)?;
+        Ok(self.channel(socket)?)
+    }
+
+    fn probe_direct_tcpip(&self, host: &str, port: u16) -> (ProbeOutcome, Duration) {
+        let started = Instant::now();
+        let outcome = match self.connect(host, port) {
+            Ok(_) => ProbeOutcome::Open,
+            Err(ChannelOpenError::TimedOut) => ProbeOutcome::TimedOut,
+            Err(ChannelOpenError::Prohibited) => ProbeOutcome::Prohibited,
+            Err(_) => ProbeOutcome::ConnectFailed,
+        };
+        (outcome, started.elapsed())
+    }
+
+    fn channel_eof(&self, channel: &LoopbackChannel) -> bool {
+        channel.eof.load(Ordering::Acquire)
+    }
+
+    fn send_eof(&self, channel: &mut LoopbackChannel) -> anyhow::Result<()> {
+        Ok(channel.socket.shutdown(Shutdown::Write)?)
+    }
+
+    fn close_channel(&self, channel: LoopbackChannel) -> anyhow::Result<()> {
+        self.inner.closed_channels.fetch_add(1, Ordering::AcqRel);
+        match channel.socket.shutdown(Shutdown::Both) {
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_timeout(&self, timeout_ms: u32) {
+        self.inner.timeout_ms.store(timeout_ms, Ordering::Release);
+    }
+
+    fn host_key_fingerprint(&self) -> Option<String> {
+        None
+    }
+
+    fn host_key(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn session_info(&self) -> SessionInfo {
+        let none = || "none".to_string();
+        SessionInfo {
+            server_version: "loopback".to_string(),
+            kex: none(),
+            host_key: none(),
+            cipher: none(),
+            mac: none(),
+            compression: none(),
+        }
+    }
+
+    fn alive(&self) -> bool {
+        !self.inner.lost.is_lost()
+    }
+
+    fn lost(&self) -> &SessionLost {
+        &self.inner.lost
+    }
+
+    fn disconnect(&self, _description: &str) {
+        self.inner.lost.close();
+    }
+
+    fn connection_failed(&self) -> bool {
+        false
+    }
+
+    /// Runs the command on this machine
+    fn exec(&self, command: &str) -> anyhow::Result<ExecOutput> {
+        #[cfg(unix)]
+        let output = std::process::Command::new("/bin/sh")
+            .args(["-c", command])
+            .output()?;
+        #[cfg(not(unix))]
+        let output = std::process::Command::new("cmd")
+            .args(["/C", command])
+            .output()?;
+        Ok(ExecOutput::from_bytes(
+            output.status.code().unwrap_or(-1),
+            &output.stdout,
+            &output.stderr,
+        ))
+    }
+
+    fn sftp_open(&self, _path: &Path, _create: Option<u32>) -> anyhow::Result<std::fs::File> {
+        bail!("the loopback transport has no SFTP")
+    }
+
+    fn sftp_stat(&self, _path: &Path) -> anyhow::Result<FileAttrs> {
+        bail!("the loopback transport has no SFTP")
+    }
+
+    fn sftp_setstat(&self, _path: &Path, _attrs: FileAttrs) -> anyhow::Result<()> {
+        bail!("the loopback transport has no SFTP")
+    }
+
+    fn forward_listen(&self) -> anyhow::Result<(TcpListener, u16)> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        Ok((listener, port))
+    }
+
+    fn forward_accept(
+        &self,
+        listener: &mut TcpListener,
+        timeout: Duration,
+    ) -> anyhow::Result<(LoopbackChannel, LoopbackStream, LoopbackStream)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match listener.accept() {
+                Ok((socket, _)) => {
+                    socket.set_nonblocking(false)?;
+                    return Ok(self.channel(socket)?);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        bail!("nobody connected within {:?}", timeout);
+                    }
+                    std::thread::sleep(ACCEPT_POLL);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn server_ip(&self) -> Option<IpAddr> {
+        Some(Ipv4Addr::LOCALHOST.into())
+    }
+}
@@ -1,16 +1,154 @@
-use clap::Parser;
+use acl::{AccessList, Cidr, DestAccessList, DestRule, Verdict};
+use anyhow::Context;
+use api::{ApiState, LiveConnection, LiveConnections};
+use audit::{json_string, AuditLog, ByteSize, ConnectionRecord, Value};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueHint};
+use completions::Shell;
+use config::AppConfig;
+use conn_state::{ConnectionState, StateMachine};
 use futures::executor::block_on;
-use futures::lock::Mutex;
-use log::{debug, error, info, trace, warn};
-use ssh2::Session;
-use ssh2::Stream;
-use std::io::Read;
+use futures::stream::{FuturesUnordered, StreamExt};
+use known_hosts::StrictHostKeyChecking;
+use latency::LatencyTracker;
+use limits::{ByteQuotas, ClientByteLimit, ConnQuota, ConnectionLimiter, RateLimit, RateLimiter};
+use local::{LocalListener, LocalStream, PeerAddr, UnixSocketOptions};
+use log::{debug, error, info, log_enabled, trace, warn, Level};
+use multi_path::{Credentials, MultiPath, SessionTarget};
+use mysql_log::MysqlLogger;
+use pool::ChannelPool;
+use preflight::Preflight;
+use proxy::Proxy;
+use receive_window::ReceiveWindow;
+use secret::Secret;
+use sni::SniRoute;
+use socks::SocksAuth;
 use std::io::Write;
-use std::sync::Arc;
+use std::io::{IsTerminal, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Instant;
+use token::{ClientToken, TunnelToken};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
 use tokio::net::TcpStream;
-use tokio::time::{sleep, Duration};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::{sleep, timeout, Duration};
+use transport::{
+    key_unreadable, ChannelOpenError, DataWait, HandshakeOptions, Ssh2Transport, SshTransport,
+};
+
+mod acl;
+mod activity;
+mod addr;
+mod api;
+mod audit;
+mod completions;
+mod config;
+mod config_file;
+mod conn_state;
+mod connstr;
+#[cfg(unix)]
+mod control;
+mod cp;
+mod dns_relay;
+mod escape_sequences;
+mod fingerprint;
+mod known_hosts;
+mod latency;
+mod limits;
+mod local;
+mod loopback;
+mod mdns;
+mod multi_path;
+mod mysql_log;
+mod pool;
+mod port_knock;
+#[cfg(unix)]
+mod preflight;
+#[cfg(target_os = "linux")]
+mod prefork;
+mod privdrop;
+mod proxy;
+#[cfg(unix)]
+mod proxy_command;
+mod receive_window;
+#[cfg(any(feature = "consul", feature = "etcd"))]
+mod registry;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod scan;
+mod secret;
+mod self_test;
+mod session_lost;
+mod shutdown;
+mod sni;
+mod socks;
+mod socks_udp;
+mod token;
+mod tos;
+mod transport;
+mod validate;
+
+#[cfg(test)]
+mod tests;
+
+/// Exit status of --test-mode when the data didn't come back unchanged
+const EXIT_TEST_FAILED: i32 = 1;
+
+/// Password for --allow-env-auth
+const PASSWORD_ENV: &str = "SSH2FWD_PASSWORD";
+
+/// Exit status when the password would have to be asked for but there is no
+/// terminal to ask on
+const EXIT_NO_TTY_FOR_PROMPT: i32 = 3;
+
+/// How strictly --sandbox is applied
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SandboxMode {
+    /// Exit when the kernel can't enforce the sandbox
+    Strict,
+    /// Run without the parts the kernel doesn't support
+    BestEffort,
+}
+
+/// What to do instead of forwarding
+#[derive(Subcommand)]
+enum Command {
+    /// Find the open ports of a host as seen from the SSH server
+    ///
+    /// The SSH server is asked to connect to each port, like nmap -sT run on
+    /// the server, e.g.
+    /// ssh2fwd -s jump -u me scan --ports 1-1024 --host remote.internal
+    Scan(scan::ScanOpts),
+    /// Copy a file to or from the SSH server over the tunnel's session
+    ///
+    /// The path on the server starts with ':', the transfer runs through
+    /// SFTP, e.g.
+    /// ssh2fwd -s jump -u me cp :/var/log/syslog ./syslog
+    Cp(cp::CpOpts),
+}
+
+/// Ways of logging in to the SSH server, for --auth-order
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum AuthMethod {
+    /// Identities of the ssh-agent
+    Agent,
+    /// The private key of --identity
+    Key,
+    /// Answering the server's prompts on the terminal
+    KeyboardInteractive,
+    /// SSH2FWD_PASSWORD with --allow-env-auth, otherwise prompted for
+    Password,
+}
+
+/// Direction data flows in when only one is forwarded
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum HalfDuplex {
+    LocalToRemote,
+    RemoteToLocal,
+}
 
 #[derive(Parser)]
 #[clap(
@@ -18,195 +156,2646 @@ use tokio::time::{sleep, Duration};
     about = "Port forwarding via SSH\n\nRun this application \
  to connect to remote SSH server\nand access a different server that is reachable via SSH \
  server to a local port\n\n\
- e.g ./ssh2fwd --sshaddress 10.0.0.1:22 --sshuser username --remote-srv localhost --remote-port 8080 -l 0.0.0.0:8181\
- "
+ e.g ./ssh2fwd --sshaddress 10.0.0.1:22 --sshuser username --remote-srv localhost --remote-port 8080 -l 127.0.0.1:8181\
+ ",
+    disable_help_subcommand = true
+)]
+#[cfg_attr(
+    any(feature = "consul", feature = "etcd"),
+    clap(group(clap::ArgGroup::new("registry").multiple(true)))
 )]
 struct Opts {
-    /// Address of the SSH server, must be in IP:PORT or DNS:PORT format
-    #[clap(short = 's', long)]
-    sshaddress: String,
+    /// Address of the SSH server as HOST[:PORT], port 22 by default. IPv6 addresses with a port go in brackets, e.g. [2001:db8::10]:2222
+    #[clap(
+        short = 's',
+        long,
+        required_unless_present_any = ["mdns_service_name", "connection_string", "sshaddress_from_env", "generate_completions", "list_identities", "test_mode"],
+        value_hint = ValueHint::Hostname
+    )]
+    sshaddress: Option<String>,
+    /// Take the address of the SSH server from this environment variable,
+    /// e.g. one an orchestrator injects. --sshaddress is the fallback when
+    /// it isn't set
+    #[clap(long, value_name = "VAR", conflicts_with = "mdns_service_name")]
+    sshaddress_from_env: Option<String>,
+    /// Find the SSH server by its mDNS/DNS-SD instance name under _ssh._tcp.local
+    /// instead of using --sshaddress
+    #[clap(long, conflicts_with = "sshaddress")]
+    mdns_service_name: Option<String>,
     /// User name to login to SSH server
-    #[clap(short = 'u', long, default_value = "invalid_user")]
+    #[clap(short = 'u', long, default_value = "invalid_user", value_hint = ValueHint::Username)]
     sshuser: String,
+    /// Path of the ssh-agent socket to use instead of SSH_AUTH_SOCK, e.g. for
+    /// gpg-agent or the 1Password agent
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    agent_socket: Option<PathBuf>,
+    /// Private key file to log in with, e.g. ~/.ssh/id_ed25519. Asks for the
+    /// passphrase of an encrypted key when there is a terminal
+    #[clap(short = 'i', long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    identity: Option<PathBuf>,
+    /// Authentication methods to try, in order. Methods without credentials,
+    /// such as key without --identity, are skipped
+    #[clap(
+        long,
+        value_name = "METHODS",
+        value_delimiter = ',',
+        default_value = "agent,key,password"
+    )]
+    auth_order: Vec<AuthMethod>,
+    /// Knock on these ports of the SSH server, in order, before connecting,
+    /// e.g. 1234,5678,9012
+    #[clap(long, value_name = "PORTS", value_delimiter = ',')]
+    port_knock: Vec<u16>,
+    /// Pause between two knocks
+    #[clap(long, value_name = "M", default_value = "100", requires = "port_knock")]
+    port_knock_delay_ms: u64,
+    /// Knock with TCP connection attempts (SYN) instead of UDP packets
+    #[clap(long, requires = "port_knock")]
+    port_knock_tcp: bool,
+    /// Time the server gets to open the SSH port after the last knock
+    #[clap(long, value_name = "M", default_value = "500", requires = "port_knock")]
+    port_knock_wait_ms: u64,
+    /// known_hosts file the server key is checked against and new hosts are
+    /// added to [default: ~/.ssh/known_hosts]
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    known_hosts: Option<PathBuf>,
+    /// What to do about a server whose key isn't known yet or has changed
+    #[clap(long, value_name = "MODE", default_value = "ask")]
+    strict_host_key_checking: StrictHostKeyChecking,
+    /// Add the key of a new server to known_hosts without asking, same as
+    /// --strict-host-key-checking accept-new
+    #[clap(long, conflicts_with = "strict_host_key_checking")]
+    accept_new: bool,
+    /// Open one SSH session from each of these local addresses and spread new
+    /// connections over them, e.g. 10.0.0.1,10.0.0.2. A failed session gets
+    /// no new connections until it is reconnected
+    #[clap(long, value_name = "ADDRS", value_delimiter = ',')]
+    source_addresses: Vec<IpAddr>,
+    /// Reach the SSH server through this command instead of connecting to it,
+    /// like ssh's ProxyCommand. The session runs over its stdin and stdout,
+    /// %h and %p in it are the host and port of -s and %r the user, e.g.
+    /// "aws ssm start-session --target %h --document-name
+    /// AWS-StartSSHSession --parameters portNumber=%p". Unix only
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        value_hint = ValueHint::CommandString,
+        conflicts_with_all = ["source_addresses", "port_knock"]
+    )]
+    proxy_command: Option<String>,
+    /// Reach the SSH server through this proxy, as
+    /// SCHEME://[USER:PASSWORD@]HOST[:PORT] with http (CONNECT), socks5 or
+    /// socks5h (the proxy resolves the server's name). HTTPS_PROXY or
+    /// ALL_PROXY name it when not given
+    #[clap(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["proxy_command", "source_addresses", "port_knock"]
+    )]
+    proxy: Option<String>,
+    /// Connect to the SSH server directly even when HTTPS_PROXY or ALL_PROXY
+    /// is set
+    #[clap(long, conflicts_with = "proxy")]
+    no_proxy: bool,
+    /// Seconds connecting to the SSH server and the SSH handshake may take
+    /// before giving up
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value = "30",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    handshake_timeout: u64,
+    /// Milliseconds opening a channel may take before the connection is given
+    /// up, e.g. when the server can't reach the remote target
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value = "20000",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    channel_open_timeout_ms: u64,
+    /// Compress the SSH session with zlib, worth it for text-heavy protocols
+    /// over slow links. Only if the server agrees, which is logged
+    #[clap(short = 'C', long, overrides_with = "no_compression")]
+    compression: bool,
+    /// Don't compress the SSH session, e.g. to override the config file
+    #[clap(long, overrides_with = "compression")]
+    no_compression: bool,
+    /// Key exchange algorithms to offer, comma separated in order of
+    /// preference, instead of libssh2's defaults
+    #[clap(long, value_name = "ALGS")]
+    kex: Option<String>,
+    /// Host key algorithms to offer, comma separated in order of preference,
+    /// e.g. ssh-ed25519 first when that is the key known_hosts has
+    #[clap(long, value_name = "ALGS")]
+    hostkey_algorithms: Option<String>,
+    /// Ciphers to offer, comma separated in order of preference
+    #[clap(long, value_name = "ALGS")]
+    cipher: Option<String>,
+    /// MAC algorithms to offer, comma separated in order of preference
+    #[clap(long, value_name = "ALGS")]
+    mac: Option<String>,
+    /// IP TOS byte for the connection to the SSH server, decimal or hex, e.g.
+    /// 0x28 for DSCP AF11 or 0xb8 for EF
+    #[clap(long, value_name = "TOS", value_parser = tos::parse)]
+    ip_tos: Option<u8>,
+    /// Mark the accepted local TCP connections with --ip-tos as well
+    #[clap(long, requires = "ip_tos")]
+    propagate_tos: bool,
+    /// Share one SSH session between the ssh2fwd processes of a user to the
+    /// same server: the first one listens on a control socket, the others
+    /// open their channels through it instead of logging in. Unix only
+    #[clap(long, conflicts_with = "dynamic")]
+    control: bool,
     /// Remote address that is reachable via SSH server
-    #[clap(short = 'r', long, default_value = "localhost")]
+    #[clap(short = 'r', long, default_value = "localhost", value_hint = ValueHint::Hostname)]
     remote_srv: String,
     /// Remote port that is reachable via SSH server
     #[clap(short = 'p', long, default_value = "8080")]
     remote_port: u16,
-    /// Local address:port we have to bind for providing connectivity to RemoteAddress:RemotePort
+    /// Take the remote port from this environment variable, --remote-port is
+    /// the fallback when it isn't set
+    #[clap(long, value_name = "VAR")]
+    remote_port_from_env: Option<String>,
+    /// Run this command on the SSH server after logging in and exit unless it
+    /// succeeds, e.g. "nc -z localhost 5432", to check the remote side can
+    /// reach the target before accepting connections
+    #[clap(long, value_name = "COMMAND")]
+    remote_check_command: Option<String>,
+    /// Check once after logging in that the SSH server reaches the remote
+    /// target, and exit unless it does: through a direct-tcpip channel, or
+    /// with bash or nc run on the server
+    #[clap(long, value_enum, value_name = "METHOD", conflicts_with = "dynamic")]
+    preflight: Option<Preflight>,
+    /// Relay DNS queries arriving on UDP at this local address to
+    /// --dns-server through the tunnel, e.g. 127.0.0.53:5300
+    #[clap(long, value_name = "ADDR", requires = "dns_server")]
+    dns_forward: Option<SocketAddr>,
+    /// DNS server as seen from the SSH server, HOST[:PORT], asked over TCP
+    /// for --dns-forward
+    #[clap(long, value_name = "HOST[:PORT]", requires = "dns_forward")]
+    dns_server: Option<String>,
+    /// Local address:port we have to bind for providing connectivity to RemoteAddress:RemotePort,
+    /// or unix:/path/to/socket to listen on a Unix socket. Link-local IPv6
+    /// addresses need the interface, e.g. [fe80::1%eth0]:8080
     #[clap(short = 'l', long, default_value = "127.0.0.1:8080")]
     local_srv_address: String,
+    /// Listen on this Windows named pipe instead of a TCP port, e.g.
+    /// \\.\pipe\ssh2fwd
+    #[cfg(windows)]
+    #[clap(long, value_name = "NAME", conflicts_with = "local_srv_address")]
+    named_pipe: Option<String>,
+    /// Listen on a non-loopback local address even though no --allow-from or
+    /// token protects it
+    #[clap(long)]
+    allow_external: bool,
+    /// Permissions of the Unix socket file, e.g. 0660
+    #[clap(long, value_name = "MODE", value_parser = local::parse_mode)]
+    unix_mode: Option<u32>,
+    /// Owner of the Unix socket file as user, user:group or :group.
+    /// Changing the owner usually needs root
+    #[clap(long, value_name = "OWNER")]
+    unix_owner: Option<String>,
+    /// Whole tunnel in one argument:
+    /// user@sshhost:22/remote-host:remote-port/local-host:local-port.
+    /// The user, SSH port and the trailing parts are optional, flags given
+    /// separately take precedence
+    #[clap(long)]
+    connection_string: Option<String>,
+    /// Only forward local clients from this address or CIDR range, can be repeated.
+    /// Without any allow rule every client is accepted
+    #[clap(long, value_name = "CIDR")]
+    allow_from: Vec<Cidr>,
+    /// Never forward local clients from this address or CIDR range, can be repeated.
+    /// Deny rules win over allow rules
+    #[clap(long, value_name = "CIDR")]
+    deny_from: Vec<Cidr>,
+    /// File with additional rules, one `allow <cidr>` or `deny <cidr>` per line
+    /// (a bare CIDR means allow), re-read on SIGHUP
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    allow_from_file: Option<PathBuf>,
+    /// Act as a SOCKS5 proxy on the local address and forward each connection
+    /// to the destination the client asks for, --remote-srv/--remote-port are
+    /// not used
+    #[clap(short = 'D', long)]
+    dynamic: bool,
+    /// Destinations SOCKS5 clients may connect to, as <host-glob|CIDR>[:port|port-range]
+    /// (e.g. *.corp.example:443 or [2001:db8::/32]:8000-8100), can be repeated.
+    /// Host globs match the name the client sent, CIDRs only literal addresses.
+    /// Without any rule every destination is allowed
+    #[clap(long, value_name = "RULE")]
+    dest_allow: Vec<DestRule>,
+    /// File with additional --dest-allow rules, one per line, re-read on SIGHUP
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    dest_allow_file: Option<PathBuf>,
+    /// User SOCKS5 clients have to log in as (RFC 1929 username/password),
+    /// clients that don't offer it are turned away
+    #[clap(
+        long,
+        visible_alias = "socks5-user",
+        value_name = "USER",
+        requires_all = ["dynamic", "socks_password"],
+        conflicts_with = "socks_users_file"
+    )]
+    socks_user: Option<String>,
+    /// Password for --socks-user. Prefer --socks-users-file, command lines
+    /// are visible to other users
+    #[clap(
+        long,
+        visible_alias = "socks5-pass",
+        value_name = "PASSWORD",
+        requires = "socks_user"
+    )]
+    socks_password: Option<String>,
+    /// File with the users SOCKS5 clients may log in as, one user:password
+    /// per line
+    #[clap(long, value_name = "PATH", requires = "dynamic", value_hint = ValueHint::FilePath)]
+    socks_users_file: Option<PathBuf>,
+    /// Accept SOCKS5 BIND, for active mode FTP and the like: the SSH server
+    /// listens on a free port for the peer to connect to. The port is open
+    /// to anyone who can reach the SSH server while the client waits.
+    #[clap(long, requires = "dynamic")]
+    socks_enable_bind: bool,
+    /// Route TLS connections by the server name (SNI) in their ClientHello
+    /// without decrypting them, as NAME=HOST[:PORT] with the port of
+    /// --remote-port by default, e.g. api.example.org=10.0.0.5:443. NAME may
+    /// be *.domain. Other connections go to --remote-srv, can be repeated
+    #[clap(
+        long,
+        value_name = "NAME=HOST[:PORT]",
+        conflicts_with_all = ["dynamic", "reuse_channel", "channel_burst_size"]
+    )]
+    sni_passthrough: Vec<SniRoute>,
+    /// Maximum number of connections forwarded at the same time for a single
+    /// client address, further connections from it are closed right away
+    #[clap(long, value_name = "N")]
+    connection_limit_per_source_ip: Option<u32>,
+    /// Maximum rate of new connections per client address as <conns/sec>[:burst],
+    /// e.g. 5:20. Connections over the rate are closed right away
+    #[clap(long, value_name = "RATE")]
+    rate_limit: Option<RateLimit>,
+    /// Don't rate limit clients connecting from a loopback address
+    #[clap(long, requires = "rate_limit")]
+    rate_limit_exempt_loopback: bool,
+    /// Close a connection once it has forwarded this much data, counting
+    /// both directions (e.g. 100M)
+    #[clap(long, value_name = "SIZE")]
+    max_bytes_per_conn: Option<ByteSize>,
+    /// Refuse new connections from a client address once its connections
+    /// forwarded this much data within the window, as <bytes>/<window>
+    /// (e.g. 10G/24h)
+    #[clap(long, value_name = "QUOTA")]
+    max_bytes_per_client: Option<ClientByteLimit>,
+    /// Only forward data in one direction and don't run the copy task for the
+    /// other, for one-way protocols such as shipping logs to a collector
+    #[clap(long, value_name = "DIRECTION")]
+    half_duplex: Option<HalfDuplex>,
+    /// Data from the remote side that may wait for a slow local client
+    /// (e.g. 256K). Beyond it the tunnel stops reading the channel, so the
+    /// remote service is held back instead of the data piling up
+    #[clap(long, value_name = "SIZE")]
+    receive_window_size: Option<ByteSize>,
+    /// Close a connection when one side takes none of the data sent to it
+    /// for this many seconds, so a stalled peer doesn't hold it open for
+    /// good. 0 waits forever
+    #[clap(long, value_name = "SECS", default_value = "300")]
+    write_timeout: u64,
+    /// Keep the SSH channel open when a local client disconnects and hand it
+    /// to the next one. Only for stateless backends: the remote server sees
+    /// one continuous TCP connection even though the local side reconnects
+    #[clap(long, conflicts_with_all = ["dynamic", "half_duplex"])]
+    reuse_channel: bool,
+    /// When a local connection leaves fewer than N opened channels waiting,
+    /// top them up to N at once in the background, so the next connections
+    /// don't wait for a channel open. Each is still used for one connection
+    /// only
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u16).range(1..),
+        conflicts_with = "dynamic"
+    )]
+    channel_burst_size: Option<u16>,
+    /// Log the SQL of MySQL queries going through the tunnel at debug level,
+    /// on by default when the remote port is 3306
+    #[clap(long)]
+    mysql_logging: bool,
+    /// Local clients must send a 32 byte token before anything else, otherwise
+    /// they are disconnected. The token is taken from SSH2FWD_TUNNEL_TOKEN
+    /// (64 hex digits) or generated and logged at startup
+    #[clap(long)]
+    require_tunnel_token: bool,
+    /// Shared secret local clients must send as a first line ("<secret>\n")
+    /// before their own data, e.g. (echo "$TOKEN"; cat) | nc host port.
+    /// Prefer --client-token-file, command lines are visible to other users
+    #[clap(long, value_name = "SECRET", conflicts_with = "client_token_file")]
+    client_token: Option<String>,
+    /// Read the --client-token secret from a file
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    client_token_file: Option<PathBuf>,
+    /// Also require the client token when the local address is loopback
+    #[clap(long)]
+    client_token_on_loopback: bool,
+    /// Never prompt for a password, for CI runners and other non-interactive use.
+    /// If the ssh-agent doesn't log in, the password is taken from
+    /// SSH2FWD_PASSWORD and without it ssh2fwd exits with status 1
+    #[clap(long)]
+    allow_env_auth: bool,
+    /// Append a JSON line for every forwarded connection and SSH session event
+    /// to this file, readable only by its owner
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    audit_log: Option<PathBuf>,
+    /// Rotate the audit log once it would grow beyond this size (e.g. 10M),
+    /// keeping 5 old files
+    #[clap(long, value_name = "SIZE", requires = "audit_log")]
+    audit_log_max_size: Option<ByteSize>,
+    /// Warn about local connections whose first byte back from the remote
+    /// side takes longer than this after they connected
+    #[clap(long, value_name = "MS")]
+    latency_sla_ms: Option<u64>,
+    /// Serve a JSON API on this address to list the forwarded connections,
+    /// close one, and show the session and the tunnel, e.g. 127.0.0.1:9091
+    #[clap(long, value_name = "ADDR")]
+    api_addr: Option<SocketAddr>,
+    /// Bearer token API requests must carry in their Authorization header,
+    /// required unless --api-addr is a loopback address
+    #[clap(long, value_name = "TOKEN", requires = "api_addr")]
+    api_token: Option<String>,
+    /// Register the tunnel with the Consul agent at this URL, e.g.
+    /// http://localhost:8500, with a TCP health check on the local port.
+    /// It is deregistered on SIGINT and SIGTERM
+    #[cfg(feature = "consul")]
+    #[clap(
+        long,
+        value_name = "URL",
+        requires = "service_name",
+        group = "registry"
+    )]
+    consul_url: Option<String>,
+    /// Write the tunnel address to /services/ssh2fwd/<NAME> in etcd at this
+    /// URL, e.g. http://localhost:2379, under a lease that is kept alive
+    /// while the tunnel runs
+    #[cfg(feature = "etcd")]
+    #[clap(
+        long,
+        value_name = "URL",
+        requires = "service_name",
+        group = "registry"
+    )]
+    etcd_url: Option<String>,
+    /// Lifetime of the etcd lease, renewed every half of it
+    #[cfg(feature = "etcd")]
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u64).range(2..),
+        requires = "etcd_url"
+    )]
+    etcd_ttl_secs: u64,
+    /// Name the tunnel is registered under with --consul-url or --etcd-url
+    #[cfg(any(feature = "consul", feature = "etcd"))]
+    #[clap(
+        long,
+        value_name = "NAME",
+        requires = "registry",
+        conflicts_with = "forwarder_pool"
+    )]
+    service_name: Option<String>,
+    /// Fork N worker processes that each open their own SSH session and accept
+    /// on the same local port (SO_REUSEPORT), restarting workers that crash.
+    /// Workers can't prompt, so this needs --allow-env-auth. Linux only
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u16).range(1..),
+        requires = "allow_env_auth",
+        conflicts_with = "api_addr"
+    )]
+    forwarder_pool: Option<u16>,
+    /// Once set up, restrict the process with Landlock and seccomp to what
+    /// forwarding needs: no file access beyond the audit log directory and the
+    /// rules files, no starting programs. Linux only; with =best-effort parts
+    /// the kernel doesn't support are skipped instead of exiting
+    #[clap(
+        long,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "strict"
+    )]
+    sandbox: Option<SandboxMode>,
+    /// Disable core dumps so passwords and keys in memory can't end up on disk
+    #[clap(long)]
+    no_core_dumps: bool,
+    /// Switch to this user (name or uid) once the local port is bound and
+    /// before connecting to the SSH server, e.g. to forward port 443 without
+    /// keeping root. Unix only
+    #[clap(long, value_name = "USER", value_hint = ValueHint::Username)]
+    user: Option<String>,
+    /// Group (name or gid) to switch to together with --user, defaults to the
+    /// primary group of that user
+    #[clap(long, value_name = "GROUP")]
+    group: Option<String>,
+    /// Run as root without --user and without the warning about it
+    #[clap(long)]
+    allow_root: bool,
+    /// Exit instead of warning when started as root without --user
+    #[clap(long, conflicts_with = "allow_root")]
+    refuse_root: bool,
+    /// Don't print the startup summary of the session and the tunnel
+    #[clap(short = 'q', long)]
+    quiet: bool,
+    /// Read settings from this TOML or YAML (.yaml, .yml) file instead of the
+    /// first one found in $XDG_CONFIG_HOME/ssh2fwd, ~/.config/ssh2fwd or
+    /// /etc/ssh2fwd. Keys are the long option names, options given on the
+    /// command line win
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+    /// Don't look for a config file in the usual places
+    #[clap(long, conflicts_with = "config")]
+    no_system_config: bool,
+    /// Print the effective configuration and exit
+    #[clap(long)]
+    print_config: bool,
+    /// Print the keys the ssh-agent offers for logging in, like ssh-add -l,
+    /// and exit
+    #[clap(long)]
+    list_identities: bool,
+    /// Print a shell completion script and exit,
+    /// e.g. eval "$(ssh2fwd --generate-completions bash)"
+    #[clap(long, value_name = "SHELL")]
+    generate_completions: Option<Shell>,
+    /// Forward test data through a tunnel to an echo server in this process,
+    /// with the SSH session stood in for by connections from this machine,
+    /// print PASS or FAIL and exit. Needs no SSH server
+    #[clap(long)]
+    test_mode: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Idle channels --reuse-channel keeps when no per client limit says otherwise
+const DEFAULT_POOL_SIZE: usize = 8;
+/// With --reuse-channel, how long the remote side has to stay quiet after the
+/// local client is done before the channel counts as idle again
+const REUSE_DRAIN: Duration = Duration::from_millis(250);
+
+/// How long the SSH server listens for the peer of a SOCKS5 BIND
+const SOCKS_BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Pause after a failed accept before trying again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Time the connections of a lost session get to close before exiting
+const SESSION_LOST_DRAIN: Duration = Duration::from_secs(2);
+
+/// Id of the next local connection, shown in the logs and the audit log so
+/// the lines of one connection can be told apart
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Session timeout while forwarding, how long a channel read blocks before
+/// the copy loop gets to do its checks. Set once for each session, it is
+/// shared by all channels of the session
+const CHANNEL_POLL_MS: u32 = 20;
+
+/// Longest sleep of an idle remote read task between two reads. Data wakes
+/// it right away, this only bounds how late the quota, API and reuse checks
+/// run on a connection with nothing coming in.
+const IDLE_WAIT: Duration = Duration::from_millis(200);
+
+/// What a copy loop does after a read fails
+#[derive(Debug)]
+enum ErrorAction {
+    /// The peer went away, an ordinary end of the connection
+    Break,
+    /// Nothing was ready (the read timed out or would block), wait for data
+    /// and do the idle checks before reading again
+    Continue,
+    /// Unexpected failure, report it and close
+    LogAndBreak,
+}
+
+fn classify_io_error(e: &io::Error) -> ErrorAction {
+    match e.kind() {
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset => ErrorAction::Break,
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ErrorAction::Continue,
+        _ => ErrorAction::LogAndBreak,
+    }
 }
 
-fn get_channels_for_remote_server(
+/// The read or write of a connection that failed, with the bytes a write
+/// was given
+#[derive(Clone, Copy)]
+enum Direction {
+    LocalRead,
+    LocalWrite(usize),
+    ChannelRead,
+    ChannelWrite(usize),
+}
+
+impl Direction {
+    fn local(self) -> bool {
+        matches!(self, Direction::LocalRead | Direction::LocalWrite(_))
+    }
+}
+
+/// Log line for a read or write of connection `conn_id` that failed with `e`
+fn io_error_message(conn_id: u64, direction: Direction, e: &io::Error) -> String {
+    let op = match direction {
+        Direction::LocalRead => "reading from the local client".to_string(),
+        Direction::LocalWrite(n) => format!("writing {} bytes to the local client", n),
+        Direction::ChannelRead => "reading from the SSH channel".to_string(),
+        Direction::ChannelWrite(n) => format!("writing {} bytes to the SSH channel", n),
+    };
+    format!("Connection #{}: {} failed: {}. Closing", conn_id, op, e)
+}
+
+/// Log a failed read or write of a connection and return its close reason.
+/// A local client that went away is an ordinary end and only logged at
+/// debug, anything else on the channel or the local socket is an error.
+fn report_io_error(conn_id: u64, direction: Direction, e: &io::Error) -> &'static str {
+    let message = io_error_message(conn_id, direction, e);
+    let gone = matches!(classify_io_error(e), ErrorAction::Break);
+    if !gone {
+        error!("{}", message);
+    } else if direction.local() {
+        debug!("{}", message);
+    } else {
+        warn!("{}", message);
+    }
+    match (direction, gone) {
+        (Direction::LocalRead | Direction::LocalWrite(_), true) => "local reset",
+        (Direction::LocalRead, false) => "local read error",
+        (Direction::LocalWrite(_), false) => "local write error",
+        (Direction::ChannelRead | Direction::ChannelWrite(_), true) => "remote reset",
+        (Direction::ChannelRead, false) => "remote read error",
+        (Direction::ChannelWrite(_), false) => "channel write error",
+    }
+}
+
+/// Write all of `buf` to a channel. While the server's window is full a
+/// write times out or would block, possibly after part of the buffer went
+/// out; the rest is written once the server makes room. Only real errors,
+/// the API closing the connection meanwhile, or no room for `stall_timeout`
+/// (a TimedOut error) stop it.
+fn write_channel(
+    session: &(impl DataWait + ?Sized),
+    channel: &mut impl Write,
+    mut buf: &[u8],
+    live: Option<&LiveConnection>,
+    stall_timeout: Option<Duration>,
+) -> io::Result<()> {
+    let mut progress = Instant::now();
+    while !buf.is_empty() {
+        if live.is_some_and(|c| c.killed()) {
+            return Err(io::Error::other("closed via api"));
+        }
+        // The window adjustment arrives like data, so wait for that
+        let seen = session.data_generation();
+        match channel.write(buf) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                progress = Instant::now();
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => match classify_io_error(&e) {
+                ErrorAction::Continue if stall_timeout.is_some_and(|t| progress.elapsed() >= t) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "write stalled"))
+                }
+                ErrorAction::Continue => session.wait_for_data(seen, IDLE_WAIT),
+                _ => return Err(e),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Open a channel off the runtime threads. Opens on one session wait for
+/// each other, during a burst of connections a runtime thread stuck in that
+/// wait would hold up every other connection.
+async fn get_channels_for_remote_server<T: SshTransport>(
     remote_srv: &str,
     remote_port: u16,
-    session: &Session,
-    stream_ref: Arc<Mutex<i32>>,
-) -> anyhow::Result<(Stream, Stream)> {
-    let mut stream_id = block_on(stream_ref.lock());
+    session: &T,
+    conn_id: u64,
+) -> anyhow::Result<(T::Channel, T::Stream, T::Stream)> {
+    info!(
+        "Connection #{}: opening channel to {}:{}",
+        conn_id, remote_srv, remote_port
+    );
+
+    let (session, srv) = (session.clone(), remote_srv.to_string());
+    let channels = spawn_blocking(move || session.open_direct_tcpip(&srv, remote_port)).await??;
+    info!("Connection #{}: channel opened", conn_id);
+    Ok(channels)
+}
+
+/// Serve a SOCKS5 BIND: have the SSH server listen on a free port, tell the
+/// client where, and wait for the peer to connect there. Both replies,
+/// failures included, are sent here.
+async fn socks_bind<T: SshTransport, S: io::AsyncWrite + Unpin>(
+    session: &T,
+    socket: &mut S,
+    conn_id: u64,
+) -> anyhow::Result<(T::Channel, T::Stream, T::Stream)> {
+    let s = session.clone();
+    let (mut listener, port) = match spawn_blocking(move || s.forward_listen()).await? {
+        Ok(listening) => listening,
+        Err(e) => {
+            let _ = socks::reply(socket, socks::REP_GENERAL_FAILURE).await;
+            return Err(e);
+        }
+    };
+    // Behind a proxy or --proxy-command the address isn't known, the client
+    // then has to know where its SSH server is
+    let bound = SocketAddr::new(
+        session.server_ip().unwrap_or(IpAddr::from([0, 0, 0, 0])),
+        port,
+    );
     info!(
-        "Trying to open channel with stream_id {} in {}:{}",
-        *stream_id, remote_srv, remote_port
+        "Connection #{}: SSH server listening on {} for SOCKS5 BIND",
+        conn_id, bound
     );
+    let accepted = match socks::reply_bound(socket, socks::REP_SUCCEEDED, bound).await {
+        Ok(()) => {
+            let s = session.clone();
+            let (returned, accepted) = spawn_blocking(move || {
+                let accepted = s.forward_accept(&mut listener, SOCKS_BIND_TIMEOUT);
+                (listener, accepted)
+            })
+            .await?;
+            listener = returned;
+            accepted
+        }
+        Err(e) => Err(e),
+    };
+    // One peer per BIND. Dropping the listener cancels the forward, which
+    // waits for the session.
+    let _ = spawn_blocking(move || drop(listener)).await;
+    let (channel, rx, tx) = match accepted {
+        Ok(channels) => channels,
+        Err(e) => {
+            let _ = socks::reply(socket, socks::REP_GENERAL_FAILURE).await;
+            return Err(e);
+        }
+    };
+    // libssh2 doesn't tell who connected, so the second reply carries
+    // 0.0.0.0:0 like the one to CONNECT
+    if let Err(e) = socks::reply(socket, socks::REP_SUCCEEDED).await {
+        close_channel(session.clone(), channel, conn_id).await;
+        return Err(e);
+    }
+    info!("Connection #{}: the SOCKS5 BIND peer connected", conn_id);
+    Ok((channel, rx, tx))
+}
 
-    match session.channel_direct_tcpip(remote_srv, remote_port, Some((remote_srv, remote_port))) {
-        Ok(c) => {
-            let writer_stream = { c.stream(*stream_id) };
-            let reader_stream = { c.stream(*stream_id) };
-            info!("stream_id {} opened", *stream_id);
-            *stream_id += 1;
-            Ok((reader_stream, writer_stream))
+/// A channel with its reader and writer, ready for a local connection, and
+/// the session it belongs to
+type IdleChannel<T> = (
+    T,
+    <T as SshTransport>::Channel,
+    <T as SshTransport>::Stream,
+    <T as SshTransport>::Stream,
+);
+
+/// Open `n` channels to the remote target at once in the background and
+/// keep them for the next local connections, for --channel-burst-size
+fn spawn_channel_burst<T: SshTransport>(
+    session: T,
+    remote_srv: String,
+    remote_port: u16,
+    n: usize,
+    pool: Arc<ChannelPool<IdleChannel<T>>>,
+    refilling: Arc<AtomicBool>,
+) {
+    debug!(
+        "Opening {} channels to {}:{} ahead of time",
+        n, remote_srv, remote_port
+    );
+    tokio::spawn(async move {
+        let mut opens: FuturesUnordered<_> = (0..n)
+            .map(|_| {
+                let (session, srv) = (session.clone(), remote_srv.clone());
+                spawn_blocking(move || session.open_direct_tcpip(&srv, remote_port))
+            })
+            .collect();
+        while let Some(opened) = opens.next().await {
+            let (channel, rx, tx) = match opened.map_err(anyhow::Error::from).and_then(|r| r) {
+                Ok(channels) => channels,
+                Err(e) => {
+                    warn!("Opening a channel ahead of time failed: {}", e);
+                    continue;
+                }
+            };
+            if let Err((session, channel, _, _)) = pool.put((session.clone(), channel, rx, tx)) {
+                let _ = spawn_blocking(move || session.close_channel(channel)).await;
+            }
         }
+        refilling.store(false, Ordering::Release);
+    });
+}
+
+/// After a channel operation failed: whether the SSH session is gone. A
+/// failed connection to the server marks it lost, so the other connections
+/// of the session stop as well.
+fn session_lost<T: SshTransport>(session: &T) -> bool {
+    if session.lost().is_lost() {
+        return true;
+    }
+    if session.connection_failed() {
+        session.lost().mark("the connection to the server failed");
+        return true;
+    }
+    false
+}
+
+/// The channel of a connection, also after a copy task panicked holding it
+fn lock_channel<C>(channel: &Mutex<C>) -> std::sync::MutexGuard<'_, C> {
+    channel.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Tell the server the client sends nothing more
+fn send_channel_eof<T: SshTransport>(
+    session: &T,
+    channel: &Mutex<T::Channel>,
+) -> anyhow::Result<()> {
+    session.send_eof(&mut lock_channel(channel))
+}
+
+/// Close the channel of a finished connection, so the server doesn't keep
+/// its state around until the session ends
+async fn close_channel<T: SshTransport>(session: T, channel: T::Channel, conn_id: u64) {
+    // Nothing to tell a server that is gone
+    if session.lost().is_lost() {
+        return;
+    }
+    match spawn_blocking(move || session.close_channel(channel)).await {
+        Ok(Ok(())) => debug!("Connection #{}: channel closed", conn_id),
+        Ok(Err(e)) => debug!("Connection #{}: closing the channel: {}", conn_id, e),
+        Err(e) => debug!("Connection #{}: closing the channel: {}", conn_id, e),
+    }
+}
+
+/// Try `password` off the runtime threads, the server may take its time to
+/// answer. The password is handed back so it can be kept for later sessions.
+async fn password_auth<T: SshTransport>(
+    session: T,
+    user: String,
+    password: Secret,
+) -> anyhow::Result<(anyhow::Result<()>, Secret)> {
+    Ok(spawn_blocking(move || {
+        let result = session.userauth_password(&user, password.expose());
+        (result, password)
+    })
+    .await?)
+}
+
+/// Try the private key file off the runtime threads, handing the passphrase
+/// back so it can be kept for later sessions
+async fn key_auth<T: SshTransport>(
+    session: T,
+    user: String,
+    key: PathBuf,
+    passphrase: Option<Secret>,
+) -> anyhow::Result<(anyhow::Result<()>, Option<Secret>)> {
+    Ok(spawn_blocking(move || {
+        let result =
+            session.userauth_key_file(&user, &key, passphrase.as_ref().map(|p| p.expose()));
+        (result, passphrase)
+    })
+    .await?)
+}
+
+/// Answer keyboard-interactive prompts on the terminal
+fn answer_prompts(instructions: &str, prompts: &[(String, bool)]) -> Vec<String> {
+    if !instructions.is_empty() {
+        eprintln!("{}", instructions);
+    }
+    prompts
+        .iter()
+        .map(|(prompt, echo)| {
+            let answer = if *echo {
+                eprint!("{}", prompt);
+                let mut line = String::new();
+                std::io::stdin()
+                    .read_line(&mut line)
+                    .map(|_| line.trim_end_matches(['\r', '\n']).to_string())
+            } else {
+                rpassword::prompt_password(prompt)
+            };
+            answer.unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Resolves once the byte cap of the connection is reached, never without one
+async fn quota_reached(quota: Option<&ConnQuota>) {
+    match quota {
+        Some(quota) => quota.closed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for a copy task and take back what it holds of the channel. A task
+/// that panicked is logged and gives nothing back, the connection is torn
+/// down as usual without it.
+async fn join_copy_task<R>(
+    task: Option<JoinHandle<R>>,
+    conn_id: u64,
+    name: &str,
+    reason: &OnceLock<&'static str>,
+) -> Option<R> {
+    match task?.await {
+        Ok(held) => Some(held),
         Err(e) => {
-            error!(
-                "Unable to open channel, error: {}, >> make sure there is server running 
-                   at {}:{} which is rechable via the SSH server! <<",
-                e, remote_srv, remote_port
-            );
-            Err(e.into())
+            error!("Connection #{}: {} task failed: {}", conn_id, name, e);
+            let _ = reason.set("internal error");
+            None
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// The local client's side of the remote read, with what a failed write
+/// has to tell the rest of the connection
+struct LocalWriter<W> {
+    wr: W,
+    write_timeout: Option<Duration>,
+    conn_id: u64,
+    reason: Arc<OnceLock<&'static str>>,
+    stalled: Option<Arc<Notify>>,
+    bytes_in: Arc<AtomicU64>,
+}
+
+impl<W: io::AsyncWrite + Unpin> LocalWriter<W> {
+    /// Write all of `data` to the client, false once the connection has to
+    /// close, with the reason set
+    async fn write(&mut self, data: &[u8]) -> bool {
+        let written = match self.write_timeout {
+            Some(t) => timeout(t, self.wr.write_all(data)).await,
+            None => Ok(self.wr.write_all(data).await),
+        };
+        match written {
+            Ok(Ok(())) => {
+                self.bytes_in
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+                true
+            }
+            Ok(Err(e)) => {
+                let _ = self.reason.set(report_io_error(
+                    self.conn_id,
+                    Direction::LocalWrite(data.len()),
+                    &e,
+                ));
+                false
+            }
+            Err(_) => {
+                warn!(
+                    "Connection #{}: the local client took no data for {:?}. Closing",
+                    self.conn_id,
+                    self.write_timeout.unwrap_or_default()
+                );
+                let _ = self.reason.set("write stalled");
+                // The local read task may be waiting for the client
+                if let Some(stalled) = &self.stalled {
+                    stalled.notify_one();
+                }
+                false
+            }
+        }
+    }
+
+    /// Pass the server's EOF on, the client may wait for it while still
+    /// being able to send
+    async fn shutdown(&mut self) {
+        let shut = async {
+            self.wr.flush().await?;
+            self.wr.shutdown().await
+        };
+        if let Err(e) = shut.await {
+            debug!("Shutting down the local write side: {}", e);
+        }
+    }
+}
+
+/// Where the remote read task puts the data for the client
+enum LocalSink<W> {
+    /// Written right away, the read waits for the client
+    Direct(LocalWriter<W>),
+    /// Queued for `deliver_local`, with --receive-window-size. An empty
+    /// chunk stands for the server's EOF.
+    Queued(mpsc::UnboundedSender<Vec<u8>>, Arc<ReceiveWindow>),
+}
+
+/// Write what the remote read task queued to the client until it is done
+/// or a write fails
+async fn deliver_local<W: io::AsyncWrite + Unpin>(
+    mut local: LocalWriter<W>,
+    mut chunks: mpsc::UnboundedReceiver<Vec<u8>>,
+    window: Arc<ReceiveWindow>,
+) {
+    while let Some(chunk) = chunks.recv().await {
+        if chunk.is_empty() {
+            local.shutdown().await;
+            return;
+        }
+        if !local.write(&chunk).await {
+            return;
+        }
+        window.delivered(chunk.len());
+    }
+}
+
+/// Resolves once the other copy task gave up on a stalled write, never
+/// without --write-timeout
+async fn write_stalled(stalled: Option<&Notify>) {
+    match stalled {
+        Some(stalled) => stalled.notified().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once the connection is closed through the API, never without it
+async fn closed_via_api(live: Option<&LiveConnection>) {
+    match live {
+        Some(live) => live.closed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Summary of the version, the SSH session and the tunnel, printed once
+/// logged in so a glance at the terminal shows what is running
+fn print_startup_banner<T: SshTransport>(config: &AppConfig, session: &T, sshaddr: &str) {
+    let info = session.session_info();
+    let mut lines = vec![
+        format!("ssh2fwd {}", env!("CARGO_PKG_VERSION")),
+        format!("Server       {} at {}", info.server_version, sshaddr),
+        format!("Kex          {}", info.kex),
+        format!("Host key     {}", info.host_key),
+        format!("Cipher       {}", info.cipher),
+        format!("MAC          {}", info.mac),
+        format!("Compression  {}", info.compression),
+    ];
+
+    let remote = if config.dynamic {
+        "SOCKS5 (-D)".to_string()
+    } else {
+        addr::join_host_port(&config.remote_srv, config.remote_port)
+    };
+    let mut options = vec![];
+    if !config.allow_from.is_empty() || config.allow_from_file.is_some() {
+        options.push("allow-from");
+    }
+    if config.require_tunnel_token {
+        options.push("tunnel-token");
+    }
+    if config.client_token.is_some() || config.client_token_file.is_some() {
+        options.push("client-token");
+    }
+    if config.socks_user.is_some() || config.socks_users_file.is_some() {
+        options.push("socks-auth");
+    }
+    if config.socks_enable_bind {
+        options.push("socks-bind");
+    }
+    if config.rate_limit.is_some() || config.connection_limit_per_source_ip.is_some() {
+        options.push("limits");
+    }
+    if config.max_bytes_per_conn.is_some() || config.max_bytes_per_client.is_some() {
+        options.push("quotas");
+    }
+    if config.half_duplex.is_some() {
+        options.push("half-duplex");
+    }
+    if config.reuse_channel {
+        options.push("reuse-channel");
+    }
+    if config.channel_burst_size.is_some() {
+        options.push("channel-burst");
+    }
+    let options = if options.is_empty() {
+        "-".to_string()
+    } else {
+        options.join(", ")
+    };
+    let rows = [
+        ["Local", "Remote", "Options"].map(String::from),
+        [config.local_srv_address.clone(), remote, options],
+    ];
+    let widths: Vec<usize> = (0..3)
+        .map(|c| rows.iter().map(|r| r[c].chars().count()).max().unwrap_or(0))
+        .collect();
+    let rule = widths
+        .iter()
+        .map(|w| "-".repeat(w + 2))
+        .collect::<Vec<_>>()
+        .join("+");
+    lines.push(format!("+{}+", rule));
+    for (i, row) in rows.iter().enumerate() {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!(" {:<w$} ", cell, w = w))
+            .collect();
+        lines.push(format!("|{}|", cells.join("|")));
+        if i == 0 {
+            lines.push(format!("+{}+", rule));
+        }
+    }
+    lines.push(format!("+{}+", rule));
+    eprintln!("{}", lines.join("\n"));
+}
+
+/// Re-read the access rules files whenever SIGHUP arrives
+#[cfg(unix)]
+fn reload_on_sighup(
+    acl: Arc<AccessList>,
+    dest_acl: Option<Arc<DestAccessList>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    quotas: Option<Arc<ByteQuotas>>,
+) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hup.recv().await.is_some() {
+            info!("SIGHUP received, reloading client access list");
+            let (per_rule, not_allowed) = acl.stats();
+            for (rule, n) in per_rule {
+                info!("Deny rule {} rejected {} connection(s)", rule, n);
+            }
+            info!("{} connection(s) matched no allow rule", not_allowed);
+            if let Some(rate_limiter) = &rate_limiter {
+                info!(
+                    "{} connection(s) were over the rate limit",
+                    rate_limiter.rejected()
+                );
+            }
+            if let Some(quotas) = &quotas {
+                let (cut, rejected) = quotas.stats();
+                info!(
+                    "{} connection(s) were closed at --max-bytes-per-conn, {} refused over --max-bytes-per-client",
+                    cut, rejected
+                );
+            }
+            if let Err(e) = acl.reload() {
+                error!("Keeping the previous access list, reload failed: {}", e);
+            }
+            if let Some(dest_acl) = &dest_acl {
+                if let Err(e) = dest_acl.reload() {
+                    error!(
+                        "Keeping the previous destination allowlist, reload failed: {}",
+                        e
+                    );
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
         .format_target(false)
         .format_timestamp(None)
         .init();
 
-    let args = Opts::parse();
-    let sshaddr = if args.sshaddress.contains(":") {
-        args.sshaddress
+    let matches = config_file::get_matches(Opts::command())?;
+    let opts = Opts::from_arg_matches(&matches)?;
+    // Forking has to happen before the runtime starts its threads
+    let exits_early = opts.generate_completions.is_some()
+        || opts.print_config
+        || opts.list_identities
+        || opts.test_mode;
+    if let (Some(workers), false) = (opts.forwarder_pool, exits_early) {
+        #[cfg(target_os = "linux")]
+        prefork::fork_workers(workers)?;
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("--forwarder-pool {} is only supported on Linux", workers);
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    // Landlock only restricts the thread that asks for it, so every runtime
+    // thread enforces a pending sandbox on itself
+    #[cfg(target_os = "linux")]
+    runtime
+        .on_thread_start(sandbox::enforce_current_thread)
+        .on_thread_unpark(sandbox::enforce_current_thread);
+    let runtime = runtime.build()?;
+    // Dropped before the runtime, the hooks may still need it
+    let _shutdown = shutdown::Guard;
+    runtime.block_on(run(opts, matches))
+}
+
+async fn run(mut opts: Opts, matches: ArgMatches) -> anyhow::Result<()> {
+    if let Some(shell) = opts.generate_completions {
+        completions::generate(shell, Opts::command());
+        return Ok(());
+    }
+    if opts.test_mode {
+        if !self_test::run().await {
+            std::process::exit(EXIT_TEST_FAILED);
+        }
+        return Ok(());
+    }
+    let command = opts.command.take();
+    if let Some(Command::Cp(cp)) = &command {
+        cp.paths()?;
+    }
+    let list_identities = opts.list_identities;
+    let mut args = AppConfig::from_args(opts, &matches)?;
+    if list_identities {
+        let socket = args.agent_socket.as_deref();
+        let identities = Ssh2Transport::agent_identities(socket).with_context(|| match socket {
+            Some(path) => format!("listing identities of the ssh-agent at {}", path.display()),
+            None => "listing ssh-agent identities, is SSH_AUTH_SOCK set?".to_string(),
+        })?;
+        if identities.is_empty() {
+            println!("The agent has no identities.");
+        }
+        for identity in identities {
+            println!(
+                "{} {} {}",
+                identity.key_type, identity.fingerprint, identity.comment
+            );
+        }
+        return Ok(());
+    }
+    let problems = validate::validate(&args);
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("{}", problem);
+        }
+        std::process::exit(validate::EXIT_INVALID_CONFIG);
+    }
+    if args.no_core_dumps {
+        #[cfg(unix)]
+        secret::disable_core_dumps()?;
+        #[cfg(not(unix))]
+        warn!("--no-core-dumps is only supported on Unix");
+    }
+    let acl = AccessList::new(
+        args.allow_from.clone(),
+        args.deny_from.clone(),
+        args.allow_from_file.clone(),
+    )?;
+    let sni_routes = Arc::new(args.sni_passthrough.clone());
+    let dest_acl = if args.dynamic {
+        Some(DestAccessList::new(
+            args.dest_allow.clone(),
+            args.dest_allow_file.clone(),
+        )?)
     } else {
-        args.sshaddress + ":22"
+        None
+    };
+    let rate_limiter = args
+        .rate_limit
+        .map(|limit| RateLimiter::new(limit, args.rate_limit_exempt_loopback));
+    let quotas = (args.max_bytes_per_conn.is_some() || args.max_bytes_per_client.is_some())
+        .then(|| ByteQuotas::new(args.max_bytes_per_conn, args.max_bytes_per_client));
+    if args.print_config {
+        args.print(&acl, dest_acl.as_deref());
+        return Ok(());
+    }
+    #[cfg(unix)]
+    reload_on_sighup(
+        acl.clone(),
+        dest_acl.clone(),
+        rate_limiter.clone(),
+        quotas.clone(),
+    )?;
+
+    let sshaddr = match (args.sshaddress.clone(), &args.mdns_service_name) {
+        (_, Some(name)) => mdns::discover_ssh_server(name).await?.to_string(),
+        (Some(addr), None) => {
+            let (host, port) = addr::split_host_port(&addr)
+                .with_context(|| format!("invalid --sshaddress {}", addr))?;
+            addr::join_host_port(&host, port.unwrap_or(22))
+        }
+        (None, None) => {
+            unreachable!(
+                "clap requires --sshaddress, --sshaddress-from-env, --mdns-service-name or \
+                 --connection-string"
+            )
+        }
     };
-    let sshuser = args.sshuser;
-    let remote_srv = args.remote_srv;
+    let audit = match &args.audit_log {
+        Some(path) => Some(AuditLog::open(path, args.audit_log_max_size)?),
+        None => None,
+    };
+    let audit_event = |event: &str, fields: &[(&str, Value)]| {
+        if let Some(audit) = &audit {
+            audit.record(event, fields);
+        }
+    };
+
+    let drop_privileges = args.user.is_some() || args.group.is_some();
+    #[cfg(unix)]
+    if privdrop::running_as_root() && args.user.is_none() && !args.allow_root {
+        if args.refuse_root {
+            anyhow::bail!(
+                "refusing to run as root, pass --user to drop privileges once the local port \
+                 is bound"
+            );
+        }
+        warn!(
+            "Running as root, pass --user to drop privileges once the local port is bound, \
+             --allow-root to silence this or --refuse-root to exit instead"
+        );
+    }
+    #[cfg(not(unix))]
+    if drop_privileges {
+        anyhow::bail!("--user and --group are only supported on Unix");
+    }
+
+    let sshuser = args.sshuser.clone();
+    let remote_srv = args.remote_srv.clone();
     let remote_port = args.remote_port;
-    let localsrv = args.local_srv_address;
+    let localsrv = args.local_srv_address.clone();
+
+    let protected = !acl.allow_rules().is_empty()
+        || args.require_tunnel_token
+        || args.client_token.is_some()
+        || args.client_token_file.is_some()
+        || args.socks_user.is_some()
+        || args.socks_users_file.is_some();
+    local::check_exposure(&localsrv, protected, args.allow_external).await?;
 
-    info!("Connecting to SSH server at {}", &sshaddr);
-    let tcp = TcpStream::connect(&sshaddr).await?;
-    let mut session = Session::new()?;
-    session.set_tcp_stream(tcp);
-    session.handshake()?;
+    let unix_opts = UnixSocketOptions {
+        mode: args.unix_mode,
+        owner: args.unix_owner.clone(),
+    };
+
+    // Bind while still privileged, everything after this runs as the new user
+    let early_listener = if drop_privileges {
+        let listener =
+            LocalListener::bind(&localsrv, &unix_opts, args.forwarder_pool.is_some()).await?;
+        #[cfg(unix)]
+        privdrop::drop_privileges(args.user.as_deref(), args.group.as_deref())?;
+        info!(
+            "Bound {}, now running as {}",
+            localsrv,
+            args.user
+                .as_deref()
+                .or(args.group.as_deref())
+                .unwrap_or("-")
+        );
+        Some(listener)
+    } else {
+        None
+    };
+
+    // Another process may already hold a session to this server
+    #[cfg(unix)]
+    let control_path = (args.control && command.is_none())
+        .then(|| control::socket_path(&format!("{}@{}", sshuser, sshaddr)));
+    #[cfg(unix)]
+    if let Some(slave) = match &control_path {
+        Some(path) => control::ControlSlave::connect(path).await,
+        None => None,
+    } {
+        let listener = match early_listener {
+            Some(listener) => listener,
+            None => LocalListener::bind(&localsrv, &unix_opts, false).await?,
+        };
+        return slave.run(listener, acl, remote_srv, remote_port).await;
+    }
+    #[cfg(not(unix))]
+    if args.control {
+        anyhow::bail!("--control is only supported on Unix");
+    }
+
+    if !args.port_knock.is_empty() {
+        port_knock::knock(
+            &sshaddr,
+            &args.port_knock,
+            Duration::from_millis(args.port_knock_delay_ms),
+            args.port_knock_tcp,
+        )
+        .await?;
+        sleep(Duration::from_millis(args.port_knock_wait_ms)).await;
+    }
+    // With --source-addresses the server is resolved once, reconnects may run
+    // inside the sandbox
+    let server_addrs: Vec<_> = if args.source_addresses.is_empty() {
+        vec![]
+    } else {
+        tokio::net::lookup_host(&sshaddr).await?.collect()
+    };
+    let handshake_timeout = Duration::from_secs(args.handshake_timeout);
+    let handshake = HandshakeOptions {
+        timeout: handshake_timeout,
+        channel_open_timeout: Duration::from_millis(args.channel_open_timeout_ms),
+        compress: args.compression,
+        ip_tos: args.ip_tos,
+        via_proxy: false,
+        kex: args.kex.clone(),
+        host_keys: args.hostkey_algorithms.clone(),
+        ciphers: args.cipher.clone(),
+        macs: args.mac.clone(),
+    };
+    let session = match &args.proxy_command {
+        #[cfg(unix)]
+        Some(command) => {
+            let (host, port) = addr::split_host_port(&sshaddr)?;
+            let command = proxy_command::expand(command, &host, port.unwrap_or(22), &sshuser)?;
+            info!("Connecting to SSH server at {} via `{}`", &sshaddr, command);
+            let socket = proxy_command::spawn(&command)?;
+            let handshake = handshake.clone();
+            spawn_blocking(move || Ssh2Transport::handshake_socket(socket, &handshake))
+                .await?
+                .with_context(|| format!("connecting to {} via `{}`", sshaddr, command))?
+        }
+        #[cfg(not(unix))]
+        Some(_) => anyhow::bail!("--proxy-command is only supported on Unix"),
+        None => {
+            // The environment only applies to plain connections
+            let proxy = match args.proxy.take() {
+                Some(proxy) => Some(proxy),
+                None if !args.no_proxy && args.source_addresses.is_empty() => Proxy::from_env()?,
+                None => None,
+            };
+            match &proxy {
+                Some(proxy) => info!("Connecting to SSH server at {} via {}", &sshaddr, proxy),
+                None => info!("Connecting to SSH server at {}", &sshaddr),
+            }
+            let connect = async {
+                match (args.source_addresses.first(), &proxy) {
+                    (Some(source), _) => multi_path::connect_from(*source, &server_addrs).await,
+                    (None, Some(proxy)) => proxy.connect(&sshaddr).await,
+                    (None, None) => Ok(TcpStream::connect(&sshaddr).await?),
+                }
+            };
+            let tcp = timeout(handshake_timeout, connect)
+                .await
+                .map_err(|_| anyhow::anyhow!("connecting to {} timed out", sshaddr))??;
+            let handshake = HandshakeOptions {
+                via_proxy: proxy.is_some(),
+                ..handshake.clone()
+            };
+            spawn_blocking(move || Ssh2Transport::handshake(tcp, &handshake))
+                .await?
+                .with_context(|| format!("connecting to {}", sshaddr))?
+        }
+    };
+    // From here on every way out tells the server
+    {
+        let session = session.clone();
+        shutdown::on_exit(move || session.disconnect("ssh2fwd exiting"));
+    }
+    shutdown::handle_signals()?;
+    let server_fingerprint = session
+        .host_key_fingerprint()
+        .unwrap_or_else(|| "-".to_string());
+    let known_hosts = match args.known_hosts.clone().or_else(known_hosts::default_path) {
+        Some(path) => path,
+        None => anyhow::bail!("no home directory for known_hosts, pass --known-hosts"),
+    };
+    let (host, port) = addr::split_host_port(&sshaddr)?;
+    let host_key = session
+        .host_key()
+        .ok_or_else(|| anyhow::anyhow!("{} sent no host key", sshaddr))?;
+    // May ask on the terminal whether to trust the server
+    let verified = {
+        let entry = known_hosts::host_entry_name(&host, port.unwrap_or(22));
+        let host_key = host_key.clone();
+        let (mode, can_prompt) = (args.strict_host_key_checking, !args.allow_env_auth);
+        spawn_blocking(move || {
+            known_hosts::verify(&known_hosts, &entry, &host_key, mode, can_prompt)
+        })
+        .await?
+    };
+    if let Err(e) = verified {
+        audit_event(
+            "host_key_rejected",
+            &[
+                ("ssh_server", Value::Str(&sshaddr)),
+                ("server_fingerprint", Value::Str(&server_fingerprint)),
+            ],
+        );
+        return Err(e);
+    }
+    audit_event(
+        "connect",
+        &[
+            ("ssh_server", Value::Str(&sshaddr)),
+            ("server_fingerprint", Value::Str(&server_fingerprint)),
+        ],
+    );
     info!(
         "Connected to {}!. Now authendicating as user: {}",
         &sshaddr, sshuser
     );
 
-    // Try to authenticate with the first identity in the agent.
-    match session.userauth_agent(&sshuser) {
-        Ok(_) => {}
-        Err(e) => {
-            warn!(
-                "ssh-agent identity did not help, try eval `ssh-agent` and ssh-add. {}",
-                e
+    // The other sessions of --source-addresses log in the same way later on
+    let keep_password = args.source_addresses.len() > 1;
+    let mut kept_password = None;
+    let mut kept_key = None;
+    let can_prompt = !args.allow_env_auth && std::io::stdin().is_terminal();
+    let mut auth_method = None;
+    for method in &args.auth_order {
+        if auth_method.is_some() {
+            break;
+        }
+        match method {
+            AuthMethod::Agent => {
+                let agent_auth = {
+                    let (session, user, socket) =
+                        (session.clone(), sshuser.clone(), args.agent_socket.clone());
+                    spawn_blocking(move || session.userauth_agent(&user, socket.as_deref())).await?
+                };
+                match agent_auth {
+                    Ok(()) if session.authenticated() => {
+                        info!("User {} logged in to {}", sshuser, sshaddr);
+                        auth_method = Some("agent");
+                    }
+                    Ok(()) => {}
+                    Err(e) => warn!(
+                        "ssh-agent identity did not help, try eval `ssh-agent` and ssh-add. {}",
+                        e
+                    ),
+                }
+            }
+            AuthMethod::Key => {
+                let Some(key) = &args.identity else {
+                    continue;
+                };
+                let (result, passphrase) =
+                    key_auth(session.clone(), sshuser.clone(), key.clone(), None).await?;
+                let (result, passphrase) = match result {
+                    Err(e) if can_prompt && key_unreadable(&e) => {
+                        let prompt = format!("Enter passphrase for {}: ", key.display());
+                        let passphrase = spawn_blocking(move || rpassword::prompt_password(prompt))
+                            .await?
+                            .context("reading the passphrase")?;
+                        key_auth(
+                            session.clone(),
+                            sshuser.clone(),
+                            key.clone(),
+                            Some(Secret::new(passphrase)),
+                        )
+                        .await?
+                    }
+                    result => (result, passphrase),
+                };
+                match result {
+                    Ok(()) => {
+                        info!(
+                            "Logged user {} via key {} with server {}",
+                            sshuser,
+                            key.display(),
+                            sshaddr
+                        );
+                        kept_key = Some((key.clone(), passphrase));
+                        auth_method = Some("key");
+                    }
+                    Err(e) => warn!("Key {} did not help. {}", key.display(), e),
+                }
+            }
+            AuthMethod::KeyboardInteractive => {
+                if !can_prompt {
+                    debug!("Skipping keyboard-interactive authentication, nobody to ask");
+                    continue;
+                }
+                let result = {
+                    let (session, user) = (session.clone(), sshuser.clone());
+                    spawn_blocking(move || {
+                        session.userauth_keyboard_interactive(&user, &mut answer_prompts)
+                    })
+                    .await?
+                };
+                match result {
+                    Ok(()) => {
+                        info!(
+                            "Logged user {} via keyboard-interactive with server {}",
+                            sshuser, sshaddr
+                        );
+                        auth_method = Some("keyboard-interactive");
+                    }
+                    Err(e) => warn!("Keyboard-interactive authentication failed. {}", e),
+                }
+            }
+            AuthMethod::Password if args.allow_env_auth => match std::env::var(PASSWORD_ENV) {
+                Ok(password) => {
+                    let password = Secret::new(password);
+                    let (result, password) =
+                        password_auth(session.clone(), sshuser.clone(), password).await?;
+                    match result {
+                        Ok(()) => {
+                            info!(
+                                "Logged user {} via {} with server {}",
+                                sshuser, PASSWORD_ENV, sshaddr
+                            );
+                            auth_method = Some("env-password");
+                        }
+                        Err(e) => error!(
+                            "Failed password authendication with {}. {}",
+                            PASSWORD_ENV, e
+                        ),
+                    }
+                    if keep_password {
+                        kept_password = Some(password);
+                    }
+                }
+                Err(_) => error!("{} is not set", PASSWORD_ENV),
+            },
+            AuthMethod::Password => {
+                if !can_prompt {
+                    continue;
+                }
+                while !session.authenticated() {
+                    // The prompt reads /dev/tty itself, it works from any thread
+                    let password =
+                        spawn_blocking(|| rpassword::prompt_password("Enter password: "))
+                            .await?
+                            .context("reading the password")?;
+                    let (result, password) =
+                        password_auth(session.clone(), sshuser.clone(), Secret::new(password))
+                            .await?;
+                    if let Err(e) = result {
+                        error!("Failed password authendication. {}", e);
+                        sleep(Duration::from_millis(1000)).await;
+                    } else if keep_password {
+                        kept_password = Some(password);
+                    }
+                }
+                info!(
+                    "Logged user {} via password with server {}",
+                    sshuser, sshaddr
+                );
+                auth_method = Some("password");
+            }
+        }
+    }
+    let Some(auth_method) = auth_method else {
+        audit_event("auth_failed", &[("user", Value::Str(&sshuser))]);
+        if args.allow_env_auth {
+            error!("No authentication method of --auth-order logged in");
+            // Prompting is off, there is nobody to ask
+            shutdown::exit(1);
+        }
+        if !std::io::stdin().is_terminal() {
+            error!(
+                "No usable authentication method and no TTY for prompting, load a key into \
+                 the ssh-agent or use --allow-env-auth with {}",
+                PASSWORD_ENV
             );
+            shutdown::exit(EXIT_NO_TTY_FOR_PROMPT);
         }
+        anyhow::bail!("no authentication method of --auth-order logged in");
+    };
+    audit_event(
+        "auth",
+        &[
+            ("user", Value::Str(&sshuser)),
+            ("method", Value::Str(auth_method)),
+            ("ssh_server", Value::Str(&sshaddr)),
+            ("server_fingerprint", Value::Str(&server_fingerprint)),
+        ],
+    );
+    if !args.quiet {
+        print_startup_banner(&args, &session, &sshaddr);
     }
-    if session.authenticated() != true {
-        while session.authenticated() != true {
-            let password = rpassword::prompt_password("Enter password: ").unwrap();
-            match session.userauth_password(&sshuser, &password) {
-                Err(e) => {
-                    error!("Failed password authendication. {}", e);
-                    sleep(Duration::from_millis(1000)).await;
+    let session_json = args.api_addr.map(|_| {
+        let info = session.session_info();
+        format!(
+            "{{\"ssh_server\":{},\"user\":{},\"server_fingerprint\":{},\"server_version\":{},\"kex\":{},\"host_key\":{},\"cipher\":{},\"mac\":{},\"compression\":{}}}",
+            json_string(&sshaddr),
+            json_string(&sshuser),
+            json_string(&server_fingerprint),
+            json_string(&info.server_version),
+            json_string(&info.kex),
+            json_string(&info.host_key),
+            json_string(&info.cipher),
+            json_string(&info.mac),
+            json_string(&info.compression)
+        )
+    });
+
+    if let Some(command) = &args.remote_check_command {
+        info!("Running remote check: {}", command);
+        let output = {
+            let (session, command) = (session.clone(), command.clone());
+            spawn_blocking(move || session.exec(&command)).await?
+        }
+        .with_context(|| format!("running remote check `{}`", command))?;
+        if output.exit_status != 0 {
+            anyhow::bail!(
+                "remote check `{}` failed on {} with exit status {}: {}",
+                command,
+                sshaddr,
+                output.exit_status,
+                output.stderr.trim()
+            );
+        }
+        debug!("Remote check output: {}", output.stdout.trim());
+        info!("Remote check passed");
+    }
+
+    match command {
+        Some(Command::Scan(scan)) => {
+            // Without a session timeout an open the server never answers
+            // blocks the scan for good
+            session.set_timeout(CHANNEL_POLL_MS);
+            return scan::run(session, scan).await;
+        }
+        Some(Command::Cp(cp)) => return cp::run(session, cp).await,
+        None => {}
+    }
+
+    if let Some(method) = args.preflight {
+        if method == Preflight::Channel {
+            session.set_timeout(CHANNEL_POLL_MS);
+        }
+        let timeout = Duration::from_millis(args.channel_open_timeout_ms);
+        preflight::run(&session, method, &remote_srv, remote_port, timeout)
+            .await
+            .with_context(|| format!("preflight check of {}:{}", remote_srv, remote_port))?;
+    }
+
+    let sessions = match args.source_addresses.split_first() {
+        Some((first, rest)) if !rest.is_empty() => {
+            let target = Arc::new(SessionTarget {
+                sshaddr: sshaddr.clone(),
+                addrs: server_addrs,
+                user: sshuser.clone(),
+                credentials: match (kept_password, kept_key) {
+                    (Some(password), _) => Credentials::Password(password),
+                    (None, Some((key, passphrase))) => Credentials::Key(key, passphrase),
+                    (None, None) => {
+                        if auth_method == "keyboard-interactive" {
+                            warn!(
+                                "The other sessions can't repeat keyboard-interactive \
+                                 authentication, they try the ssh-agent"
+                            );
+                        }
+                        Credentials::Agent(args.agent_socket.clone())
+                    }
+                },
+                host_key,
+                handshake,
+            });
+            let mut paths = vec![(*first, Some(session))];
+            for source in rest {
+                match target.connect::<Ssh2Transport>(*source).await {
+                    Ok(session) => {
+                        info!("Opened another SSH session from {}", source);
+                        paths.push((*source, Some(session)));
+                    }
+                    Err(e) => {
+                        warn!(
+                            "No SSH session from {} for now, retrying in the background: {}",
+                            source, e
+                        );
+                        paths.push((*source, None));
+                    }
                 }
-                Ok(_) => {}
             }
+            let sessions = MultiPath::new(paths, CHANNEL_POLL_MS);
+            sessions.spawn_monitor(Some(target));
+            {
+                let sessions = sessions.clone();
+                shutdown::on_exit(move || sessions.disconnect("ssh2fwd exiting"));
+            }
+            sessions
         }
-        info!(
-            "Logged user {} via password with server {}",
-            sshuser, sshaddr
+        first => {
+            let sessions = MultiPath::new(
+                vec![(
+                    first.map_or(IpAddr::from([0, 0, 0, 0]), |(first, _)| *first),
+                    Some(session),
+                )],
+                CHANNEL_POLL_MS,
+            );
+            // Nothing to reconnect with, a lost session ends the tunnel
+            sessions.spawn_monitor(None);
+            sessions
+        }
+    };
+
+    #[cfg(unix)]
+    let _control = match &control_path {
+        Some(path) => Some(control::ControlMaster::spawn(path, sessions.clone())?),
+        None => None,
+    };
+
+    if let (Some(addr), Some(server)) = (args.dns_forward, &args.dns_server) {
+        let (host, port) = addr::split_host_port(server)?;
+        dns_relay::spawn(addr, host, port.unwrap_or(53), sessions.clone()).await?;
+    }
+
+    let token = if args.require_tunnel_token {
+        let token = match std::env::var(token::TOKEN_ENV) {
+            Ok(hex) => TunnelToken::from_hex(Secret::new(hex).expose())?,
+            Err(_) => TunnelToken::generate(),
+        };
+        info!("Local clients must send tunnel token {}", token.to_hex());
+        Some(Arc::new(token))
+    } else {
+        None
+    };
+
+    let limiter = args
+        .connection_limit_per_source_ip
+        .map(ConnectionLimiter::new);
+
+    let burst = args.channel_burst_size.map(usize::from);
+    let pool = if args.reuse_channel {
+        warn!(
+            "Reusing SSH channels between local connections, the remote server sees one \
+             continuous TCP connection. Only use this with stateless backends"
         );
+        Some(ChannelPool::new(
+            args.connection_limit_per_source_ip
+                .map_or(DEFAULT_POOL_SIZE, |n| n as usize)
+                .max(burst.unwrap_or(0)),
+        ))
     } else {
-        info!("User {} logged in to {}", sshuser, sshaddr);
+        burst.map(ChannelPool::new)
+    };
+    let refilling = Arc::new(AtomicBool::new(false));
+
+    let listener = match early_listener {
+        Some(listener) => listener,
+        None => LocalListener::bind(&localsrv, &unix_opts, args.forwarder_pool.is_some()).await?,
+    };
+
+    let client_token = match (args.client_token.take(), &args.client_token_file) {
+        (Some(secret), _) => Some(ClientToken::new(secret)?),
+        (None, Some(path)) => Some(ClientToken::from_file(path)?),
+        (None, None) => None,
+    };
+    let client_token = match client_token {
+        Some(_) if listener.is_local_only()? && !args.client_token_on_loopback => {
+            info!("Not asking for the client token on a local only address");
+            None
+        }
+        token => token.map(Arc::new),
+    };
+
+    let socks_auth = match (args.socks_user.take(), &args.socks_users_file) {
+        (Some(user), _) => Some(SocksAuth::new(
+            user,
+            args.socks_password
+                .take()
+                .expect("clap requires --socks-password"),
+        )?),
+        (None, Some(path)) => Some(SocksAuth::from_file(path)?),
+        (None, None) => None,
+    };
+    if let Some(auth) = &socks_auth {
+        info!("SOCKS5 clients must log in, {} user(s)", auth.len());
     }
+    let socks_auth = socks_auth.map(Arc::new);
 
-    let listener = TcpListener::bind(localsrv).await?;
+    let latency = (args.latency_sla_ms.is_some() || args.api_addr.is_some())
+        .then(|| LatencyTracker::new(args.latency_sla_ms.map(Duration::from_millis)));
 
+    let live = match (args.api_addr, session_json) {
+        (Some(api_addr), Some(session)) => {
+            let remote = if args.dynamic {
+                "socks5".to_string()
+            } else {
+                addr::join_host_port(&remote_srv, remote_port)
+            };
+            let connections = Arc::new(LiveConnections::default());
+            api::serve(
+                api_addr,
+                ApiState {
+                    connections: connections.clone(),
+                    session,
+                    tunnels: format!(
+                        "[{{\"local\":{},\"remote\":{}}}]",
+                        json_string(&localsrv),
+                        json_string(&remote)
+                    ),
+                    latency: latency.clone(),
+                    token: args.api_token.take(),
+                },
+            )
+            .await?;
+            Some(connections)
+        }
+        _ => None,
+    };
+    // The API shares its table of connections with ~#
+    let live = if std::io::stdin().is_terminal() && args.forwarder_pool.is_none() {
+        let live = live.unwrap_or_default();
+        escape_sequences::spawn(live.clone())?;
+        Some(live)
+    } else {
+        live
+    };
+
+    #[cfg(any(feature = "consul", feature = "etcd"))]
+    if let Some(name) = &args.service_name {
+        let addr = match listener.tcp_addr()? {
+            Some(addr) => addr,
+            None => anyhow::bail!("--service-name needs a TCP local address"),
+        };
+        let service = registry::Service {
+            name: name.clone(),
+            addr,
+        };
+        let mut registrations = registry::Registrations::default();
+        #[cfg(feature = "consul")]
+        if let Some(url) = &args.consul_url {
+            registrations.consul = Some(registry::Consul::register(url, &service).await?);
+        }
+        #[cfg(feature = "etcd")]
+        if let Some(url) = &args.etcd_url {
+            registrations.etcd =
+                Some(registry::Etcd::register(url, &service, args.etcd_ttl_secs).await?);
+        }
+        registrations.deregister_on_exit();
+    }
+
+    if let Some(mode) = args.sandbox {
+        #[cfg(target_os = "linux")]
+        {
+            use sandbox::Access;
+            let mut allowed = vec![];
+            // Reconnects of --source-addresses read the key again
+            for path in [&args.allow_from_file, &args.dest_allow_file, &args.identity]
+                .into_iter()
+                .flatten()
+            {
+                allowed.push((path.clone(), Access::ReadFile));
+            }
+            if let Some(path) = &args.audit_log {
+                let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+                allowed.push((
+                    dir.unwrap_or(std::path::Path::new(".")).to_path_buf(),
+                    Access::WriteDir,
+                ));
+            }
+            sandbox::apply(mode, &allowed)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        match mode {
+            SandboxMode::Strict => anyhow::bail!("--sandbox is only supported on Linux"),
+            SandboxMode::BestEffort => warn!("--sandbox is only supported on Linux, ignoring it"),
+        }
+    }
+
+    let tunnel = Arc::new(Tunnel {
+        token,
+        client_token,
+        dest_acl,
+        sni_routes,
+        socks_auth,
+        enable_bind: args.socks_enable_bind,
+        half_duplex: args.half_duplex,
+        receive_window_size: args.receive_window_size.map(|s| s.0 as usize),
+        local_tos: args.ip_tos.filter(|_| args.propagate_tos),
+        write_timeout: (args.write_timeout > 0).then(|| Duration::from_secs(args.write_timeout)),
+        pool,
+        reuse: args.reuse_channel,
+        burst,
+        refilling,
+        mysql_logging: args.mysql_logging,
+        audit: audit.clone(),
+        quotas: quotas.clone(),
+        live,
+        latency,
+        ..Tunnel::new(sessions.clone(), remote_srv, remote_port)
+    });
+
+    // Every connection task holds a sender, all of them gone means all
+    // connections are closed
+    let (drain_tx, mut drain_rx) = tokio::sync::mpsc::channel::<()>(1);
     loop {
-        let (socket, info) = listener.accept().await?;
-        let handle_session = session.clone();
-        let stream = Arc::new(Mutex::new(0));
-        let remote_srvc = remote_srv.clone();
+        // While no session is up new connections wait in the listen queue
+        let changed = sessions.changed();
+        if !sessions.usable() {
+            if !sessions.reconnects() {
+                break;
+            }
+            info!("No SSH session is up, new connections wait for a reconnect");
+            changed.await;
+            continue;
+        }
+        let accepted = tokio::select! {
+            accepted = listener.accept_retrying() => accepted,
+            _ = changed => continue,
+        };
+        let (socket, info) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                audit_event(
+                    "disconnect",
+                    &[
+                        ("ssh_server", Value::Str(&sshaddr)),
+                        ("reason", Value::Str(&e.to_string())),
+                    ],
+                );
+                return Err(e.into());
+            }
+        };
+        // Address based rules only apply to TCP clients, Unix socket clients
+        // are restricted by the socket file permissions
+        if let Some(ip) = info.ip() {
+            let verdict = acl.check(ip);
+            if !matches!(verdict, Verdict::Allowed) {
+                acl.log_rejected(ip, &verdict);
+                drop(socket);
+                continue;
+            }
+        }
+        if let (Some(rate_limiter), Some(ip)) = (&rate_limiter, info.ip()) {
+            if !rate_limiter.check(ip) {
+                drop(socket);
+                continue;
+            }
+        }
+        if let (Some(quotas), Some(ip)) = (&quotas, info.ip()) {
+            if !quotas.check_client(ip) {
+                drop(socket);
+                continue;
+            }
+        }
+        let conn_guard = match (&limiter, info.ip()) {
+            (Some(limiter), Some(ip)) => match limiter.try_acquire(ip) {
+                Some(guard) => Some(guard),
+                None => {
+                    warn!(
+                        "Closed connection from {}, it already has {} open connection(s)",
+                        info,
+                        limiter.active(ip)
+                    );
+                    drop(socket);
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let tunnel = tunnel.clone();
+        let drain = drain_tx.clone();
 
-        info!("New local connection for tunneling. {:?}", info);
+        info!("New local connection #{} for tunneling. {}", conn_id, info);
         tokio::spawn(async move {
-            let (mut rxchan, mut txchan) = get_channels_for_remote_server(
-                &remote_srvc,
-                remote_port,
-                &handle_session,
-                stream.clone(),
-            )
-            .unwrap();
-            let (mut local_rd, mut local_wr) = socket.into_split();
-
-            handle_session.set_timeout(20);
-
-            let t1 = tokio::task::spawn_blocking(move || {
-                let mut buf = vec![0; 1024];
-                debug!("Running new local read task");
-                loop {
-                    match block_on(local_rd.read(&mut buf)) {
-                        Ok(0) => {
-                            warn!("No bytes read from local connection. Closing.");
-                            break;
+            let _conn_guard = conn_guard;
+            let _drain = drain;
+            forward_connection(tunnel, socket, info, conn_id).await;
+        });
+    }
+
+    drop(drain_tx);
+    info!("Not accepting connections any more, waiting for the open ones to close");
+    if timeout(SESSION_LOST_DRAIN, drain_rx.recv()).await.is_err() {
+        warn!("Some connections did not close in time");
+    }
+    audit_event(
+        "disconnect",
+        &[
+            ("ssh_server", Value::Str(&sshaddr)),
+            ("reason", Value::Str("session lost")),
+        ],
+    );
+    anyhow::bail!("lost the SSH session to {}", sshaddr)
+}
+
+/// What the connections of the tunnel share, set up once before accepting
+struct Tunnel<T: SshTransport> {
+    sessions: Arc<MultiPath<T>>,
+    remote_srv: String,
+    remote_port: u16,
+    token: Option<Arc<TunnelToken>>,
+    client_token: Option<Arc<ClientToken>>,
+    dest_acl: Option<Arc<DestAccessList>>,
+    sni_routes: Arc<Vec<SniRoute>>,
+    socks_auth: Option<Arc<SocksAuth>>,
+    enable_bind: bool,
+    half_duplex: Option<HalfDuplex>,
+    receive_window_size: Option<usize>,
+    local_tos: Option<u8>,
+    write_timeout: Option<Duration>,
+    pool: Option<Arc<ChannelPool<IdleChannel<T>>>>,
+    reuse: bool,
+    burst: Option<usize>,
+    refilling: Arc<AtomicBool>,
+    mysql_logging: bool,
+    audit: Option<Arc<AuditLog>>,
+    quotas: Option<Arc<ByteQuotas>>,
+    live: Option<Arc<LiveConnections>>,
+    latency: Option<Arc<LatencyTracker>>,
+}
+
+impl<T: SshTransport> Tunnel<T> {
+    /// Plain forwarding to `remote_srv`:`remote_port`, every option off
+    fn new(sessions: Arc<MultiPath<T>>, remote_srv: String, remote_port: u16) -> Self {
+        Tunnel {
+            sessions,
+            remote_srv,
+            remote_port,
+            token: None,
+            client_token: None,
+            dest_acl: None,
+            sni_routes: Arc::default(),
+            socks_auth: None,
+            enable_bind: false,
+            half_duplex: None,
+            receive_window_size: None,
+            local_tos: None,
+            write_timeout: None,
+            pool: None,
+            reuse: false,
+            burst: None,
+            refilling: Arc::new(AtomicBool::new(false)),
+            mysql_logging: false,
+            audit: None,
+            quotas: None,
+            live: None,
+            latency: None,
+        }
+    }
+}
+
+/// Forward one accepted local connection through a channel, from the token
+/// and SOCKS5 negotiation to closing or keeping the channel
+async fn forward_connection<T: SshTransport>(
+    tunnel: Arc<Tunnel<T>>,
+    mut socket: LocalStream,
+    info: PeerAddr,
+    conn_id: u64,
+) {
+    let accepted_at = tokio::time::Instant::now();
+    let sessions = tunnel.sessions.clone();
+    let (_, mut handle_session) = sessions
+        .pick()
+        .expect("the first SSH session is never taken out");
+    let remote_srvc = tunnel.remote_srv.clone();
+    let remote_port = tunnel.remote_port;
+    let token = tunnel.token.clone();
+    let client_token = tunnel.client_token.clone();
+    let dest_acl = tunnel.dest_acl.clone();
+    let sni_routes = tunnel.sni_routes.clone();
+    let socks_auth = tunnel.socks_auth.clone();
+    let enable_bind = tunnel.enable_bind;
+    let half_duplex = tunnel.half_duplex;
+    let receive_window_size = tunnel.receive_window_size;
+    let local_tos = tunnel.local_tos;
+    let write_timeout = tunnel.write_timeout;
+    let pool = tunnel.pool.clone();
+    let reuse = tunnel.reuse;
+    let burst = tunnel.burst;
+    let refilling = tunnel.refilling.clone();
+    let mysql_logging = tunnel.mysql_logging;
+    let audit = tunnel.audit.clone();
+    let quotas = tunnel.quotas.clone();
+    let live = tunnel.live.clone();
+    let latency = tunnel.latency.clone();
+
+    let state = StateMachine::new(conn_id);
+    let mut record = ConnectionRecord::new(audit, conn_id, info);
+    let conn_quota = quotas.as_ref().and_then(|q| q.conn_quota());
+    record.quotas = quotas;
+    let registration = live.as_ref().map(|live| {
+        live.register(
+            conn_id,
+            info,
+            state.shared(),
+            record.bytes_out.clone(),
+            record.bytes_in.clone(),
+        )
+    });
+    let live_conn = registration.as_ref().map(|r| r.conn.clone());
+    if let Some(token) = token {
+        if !token.verify(&mut socket).await {
+            warn!(
+                "Closing connection #{} from {}, missing or wrong tunnel token",
+                conn_id, info
+            );
+            record.close("bad tunnel token");
+            return;
+        }
+        record.identity = Some("tunnel-token".to_string());
+    }
+    if let Some(client_token) = client_token {
+        if !client_token.verify(&mut socket).await {
+            warn!(
+                "Closing connection #{} from {}, missing or wrong client token",
+                conn_id, info
+            );
+            record.close("bad client token");
+            return;
+        }
+        record.identity = Some("client-token".to_string());
+    }
+    // Read looking for the server name, forwarded first
+    let mut early_data = Vec::new();
+    let mut bind = false;
+    let (target_srv, target_port) = match &dest_acl {
+        Some(dest_acl) => {
+            let request =
+                socks::handshake(&mut socket, dest_acl, socks_auth.as_deref(), enable_bind).await;
+            match request {
+                Ok(socks::Request {
+                    bind: is_bind,
+                    udp_associate,
+                    host,
+                    port,
+                    user,
+                }) => {
+                    bind = is_bind;
+                    if let Some(user) = user {
+                        info!(
+                            "Connection #{} from {} logged in as SOCKS5 user {}",
+                            conn_id, info, user
+                        );
+                        record.identity = Some(format!("socks:{}", user));
+                    }
+                    if udp_associate {
+                        let client_ip = match info {
+                            PeerAddr::Tcp(addr) => Some(addr.ip()),
+                            _ => None,
+                        };
+                        let socket_ip = socket.local_ip();
+                        state.set(ConnectionState::Forwarding);
+                        let associated = socks_udp::associate(
+                            &handle_session,
+                            &mut socket,
+                            socket_ip,
+                            client_ip,
+                            dest_acl,
+                            socks_udp::DNS_PORT,
+                            conn_id,
+                        )
+                        .await;
+                        match associated {
+                            Ok(()) => record.close("local closed"),
+                            Err(e) => {
+                                warn!(
+                                    "Connection #{}: SOCKS5 UDP ASSOCIATE failed: {}",
+                                    conn_id, e
+                                );
+                                record.close("socks udp associate failed");
+                            }
                         }
-                        Ok(n) => {
-                            trace!("Local connection read {} bytes", n);
-                            if txchan.write_all(&buf[..n]).is_err() {
-                                error!("Write to ssh channel failure {} bytes. Closing", n);
-                                break;
+                        return;
+                    }
+                    (host, port)
+                }
+                Err(e) => {
+                    warn!(
+                        "Connection #{}: SOCKS5 negotiation with {} failed: {}",
+                        conn_id, info, e
+                    );
+                    record.close("socks negotiation failed");
+                    return;
+                }
+            }
+        }
+        None if sni_routes.is_empty() => (remote_srvc, remote_port),
+        None => {
+            let name = sni::read_client_hello(&mut socket, &mut early_data).await;
+            match name
+                .as_deref()
+                .and_then(|name| sni::route(&sni_routes, name))
+            {
+                Some(route) => {
+                    debug!(
+                        "Connection #{}: server name {} routed to {}",
+                        conn_id,
+                        name.as_deref().unwrap_or_default(),
+                        route
+                    );
+                    (route.host.clone(), route.port.unwrap_or(remote_port))
+                }
+                None => {
+                    debug!(
+                        "Connection #{}: no route for server name {}",
+                        conn_id,
+                        name.as_deref().unwrap_or("(none)")
+                    );
+                    (remote_srvc, remote_port)
+                }
+            }
+        }
+    };
+    record.target = Some(addr::join_host_port(&target_srv, target_port));
+    if let Some(live) = &live_conn {
+        live.set_target(&addr::join_host_port(&target_srv, target_port));
+        if let Some(identity) = &record.identity {
+            live.set_identity(identity);
+        }
+    }
+    state.set(ConnectionState::ChannelOpening);
+    let idle = pool
+        .as_ref()
+        .and_then(|pool| pool.take())
+        .filter(|idle: &IdleChannel<T>| !idle.0.lost().is_lost());
+    let channels = match idle {
+        _ if bind => match socks_bind(&handle_session, &mut socket, conn_id).await {
+            Ok(channels) => Ok(channels),
+            Err(e) => {
+                warn!("Connection #{}: SOCKS5 BIND failed: {}", conn_id, e);
+                record.close("socks bind failed");
+                return;
+            }
+        },
+        // The channel belongs to the session it was opened on, which
+        // with --source-addresses may not be the one picked above
+        Some((session, channel, rx, tx)) => {
+            debug!("Connection #{}: using an idle channel", conn_id);
+            handle_session = session;
+            Ok((channel, rx, tx))
+        }
+        None => {
+            get_channels_for_remote_server(&target_srv, target_port, &handle_session, conn_id).await
+        }
+    };
+    if dest_acl.is_some() && !bind {
+        let code = match &channels {
+            Ok(_) => socks::REP_SUCCEEDED,
+            Err(e) => match e.downcast_ref::<ChannelOpenError>() {
+                Some(ChannelOpenError::ConnectFailed) => socks::REP_CONNECTION_REFUSED,
+                Some(ChannelOpenError::Prohibited) => socks::REP_NOT_ALLOWED,
+                _ => socks::REP_GENERAL_FAILURE,
+            },
+        };
+        if socks::reply(&mut socket, code).await.is_err() {
+            if let Ok((channel, _, _)) = channels {
+                record.close("local write error");
+                close_channel(handle_session, channel, conn_id).await;
+                return;
+            }
+        }
+    }
+    // Returning drops the local socket, so the client sees the
+    // connection closed right away instead of waiting for data
+    let (channel, mut rxchan, mut txchan) = match channels {
+        Ok(channels) => channels,
+        Err(e) => {
+            sessions.report_failure(&handle_session);
+            if handle_session.lost().is_lost() {
+                info!(
+                    "Closing connection #{} from {}, the SSH session is lost",
+                    conn_id, info
+                );
+                record.close("session lost");
+                return;
+            }
+            error!(
+                "Closing connection #{} from {}, no channel to {}:{}: {}",
+                conn_id, info, target_srv, target_port, e
+            );
+            record.close(
+                e.downcast_ref::<ChannelOpenError>()
+                    .map_or("channel open failed", ChannelOpenError::reason),
+            );
+            return;
+        }
+    };
+    state.set(ConnectionState::Forwarding);
+    if let (Some(n), Some(pool)) = (burst, &pool) {
+        let idle = pool.idle();
+        if idle < n && !refilling.swap(true, Ordering::AcqRel) {
+            spawn_channel_burst(
+                handle_session.clone(),
+                target_srv.clone(),
+                target_port,
+                n - idle,
+                pool.clone(),
+                refilling.clone(),
+            );
+        }
+    }
+    if let Some(direction) = half_duplex {
+        debug!("Forwarding {:?} only", direction);
+    }
+    if let Some(size) = receive_window_size {
+        if let Err(e) = socket.set_send_buffer_size(size) {
+            warn!(
+                "Connection #{}: limiting the receive window failed: {}",
+                conn_id, e
+            );
+        }
+    }
+    if let Some(tos) = local_tos {
+        if let Err(e) = socket.set_tos(tos) {
+            warn!("Connection #{}: setting the IP TOS failed: {}", conn_id, e);
+        }
+    }
+    let (mut local_rd, mut local_wr) = io::split(socket);
+    let mut mysql = ((mysql_logging || target_port == 3306) && log_enabled!(Level::Debug))
+        .then(|| MysqlLogger::new(info));
+
+    let local_done = Arc::new(AtomicBool::new(false));
+    let local_done_rd = local_done.clone();
+    // The local read task sends EOF on it while the remote read task asks
+    // it whether the server is done
+    let channel = Arc::new(Mutex::new(channel));
+    let (bytes_out, bytes_in) = (record.bytes_out.clone(), record.bytes_in.clone());
+    let (reason_rd, reason_wr) = (record.reason.clone(), record.reason.clone());
+    let (quota_rd, quota_wr) = (conn_quota.clone(), conn_quota.clone());
+    let (live_rd, live_wr) = (live_conn.clone(), live_conn.clone());
+    let stalled = write_timeout.map(|_| Arc::new(Notify::new()));
+    let (stalled_rd, stalled_wr) = (stalled.clone(), stalled);
+
+    let t1 = if half_duplex == Some(HalfDuplex::RemoteToLocal) {
+        // Both streams are of the same channel, dropping one closes
+        // nothing. Tell the server nothing is coming.
+        drop(txchan);
+        drop(local_rd);
+        if !reuse {
+            let (session, channel) = (handle_session.clone(), channel.clone());
+            if let Ok(Err(e)) = spawn_blocking(move || send_channel_eof(&session, &channel)).await {
+                debug!("Connection #{}: sending EOF on the channel: {}", conn_id, e);
+            }
+        }
+        None
+    } else {
+        let session = handle_session.clone();
+        let channel = channel.clone();
+        Some(spawn_blocking(move || {
+            #[cfg(target_os = "linux")]
+            sandbox::enforce_current_thread();
+            let mut buf = vec![0; 1024];
+            debug!("Running new local read task");
+            if !early_data.is_empty() {
+                if let Err(e) = write_channel(
+                    &session,
+                    &mut txchan,
+                    &early_data,
+                    live_rd.as_deref(),
+                    write_timeout,
+                ) {
+                    let _ = reason_rd.set(report_io_error(
+                        conn_id,
+                        Direction::ChannelWrite(early_data.len()),
+                        &e,
+                    ));
+                    local_done_rd.store(true, Ordering::Release);
+                    return None;
+                }
+                bytes_out.fetch_add(early_data.len() as u64, Ordering::Relaxed);
+            }
+            let clean = loop {
+                // The cap may also be reached by the other direction,
+                // the API close the connection, the client stop taking
+                // data, or the session be lost, while this one waits
+                // for the client
+                let read = block_on(async {
+                    tokio::select! {
+                        r = local_rd.read(&mut buf) => Ok(r),
+                        _ = quota_reached(quota_rd.as_deref()) => Err("byte quota"),
+                        _ = closed_via_api(live_rd.as_deref()) => Err("closed via api"),
+                        _ = write_stalled(stalled_rd.as_deref()) => Err("write stalled"),
+                        _ = session.lost().wait() => Err("session lost"),
+                    }
+                });
+                let read = match read {
+                    Ok(read) => read,
+                    Err(reason) => {
+                        let _ = reason_rd.set(reason);
+                        break false;
+                    }
+                };
+                match read {
+                    Ok(0) => {
+                        warn!("No bytes read from local connection. Closing.");
+                        let _ = reason_rd.set("local closed");
+                        // The client is done sending, which only ends the
+                        // other direction once the server answered in
+                        // full. A reused channel stays open for the next
+                        // client.
+                        if !reuse {
+                            if let Err(e) = send_channel_eof(&session, &channel) {
+                                debug!(
+                                    "Connection #{}: sending EOF on the channel: {}",
+                                    conn_id, e
+                                );
                             }
                         }
-                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                            continue;
+                        break true;
+                    }
+                    Ok(n) => {
+                        trace!("Local connection read {} bytes", n);
+                        let n = quota_rd.as_ref().map_or(n, |q| q.take(n));
+                        if let Some(mysql) = &mut mysql {
+                            mysql.feed(&buf[..n]);
                         }
-                        Err(e) => {
-                            error!("Error on reading from local connection {:?}. Closing", e);
-                            break;
+                        if let Err(e) = write_channel(
+                            &session,
+                            &mut txchan,
+                            &buf[..n],
+                            live_rd.as_deref(),
+                            write_timeout,
+                        ) {
+                            if live_rd.as_ref().is_some_and(|c| c.killed()) {
+                                let _ = reason_rd.set("closed via api");
+                            } else if session_lost(&session) {
+                                let _ = reason_rd.set("session lost");
+                            } else if e.kind() == io::ErrorKind::TimedOut {
+                                warn!(
+                                    "Connection #{}: the remote side took no data for {:?}. Closing",
+                                    conn_id,
+                                    write_timeout.unwrap_or_default()
+                                );
+                                let _ = reason_rd.set("write stalled");
+                            } else {
+                                let _ = reason_rd.set(report_io_error(
+                                    conn_id,
+                                    Direction::ChannelWrite(n),
+                                    &e,
+                                ));
+                            }
+                            break false;
+                        }
+                        bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                        if quota_rd.as_ref().is_some_and(|q| q.exceeded()) {
+                            let _ = reason_rd.set("byte quota");
+                            break false;
                         }
                     }
+                    Err(e) => match classify_io_error(&e) {
+                        ErrorAction::Continue => continue,
+                        ErrorAction::Break | ErrorAction::LogAndBreak => {
+                            let _ =
+                                reason_rd.set(report_io_error(conn_id, Direction::LocalRead, &e));
+                            break false;
+                        }
+                    },
                 }
-            });
+            };
+            local_done_rd.store(true, Ordering::Release);
+            clean.then_some(txchan)
+        }))
+    };
 
-            let t2 = tokio::task::spawn_blocking(move || {
-                let mut buf = vec![0; 1024];
-                debug!("Running new remote read task");
-                loop {
-                    match rxchan.read(&mut buf) {
-                        Ok(0) => {
-                            warn!("No bytes read from remote channel. Closing");
-                            break;
+    let mut deliver = None;
+    let t2 = if half_duplex == Some(HalfDuplex::LocalToRemote) {
+        drop(rxchan);
+        // Tell the client nothing is coming
+        if let Err(e) = local_wr.shutdown().await {
+            debug!("Shutting down the local write side: {}", e);
+        }
+        drop(local_wr);
+        None
+    } else {
+        let local = LocalWriter {
+            wr: local_wr,
+            write_timeout,
+            conn_id,
+            reason: reason_wr.clone(),
+            stalled: stalled_wr,
+            bytes_in,
+        };
+        let mut sink = match receive_window_size {
+            Some(size) => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                let window = ReceiveWindow::new(size);
+                deliver = Some(tokio::spawn(deliver_local(local, rx, window.clone())));
+                LocalSink::Queued(tx, window)
+            }
+            None => LocalSink::Direct(local),
+        };
+        let session = handle_session.clone();
+        // To tell the end of the data from a read that merely came back empty
+        let channel = channel.clone();
+        Some(spawn_blocking(move || {
+            #[cfg(target_os = "linux")]
+            sandbox::enforce_current_thread();
+            let mut buf = vec![0; 1024];
+            debug!("Running new remote read task");
+            let mut quiet_since = None;
+            let mut waiting_since = latency.is_some().then_some(accepted_at);
+            let clean = loop {
+                if quota_wr.as_ref().is_some_and(|q| q.exceeded()) {
+                    let _ = reason_wr.set("byte quota");
+                    break false;
+                }
+                if live_wr.as_ref().is_some_and(|c| c.killed()) {
+                    let _ = reason_wr.set("closed via api");
+                    break false;
+                }
+                // The local read task gave up on the channel
+                if reason_wr.get() == Some(&"write stalled") {
+                    break false;
+                }
+                if session.lost().is_lost() {
+                    let _ = reason_wr.set("session lost");
+                    break false;
+                }
+                if let LocalSink::Queued(chunks, window) = &sink {
+                    // The client's writes failed
+                    if chunks.is_closed() {
+                        break false;
+                    }
+                    // Leave the data with the server while the client is
+                    // behind, looking again now and then for the above
+                    if block_on(timeout(IDLE_WAIT, window.room())).is_err() {
+                        continue;
+                    }
+                }
+                let seen = session.data_generation();
+                match rxchan.read(&mut buf) {
+                    Ok(0) if !session.channel_eof(&lock_channel(&channel)) => {
+                        session.wait_for_data(seen, IDLE_WAIT);
+                        continue;
+                    }
+                    Ok(0) => {
+                        warn!("No bytes read from remote channel. Closing");
+                        let _ = reason_wr.set("remote closed");
+                        match &mut sink {
+                            LocalSink::Direct(local) => block_on(local.shutdown()),
+                            LocalSink::Queued(chunks, _) => {
+                                let _ = chunks.send(Vec::new());
+                            }
                         }
-                        Ok(n) => {
-                            trace!("Remote channel read {} bytes", n);
-                            if block_on(local_wr.write_all(&buf[..n])).is_err() {
-                                error!("Writing to local socket {}. Closing", n);
-                                break;
+                        break false;
+                    }
+                    Ok(n) => {
+                        trace!("Remote channel read {} bytes", n);
+                        session.data_read();
+                        if let (Some(latency), Some(since)) = (&latency, waiting_since.take()) {
+                            latency.record(conn_id, since.elapsed());
+                        }
+                        quiet_since = None;
+                        let n = quota_wr.as_ref().map_or(n, |q| q.take(n));
+                        let passed_on = match &mut sink {
+                            LocalSink::Direct(local) => block_on(local.write(&buf[..n])),
+                            LocalSink::Queued(_, _) if n == 0 => true,
+                            LocalSink::Queued(chunks, window) => {
+                                window.queued(n);
+                                chunks.send(buf[..n].to_vec()).is_ok()
                             }
+                        };
+                        if !passed_on {
+                            break false;
                         }
-                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    }
+                    Err(e) => match classify_io_error(&e) {
+                        ErrorAction::Continue => {
+                            // A reused channel is never closed by the remote, so
+                            // stop once the local side is gone and the answer drained
+                            if reuse && local_done.load(Ordering::Acquire) {
+                                let since = *quiet_since.get_or_insert_with(Instant::now);
+                                if since.elapsed() >= REUSE_DRAIN {
+                                    break true;
+                                }
+                            }
+                            // Data that came in during the read may have
+                            // been for other channels, they look again
+                            if session.data_generation() != seen {
+                                session.data_read();
+                            }
+                            session.wait_for_data(seen, IDLE_WAIT);
                             continue;
                         }
-                        Err(e) => {
-                            error!("Error on writing to remote channel {:?}. Closing.", e);
-                            break;
+                        _ if session_lost(&session) => {
+                            let _ = reason_wr.set("session lost");
+                            break false;
                         }
-                    }
+                        ErrorAction::Break | ErrorAction::LogAndBreak => {
+                            let _ =
+                                reason_wr.set(report_io_error(conn_id, Direction::ChannelRead, &e));
+                            break false;
+                        }
+                    },
                 }
-            });
-
-            t1.await.unwrap();
-            t2.await.unwrap();
+            };
+            clean.then_some(rxchan)
+        }))
+    };
 
-            handle_session.set_timeout(3000);
-        });
+    let (txchan, remote_read, _) = tokio::join!(
+        join_copy_task(t1, conn_id, "local read", &record.reason),
+        join_copy_task(t2, conn_id, "remote read", &record.reason),
+        join_copy_task(deliver, conn_id, "local write", &record.reason)
+    );
+    state.set(ConnectionState::Closing);
+    let (txchan, rxchan) = (txchan.flatten(), remote_read.flatten());
+    // Both tasks are done, a panicked one dropped its handle unwinding
+    let Ok(channel) = Arc::try_unwrap(channel) else {
+        return;
+    };
+    let channel = channel.into_inner().unwrap_or_else(PoisonError::into_inner);
+    if conn_quota.as_ref().is_some_and(|q| q.exceeded()) {
+        if let Some(quotas) = &record.quotas {
+            quotas.conn_cut(conn_id, &info, record.identity.as_deref());
+        }
     }
+    // Both copy loops are done with the channel, keep it for the next
+    // connection or close it
+    let channel = match (pool.as_ref().filter(|_| reuse), rxchan, txchan) {
+        (Some(pool), Some(rxchan), Some(txchan)) => {
+            match pool.put((handle_session.clone(), channel, rxchan, txchan)) {
+                Ok(()) => {
+                    debug!("Channel kept open for the next local connection");
+                    return;
+                }
+                Err((_, channel, _, _)) => channel,
+            }
+        }
+        _ => channel,
+    };
+    close_channel(handle_session, channel, conn_id).await;
 }
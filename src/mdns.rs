@@ -0,0 +1,178 @@
+use log::{debug, info};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Duration, Instant};
+
+const MDNS_ADDR: &str = "224.0.0.251:5353";
+const SSH_SERVICE: &str = "_ssh._tcp.local";
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+const QUERY_INTERVAL: Duration = Duration::from_millis(500);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// Browse `_ssh._tcp.local` and return the address advertised by the
+/// instance named `name` (e.g. "myserver" for "myserver._ssh._tcp.local").
+///
+/// The queries are sent from an ephemeral port, so responders answer with
+/// legacy unicast replies and we don't have to join the multicast group.
+pub async fn discover_ssh_server(name: &str) -> anyhow::Result<SocketAddr> {
+    let instance = format!("{}.{}", name, SSH_SERVICE).to_lowercase();
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+
+    let mut srv: Option<(String, u16)> = None;
+    let mut addrs: Vec<(String, IpAddr)> = vec![];
+    let mut buf = vec![0; 9000];
+
+    info!("Looking for SSH server {} via mDNS", instance);
+    loop {
+        let query = match &srv {
+            None => build_query(SSH_SERVICE, TYPE_PTR),
+            Some((target, _)) => build_query(target, TYPE_A),
+        };
+        socket.send_to(&query, MDNS_ADDR).await?;
+
+        let round = Instant::now() + QUERY_INTERVAL;
+        while Instant::now() < round.min(deadline) {
+            let n = match timeout_at(round.min(deadline), socket.recv_from(&mut buf)).await {
+                Ok(Ok((n, from))) => {
+                    debug!("mDNS response of {} bytes from {}", n, from);
+                    n
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => break,
+            };
+            let records = match parse_records(&buf[..n]) {
+                Some(r) => r,
+                None => {
+                    debug!("Ignoring malformed mDNS response");
+                    continue;
+                }
+            };
+            for r in records {
+                match r.data {
+                    RecordData::Srv(port, target) if r.name == instance => {
+                        srv = Some((target, port));
+                    }
+                    RecordData::Addr(ip) => addrs.push((r.name, ip)),
+                    RecordData::Srv(..) | RecordData::Other => {}
+                }
+            }
+            if let Some((target, port)) = &srv {
+                let mut found: Vec<IpAddr> = addrs
+                    .iter()
+                    .filter(|(n, _)| n == target)
+                    .map(|(_, ip)| *ip)
+                    .collect();
+                // Prefer IPv4, link-local IPv6 would need a scope id
+                found.sort_by_key(|ip| ip.is_ipv6());
+                if let Some(ip) = found.first() {
+                    let addr = SocketAddr::new(*ip, *port);
+                    info!("Found {} at {}", instance, addr);
+                    return Ok(addr);
+                }
+            }
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!(
+                "No mDNS advertisement found for {} within {:?}",
+                instance,
+                DISCOVERY_TIMEOUT
+            );
+        }
+    }
+}
+
+enum RecordData {
+    Srv(u16, String),
+    Addr(IpAddr),
+    Other,
+}
+
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+fn build_query(name: &str, qtype: u16) -> Vec<u8> {
+    // id, flags, qdcount = 1, ancount, nscount, arcount
+    let mut q = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&qtype.to_be_bytes());
+    q.extend_from_slice(&CLASS_IN.to_be_bytes());
+    q
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+/// Read a possibly compressed name at `pos`, returns the lowercased name and
+/// the position right after it
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = vec![];
+    let mut end = None;
+    // Bound the number of pointer jumps so a malicious packet can't loop us
+    for _ in 0..128 {
+        let len = *msg.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join(".").to_lowercase(), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let ptr = (read_u16(msg, pos)? & 0x3fff) as usize;
+            end.get_or_insert(pos + 2);
+            pos = ptr;
+            continue;
+        }
+        let label = msg.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
+
+fn parse_records(msg: &[u8]) -> Option<Vec<Record>> {
+    let qdcount = read_u16(msg, 4)?;
+    let rrcount =
+        read_u16(msg, 6)? as usize + read_u16(msg, 8)? as usize + read_u16(msg, 10)? as usize;
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        let (_, next) = read_name(msg, pos)?;
+        pos = next + 4;
+    }
+
+    let mut records = vec![];
+    for _ in 0..rrcount {
+        let (name, next) = read_name(msg, pos)?;
+        let rtype = read_u16(msg, next)?;
+        let rdlen = read_u16(msg, next + 8)? as usize;
+        let rdata_pos = next + 10;
+        let rdata = msg.get(rdata_pos..rdata_pos + rdlen)?;
+        let data = match rtype {
+            TYPE_SRV if rdlen > 6 => {
+                let port = read_u16(rdata, 4)?;
+                let (target, _) = read_name(msg, rdata_pos + 6)?;
+                RecordData::Srv(port, target)
+            }
+            TYPE_A if rdlen == 4 => {
+                RecordData::Addr(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).into())
+            }
+            TYPE_AAAA if rdlen == 16 => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                RecordData::Addr(Ipv6Addr::from(octets).into())
+            }
+            _ => RecordData::Other,
+        };
+        records.push(Record { name, data });
+        pos = rdata_pos + rdlen;
+    }
+    Some(records)
+}
@@ -0,0 +1,279 @@
+use crate::secret::Secret;
+use crate::transport::{HandshakeOptions, SshTransport};
+use anyhow::bail;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// How often the sessions are checked
+const HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest wait between two reconnect attempts of a failed path
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Connect to the first reachable of `addrs` from `source`. Only addresses
+/// of the same family as the source are tried.
+pub async fn connect_from(source: IpAddr, addrs: &[SocketAddr]) -> anyhow::Result<TcpStream> {
+    let mut last_err = None;
+    for target in addrs.iter().filter(|a| a.is_ipv4() == source.is_ipv4()) {
+        let socket = match source {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(source, 0))?;
+        match socket.connect(*target).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    match last_err {
+        Some(e) => Err(e.into()),
+        None => bail!("no address of the SSH server is reachable from {}", source),
+    }
+}
+
+/// How the first session logged in, replayed for the other paths and for
+/// reconnects since there is nobody to ask by then
+pub enum Credentials {
+    Agent(Option<PathBuf>),
+    Password(Secret),
+    /// Private key file and its passphrase
+    Key(PathBuf, Option<Secret>),
+}
+
+/// Everything needed to open another session to the same server
+pub struct SessionTarget {
+    pub sshaddr: String,
+    /// Resolved once at startup, the sandbox may not allow reading
+    /// /etc/hosts or resolv.conf later
+    pub addrs: Vec<SocketAddr>,
+    pub user: String,
+    pub credentials: Credentials,
+    /// Key the first session accepted, other sessions must see the same
+    pub host_key: Vec<u8>,
+    pub handshake: HandshakeOptions,
+}
+
+impl SessionTarget {
+    pub async fn connect<T: SshTransport>(self: &Arc<Self>, source: IpAddr) -> anyhow::Result<T> {
+        let tcp = match timeout(self.handshake.timeout, connect_from(source, &self.addrs)).await {
+            Ok(tcp) => tcp?,
+            Err(_) => bail!("connecting to {} timed out", self.sshaddr),
+        };
+        // Handshake and login block, the forwarding on the other paths
+        // must go on meanwhile
+        let target = self.clone();
+        spawn_blocking(move || target.login(T::handshake(tcp, &target.handshake)?)).await?
+    }
+
+    fn login<T: SshTransport>(&self, session: T) -> anyhow::Result<T> {
+        if session.host_key().as_deref() != Some(&self.host_key[..]) {
+            bail!(
+                "{} presented a different host key than on the first connection",
+                self.sshaddr
+            );
+        }
+        match &self.credentials {
+            Credentials::Agent(socket) => session.userauth_agent(&self.user, socket.as_deref())?,
+            Credentials::Password(password) => {
+                session.userauth_password(&self.user, password.expose())?
+            }
+            Credentials::Key(key, passphrase) => session.userauth_key_file(
+                &self.user,
+                key,
+                passphrase.as_ref().map(|p| p.expose()),
+            )?,
+        }
+        if !session.authenticated() {
+            bail!("{} did not accept the credentials", self.sshaddr);
+        }
+        Ok(session)
+    }
+}
+
+struct PathState<T> {
+    source: IpAddr,
+    session: Mutex<Option<T>>,
+    healthy: AtomicBool,
+}
+
+/// Independent SSH sessions to the same server, one per source address, that
+/// new local connections are spread over round robin. A failed path gets no
+/// new connections until it is reconnected.
+pub struct MultiPath<T> {
+    paths: Vec<PathState<T>>,
+    next: AtomicUsize,
+    /// Session timeout set on every session handed out, this is its only
+    /// owner once connections are forwarded
+    timeout_ms: u32,
+    /// Whether failed paths are reconnected, only with a `SessionTarget`
+    reconnects: AtomicBool,
+    /// A path failed or came back
+    changed: Notify,
+}
+
+impl<T: SshTransport> MultiPath<T> {
+    /// `sessions` holds one entry per path, None for a path that could not be
+    /// connected yet
+    pub fn new(sessions: Vec<(IpAddr, Option<T>)>, timeout_ms: u32) -> Arc<Self> {
+        Arc::new(MultiPath {
+            paths: sessions
+                .into_iter()
+                .map(|(source, session)| {
+                    if let Some(session) = &session {
+                        session.set_timeout(timeout_ms);
+                    }
+                    PathState {
+                        source,
+                        healthy: AtomicBool::new(session.is_some()),
+                        session: Mutex::new(session),
+                    }
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            timeout_ms,
+            reconnects: AtomicBool::new(false),
+            changed: Notify::new(),
+        })
+    }
+
+    /// Whether any path has a session that is up
+    pub fn usable(&self) -> bool {
+        self.paths.iter().any(|p| p.healthy.load(Ordering::Acquire))
+    }
+
+    /// Whether a failed path comes back by itself
+    pub fn reconnects(&self) -> bool {
+        self.reconnects.load(Ordering::Acquire)
+    }
+
+    /// Resolves once a path fails or comes back after this call
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+
+    /// Path and session for the next local connection. Without any healthy
+    /// path whatever session is left is used, opening a channel on it will
+    /// tell.
+    pub fn pick(&self) -> Option<(usize, T)> {
+        let n = self.paths.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let healthy = (0..n)
+            .map(|i| (start + i) % n)
+            .find(|&i| self.paths[i].healthy.load(Ordering::Acquire));
+        let candidates = healthy.into_iter().chain((0..n).map(|i| (start + i) % n));
+        for i in candidates {
+            if let Some(session) = self.paths[i].session.lock().unwrap().clone() {
+                return Some((i, session));
+            }
+        }
+        None
+    }
+
+    /// Disconnect the session of every path
+    pub fn disconnect(&self, description: &str) {
+        for state in &self.paths {
+            if let Some(session) = state.session.lock().unwrap().clone() {
+                session.disconnect(description);
+            }
+        }
+    }
+
+    /// A channel could not be opened on `session`. If the session itself is
+    /// gone it is marked lost and the monitor takes its path out.
+    pub fn report_failure(&self, session: &T) {
+        if !session.lost().is_lost() && (session.connection_failed() || !session.alive()) {
+            session.lost().mark("channel open failed");
+        }
+    }
+
+    /// Check the sessions in the background with keepalives and take out
+    /// the ones found lost, meanwhile or by the copy loops. With `target`
+    /// failed paths are reconnected with a growing delay.
+    pub fn spawn_monitor(self: &Arc<Self>, target: Option<Arc<SessionTarget>>) {
+        let paths = self.clone();
+        paths.reconnects.store(target.is_some(), Ordering::Release);
+        tokio::spawn(async move {
+            let mut retry: Vec<(Instant, Duration)> =
+                vec![(Instant::now(), HEALTH_INTERVAL); paths.paths.len()];
+            loop {
+                let sessions: Vec<T> = paths
+                    .paths
+                    .iter()
+                    .filter(|state| state.healthy.load(Ordering::Acquire))
+                    .filter_map(|state| state.session.lock().unwrap().clone())
+                    .collect();
+                let mut lost: FuturesUnordered<_> =
+                    sessions.iter().map(|s| s.lost().wait()).collect();
+                tokio::select! {
+                    _ = sleep(HEALTH_INTERVAL) => {}
+                    _ = lost.next(), if !lost.is_empty() => {}
+                }
+                drop(lost);
+                for (i, state) in paths.paths.iter().enumerate() {
+                    if state.healthy.load(Ordering::Acquire) {
+                        let session = state.session.lock().unwrap().clone();
+                        let alive = match session {
+                            Some(session) if !session.lost().is_lost() => {
+                                spawn_blocking(move || {
+                                    session.alive() || {
+                                        session.lost().mark("keepalive failed");
+                                        false
+                                    }
+                                })
+                                .await
+                                .unwrap_or(false)
+                            }
+                            _ => false,
+                        };
+                        if !alive {
+                            state.healthy.store(false, Ordering::Release);
+                            paths.changed.notify_waiters();
+                            if paths.paths.len() > 1 {
+                                warn!(
+                                    "SSH session from {} failed, new connections go to the other paths",
+                                    state.source
+                                );
+                            }
+                            retry[i] = (Instant::now(), HEALTH_INTERVAL);
+                        }
+                        continue;
+                    }
+                    let Some(target) = &target else {
+                        continue;
+                    };
+                    let (at, delay) = retry[i];
+                    if Instant::now() < at {
+                        continue;
+                    }
+                    match target.connect::<T>(state.source).await {
+                        Ok(session) => {
+                            session.set_timeout(paths.timeout_ms);
+                            *state.session.lock().unwrap() = Some(session);
+                            state.healthy.store(true, Ordering::Release);
+                            paths.changed.notify_waiters();
+                            info!("SSH session from {} reconnected", state.source);
+                        }
+                        Err(e) => {
+                            let delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                            warn!(
+                                "Reconnecting SSH session from {} failed, next try in {}s: {}",
+                                state.source,
+                                delay.as_secs(),
+                                e
+                            );
+                            retry[i] = (Instant::now() + delay, delay);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,243 @@
+use crate::local::PeerAddr;
+use log::debug;
+
+/// Longest query text that is logged, the rest is cut off
+const MAX_QUERY_LOG: usize = 500;
+const COM_QUERY: u8 = 0x03;
+/// Capability flag a client sets in its SSLRequest before switching to TLS
+const CLIENT_SSL: u32 = 0x0800;
+/// Size of the SSLRequest payload, a full handshake response is longer
+const SSL_REQUEST_LEN: usize = 32;
+
+/// Follows the client to server half of a MySQL connection and logs the SQL
+/// of every COM_QUERY at debug level. Data is only inspected, it is forwarded
+/// unchanged by the caller.
+pub struct MysqlLogger {
+    peer: PeerAddr,
+    header: Vec<u8>,
+    /// Payload bytes of the current packet still to come
+    remaining: usize,
+    len: usize,
+    seq: u8,
+    /// Start of the current payload, enough for the command byte and the
+    /// logged part of a query
+    payload: Vec<u8>,
+    seen_handshake_response: bool,
+    disabled: bool,
+}
+
+impl MysqlLogger {
+    pub fn new(peer: PeerAddr) -> Self {
+        MysqlLogger {
+            peer,
+            header: Vec::with_capacity(4),
+            remaining: 0,
+            len: 0,
+            seq: 0,
+            payload: Vec::new(),
+            seen_handshake_response: false,
+            disabled: false,
+        }
+    }
+
+    /// Feed bytes read from the local client, in order. Packets may be split
+    /// across calls in any way. Returns the queries these bytes completed,
+    /// as logged.
+    pub fn feed(&mut self, mut data: &[u8]) -> Vec<String> {
+        let mut queries = vec![];
+        while !data.is_empty() && !self.disabled {
+            if self.remaining == 0 {
+                // Packet header: 3 byte little endian length and sequence id
+                self.header.push(data[0]);
+                data = &data[1..];
+                if self.header.len() == 4 {
+                    let h = &self.header;
+                    self.len = h[0] as usize | (h[1] as usize) << 8 | (h[2] as usize) << 16;
+                    self.seq = h[3];
+                    self.remaining = self.len;
+                    self.header.clear();
+                    self.payload.clear();
+                    if self.len == 0 {
+                        queries.extend(self.packet_done());
+                    }
+                }
+                continue;
+            }
+            let take = self.remaining.min(data.len());
+            let room = (1 + MAX_QUERY_LOG).saturating_sub(self.payload.len());
+            self.payload.extend_from_slice(&data[..take.min(room)]);
+            self.remaining -= take;
+            data = &data[take..];
+            if self.remaining == 0 {
+                queries.extend(self.packet_done());
+            }
+        }
+        queries
+    }
+
+    fn packet_done(&mut self) -> Option<String> {
+        // The first client packet answers the server greeting. If it is an
+        // SSLRequest everything after it is encrypted.
+        if !self.seen_handshake_response {
+            self.seen_handshake_response = true;
+            if self.len == SSL_REQUEST_LEN && self.payload.len() >= 4 {
+                let caps = u32::from_le_bytes([
+                    self.payload[0],
+                    self.payload[1],
+                    self.payload[2],
+                    self.payload[3],
+                ]);
+                if caps & CLIENT_SSL != 0 {
+                    debug!(
+                        "MySQL client {} switched to TLS, not logging its queries",
+                        self.peer
+                    );
+                    self.disabled = true;
+                }
+            }
+            return None;
+        }
+        // Commands start a new sequence, continuation packets of large
+        // queries (seq > 0) carry no command byte
+        if self.seq != 0 || self.payload.first() != Some(&COM_QUERY) {
+            return None;
+        }
+        let sql = String::from_utf8_lossy(&self.payload[1..]);
+        let truncated = if self.len - 1 > MAX_QUERY_LOG {
+            "..."
+        } else {
+            ""
+        };
+        let query = format!("{}{}", sql, truncated);
+        debug!("MySQL query from {}: {}", self.peer, query);
+        Some(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger() -> MysqlLogger {
+        MysqlLogger::new(PeerAddr::Tcp("127.0.0.1:40000".parse().unwrap()))
+    }
+
+    fn packet(seq: u8, payload: &[u8]) -> Vec<u8> {
+        let len = payload.len().to_le_bytes();
+        let mut packet = vec![len[0], len[1], len[2], seq];
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Handshake response of the mysql 8 client logging in as root
+    const HANDSHAKE_RESPONSE: [u8; 66] = [
+        0x3e, 0x00, 0x00, 0x01, 0x8d, 0xa6, 0xff, 0x19, 0x00, 0x00, 0x00, 0x01, 0xff, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x72, 0x6f, 0x6f, 0x74, 0x00, 0x00, 0x63, 0x61, 0x63,
+        0x68, 0x69, 0x6e, 0x67, 0x5f, 0x73, 0x68, 0x61, 0x32, 0x5f, 0x70, 0x61, 0x73, 0x73, 0x77,
+        0x6f, 0x72, 0x64, 0x00, 0x00, 0x00,
+    ];
+
+    /// COM_QUERY "select @@version_comment limit 1", the mysql client sends it first
+    const VERSION_QUERY: [u8; 37] = [
+        0x21, 0x00, 0x00, 0x00, 0x03, 0x73, 0x65, 0x6c, 0x65, 0x63, 0x74, 0x20, 0x40, 0x40, 0x76,
+        0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x5f, 0x63, 0x6f, 0x6d, 0x6d, 0x65, 0x6e, 0x74, 0x20,
+        0x6c, 0x69, 0x6d, 0x69, 0x74, 0x20, 0x31,
+    ];
+
+    /// COM_PING
+    const PING: [u8; 5] = [0x01, 0x00, 0x00, 0x00, 0x0e];
+
+    /// SSLRequest of the mysql 8 client, CLIENT_SSL set
+    const SSL_REQUEST: [u8; 36] = [
+        0x20, 0x00, 0x00, 0x01, 0x8d, 0xae, 0xff, 0x19, 0x00, 0x00, 0x00, 0x01, 0xff, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn logs_queries_after_the_handshake() {
+        let mut logger = logger();
+        assert!(logger.feed(&HANDSHAKE_RESPONSE).is_empty());
+        assert_eq!(
+            logger.feed(&VERSION_QUERY),
+            ["select @@version_comment limit 1"]
+        );
+        assert!(logger.feed(&PING).is_empty());
+    }
+
+    #[test]
+    fn handshake_response_is_not_a_query() {
+        // Its first payload byte could pass for COM_QUERY
+        let mut logger = logger();
+        assert!(logger.feed(&packet(1, b"\x03not a query")).is_empty());
+    }
+
+    #[test]
+    fn packets_split_anywhere() {
+        let mut stream = HANDSHAKE_RESPONSE.to_vec();
+        stream.extend_from_slice(&VERSION_QUERY);
+        stream.extend_from_slice(&PING);
+        stream.extend_from_slice(&packet(0, b"\x03SELECT 2"));
+        for chunk in [1, 2, 3, 5, 7, 64] {
+            let mut logger = logger();
+            let queries: Vec<String> = stream.chunks(chunk).flat_map(|c| logger.feed(c)).collect();
+            assert_eq!(
+                queries,
+                ["select @@version_comment limit 1", "SELECT 2"],
+                "chunks of {}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn several_packets_in_one_read() {
+        let mut logger = logger();
+        let mut stream = HANDSHAKE_RESPONSE.to_vec();
+        stream.extend_from_slice(&packet(0, b"\x03BEGIN"));
+        stream.extend_from_slice(&packet(0, b""));
+        stream.extend_from_slice(&packet(0, b"\x03COMMIT"));
+        assert_eq!(logger.feed(&stream), ["BEGIN", "COMMIT"]);
+    }
+
+    #[test]
+    fn long_queries_are_cut() {
+        let mut logger = logger();
+        logger.feed(&HANDSHAKE_RESPONSE);
+        let mut payload = vec![COM_QUERY];
+        payload.resize(1 + 2 * MAX_QUERY_LOG, b'x');
+        let queries = logger.feed(&packet(0, &payload));
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].len(), MAX_QUERY_LOG + 3);
+        assert!(queries[0].ends_with("x..."));
+    }
+
+    #[test]
+    fn continuation_packets_are_not_commands() {
+        let mut logger = logger();
+        logger.feed(&HANDSHAKE_RESPONSE);
+        assert!(logger
+            .feed(&packet(1, b"\x03more of a long query"))
+            .is_empty());
+    }
+
+    #[test]
+    fn stops_at_tls() {
+        let mut logger = logger();
+        assert!(logger.feed(&SSL_REQUEST).is_empty());
+        assert!(logger.disabled);
+        // Encrypted records that happen to look like a query
+        assert!(logger.feed(&VERSION_QUERY).is_empty());
+    }
+
+    #[test]
+    fn handshake_response_of_ssl_request_size_without_ssl_flag() {
+        let mut request = SSL_REQUEST;
+        request[5] &= !0x08;
+        let mut logger = logger();
+        logger.feed(&request);
+        assert!(!logger.disabled);
+        assert_eq!(logger.feed(&packet(0, b"\x03SELECT 1")), ["SELECT 1"]);
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::{Arc, Mutex};
+
+/// Idle SSH channels to the fixed remote target, kept for --reuse-channel or
+/// opened ahead of time for --channel-burst-size, so short lived local
+/// connections don't pay for a channel open each time
+pub struct ChannelPool<C> {
+    max_idle: usize,
+    idle: Mutex<Vec<C>>,
+}
+
+impl<C> ChannelPool<C> {
+    pub fn new(max_idle: usize) -> Arc<Self> {
+        Arc::new(ChannelPool {
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Number of channels waiting
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Most recently returned channel first, it is the least likely to have
+    /// been closed by the remote side in the meantime
+    pub fn take(&self) -> Option<C> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    /// Keep a channel for the next local connection. The channel is handed
+    /// back when the pool is already full.
+    pub fn put(&self, channel: C) -> Result<(), C> {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() >= self.max_idle {
+            return Err(channel);
+        }
+        idle.push(channel);
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::anyhow;
+use log::{debug, info};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::{sleep, timeout, Duration};
+
+/// How long a TCP knock waits before giving up on the connection attempt.
+/// The SYN is what counts, a firewall guarding the port usually drops it.
+const TCP_KNOCK_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Knock on `ports` of the host in `sshaddr`, in order and `delay` apart,
+/// so a port knocking daemon in front of the server opens the SSH port.
+/// UDP knocks are single empty datagrams, TCP knocks are connection attempts
+/// that are dropped right after the SYN went out.
+pub async fn knock(sshaddr: &str, ports: &[u16], delay: Duration, tcp: bool) -> anyhow::Result<()> {
+    let ip = lookup_host(sshaddr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("{} did not resolve to any address", sshaddr))?
+        .ip();
+    info!(
+        "Knocking on {} {} port(s) {:?}",
+        ip,
+        if tcp { "TCP" } else { "UDP" },
+        ports
+    );
+    let udp = if tcp {
+        None
+    } else {
+        let any: IpAddr = match ip {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        Some(UdpSocket::bind(SocketAddr::new(any, 0)).await?)
+    };
+    for (i, port) in ports.iter().enumerate() {
+        if i > 0 {
+            sleep(delay).await;
+        }
+        let target = SocketAddr::new(ip, *port);
+        match &udp {
+            Some(socket) => {
+                socket.send_to(&[], target).await?;
+            }
+            None => {
+                // Refused or timed out are both fine, only the SYN matters
+                if let Ok(Err(e)) = timeout(TCP_KNOCK_TIMEOUT, TcpStream::connect(target)).await {
+                    debug!("TCP knock on {}: {}", target, e);
+                }
+            }
+        }
+        debug!("Knocked on {}", target);
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use crate::transport::{ProbeOutcome, SshTransport};
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use log::{debug, info};
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+
+/// Exit status of coreutils/busybox timeout when the command ran out of time
+const TIMEOUT_EXIT: i32 = 124;
+/// Exit status of a shell that found neither bash nor nc
+const NOT_FOUND_EXIT: i32 = 127;
+
+/// How to check that the SSH server reaches the remote target, for
+/// --preflight
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Preflight {
+    /// Open a direct-tcpip channel to the target and close it again
+    Channel,
+    /// Connect from a shell on the SSH server, for servers that only allow
+    /// forwarding to some ports or none
+    Exec,
+}
+
+/// Check once that the SSH server can connect to host:port, giving up after
+/// `timeout`
+pub async fn run<T: SshTransport>(
+    session: &T,
+    method: Preflight,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    info!("Preflight: checking {}:{} via {:?}", host, port, method);
+    let (session, target) = (session.clone(), host.to_string());
+    match method {
+        Preflight::Channel => {
+            let (outcome, _) =
+                spawn_blocking(move || session.probe_direct_tcpip(&target, port)).await?;
+            match outcome {
+                ProbeOutcome::Open => {}
+                ProbeOutcome::ConnectFailed => bail!("the SSH server can't connect there"),
+                ProbeOutcome::Prohibited => bail!(
+                    "the SSH server doesn't forward there, --preflight exec checks without \
+                     forwarding"
+                ),
+                ProbeOutcome::TimedOut => bail!("no answer from the SSH server in time"),
+                ProbeOutcome::Failed(e) => bail!("{}", e),
+            }
+        }
+        Preflight::Exec => {
+            let command = probe_command(host, port, timeout);
+            debug!("Preflight command: {}", command);
+            let output = spawn_blocking(move || session.exec(&command)).await??;
+            match output.exit_status {
+                0 => {}
+                TIMEOUT_EXIT => bail!("connecting took longer than {:?}", timeout),
+                NOT_FOUND_EXIT => bail!(
+                    "the SSH server has neither bash nor nc to connect with: {}",
+                    output.stderr.trim()
+                ),
+                status => {
+                    return Err(anyhow!(
+                        "connecting from the SSH server failed with exit status {}: {}",
+                        status,
+                        output.stderr.trim()
+                    ))
+                }
+            }
+        }
+    }
+    info!("Preflight: {}:{} is reachable", host, port);
+    Ok(())
+}
+
+/// A POSIX shell command connecting to host:port through bash's /dev/tcp,
+/// or nc -z without bash, under `timeout` where the server has it
+fn probe_command(host: &str, port: u16, timeout: Duration) -> String {
+    let secs = timeout.as_secs().max(1);
+    let host = shell_quote(host);
+    format!(
+        "t=; command -v timeout >/dev/null 2>&1 && t='timeout {0}'; \
+         if command -v bash >/dev/null 2>&1; \
+         then $t bash -c 'exec 3<>\"/dev/tcp/$0/$1\"' {1} {2}; \
+         else $t nc -z -w {0} {1} {2}; fi",
+        secs, host, port
+    )
+}
+
+/// `s` in single quotes for the shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
@@ -0,0 +1,121 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// A worker that ran this long before dying counts as having started fine,
+/// the respawn delay starts over
+const HEALTHY_UPTIME: Duration = Duration::from_secs(30);
+/// Longest wait before restarting a worker that keeps dying
+const MAX_RESPAWN_DELAY: Duration = Duration::from_secs(60);
+
+/// Fork `workers` processes that each run the whole forwarder with their own
+/// SSH session and runtime. Returns in the workers; the parent stays in here,
+/// restarts workers that crash and exits once all of them have finished.
+///
+/// Must be called before any thread is started, the tokio runtime included.
+pub fn fork_workers(workers: u16) -> anyhow::Result<()> {
+    let parent = unsafe { libc::getpid() };
+    let mut children: HashMap<libc::pid_t, (u16, Instant)> = HashMap::new();
+    for slot in 0..workers {
+        match spawn(parent)? {
+            Some(pid) => children.insert(pid, (slot, Instant::now())),
+            None => return Ok(()),
+        };
+    }
+    info!("Started {} forwarder workers", workers);
+    forward_sighup()?;
+
+    let mut delays = vec![Duration::from_secs(1); workers as usize];
+    while !children.is_empty() {
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut status, 0) };
+        if pid < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                if SIGHUP_RECEIVED.swap(false, Ordering::AcqRel) {
+                    info!("Passing SIGHUP on to the workers");
+                    for pid in children.keys() {
+                        unsafe { libc::kill(*pid, libc::SIGHUP) };
+                    }
+                }
+                continue;
+            }
+            return Err(e.into());
+        }
+        let (slot, started) = match children.remove(&pid) {
+            Some(child) => child,
+            None => continue,
+        };
+        if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+            info!("Worker {} (pid {}) finished", slot, pid);
+            continue;
+        }
+        let delay = &mut delays[slot as usize];
+        if started.elapsed() >= HEALTHY_UPTIME {
+            *delay = Duration::from_secs(1);
+        }
+        warn!(
+            "Worker {} (pid {}) {}, restarting it in {}s",
+            slot,
+            pid,
+            describe(status),
+            delay.as_secs()
+        );
+        std::thread::sleep(*delay);
+        *delay = (*delay * 2).min(MAX_RESPAWN_DELAY);
+        match spawn(parent)? {
+            Some(pid) => children.insert(pid, (slot, Instant::now())),
+            None => return Ok(()),
+        };
+    }
+    std::process::exit(0);
+}
+
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    SIGHUP_RECEIVED.store(true, Ordering::Release);
+}
+
+/// The workers reload their rules on SIGHUP, a SIGHUP sent to the parent is
+/// handed on to them. Without SA_RESTART it interrupts the waitpid.
+fn forward_sighup() -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Fork one worker. None in the worker, its pid in the parent.
+fn spawn(parent: libc::pid_t) -> anyhow::Result<Option<libc::pid_t>> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            // Workers go away with the parent instead of forwarding on
+            // unsupervised
+            unsafe {
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+                if libc::getppid() != parent {
+                    libc::_exit(0);
+                }
+            }
+            Ok(None)
+        }
+        pid => Ok(Some(pid)),
+    }
+}
+
+fn describe(status: libc::c_int) -> String {
+    if libc::WIFSIGNALED(status) {
+        format!("was killed by signal {}", libc::WTERMSIG(status))
+    } else {
+        format!("exited with status {}", libc::WEXITSTATUS(status))
+    }
+}
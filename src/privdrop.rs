@@ -0,0 +1,104 @@
+use anyhow::{anyhow, bail, Context};
+use std::ffi::CString;
+use std::io;
+
+pub fn running_as_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Switch to `user` and/or `group` for good. Supplementary groups are
+/// cleared, the group is set before the user (setgid needs root) and the
+/// result is checked so a failed drop never goes unnoticed. Without --group
+/// the primary group of the user is used.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match (group, &user) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some((_, gid))) => Some(*gid),
+        (None, None) => None,
+    };
+
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+            return Err(io::Error::last_os_error()).context("clearing supplementary groups");
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(io::Error::last_os_error()).context(format!("setgid({})", gid));
+        }
+        if unsafe { libc::getgid() != gid || libc::getegid() != gid } {
+            bail!("group is still not {} after setgid", gid);
+        }
+    }
+    if let Some((uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(io::Error::last_os_error()).context(format!("setuid({})", uid));
+        }
+        if unsafe { libc::getuid() != uid || libc::geteuid() != uid } {
+            bail!("user is still not {} after setuid", uid);
+        }
+        // Getting root back must be impossible now
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            bail!("could regain root after dropping to uid {}", uid);
+        }
+    }
+    Ok(())
+}
+
+/// Name or numeric id to (uid, primary gid)
+pub fn lookup_user(user: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t)> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let rc = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => {
+            let name = CString::new(user)?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc)).context(format!("looking up user {}", user));
+    }
+    if result.is_null() {
+        return Err(anyhow!("no such user {}", user));
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+/// Name or numeric id to gid
+pub fn lookup_group(group: &str) -> anyhow::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc))
+            .context(format!("looking up group {}", group));
+    }
+    if result.is_null() {
+        return Err(anyhow!("no such group {}", group));
+    }
+    Ok(grp.gr_gid)
+}
@@ -0,0 +1,82 @@
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+/// Replace the tokens of ssh's ProxyCommand in `command`: %h the host and
+/// %p the port of the SSH server, %r the user and %% a literal %
+pub fn expand(command: &str, host: &str, port: u16, user: &str) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(command.len());
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => out.push_str(host),
+            Some('p') => out.push_str(&port.to_string()),
+            Some('r') => out.push_str(user),
+            Some('%') => out.push('%'),
+            Some(other) => bail!("unknown token %{} in proxy command", other),
+            None => bail!("proxy command ends in a lone %"),
+        }
+    }
+    Ok(out)
+}
+
+/// Start `command` through the shell like ssh's ProxyCommand. Its stdin and
+/// stdout are one end of a socket pair, the SSH session runs over the other
+/// end that is returned. Its stderr goes to the log at debug level. The
+/// proxy is watched in the background so its exit shows up in the log, it
+/// is killed when ssh2fwd exits.
+pub fn spawn(command: &str) -> anyhow::Result<UnixStream> {
+    let (ours, theirs) = UnixStream::pair()?;
+    let theirs = OwnedFd::from(theirs);
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::from(theirs.try_clone()?))
+        .stdout(Stdio::from(theirs))
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("starting proxy command `{}`", command))?;
+    let pid = child.id().unwrap_or_default();
+    info!("Started proxy command, pid {}", pid);
+
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                debug!("Proxy command: {}", line);
+            }
+        });
+    }
+    // The runtime doesn't get to drop the child on every way out
+    let exited = Arc::new(AtomicBool::new(false));
+    {
+        let exited = exited.clone();
+        crate::shutdown::on_exit(move || {
+            if pid != 0 && !exited.load(Ordering::Acquire) {
+                // Safety: a plain syscall. The pid is the child's until the
+                // wait below reaps it, moments before `exited` is set.
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            }
+        });
+    }
+    tokio::spawn(async move {
+        let status = child.wait().await;
+        exited.store(true, Ordering::Release);
+        match status {
+            Ok(status) => warn!("Proxy command exited, {}", status),
+            Err(e) => warn!("Waiting for the proxy command failed: {}", e),
+        }
+    });
+    Ok(ours)
+}
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// --receive-window-size of one connection: data read from the channel that
+/// the local client wasn't sent yet. The remote read waits while that is at
+/// the size, so a slow client holds back the remote side through the SSH
+/// window instead of the data piling up here.
+pub struct ReceiveWindow {
+    size: usize,
+    undelivered: Arc<AtomicUsize>,
+    delivered: Notify,
+}
+
+impl ReceiveWindow {
+    pub fn new(size: usize) -> Arc<Self> {
+        Arc::new(ReceiveWindow {
+            size,
+            undelivered: Arc::default(),
+            delivered: Notify::new(),
+        })
+    }
+
+    pub fn undelivered(&self) -> usize {
+        self.undelivered.load(Ordering::Acquire)
+    }
+
+    /// Resolves once less than the size waits for the client
+    pub async fn room(&self) {
+        loop {
+            let delivered = self.delivered.notified();
+            if self.undelivered() < self.size {
+                return;
+            }
+            delivered.await;
+        }
+    }
+
+    /// `n` bytes were read from the channel for the client
+    pub fn queued(&self, n: usize) {
+        self.undelivered.fetch_add(n, Ordering::AcqRel);
+    }
+
+    /// `n` bytes were written to the client
+    pub fn delivered(&self, n: usize) {
+        self.undelivered.fetch_sub(n, Ordering::AcqRel);
+        self.delivered.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn room_waits_for_the_client() {
+        let window = ReceiveWindow::new(100);
+        window.room().await;
+        window.queued(60);
+        window.room().await;
+        window.queued(60);
+        let full = tokio::time::timeout(Duration::from_millis(50), window.room()).await;
+        assert!(
+            full.is_err(),
+            "room with {} undelivered",
+            window.undelivered()
+        );
+        let waiter = tokio::spawn({
+            let window = window.clone();
+            async move { window.room().await }
+        });
+        window.delivered(30);
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(window.undelivered(), 90);
+    }
+}
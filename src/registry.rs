@@ -0,0 +1,302 @@
+#[cfg(feature = "etcd")]
+use crate::audit::json_field;
+use crate::audit::json_string;
+use anyhow::{bail, Context};
+use log::{info, warn};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+/// Time a Consul or etcd request may take before it counts as failed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The tunnel as it is advertised
+pub struct Service {
+    pub name: String,
+    /// Local address of the tunnel, an unspecified IP is left for the
+    /// registry to fill in where it can
+    pub addr: SocketAddr,
+}
+
+/// A plain http:// endpoint, resolved once at startup since the sandbox may
+/// not allow reading /etc/hosts later
+struct Endpoint {
+    host: String,
+    addr: SocketAddr,
+}
+
+impl Endpoint {
+    async fn parse(url: &str) -> anyhow::Result<Self> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => bail!("{} is not an http:// URL, https isn't supported", url),
+        };
+        let host = rest.split('/').next().unwrap_or(rest);
+        let (name, port) = crate::addr::split_host_port(host)?;
+        let addr = tokio::net::lookup_host((name.as_str(), port.unwrap_or(80)))
+            .await
+            .with_context(|| format!("resolving {}", url))?
+            .next()
+            .with_context(|| format!("{} has no address", url))?;
+        Ok(Endpoint {
+            host: host.to_string(),
+            addr,
+        })
+    }
+
+    /// Send one request and return the status and body of the response
+    async fn request(&self, method: &str, path: &str, body: &str) -> anyhow::Result<(u16, String)> {
+        let exchange = async {
+            let mut socket = TcpStream::connect(self.addr).await?;
+            let request = format!(
+                "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                method,
+                path,
+                self.host,
+                body.len(),
+                body
+            );
+            socket.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            socket.read_to_end(&mut response).await?;
+            anyhow::Ok(response)
+        };
+        let response = match timeout(REQUEST_TIMEOUT, exchange).await {
+            Ok(response) => response?,
+            Err(_) => bail!("{} did not answer within {:?}", self.host, REQUEST_TIMEOUT),
+        };
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head
+            .split(' ')
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .with_context(|| format!("malformed HTTP response from {}", self.host))?;
+        Ok((status, body.to_string()))
+    }
+
+    /// Like `request`, a status other than 2xx is an error
+    async fn call(&self, method: &str, path: &str, body: &str) -> anyhow::Result<String> {
+        let (status, body) = self.request(method, path, body).await?;
+        if !(200..300).contains(&status) {
+            bail!("{} {} answered {}: {}", method, path, status, body.trim());
+        }
+        Ok(body)
+    }
+}
+
+/// Registration with a local Consul agent, with a TCP health check on the
+/// tunnel port
+#[cfg(feature = "consul")]
+pub struct Consul {
+    agent: Endpoint,
+    id: String,
+}
+
+#[cfg(feature = "consul")]
+impl Consul {
+    pub async fn register(url: &str, service: &Service) -> anyhow::Result<Self> {
+        let agent = Endpoint::parse(url).await?;
+        let id = format!("ssh2fwd-{}", service.name);
+        // Without an address Consul takes the agent's, the check then goes
+        // through loopback, which reaches a tunnel listening on all addresses
+        let (address, check) = if service.addr.ip().is_unspecified() {
+            (String::new(), format!("127.0.0.1:{}", service.addr.port()))
+        } else {
+            (
+                format!(
+                    "\"Address\":{},",
+                    json_string(&service.addr.ip().to_string())
+                ),
+                service.addr.to_string(),
+            )
+        };
+        let body = format!(
+            "{{\"ID\":{},\"Name\":{},{}\"Port\":{},\"Check\":{{\"TCP\":{},\"Interval\":\"10s\",\"Timeout\":\"2s\",\"DeregisterCriticalServiceAfter\":\"5m\"}}}}",
+            json_string(&id),
+            json_string(&service.name),
+            address,
+            service.addr.port(),
+            json_string(&check)
+        );
+        agent
+            .call("PUT", "/v1/agent/service/register", &body)
+            .await
+            .context("registering with Consul")?;
+        info!(
+            "Registered as {} with the Consul agent at {}",
+            id, agent.host
+        );
+        Ok(Consul { agent, id })
+    }
+
+    pub async fn deregister(&self) {
+        let path = format!("/v1/agent/service/deregister/{}", self.id);
+        match self.agent.call("PUT", &path, "").await {
+            Ok(_) => info!("Deregistered {} from Consul", self.id),
+            Err(e) => warn!("Deregistering {} from Consul failed: {}", self.id, e),
+        }
+    }
+}
+
+/// A key under /services/ssh2fwd/ in etcd, attached to a lease that is kept
+/// alive while the tunnel runs. Talks to the JSON gateway of etcd v3.
+#[cfg(feature = "etcd")]
+pub struct Etcd {
+    endpoint: Endpoint,
+    key: String,
+    value: String,
+    ttl: u64,
+    lease: std::sync::Mutex<String>,
+}
+
+#[cfg(feature = "etcd")]
+impl Etcd {
+    pub async fn register(
+        url: &str,
+        service: &Service,
+        ttl: u64,
+    ) -> anyhow::Result<std::sync::Arc<Self>> {
+        let etcd = std::sync::Arc::new(Etcd {
+            endpoint: Endpoint::parse(url).await?,
+            key: format!("/services/ssh2fwd/{}", service.name),
+            value: format!(
+                "{{\"name\":{},\"address\":{},\"port\":{}}}",
+                json_string(&service.name),
+                json_string(&service.addr.ip().to_string()),
+                service.addr.port()
+            ),
+            ttl,
+            lease: std::sync::Mutex::new(String::new()),
+        });
+        etcd.put().await.context("registering with etcd")?;
+        info!(
+            "Registered as {} in etcd at {}, TTL {}s",
+            etcd.key, etcd.endpoint.host, ttl
+        );
+        etcd.spawn_keepalive();
+        Ok(etcd)
+    }
+
+    /// Take a new lease and write the key with it
+    async fn put(&self) -> anyhow::Result<()> {
+        let body = self
+            .endpoint
+            .call(
+                "POST",
+                "/v3/lease/grant",
+                &format!("{{\"TTL\":{}}}", self.ttl),
+            )
+            .await?;
+        let lease = match json_field(&body, "ID") {
+            Some(id) => id,
+            None => bail!("no lease ID in the etcd response: {}", body.trim()),
+        };
+        let body = format!(
+            "{{\"key\":{},\"value\":{},\"lease\":{}}}",
+            json_string(&base64(self.key.as_bytes())),
+            json_string(&base64(self.value.as_bytes())),
+            json_string(&lease)
+        );
+        self.endpoint.call("POST", "/v3/kv/put", &body).await?;
+        *self.lease.lock().unwrap() = lease;
+        Ok(())
+    }
+
+    /// Renew the lease every half TTL. Once it has expired anyway, e.g.
+    /// after etcd was unreachable, the key is written again.
+    fn spawn_keepalive(self: &std::sync::Arc<Self>) {
+        let etcd = self.clone();
+        tokio::spawn(async move {
+            let interval = Duration::from_secs((etcd.ttl / 2).max(1));
+            loop {
+                tokio::time::sleep(interval).await;
+                let lease = etcd.lease.lock().unwrap().clone();
+                let body = format!("{{\"ID\":{}}}", json_string(&lease));
+                let renewed = match etcd
+                    .endpoint
+                    .call("POST", "/v3/lease/keepalive", &body)
+                    .await
+                {
+                    // An expired lease comes back with a TTL of 0 or none
+                    Ok(body) => json_field(&body, "TTL").is_some_and(|ttl| ttl != "0"),
+                    Err(e) => {
+                        warn!("Renewing the etcd lease failed: {}", e);
+                        continue;
+                    }
+                };
+                if !renewed {
+                    warn!("The etcd lease of {} expired, registering again", etcd.key);
+                    if let Err(e) = etcd.put().await {
+                        warn!("Registering {} in etcd again failed: {}", etcd.key, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Revoking the lease deletes the key
+    pub async fn deregister(&self) {
+        let lease = self.lease.lock().unwrap().clone();
+        let body = format!("{{\"ID\":{}}}", json_string(&lease));
+        match self.endpoint.call("POST", "/v3/lease/revoke", &body).await {
+            Ok(_) => info!("Removed {} from etcd", self.key),
+            Err(e) => warn!("Removing {} from etcd failed: {}", self.key, e),
+        }
+    }
+}
+
+/// etcd's JSON gateway wants keys and values base64 encoded
+#[cfg(feature = "etcd")]
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Registrations to undo when the process is asked to stop
+#[derive(Default)]
+pub struct Registrations {
+    #[cfg(feature = "consul")]
+    pub consul: Option<Consul>,
+    #[cfg(feature = "etcd")]
+    pub etcd: Option<std::sync::Arc<Etcd>>,
+}
+
+impl Registrations {
+    /// Remove the registrations when the process exits
+    pub fn deregister_on_exit(self) {
+        let handle = tokio::runtime::Handle::current();
+        crate::shutdown::on_exit(move || {
+            info!("Stopping, removing the service registration");
+            handle.block_on(self.deregister());
+        });
+    }
+
+    async fn deregister(&self) {
+        #[cfg(feature = "consul")]
+        if let Some(consul) = &self.consul {
+            consul.deregister().await;
+        }
+        #[cfg(feature = "etcd")]
+        if let Some(etcd) = &self.etcd {
+            etcd.deregister().await;
+        }
+    }
+}
@@ -0,0 +1,503 @@
+use crate::SandboxMode;
+use anyhow::{bail, Context};
+use log::{error, info, warn};
+use std::cell::Cell;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// What a path outside the sandbox may still be used for
+pub enum Access {
+    /// Read a single file, e.g. an access list re-read on SIGHUP
+    ReadFile,
+    /// Create, write, rename and remove files in a directory, e.g. for
+    /// audit log rotation
+    WriteDir,
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Landlock ruleset every thread has to enforce on itself, the kernel has
+/// no way to restrict the other threads of a process from one of them
+static RULESET: OnceLock<(libc::c_int, SandboxMode)> = OnceLock::new();
+
+thread_local! {
+    static ENFORCED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Restrict the process to the data path: no filesystem access except
+/// `allowed`, and only the system calls forwarding needs. Threads started
+/// later pick the restrictions up through `enforce_current_thread`.
+pub fn apply(mode: SandboxMode, allowed: &[(PathBuf, Access)]) -> anyhow::Result<()> {
+    let fail = |what: &str, e: anyhow::Error| -> anyhow::Result<()> {
+        match mode {
+            SandboxMode::Strict => Err(e.context(format!("{} not available", what))),
+            SandboxMode::BestEffort => {
+                warn!("Running without {}: {:#}", what, e);
+                Ok(())
+            }
+        }
+    };
+    // no_new_privs is a precondition for both and is copied to every thread
+    // by the seccomp thread sync
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("setting no_new_privs");
+    }
+    // The ruleset is built while the filter still lets the Landlock calls
+    // for that through
+    let ruleset = landlock_ruleset(allowed);
+    match install_seccomp() {
+        Ok(()) => info!("Seccomp filter installed"),
+        Err(e) => fail("the seccomp filter", e)?,
+    }
+    match ruleset {
+        Ok(fd) => {
+            let _ = RULESET.set((fd, mode));
+            enforce_current_thread();
+            info!("Filesystem access restricted with Landlock");
+        }
+        Err(e) => fail("Landlock", e)?,
+    }
+    Ok(())
+}
+
+/// Put the calling thread under the Landlock ruleset, once. Called from the
+/// runtime thread hooks and at the start of every blocking copy task.
+pub fn enforce_current_thread() {
+    let (fd, mode) = match RULESET.get() {
+        Some(ruleset) => *ruleset,
+        None => return,
+    };
+    if ENFORCED.with(|e| e.replace(true)) {
+        return;
+    }
+    let ok = unsafe {
+        libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+            && libc::syscall(libc::SYS_landlock_restrict_self, fd, 0) == 0
+    };
+    if !ok {
+        let e = io::Error::last_os_error();
+        error!("Unable to enforce Landlock on a thread: {}", e);
+        if mode == SandboxMode::Strict {
+            std::process::exit(1);
+        }
+    }
+}
+
+fn landlock_ruleset(allowed: &[(PathBuf, Access)]) -> anyhow::Result<libc::c_int> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Everything the running ABI knows about is denied unless a rule allows it
+    let mut handled = (1 << 13) - 1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    } as libc::c_int;
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("creating Landlock ruleset");
+    }
+    for (path, access) in allowed {
+        let rights = match access {
+            Access::ReadFile => ACCESS_FS_READ_FILE,
+            Access::WriteDir => {
+                ACCESS_FS_READ_FILE
+                    | ACCESS_FS_WRITE_FILE
+                    | ACCESS_FS_READ_DIR
+                    | ACCESS_FS_REMOVE_FILE
+                    | ACCESS_FS_MAKE_REG
+                    | ACCESS_FS_TRUNCATE
+            }
+        };
+        add_path_rule(fd, path, rights & handled)
+            .with_context(|| format!("allowing access to {}", path.display()))?;
+    }
+    Ok(fd)
+}
+
+fn add_path_rule(ruleset: libc::c_int, path: &Path, rights: u64) -> anyhow::Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let rule = PathBeneathAttr {
+        allowed_access: rights,
+        parent_fd: fd,
+    };
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset,
+            LANDLOCK_RULE_PATH_BENEATH,
+            &rule,
+            0,
+        )
+    };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if rc != 0 {
+        bail!(err);
+    }
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// System calls a running tunnel makes: I/O on sockets, pipes and the few
+/// files it may still open, memory, threads, time and signals. Everything
+/// else, starting programs, debugging other processes, changing identity,
+/// mounts, kernel modules and whatever else a tunnel has no use for, fails
+/// with EPERM.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn allowed_syscalls() -> Vec<libc::c_long> {
+    let mut allowed = vec![
+        // Files, sockets and pipes
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_openat,
+        libc::SYS_close,
+        libc::SYS_close_range,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_statfs,
+        libc::SYS_fstatfs,
+        libc::SYS_lseek,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_flock,
+        libc::SYS_getdents64,
+        libc::SYS_readlinkat,
+        libc::SYS_faccessat,
+        libc::SYS_faccessat2,
+        libc::SYS_getcwd,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_unlinkat,
+        libc::SYS_ftruncate,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_fchmod,
+        libc::SYS_copy_file_range,
+        libc::SYS_sendfile,
+        libc::SYS_splice,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        libc::SYS_eventfd2,
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_connect,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_shutdown,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_epoll_pwait2,
+        // Memory
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_membarrier,
+        // Threads, clone is checked separately
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_set_robust_list,
+        libc::SYS_set_tid_address,
+        libc::SYS_rseq,
+        libc::SYS_prctl,
+        libc::SYS_landlock_restrict_self,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        // Signals and the proxy command child
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_tgkill,
+        libc::SYS_kill,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+        // Time, identity and limits as far as reading them
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_gettimeofday,
+        libc::SYS_getrandom,
+        libc::SYS_getpid,
+        libc::SYS_getppid,
+        libc::SYS_gettid,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_uname,
+        libc::SYS_sysinfo,
+        libc::SYS_getrlimit,
+        libc::SYS_prlimit64,
+    ];
+    // The older calls libc still uses there
+    #[cfg(target_arch = "x86_64")]
+    allowed.extend([
+        libc::SYS_open,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_access,
+        libc::SYS_readlink,
+        libc::SYS_rename,
+        libc::SYS_unlink,
+        libc::SYS_getdents,
+        libc::SYS_pipe,
+        libc::SYS_dup2,
+        libc::SYS_poll,
+        libc::SYS_select,
+        libc::SYS_epoll_create,
+        libc::SYS_epoll_wait,
+        libc::SYS_arch_prctl,
+        libc::SYS_time,
+    ]);
+    allowed
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn install_seccomp() -> anyhow::Result<()> {
+    use libc::{
+        sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET,
+        BPF_W, SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+    };
+
+    let stmt = |code: u32, k: u32| sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code: u32, k: u32, jt: u8, jf: u8| sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    // Offsets into struct seccomp_data
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    const ARG0_LOW: u32 = 16;
+
+    let mut prog = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD | BPF_W | BPF_ABS, NR),
+        // clone3 can't be inspected, ENOSYS makes libc fall back to clone
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone3 as u32, 0, 1),
+        stmt(BPF_RET | BPF_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        // clone only for new threads, not for new processes
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone as u32, 0, 4),
+        stmt(BPF_LD | BPF_W | BPF_ABS, ARG0_LOW),
+        jump(BPF_JMP | BPF_JSET | BPF_K, libc::CLONE_THREAD as u32, 1, 0),
+        stmt(BPF_RET | BPF_K, SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+    ];
+    for nr in allowed_syscalls() {
+        prog.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+        prog.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+    }
+    prog.push(stmt(
+        BPF_RET | BPF_K,
+        SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    ));
+
+    let fprog = sock_fprog {
+        len: prog.len() as u16,
+        filter: prog.as_mut_ptr(),
+    };
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog,
+        )
+    };
+    match rc {
+        0 => Ok(()),
+        rc if rc < 0 => Err(io::Error::last_os_error().into()),
+        // With TSYNC a positive result is the id of a thread that could not
+        // be synchronized
+        tid => bail!("thread {} could not be put under the filter", tid),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn install_seccomp() -> anyhow::Result<()> {
+    bail!("no seccomp filter for this architecture")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::in_child;
+    use std::process::Command;
+
+    /// A fresh directory for the files the sandbox is tried on
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ssh2fwd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn denied(result: io::Result<impl Sized>) -> bool {
+        matches!(result, Err(e) if e.kind() == io::ErrorKind::PermissionDenied)
+    }
+
+    #[test]
+    fn files_outside_the_allowed_set_are_off_limits() {
+        if !in_child("sandbox::tests::files_outside_the_allowed_set_are_off_limits") {
+            return;
+        }
+        let dir = scratch_dir("landlock");
+        let (rules, key, other) = (dir.join("rules"), dir.join("id_ed25519"), dir.join("other"));
+        let audit = dir.join("audit");
+        std::fs::create_dir(&audit).unwrap();
+        for file in [&rules, &key, &other] {
+            std::fs::write(file, "x").unwrap();
+        }
+        apply(
+            SandboxMode::Strict,
+            &[
+                (rules.clone(), Access::ReadFile),
+                (key.clone(), Access::ReadFile),
+                (audit.clone(), Access::WriteDir),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&rules).unwrap(), "x");
+        assert_eq!(std::fs::read_to_string(&key).unwrap(), "x");
+        assert!(denied(std::fs::read(&other)));
+        assert!(denied(std::fs::read("/etc/passwd")));
+        assert!(denied(std::fs::write(&rules, "y")));
+        assert!(denied(std::fs::write(dir.join("new"), "y")));
+        // Audit log rotation
+        std::fs::write(audit.join("audit.log"), "line\n").unwrap();
+        std::fs::rename(audit.join("audit.log"), audit.join("audit.log.1")).unwrap();
+        std::fs::remove_file(audit.join("audit.log.1")).unwrap();
+        // Threads only get the rules once they enforce them, as the copy
+        // tasks do
+        let thread = std::thread::spawn(move || {
+            enforce_current_thread();
+            denied(std::fs::read(&other))
+        });
+        assert!(thread.join().unwrap());
+    }
+
+    #[test]
+    fn only_allowed_system_calls_go_through() {
+        if !in_child("sandbox::tests::only_allowed_system_calls_go_through") {
+            return;
+        }
+        apply(SandboxMode::Strict, &[]).unwrap();
+        let last_error = || io::Error::last_os_error().raw_os_error();
+        assert!(Command::new("/bin/true").status().is_err());
+        for nr in [libc::SYS_ptrace, libc::SYS_setuid, libc::SYS_getcpu] {
+            assert_eq!(unsafe { libc::syscall(nr, 0, 0, 0) }, -1, "{}", nr);
+            assert_eq!(last_error(), Some(libc::EPERM), "{}", nr);
+        }
+        // A new process, not a thread
+        assert!(unsafe { libc::fork() } < 0);
+        assert!(std::thread::spawn(|| 1).join().is_ok());
+    }
+
+    /// The allowlist holds everything the data path needs
+    #[test]
+    fn forwarding_works_inside() {
+        if !in_child("sandbox::tests::forwarding_works_inside") {
+            return;
+        }
+        apply(SandboxMode::Strict, &[]).unwrap();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .on_thread_start(enforce_current_thread)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            use crate::tests::*;
+            let addr = start_tunnel(tunnel_to(loopback(), echo_server().await)).await;
+            let data = pattern(1 << 20);
+            assert!(within(round_trip(addr, &data)).await == data);
+        });
+    }
+}
@@ -0,0 +1,137 @@
+use crate::transport::{ProbeOutcome, SshTransport};
+use anyhow::{anyhow, bail};
+use clap::{Args, ValueHint};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::str::FromStr;
+use tokio::task::spawn_blocking;
+use tokio::time::Duration;
+
+/// Options of `ssh2fwd scan`
+#[derive(Args)]
+pub struct ScanOpts {
+    /// Ports to try, as a list of ports and ranges, e.g. 1-1024 or 22,80,8000-8100
+    #[clap(long, value_name = "PORTS")]
+    pub ports: PortList,
+    /// Host to scan, resolved by the SSH server
+    #[clap(long, default_value = "localhost", value_hint = ValueHint::Hostname)]
+    pub host: String,
+    /// A port the server could not connect to within this time counts as
+    /// filtered rather than closed
+    #[clap(long, value_name = "MS", default_value = "500")]
+    pub timeout_ms: u64,
+    /// Channel opens asked for at the same time
+    #[clap(
+        long,
+        value_name = "N",
+        default_value = "16",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub concurrency: u16,
+}
+
+/// Ports in the order given, duplicates removed
+#[derive(Clone)]
+pub struct PortList(Vec<u16>);
+
+impl FromStr for PortList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<u16>()
+                .map_err(|_| anyhow!("invalid port '{}' in '{}'", v, s))
+        };
+        let mut ports = vec![];
+        for part in s.split(',') {
+            let (lo, hi) = match part.split_once('-') {
+                Some((lo, hi)) => (parse(lo)?, parse(hi)?),
+                None => (parse(part)?, parse(part)?),
+            };
+            if lo > hi {
+                bail!("empty port range '{}'", part);
+            }
+            ports.extend(lo..=hi);
+        }
+        let mut seen = std::collections::HashSet::new();
+        ports.retain(|p| seen.insert(*p));
+        Ok(PortList(ports))
+    }
+}
+
+/// What the scan says about a port, like nmap -sT
+#[derive(Clone, Copy, PartialEq)]
+enum PortState {
+    Open,
+    Closed,
+    Filtered,
+    /// The server doesn't forward to the port
+    Prohibited,
+    Error,
+}
+
+impl PortState {
+    fn name(self) -> &'static str {
+        match self {
+            PortState::Open => "open",
+            PortState::Closed => "closed",
+            PortState::Filtered => "filtered",
+            PortState::Prohibited => "prohibited",
+            PortState::Error => "error",
+        }
+    }
+}
+
+/// Ask the SSH server to connect to every port of the host and print what
+/// became of each as the answers come in. libssh2 opens the channels of a
+/// session one after the other; the opens beyond the first are queued so
+/// the next one goes out as soon as an answer is in.
+pub async fn run<T: SshTransport>(session: T, opts: ScanOpts) -> anyhow::Result<()> {
+    let timeout = Duration::from_millis(opts.timeout_ms);
+    let mut ports = opts.ports.0.iter().copied();
+    let mut probes = FuturesUnordered::new();
+    let mut counts = [0usize; 5];
+    loop {
+        while probes.len() < opts.concurrency as usize {
+            let port = match ports.next() {
+                Some(port) => port,
+                None => break,
+            };
+            let (session, host) = (session.clone(), opts.host.clone());
+            probes.push(async move {
+                let probed = spawn_blocking(move || session.probe_direct_tcpip(&host, port)).await;
+                (port, probed)
+            });
+        }
+        let (port, probed) = match probes.next().await {
+            Some(probe) => probe,
+            None => break,
+        };
+        let (outcome, elapsed) = probed?;
+        let (state, detail) = match outcome {
+            ProbeOutcome::Open => (PortState::Open, None),
+            // A server that gave up connecting after a while ran into its own
+            // connect timeout, nothing answered there
+            ProbeOutcome::ConnectFailed if elapsed <= timeout => (PortState::Closed, None),
+            ProbeOutcome::ConnectFailed | ProbeOutcome::TimedOut => (PortState::Filtered, None),
+            ProbeOutcome::Prohibited => (PortState::Prohibited, None),
+            ProbeOutcome::Failed(e) => (PortState::Error, Some(e)),
+        };
+        counts[state as usize] += 1;
+        match detail {
+            Some(detail) => println!("{}:{} {}: {}", opts.host, port, state.name(), detail),
+            None => println!("{}:{} {}", opts.host, port, state.name()),
+        }
+    }
+    println!(
+        "Scanned {} ports of {}: {} open, {} closed, {} filtered, {} prohibited, {} errors",
+        opts.ports.0.len(),
+        opts.host,
+        counts[PortState::Open as usize],
+        counts[PortState::Closed as usize],
+        counts[PortState::Filtered as usize],
+        counts[PortState::Prohibited as usize],
+        counts[PortState::Error as usize]
+    );
+    Ok(())
+}
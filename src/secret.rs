@@ -0,0 +1,124 @@
+use std::fmt;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+/// Owned secret text such as a password. The buffer is overwritten when the
+/// value is dropped and formatting never shows the content.
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(s: String) -> Self {
+        Secret(s)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Secret::new(s)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // Safety: zeroes are valid UTF-8, and the whole capacity is ours
+        unsafe { zeroize(self.0.as_mut_vec()) };
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Overwrite the whole allocation, including spare capacity that may still
+/// hold an older copy of the data. Volatile writes keep the compiler from
+/// dropping the stores as dead.
+pub fn zeroize(buf: &mut Vec<u8>) {
+    buf.clear();
+    let ptr = buf.as_mut_ptr();
+    for i in 0..buf.capacity() {
+        unsafe { std::ptr::write_volatile(ptr.add(i), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Overwrite a fixed size buffer
+pub fn zeroize_array(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Keep the kernel from writing core dumps of this process, which would
+/// contain whatever secrets are in memory at the time
+#[cfg(unix)]
+pub fn disable_core_dumps() -> anyhow::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting_redacts() {
+        let secret = Secret::new("hunter2".to_string());
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+        assert_eq!(format!("{:#?}", secret), "Secret(<redacted>)");
+        assert_eq!(format!("{}", secret), "<redacted>");
+        assert_eq!(secret.expose(), "hunter2");
+    }
+
+    #[test]
+    fn redacted_inside_derived_debug() {
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Login {
+            user: String,
+            password: Option<Secret>,
+        }
+        let login = Login {
+            user: "me".to_string(),
+            password: Some("hunter2".to_string().into()),
+        };
+        let shown = format!("{:?}", login);
+        assert!(!shown.contains("hunter2"), "{}", shown);
+        assert!(shown.contains("Secret(<redacted>)"), "{}", shown);
+    }
+
+    #[test]
+    fn zeroize_clears_spare_capacity() {
+        let mut buf = b"hunter2".to_vec();
+        buf.truncate(3);
+        let (ptr, capacity) = (buf.as_ptr(), buf.capacity());
+        zeroize(&mut buf);
+        assert!(buf.is_empty());
+        let whole = unsafe { std::slice::from_raw_parts(ptr, capacity) };
+        assert!(whole.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn zeroize_array_clears() {
+        let mut buf = *b"hunter2";
+        zeroize_array(&mut buf);
+        assert_eq!(buf, [0; 7]);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::local::{LocalStream, PeerAddr};
+use crate::loopback::LoopbackTransport;
+use crate::multi_path::MultiPath;
+use crate::{forward_connection, Tunnel, CHANNEL_POLL_MS, NEXT_CONNECTION_ID};
+use anyhow::{anyhow, bail, Context};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Longest one round trip of --test-mode may take
+const ROUND_TRIP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Sizes of the data sent through: a short message, and one larger than the
+/// buffers of the copy loops and the kernel's
+const SIZES: [usize; 2] = [32, 4 << 20];
+
+/// --test-mode: forward data through a tunnel over the loopback transport
+/// to an echo server in this process and check it comes back unchanged.
+/// Prints PASS or FAIL with what went wrong, false on FAIL.
+pub async fn run() -> bool {
+    match check().await {
+        Ok(summary) => {
+            println!("PASS: {}", summary);
+            true
+        }
+        Err(e) => {
+            println!("FAIL: {:#}", e);
+            false
+        }
+    }
+}
+
+async fn check() -> anyhow::Result<String> {
+    let echo = echo_server().await.context("starting the echo server")?;
+    let session = LoopbackTransport::new(Duration::from_secs(5));
+    let paths = vec![(IpAddr::from(Ipv4Addr::LOCALHOST), Some(session))];
+    let tunnel = Arc::new(Tunnel::new(
+        MultiPath::new(paths, CHANNEL_POLL_MS),
+        echo.ip().to_string(),
+        echo.port(),
+    ));
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .context("binding the local port")?;
+    let local = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((socket, peer)) = listener.accept().await {
+            let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(forward_connection(
+                tunnel.clone(),
+                LocalStream::Tcp(socket),
+                PeerAddr::Tcp(peer),
+                conn_id,
+            ));
+        }
+    });
+    for size in SIZES {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let received = timeout(ROUND_TRIP_TIMEOUT, round_trip(local, &data))
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "{} bytes didn't come back within {:?}",
+                    size,
+                    ROUND_TRIP_TIMEOUT
+                )
+            })?
+            .with_context(|| format!("sending {} bytes through {}", size, local))?;
+        if received != data {
+            let differs = received
+                .iter()
+                .zip(&data)
+                .position(|(a, b)| a != b)
+                .unwrap_or(received.len().min(size));
+            bail!(
+                "sent {} bytes, got {} back, differing from byte {} on",
+                size,
+                received.len(),
+                differs
+            );
+        }
+    }
+    Ok(format!(
+        "{} bytes echoed through {} to {}",
+        SIZES.iter().sum::<usize>(),
+        local,
+        echo
+    ))
+}
+
+/// Sends back what it gets and closes after the client did
+async fn echo_server() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut rd, mut wr) = socket.split();
+                let _ = tokio::io::copy(&mut rd, &mut wr).await;
+                let _ = wr.shutdown().await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Send `data`, close the sending side and return what comes back until the
+/// other end closes
+async fn round_trip(addr: SocketAddr, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut socket = TcpStream::connect(addr).await?;
+    let (mut rd, mut wr) = socket.split();
+    let mut received = Vec::new();
+    let (written, read) = tokio::join!(
+        async {
+            wr.write_all(data).await?;
+            wr.shutdown().await
+        },
+        rd.read_to_end(&mut received)
+    );
+    written?;
+    read?;
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn passes() {
+        if let Err(e) = super::check().await {
+            panic!("{:#}", e);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use log::error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Set once the SSH connection of a session is known to be gone, shared by
+/// every clone of the session. The copy loops of all its connections watch
+/// it, so they stop together instead of each running into its own error or
+/// timeout.
+pub struct SessionLost {
+    lost: AtomicBool,
+    notify: Notify,
+}
+
+impl SessionLost {
+    pub fn new() -> Arc<Self> {
+        Arc::new(SessionLost {
+            lost: AtomicBool::new(false),
+            notify: Notify::new(),
+        })
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Acquire)
+    }
+
+    /// The session is gone because of `reason`. Only the first call logs
+    /// and wakes the waiters, returning true.
+    pub fn mark(&self, reason: &str) -> bool {
+        if self.lost.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        error!("SSH session lost ({}), closing its connections", reason);
+        self.notify.notify_waiters();
+        true
+    }
+
+    /// The session is being ended on purpose. Like `mark` without the
+    /// error, false when it was already lost or closed.
+    pub fn close(&self) -> bool {
+        if self.lost.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.notify.notify_waiters();
+        true
+    }
+
+    /// Wait until the session is lost
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        if self.is_lost() {
+            return;
+        }
+        notified.await
+    }
+}
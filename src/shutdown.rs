@@ -0,0 +1,69 @@
+use log::info;
+use std::sync::Mutex;
+
+/// Work to do before the process ends, such as telling the SSH server
+type Hook = Box<dyn FnOnce() + Send>;
+
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+
+/// Run `hook` before the process exits, whether it returns from main, exits
+/// on a signal or `~.`, or panics. Hooks may block briefly.
+pub fn on_exit(hook: impl FnOnce() + Send + 'static) {
+    HOOKS.lock().unwrap().push(Box::new(hook));
+}
+
+/// Run the hooks not run yet, the last registered first
+pub fn run_hooks() {
+    let hooks = std::mem::take(&mut *HOOKS.lock().unwrap_or_else(|e| e.into_inner()));
+    for hook in hooks.into_iter().rev() {
+        hook();
+    }
+}
+
+/// Run the hooks and exit with `code`
+pub fn exit(code: i32) -> ! {
+    match tokio::runtime::Handle::try_current() {
+        // The hooks may block and wait for the runtime themselves
+        Ok(_) => tokio::task::block_in_place(run_hooks),
+        Err(_) => run_hooks(),
+    }
+    std::process::exit(code)
+}
+
+/// Runs the hooks when main returns or unwinds
+pub struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        run_hooks();
+    }
+}
+
+/// Exit through the hooks on SIGINT and SIGTERM instead of being killed
+#[cfg(unix)]
+pub fn handle_signals() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut term = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        let (signo, name) = tokio::select! {
+            _ = int.recv() => (libc::SIGINT, "SIGINT"),
+            _ = term.recv() => (libc::SIGTERM, "SIGTERM"),
+        };
+        info!("{} received, stopping", name);
+        exit(128 + signo);
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn handle_signals() -> anyhow::Result<()> {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Ctrl-C received, stopping");
+            exit(130);
+        }
+    });
+    Ok(())
+}
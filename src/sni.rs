@@ -0,0 +1,174 @@
+use crate::addr::split_host_port;
+use anyhow::bail;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+/// TLS record type of a handshake message
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+/// Largest TLS record, the ClientHello is looked for in the first one
+const MAX_RECORD: usize = 16 * 1024;
+/// How long a client gets to send its ClientHello. Clients of protocols
+/// where the server speaks first send nothing and go to the default target.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where TLS connections for a server name go, NAME=HOST[:PORT]. NAME may
+/// be *.example.org for any name below example.org.
+#[derive(Clone)]
+pub struct SniRoute {
+    name: String,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl SniRoute {
+    fn matches(&self, name: &str) -> bool {
+        match self.name.strip_prefix("*.") {
+            Some(domain) => name
+                .strip_suffix(domain)
+                .and_then(|sub| sub.strip_suffix('.'))
+                .is_some_and(|sub| !sub.is_empty()),
+            None => self.name == name,
+        }
+    }
+}
+
+impl FromStr for SniRoute {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((name, target)) = s.split_once('=') else {
+            bail!("'{}' is not NAME=HOST[:PORT]", s);
+        };
+        if name.is_empty() || (name.contains('*') && !name.starts_with("*.")) {
+            bail!("'{}' is not a server name or *.domain", name);
+        }
+        let (host, port) = split_host_port(target)?;
+        Ok(SniRoute {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            host,
+            port,
+        })
+    }
+}
+
+impl fmt::Display for SniRoute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first route for the server name `name`
+pub fn route<'a>(routes: &'a [SniRoute], name: &str) -> Option<&'a SniRoute> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    routes.iter().find(|r| r.matches(&name))
+}
+
+/// Read the first TLS record of `stream` into `buf` and return the server
+/// name of the ClientHello in it. Anything that isn't TLS is given up on as
+/// soon as that shows; whatever was read stays in `buf` and has to be
+/// forwarded before the rest of the connection.
+pub async fn read_client_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> Option<String> {
+    let read = async {
+        fill(stream, buf, 5).await?;
+        if buf[0] != CONTENT_HANDSHAKE {
+            return None;
+        }
+        let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        fill(stream, buf, 5 + len.min(MAX_RECORD)).await?;
+        server_name(&buf[5..])
+    };
+    timeout(HELLO_TIMEOUT, read).await.ok().flatten()
+}
+
+/// Read until `buf` holds `len` bytes, None once the stream ends first
+async fn fill<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut Vec<u8>, len: usize) -> Option<()> {
+    let mut chunk = [0; 1024];
+    while buf.len() < len {
+        let want = (len - buf.len()).min(chunk.len());
+        match stream.read(&mut chunk[..want]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    Some(())
+}
+
+/// The host_name of the server_name extension of a ClientHello handshake
+/// message
+fn server_name(handshake: &[u8]) -> Option<String> {
+    let mut r = Reader(handshake);
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    r.skip(3)?; // length
+    r.skip(2 + 32)?; // version, random
+    r.vec8()?; // session id
+    r.vec16()?; // cipher suites
+    r.vec8()?; // compression methods
+    let mut extensions = Reader(r.vec16()?);
+    while let Some(ext_type) = extensions.u16() {
+        let data = extensions.vec16()?;
+        if ext_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut list = Reader(Reader(data).vec16()?);
+        while let Some(name_type) = list.u8() {
+            let name = list.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_string);
+            }
+        }
+    }
+    None
+}
+
+/// Big endian fields off the front of a byte slice
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// Bytes after a one byte length
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    /// Bytes after a two byte length
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
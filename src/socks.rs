@@ -0,0 +1,302 @@
+use crate::acl::{DestAccessList, Destination};
+use crate::secret::{zeroize, Secret};
+use crate::token::constant_time_eq;
+use anyhow::{bail, Context};
+use log::warn;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep, Duration};
+
+pub const VERSION: u8 = 5;
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+pub const CMD_CONNECT: u8 = 0x01;
+const CMD_BIND: u8 = 0x02;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x03;
+pub const ATYP_IPV6: u8 = 0x04;
+
+/// Reply codes from RFC 1928
+pub const REP_SUCCEEDED: u8 = 0x00;
+pub const REP_GENERAL_FAILURE: u8 = 0x01;
+pub const REP_NOT_ALLOWED: u8 = 0x02;
+pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REP_ATYP_NOT_SUPPORTED: u8 = 0x08;
+
+/// What a SOCKS5 reply code means, for messages
+pub fn reply_text(code: u8) -> &'static str {
+    match code {
+        REP_GENERAL_FAILURE => "general failure",
+        REP_NOT_ALLOWED => "not allowed by the proxy's rules",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        REP_CONNECTION_REFUSED => "connection refused",
+        0x06 => "TTL expired",
+        REP_COMMAND_NOT_SUPPORTED => "command not supported",
+        REP_ATYP_NOT_SUPPORTED => "address type not supported",
+        _ => "unknown reply code",
+    }
+}
+
+/// Username/password sub-negotiation from RFC 1929
+pub const USER_PASS_VERSION: u8 = 0x01;
+pub const USER_PASS_SUCCESS: u8 = 0x00;
+const USER_PASS_FAILURE: u8 = 0x01;
+/// Pause before closing a client with wrong credentials to slow down guessing
+const AUTH_FAIL_DELAY: Duration = Duration::from_secs(1);
+
+/// Users SOCKS5 clients have to log in as with RFC 1929 username/password
+/// authentication
+pub struct SocksAuth {
+    users: Vec<(String, Secret)>,
+}
+
+impl SocksAuth {
+    pub fn new(user: String, password: Secret) -> anyhow::Result<Self> {
+        let mut auth = SocksAuth { users: vec![] };
+        auth.add(user, password)?;
+        Ok(auth)
+    }
+
+    /// Read `user:password` lines, htpasswd style but with the password in
+    /// plain text. Empty lines and lines starting with # are skipped.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = Secret::new(
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?,
+        );
+        let mut auth = SocksAuth { users: vec![] };
+        for (n, line) in content.expose().lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, password) = line
+                .split_once(':')
+                .with_context(|| format!("{}:{}: expected user:password", path.display(), n + 1))?;
+            auth.add(user.to_string(), Secret::new(password.to_string()))
+                .with_context(|| format!("{}:{}", path.display(), n + 1))?;
+        }
+        if auth.users.is_empty() {
+            bail!("no users in {}", path.display());
+        }
+        Ok(auth)
+    }
+
+    fn add(&mut self, user: String, password: Secret) -> anyhow::Result<()> {
+        // Both are sent with a one byte length
+        if user.is_empty() || user.len() > 255 {
+            bail!("SOCKS5 user names must be 1 to 255 bytes long");
+        }
+        if password.expose().is_empty() || password.expose().len() > 255 {
+            bail!("SOCKS5 password for {} must be 1 to 255 bytes long", user);
+        }
+        if self.users.iter().any(|(u, _)| *u == user) {
+            bail!("SOCKS5 user {} given twice", user);
+        }
+        self.users.push((user, password));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// Every entry is compared in full, so the time taken tells neither
+    /// whether the user exists nor how much of the password was right
+    fn check(&self, user: &[u8], password: &[u8]) -> Option<&str> {
+        let mut found = None;
+        for (u, p) in &self.users {
+            let user_ok = constant_time_eq(u.as_bytes(), user);
+            let password_ok = constant_time_eq(p.expose().as_bytes(), password);
+            if user_ok & password_ok {
+                found = Some(u.as_str());
+            }
+        }
+        found
+    }
+
+    /// Run the RFC 1929 sub-negotiation, returns the user on success
+    async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        socket: &mut S,
+    ) -> anyhow::Result<String> {
+        let mut head = [0; 2];
+        socket.read_exact(&mut head).await?;
+        if head[0] != USER_PASS_VERSION {
+            bail!("unknown username/password auth version {}", head[0]);
+        }
+        let mut user = vec![0; head[1] as usize];
+        socket.read_exact(&mut user).await?;
+        let mut password = vec![0; socket.read_u8().await? as usize];
+        let read = socket.read_exact(&mut password).await;
+        let found = read.is_ok().then(|| self.check(&user, &password)).flatten();
+        zeroize(&mut password);
+        read?;
+        match found {
+            Some(user) => {
+                socket
+                    .write_all(&[USER_PASS_VERSION, USER_PASS_SUCCESS])
+                    .await?;
+                Ok(user.to_string())
+            }
+            None => {
+                sleep(AUTH_FAIL_DELAY).await;
+                socket
+                    .write_all(&[USER_PASS_VERSION, USER_PASS_FAILURE])
+                    .await?;
+                bail!(
+                    "wrong SOCKS5 credentials for user {:?}",
+                    String::from_utf8_lossy(&user)
+                );
+            }
+        }
+    }
+}
+
+/// What a SOCKS5 client asked for
+pub struct Request {
+    /// BIND: wait for a connection from host:port instead of making one
+    pub bind: bool,
+    /// UDP ASSOCIATE: relay datagrams, host:port is where the client sends
+    /// them from, 0.0.0.0:0 when it doesn't know yet
+    pub udp_associate: bool,
+    pub host: String,
+    pub port: u16,
+    /// Who logged in, with `SocksAuth`
+    pub user: Option<String>,
+}
+
+/// Run the SOCKS5 negotiation with a local client up to the point where it
+/// told us where to connect. With `auth` the client has to log in, the user
+/// is returned along with the destination. BIND is only accepted with
+/// `enable_bind`. Requests for destinations not allowed by `dest_acl` are
+/// answered with "not allowed by ruleset" here, other failures after this
+/// point are for the caller to report with `reply`. The destinations of a
+/// UDP ASSOCIATE come with each datagram, the caller checks those.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    dest_acl: &DestAccessList,
+    auth: Option<&SocksAuth>,
+    enable_bind: bool,
+) -> anyhow::Result<Request> {
+    let mut head = [0; 2];
+    socket.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        bail!("not a SOCKS5 client, version byte {}", head[0]);
+    }
+    let mut methods = vec![0; head[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    let method = match auth {
+        Some(_) => METHOD_USER_PASS,
+        None => METHOD_NO_AUTH,
+    };
+    if !methods.contains(&method) {
+        socket.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        match auth {
+            Some(_) => bail!("SOCKS5 client did not offer username/password authentication"),
+            None => bail!("SOCKS5 client offered no usable authentication method"),
+        }
+    }
+    socket.write_all(&[VERSION, method]).await?;
+    let user = match auth {
+        Some(auth) => Some(auth.authenticate(socket).await?),
+        None => None,
+    };
+
+    let mut req = [0; 4];
+    socket.read_exact(&mut req).await?;
+    let bind = req[1] == CMD_BIND && enable_bind;
+    let udp_associate = req[1] == CMD_UDP_ASSOCIATE;
+    if req[1] != CMD_CONNECT && !bind && !udp_associate {
+        reply(socket, REP_COMMAND_NOT_SUPPORTED).await?;
+        match req[1] {
+            CMD_BIND => bail!("SOCKS5 BIND is not enabled, see --socks-enable-bind"),
+            cmd => bail!("unsupported SOCKS5 command {}", cmd),
+        }
+    }
+    let host = match req[3] {
+        ATYP_IPV4 => {
+            let mut a = [0; 4];
+            socket.read_exact(&mut a).await?;
+            IpAddr::from(Ipv4Addr::from(a)).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut a = [0; 16];
+            socket.read_exact(&mut a).await?;
+            IpAddr::from(Ipv6Addr::from(a)).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = socket.read_u8().await? as usize;
+            let mut name = vec![0; len];
+            socket.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        atyp => {
+            reply(socket, REP_ATYP_NOT_SUPPORTED).await?;
+            bail!("unsupported SOCKS5 address type {}", atyp);
+        }
+    };
+    let port = socket.read_u16().await?;
+    if udp_associate {
+        return Ok(Request {
+            bind,
+            udp_associate,
+            host,
+            port,
+            user,
+        });
+    }
+
+    let dest = match (req[3], host.parse::<IpAddr>()) {
+        (ATYP_DOMAIN, _) | (_, Err(_)) => Destination::Name(&host),
+        (_, Ok(ip)) => Destination::Ip(ip),
+    };
+    if !dest_acl.is_allowed(&dest, port) {
+        warn!(
+            "SOCKS5 request for {}:{} denied by destination allowlist",
+            host, port
+        );
+        reply(socket, REP_NOT_ALLOWED).await?;
+        bail!("destination {}:{} not allowed", host, port);
+    }
+    Ok(Request {
+        bind,
+        udp_associate,
+        host,
+        port,
+        user,
+    })
+}
+
+/// Send the reply to a CONNECT request. The bound address is not meaningful
+/// for a tunneled connection, so it is always reported as 0.0.0.0:0.
+pub async fn reply<S: AsyncWrite + Unpin>(socket: &mut S, code: u8) -> anyhow::Result<()> {
+    reply_bound(socket, code, SocketAddr::from(([0, 0, 0, 0], 0))).await
+}
+
+/// Send a reply with the address `addr`, for BIND the one listening first
+/// and the one that connected second
+pub async fn reply_bound<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    code: u8,
+    addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let mut msg = vec![VERSION, code, 0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            msg.push(ATYP_IPV4);
+            msg.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            msg.push(ATYP_IPV6);
+            msg.extend_from_slice(&ip.octets());
+        }
+    }
+    msg.extend_from_slice(&addr.port().to_be_bytes());
+    socket.write_all(&msg).await?;
+    Ok(())
+}
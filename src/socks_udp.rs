@@ -0,0 +1,359 @@
+use crate::acl::{DestAccessList, Destination};
+use crate::dns_relay;
+use crate::socks::{self, ATYP_DOMAIN, ATYP_IPV4, ATYP_IPV6};
+use crate::transport::SshTransport;
+use anyhow::bail;
+use log::{debug, info, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+use tokio::task::{spawn_blocking, JoinSet};
+
+/// The datagrams a UDP association carries: DNS queries, asked over TCP
+/// through a channel like --dns-forward does. SSH channels are streams, other
+/// datagrams have no way to the server's side.
+pub const DNS_PORT: u16 = 53;
+/// Largest datagram, SOCKS5 header included
+const MAX_DATAGRAM: usize = 65535;
+/// Queries of one association relayed at the same time, more are dropped for
+/// the client to retry. Each takes a channel of its own.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// A datagram from the client with the SOCKS5 UDP request header taken apart
+#[derive(Debug, PartialEq)]
+struct Datagram<'a> {
+    /// ATYP, address and port as the client gave them, the answer is sent
+    /// back as coming from there
+    address: &'a [u8],
+    host: String,
+    port: u16,
+    /// `host` is a name, not an address
+    named: bool,
+    payload: &'a [u8],
+}
+
+/// Take apart RSV, FRAG, ATYP, DST.ADDR and DST.PORT of RFC 1928. Fragments
+/// aren't supported, as the RFC allows.
+fn parse(datagram: &[u8]) -> anyhow::Result<Datagram<'_>> {
+    let Some(&[_, _, frag, atyp]) = datagram.get(..4) else {
+        bail!("{} bytes are no SOCKS5 UDP header", datagram.len());
+    };
+    if frag != 0 {
+        bail!(
+            "fragment {} of a datagram, fragments aren't supported",
+            frag
+        );
+    }
+    let addr = &datagram[4..];
+    let (host, len, named) = match atyp {
+        ATYP_IPV4 if addr.len() >= 4 => {
+            let a: [u8; 4] = addr[..4].try_into().unwrap();
+            (Ipv4Addr::from(a).to_string(), 4, false)
+        }
+        ATYP_IPV6 if addr.len() >= 16 => {
+            let a: [u8; 16] = addr[..16].try_into().unwrap();
+            (Ipv6Addr::from(a).to_string(), 16, false)
+        }
+        ATYP_DOMAIN if !addr.is_empty() && addr.len() > usize::from(addr[0]) => {
+            let name = &addr[1..=usize::from(addr[0])];
+            (
+                String::from_utf8_lossy(name).into_owned(),
+                1 + name.len(),
+                true,
+            )
+        }
+        ATYP_IPV4 | ATYP_IPV6 | ATYP_DOMAIN => bail!("the address is cut short"),
+        atyp => bail!("unsupported address type {}", atyp),
+    };
+    let Some(port) = addr.get(len..len + 2) else {
+        bail!("the port is cut short");
+    };
+    Ok(Datagram {
+        address: &datagram[3..4 + len + 2],
+        host,
+        port: u16::from_be_bytes([port[0], port[1]]),
+        named,
+        payload: &addr[len + 2..],
+    })
+}
+
+/// An answer for the client, with the header of a datagram from `address`
+fn encapsulate(address: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(3 + address.len() + payload.len());
+    datagram.extend_from_slice(&[0, 0, 0]);
+    datagram.extend_from_slice(address);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// Serve a SOCKS5 UDP ASSOCIATE on its control connection `socket`, the
+/// reply included. The relay listens on `bind_ip` and only takes datagrams
+/// from `client_ip`, or from whoever sends first without one, then only
+/// from that address. Datagrams to port `dns_port` are relayed as DNS
+/// queries through `session`, others are dropped. The association ends when
+/// the client closes the control connection.
+pub async fn associate<T: SshTransport, S: AsyncRead + AsyncWrite + Unpin>(
+    session: &T,
+    socket: &mut S,
+    bind_ip: IpAddr,
+    client_ip: Option<IpAddr>,
+    dest_acl: &DestAccessList,
+    dns_port: u16,
+    conn_id: u64,
+) -> anyhow::Result<()> {
+    let relay = match UdpSocket::bind((bind_ip, 0)).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            let _ = socks::reply(socket, socks::REP_GENERAL_FAILURE).await;
+            return Err(e.into());
+        }
+    };
+    let bound = relay.local_addr()?;
+    socks::reply_bound(socket, socks::REP_SUCCEEDED, bound).await?;
+    info!(
+        "Connection #{}: relaying SOCKS5 UDP on {}, DNS only",
+        conn_id, bound
+    );
+    let relay = Arc::new(relay);
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    // Dropped at the end, which cancels the queries still waiting
+    let mut queries = JoinSet::new();
+    let mut client: Option<SocketAddr> = None;
+    let mut warned = false;
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let mut control = [0; 64];
+    loop {
+        let (n, from) = tokio::select! {
+            read = socket.read(&mut control) => match read {
+                // Nothing is sent on the control connection, only its end
+                // matters
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            },
+            received = relay.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Connection #{}: receiving a datagram failed: {}", conn_id, e);
+                    continue;
+                }
+            },
+            Some(_) = queries.join_next(), if !queries.is_empty() => continue,
+        };
+        let expected = match client {
+            Some(client) => from == client,
+            None => client_ip.is_none_or(|ip| ip == from.ip()),
+        };
+        if !expected {
+            debug!(
+                "Connection #{}: dropping a datagram from {}, not the client",
+                conn_id, from
+            );
+            continue;
+        }
+        client = Some(from);
+        let datagram = match parse(&buf[..n]) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                debug!("Connection #{}: dropping a datagram: {}", conn_id, e);
+                continue;
+            }
+        };
+        let dest = match datagram.named {
+            true => Destination::Name(&datagram.host),
+            false => Destination::Ip(datagram.host.parse().unwrap()),
+        };
+        if !dest_acl.is_allowed(&dest, datagram.port) {
+            warn!(
+                "Connection #{}: datagram to {}:{} denied by destination allowlist",
+                conn_id, datagram.host, datagram.port
+            );
+            continue;
+        }
+        if datagram.port != dns_port {
+            if !warned {
+                warn!(
+                    "Connection #{}: dropping datagrams to {}:{}, only DNS to port {} is relayed",
+                    conn_id, datagram.host, datagram.port, dns_port
+                );
+                warned = true;
+            }
+            continue;
+        }
+        let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+            warn!(
+                "Connection #{}: too many DNS queries at once, dropping one",
+                conn_id
+            );
+            continue;
+        };
+        let (session, relay) = (session.clone(), relay.clone());
+        let (address, host, port) = (datagram.address.to_vec(), datagram.host, datagram.port);
+        let query = datagram.payload.to_vec();
+        queries.spawn(async move {
+            let _permit = permit;
+            let answer =
+                spawn_blocking(move || dns_relay::exchange(&session, &host, port, &query)).await;
+            match answer {
+                Ok(Ok(answer)) => {
+                    let datagram = encapsulate(&address, &answer);
+                    if let Err(e) = relay.send_to(&datagram, from).await {
+                        debug!("Connection #{}: answering {} failed: {}", conn_id, from, e);
+                    }
+                }
+                Ok(Err(e)) => warn!("Connection #{}: DNS query failed: {}", conn_id, e),
+                Err(e) => warn!("Connection #{}: DNS query failed: {}", conn_id, e),
+            }
+        });
+    }
+    info!(
+        "Connection #{}: SOCKS5 UDP association on {} ended",
+        conn_id, bound
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackTransport;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// Longest a test step may take
+    const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn header(address: &[u8]) -> Vec<u8> {
+        encapsulate(address, b"payload")
+    }
+
+    #[test]
+    fn parses_each_address_type() {
+        let v4 = header(&[ATYP_IPV4, 10, 0, 0, 1, 0, 53]);
+        let d = parse(&v4).unwrap();
+        assert_eq!((d.host.as_str(), d.port, d.named), ("10.0.0.1", 53, false));
+        assert_eq!(d.address, &v4[3..10]);
+        assert_eq!(d.payload, b"payload");
+
+        let mut v6 = vec![ATYP_IPV6];
+        v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        v6.extend_from_slice(&5353u16.to_be_bytes());
+        let v6 = header(&v6);
+        let d = parse(&v6).unwrap();
+        assert_eq!((d.host.as_str(), d.port, d.named), ("::1", 5353, false));
+
+        let named = header(&[ATYP_DOMAIN, 3, b'd', b'n', b's', 0, 53]);
+        let d = parse(&named).unwrap();
+        assert_eq!((d.host.as_str(), d.port, d.named), ("dns", 53, true));
+        assert_eq!(d.payload, b"payload");
+    }
+
+    #[test]
+    fn fragments_and_bad_headers_are_rejected() {
+        let mut fragment = header(&[ATYP_IPV4, 10, 0, 0, 1, 0, 53]);
+        fragment[2] = 1;
+        let e = parse(&fragment).unwrap_err();
+        assert!(e.to_string().contains("fragment"), "{}", e);
+        for datagram in [
+            &[0, 0, 0][..],
+            &[0, 0, 0, ATYP_IPV4, 10, 0, 0],
+            &[0, 0, 0, ATYP_IPV4, 10, 0, 0, 1, 0],
+            &[0, 0, 0, ATYP_DOMAIN, 5, b'a'],
+            &[0, 0, 0, 0x09, 10, 0, 0, 1, 0, 53],
+        ] {
+            assert!(parse(datagram).is_err(), "{:?} was taken", datagram);
+        }
+    }
+
+    /// A DNS server over TCP that answers each query with itself reversed
+    async fn dns_server() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let len = socket.read_u16().await.unwrap();
+                    let mut query = vec![0; usize::from(len)];
+                    socket.read_exact(&mut query).await.unwrap();
+                    query.reverse();
+                    socket.write_u16(len).await.unwrap();
+                    socket.write_all(&query).await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    /// An association with the control connection's client end, the relay
+    /// address and the task serving it
+    async fn start(
+        dns_port: u16,
+    ) -> (
+        tokio::io::DuplexStream,
+        SocketAddr,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        let (mut control, mut served) = tokio::io::duplex(64);
+        let association = tokio::spawn(async move {
+            let session = LoopbackTransport::new(Duration::from_secs(5));
+            let acl = DestAccessList::new(vec![], None).unwrap();
+            let localhost = IpAddr::from(Ipv4Addr::LOCALHOST);
+            associate(
+                &session,
+                &mut served,
+                localhost,
+                Some(localhost),
+                &acl,
+                dns_port,
+                0,
+            )
+            .await
+        });
+        let mut reply = [0; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            reply[..4],
+            [socks::VERSION, socks::REP_SUCCEEDED, 0, ATYP_IPV4]
+        );
+        let ip: [u8; 4] = reply[4..8].try_into().unwrap();
+        let relay = SocketAddr::from((ip, u16::from_be_bytes([reply[8], reply[9]])));
+        (control, relay, association)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dns_query_comes_back_through_the_session() {
+        let server = dns_server().await;
+        let (_control, relay, _) = start(server.port()).await;
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut address = vec![ATYP_IPV4, 127, 0, 0, 1];
+        address.extend_from_slice(&server.port().to_be_bytes());
+        let mut fragment = encapsulate(&address, b"lost");
+        fragment[2] = 1;
+        client.send_to(&fragment, relay).await.unwrap();
+        client
+            .send_to(&encapsulate(&address, b"query"), relay)
+            .await
+            .unwrap();
+        let mut buf = [0; 512];
+        let (n, from) = tokio::time::timeout(STEP_TIMEOUT, client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, relay);
+        // Only the answer to the whole datagram, the fragment was dropped
+        assert_eq!(&buf[..n], encapsulate(&address, b"yreuq"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn closing_the_control_connection_ends_the_association() {
+        let (control, _, association) = start(DNS_PORT).await;
+        drop(control);
+        tokio::time::timeout(STEP_TIMEOUT, association)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+}
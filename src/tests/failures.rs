@@ -0,0 +1,187 @@
+use super::*;
+use crate::transport::{ChannelOpenError, ProbeOutcome, SshTransport};
+use std::time::Instant;
+
+/// A port on this machine nothing listens on
+async fn closed_port() -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// The client's read ends right away when the target refuses the
+/// connection, and the tunnel keeps serving
+async fn closed_target_port_closes_the_client<T: SshTransport>(session: T) {
+    let addr = start_tunnel(tunnel_to(session, closed_port().await)).await;
+    for _ in 0..3 {
+        let started = Instant::now();
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let _ = socket.write_all(b"anyone there?").await;
+        let mut received = Vec::new();
+        // Closed or reset, either way no data and no hang
+        let _ = within(socket.read_to_end(&mut received)).await;
+        assert!(received.is_empty());
+        assert!(
+            started.elapsed() < PROMPT_CLOSE,
+            "took {:?}",
+            started.elapsed()
+        );
+    }
+}
+
+/// Why a channel to `target` couldn't be opened, and what a probe there
+/// makes of it
+async fn open_failure<T: SshTransport>(
+    session: T,
+    target: SocketAddr,
+) -> (ChannelOpenError, ProbeOutcome) {
+    within(tokio::task::spawn_blocking(move || {
+        let host = target.ip().to_string();
+        let e = match session.open_direct_tcpip(&host, target.port()) {
+            Ok(_) => panic!("a channel to {} was opened", target),
+            Err(e) => e.downcast::<ChannelOpenError>().unwrap(),
+        };
+        (e, session.probe_direct_tcpip(&host, target.port()).0)
+    }))
+    .await
+    .unwrap()
+}
+
+/// A target that refuses the connection is told apart from forwarding the
+/// server doesn't allow
+async fn refused_target_is_a_failed_connect<T: SshTransport>(session: T) {
+    let (e, probe) = open_failure(session, closed_port().await).await;
+    assert!(matches!(e, ChannelOpenError::ConnectFailed), "{:?}", e);
+    assert!(matches!(probe, ProbeOutcome::ConnectFailed), "{:?}", probe);
+}
+
+async fn prohibited_forwarding<T: SshTransport>(session: T) {
+    let (e, probe) = open_failure(session, echo_server().await).await;
+    assert!(matches!(e, ChannelOpenError::Prohibited), "{:?}", e);
+    assert!(matches!(probe, ProbeOutcome::Prohibited), "{:?}", probe);
+    assert_eq!(e.reason(), "forward prohibited");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn prohibited_forwarding_is_its_own_failure() {
+    prohibited_forwarding(loopback().prohibit_forwarding()).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs SSH2FWD_TEST_SSH_NO_FORWARD"]
+async fn prohibited_ssh2_forwarding_is_its_own_failure() {
+    prohibited_forwarding(ssh2_without_forwarding().await).await
+}
+
+/// --write-timeout of the stall tests
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A target that hands its connection to the test instead of serving it
+async fn handed_over_target() -> (SocketAddr, tokio::sync::oneshot::Receiver<TcpStream>) {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let tx = std::sync::Mutex::new(Some(tx));
+    let addr = target(move |socket| {
+        let tx = tx.lock().unwrap().take();
+        async move {
+            let _ = tx.unwrap().send(socket);
+        }
+    })
+    .await;
+    (addr, rx)
+}
+
+/// Read what a connection nobody read from still holds, until it closes
+async fn drained(socket: &mut (impl tokio::io::AsyncRead + Unpin)) {
+    let mut rest = Vec::new();
+    let _ = within(socket.read_to_end(&mut rest)).await;
+}
+
+/// The target stops reading, the connection closes once the client's data
+/// went nowhere for the write timeout, on both ends
+async fn non_reading_target_closes_at_the_deadline<T: SshTransport>(session: T) {
+    let (target, handed_over) = handed_over_target().await;
+    let tunnel = Tunnel {
+        write_timeout: Some(WRITE_TIMEOUT),
+        ..tunnel_to(session, target)
+    };
+    let addr = start_tunnel(tunnel).await;
+    let (mut rd, mut wr) = TcpStream::connect(addr).await.unwrap().into_split();
+    tokio::spawn(async move {
+        let chunk = pattern(64 << 10);
+        while wr.write_all(&chunk).await.is_ok() {}
+    });
+    let mut target = within(handed_over).await.unwrap();
+    let started = Instant::now();
+    drained(&mut rd).await;
+    assert!(
+        started.elapsed() >= WRITE_TIMEOUT,
+        "closed after {:?}",
+        started.elapsed()
+    );
+    drained(&mut target).await;
+}
+
+/// The client stops reading while the target keeps sending, the
+/// connection closes once that went nowhere for the write timeout
+async fn non_reading_client_closes_at_the_deadline<T: SshTransport>(session: T) {
+    let (target, handed_over) = handed_over_target().await;
+    let tunnel = Tunnel {
+        write_timeout: Some(WRITE_TIMEOUT),
+        ..tunnel_to(session, target)
+    };
+    let addr = start_tunnel(tunnel).await;
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let (mut rd, mut wr) = within(handed_over).await.unwrap().into_split();
+    let sender = tokio::spawn(async move {
+        let chunk = pattern(64 << 10);
+        while wr.write_all(&chunk).await.is_ok() {}
+    });
+    tokio::time::sleep(WRITE_TIMEOUT + Duration::from_secs(1)).await;
+    drained(&mut socket).await;
+    drained(&mut rd).await;
+    within(sender).await.unwrap();
+}
+
+/// A panic in the remote read task takes down neither the other direction
+/// nor the tunnel
+#[tokio::test(flavor = "multi_thread")]
+async fn a_panicking_direction_leaves_the_rest_running() {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let tx = std::sync::Mutex::new(Some(tx));
+    let target = target(move |mut socket| {
+        let tx = tx.lock().unwrap().take();
+        async move {
+            let Some(tx) = tx else {
+                // Later connections get an echo
+                let (mut rd, mut wr) = socket.split();
+                let _ = tokio::io::copy(&mut rd, &mut wr).await;
+                return;
+            };
+            socket.write_all(b"boom").await.unwrap();
+            let mut request = Vec::new();
+            socket.read_to_end(&mut request).await.unwrap();
+            let _ = tx.send(request);
+        }
+    })
+    .await;
+    let session = loopback();
+    session.panic_on_next_read();
+    let addr = start_tunnel(tunnel_to(session, target)).await;
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    // Let the answer reach the remote read first
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let request = pattern(100_000);
+    socket.write_all(&request).await.unwrap();
+    socket.shutdown().await.unwrap();
+    let mut nothing = Vec::new();
+    let _ = within(socket.read_to_end(&mut nothing)).await;
+    assert!(nothing.is_empty());
+    assert!(within(rx).await.unwrap() == request, "the request was cut");
+    assert_eq!(within(round_trip(addr, b"still here")).await, b"still here");
+}
+
+both_backends!(
+    closed_target_port_closes_the_client,
+    refused_target_is_a_failed_connect,
+    non_reading_target_closes_at_the_deadline,
+    non_reading_client_closes_at_the_deadline,
+);
@@ -0,0 +1,321 @@
+use super::*;
+use crate::transport::SshTransport;
+use std::time::Instant;
+
+async fn echo_round_trip<T: SshTransport>(session: T) {
+    let addr = start_tunnel(tunnel_to(session, echo_server().await)).await;
+    let received = within(round_trip(addr, b"hello through the tunnel")).await;
+    assert_eq!(received, b"hello through the tunnel");
+}
+
+async fn bulk_transfer<T: SshTransport>(session: T) {
+    let addr = start_tunnel(tunnel_to(session, echo_server().await)).await;
+    let data = pattern(4 << 20);
+    let received = within(round_trip(addr, &data)).await;
+    assert!(
+        received == data,
+        "got {} of {} bytes back",
+        received.len(),
+        data.len()
+    );
+}
+
+/// The server only answers once the request is complete, which it knows
+/// from the EOF the tunnel passes on
+async fn eof_reaches_the_target<T: SshTransport>(session: T) {
+    let target = target(|mut socket| async move {
+        let mut request = Vec::new();
+        socket.read_to_end(&mut request).await.unwrap();
+        socket
+            .write_all(&request.len().to_be_bytes())
+            .await
+            .unwrap();
+    })
+    .await;
+    let addr = start_tunnel(tunnel_to(session, target)).await;
+    let received = within(round_trip(addr, &pattern(100_000))).await;
+    assert_eq!(received, 100_000usize.to_be_bytes());
+}
+
+/// The EOF goes out as soon as the client closed, not when the reader of
+/// the other direction next looks
+async fn eof_reaches_the_target_promptly<T: SshTransport>(session: T) {
+    const RUNS: u32 = 10;
+    let target = target(|mut socket| async move {
+        let mut request = Vec::new();
+        socket.read_to_end(&mut request).await.unwrap();
+        socket.write_all(b"done").await.unwrap();
+    })
+    .await;
+    let addr = start_tunnel(tunnel_to(session, target)).await;
+    let started = Instant::now();
+    for _ in 0..RUNS {
+        assert_eq!(within(round_trip(addr, b"request")).await, b"done");
+    }
+    // Waiting for the reader each time would take about IDLE_WAIT
+    assert!(
+        started.elapsed() < crate::IDLE_WAIT * RUNS / 2,
+        "took {:?}",
+        started.elapsed()
+    );
+}
+
+/// A client that closes its side right after the request, like one sending
+/// `Connection: close`, gets all of a large answer the server only starts
+/// on the EOF, every time
+async fn answer_after_the_request_eof_is_never_cut<T: SshTransport>(session: T) {
+    const RUNS: usize = 200;
+    const ANSWER: usize = 256 << 10;
+    let target = target(|mut socket| async move {
+        let mut request = Vec::new();
+        socket.read_to_end(&mut request).await.unwrap();
+        socket.write_all(&pattern(ANSWER)).await.unwrap();
+    })
+    .await;
+    let addr = start_tunnel(tunnel_to(session, target)).await;
+    let request = b"GET / HTTP/1.1\r\nHost: target\r\nConnection: close\r\n\r\n";
+    let answer = pattern(ANSWER);
+    let mut cut = 0;
+    for _ in 0..RUNS {
+        if within(round_trip(addr, request)).await != answer {
+            cut += 1;
+        }
+    }
+    assert_eq!(cut, 0, "{} of {} answers were cut", cut, RUNS);
+}
+
+/// The server closing first ends the connection with everything it sent
+async fn target_close_reaches_the_client<T: SshTransport>(session: T) {
+    let target = target(|mut socket| async move {
+        socket.write_all(b"bye").await.unwrap();
+    })
+    .await;
+    let addr = start_tunnel(tunnel_to(session, target)).await;
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let mut received = Vec::new();
+    within(socket.read_to_end(&mut received)).await.unwrap();
+    assert_eq!(received, b"bye");
+}
+
+/// Like an HTTP/1.0 server: it answers and closes while the client still
+/// has its sending side open, the client's read ends right away
+async fn response_then_close_completes_promptly<T: SshTransport>(session: T) {
+    let target = target(|mut socket| async move {
+        let mut request = [0u8; 18];
+        socket.read_exact(&mut request).await.unwrap();
+        socket
+            .write_all(b"HTTP/1.0 200 OK\r\n\r\nhello")
+            .await
+            .unwrap();
+    })
+    .await;
+    let addr = start_tunnel(tunnel_to(session, target)).await;
+    let started = Instant::now();
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut received = Vec::new();
+    within(socket.read_to_end(&mut received)).await.unwrap();
+    assert_eq!(received, b"HTTP/1.0 200 OK\r\n\r\nhello");
+    assert!(
+        started.elapsed() < PROMPT_CLOSE,
+        "took {:?}",
+        started.elapsed()
+    );
+}
+
+/// The server goes quiet for longer than the reads wait, the connection
+/// stays up and delivers what comes after
+async fn paused_remote_writer<T: SshTransport>(session: T) {
+    let target = target(|mut socket| async move {
+        socket.write_all(b"before ").await.unwrap();
+        tokio::time::sleep(crate::IDLE_WAIT * 3).await;
+        socket.write_all(b"after").await.unwrap();
+    })
+    .await;
+    let addr = start_tunnel(tunnel_to(session, target)).await;
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let mut received = Vec::new();
+    within(socket.read_to_end(&mut received)).await.unwrap();
+    assert_eq!(received, b"before after");
+}
+
+/// Send `data` on the writer of a channel to an echo server and read it
+/// back on its reader, without the copy loops
+fn echo_over_channel<T: SshTransport>(session: &T, target: SocketAddr, data: &[u8]) -> Vec<u8> {
+    use std::io::{ErrorKind, Read, Write};
+    let retry =
+        |e: &std::io::Error| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+    let (mut channel, mut reader, mut writer) = session
+        .open_direct_tcpip(&target.ip().to_string(), target.port())
+        .unwrap();
+    let mut sent = 0;
+    while sent < data.len() {
+        match writer.write(&data[sent..]) {
+            Ok(n) => sent += n,
+            Err(e) if retry(&e) => {}
+            Err(e) => panic!("writing to the channel: {}", e),
+        }
+    }
+    session.send_eof(&mut channel).unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) if session.channel_eof(&channel) => break,
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(e) if retry(&e) => {}
+            Err(e) => panic!("reading from the channel: {}", e),
+        }
+    }
+    session.close_channel(channel).unwrap();
+    received
+}
+
+/// Every channel carries its data on stream 0, also the ones opened after
+/// the first, and its reader gets what the target sent back
+async fn channels_carry_the_data_on_stream_0<T: SshTransport>(session: T) {
+    let target = echo_server().await;
+    session.set_timeout(CHANNEL_POLL_MS);
+    let received = within(tokio::task::spawn_blocking(move || {
+        (0..3u8)
+            .map(|i| echo_over_channel(&session, target, &vec![i; 16 << 10]))
+            .collect::<Vec<_>>()
+    }))
+    .await
+    .unwrap();
+    for (i, received) in received.into_iter().enumerate() {
+        assert!(
+            received == vec![i as u8; 16 << 10],
+            "channel {} got {} bytes back",
+            i,
+            received.len()
+        );
+    }
+}
+
+async fn concurrent_connections<T: SshTransport>(session: T) {
+    let addr = start_tunnel(tunnel_to(session, echo_server().await)).await;
+    let clients = (0..10u8).map(|i| {
+        tokio::spawn(async move {
+            let data = vec![i; 10_000 + usize::from(i)];
+            assert_eq!(round_trip(addr, &data).await, data);
+        })
+    });
+    for client in clients {
+        within(client).await.unwrap();
+    }
+}
+
+/// A burst of connections opens its channels at the same time, every one
+/// of them has to make it
+async fn hundred_simultaneous_connections<T: SshTransport>(session: T) {
+    let addr = start_tunnel(tunnel_to(session, echo_server().await)).await;
+    let start = Arc::new(tokio::sync::Barrier::new(100));
+    let clients: Vec<_> = (0..100u32)
+        .map(|i| {
+            let start = start.clone();
+            tokio::spawn(async move {
+                let data = i.to_be_bytes().repeat(1000);
+                start.wait().await;
+                round_trip(addr, &data).await == data
+            })
+        })
+        .collect();
+    let mut failed = 0;
+    for client in clients {
+        // A connection that failed panicked its task or got other data
+        if !matches!(within(client).await, Ok(true)) {
+            failed += 1;
+        }
+    }
+    assert_eq!(failed, 0, "{} of 100 connections failed", failed);
+}
+
+/// A bulk transfer keeps going while other connections open and close
+/// next to it, none of them changes the timeout the others run under
+async fn bulk_transfer_while_connections_churn<T: SshTransport>(session: T) {
+    churn(start_tunnel(tunnel_to(session, echo_server().await)).await).await
+}
+
+async fn churn(addr: SocketAddr) {
+    let data = pattern(1 << 20);
+    let bulk = tokio::spawn(async move { round_trip(addr, &data).await == data });
+    for i in 0..50u8 {
+        let data = vec![i; 100];
+        assert_eq!(within(round_trip(addr, &data)).await, data);
+    }
+    assert!(within(bulk).await.unwrap(), "the bulk transfer got mangled");
+}
+
+/// Connections one after the other over one session, many more than a
+/// server allows channels at a time, so one that isn't closed shows
+const SOAK_CONNECTIONS: u32 = 5_000;
+
+async fn sequential_connections_soak<T: SshTransport>(session: T) {
+    soak(start_tunnel(tunnel_to(session, echo_server().await)).await).await
+}
+
+async fn soak(addr: SocketAddr) {
+    for i in 0..SOAK_CONNECTIONS {
+        let data = i.to_be_bytes();
+        assert_eq!(
+            within(round_trip(addr, &data)).await,
+            data,
+            "connection {}",
+            i
+        );
+    }
+}
+
+both_backends!(
+    echo_round_trip,
+    bulk_transfer,
+    eof_reaches_the_target,
+    eof_reaches_the_target_promptly,
+    answer_after_the_request_eof_is_never_cut,
+    target_close_reaches_the_client,
+    response_then_close_completes_promptly,
+    paused_remote_writer,
+    channels_carry_the_data_on_stream_0,
+    concurrent_connections,
+    hundred_simultaneous_connections,
+    bulk_transfer_while_connections_churn,
+    sequential_connections_soak,
+);
+
+/// Reads that come back empty before the server is done don't end the
+/// connection
+#[tokio::test(flavor = "multi_thread")]
+async fn paused_remote_writer_with_empty_reads() {
+    paused_remote_writer(loopback().empty_reads()).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn churn_over_a_slow_link() {
+    let session = loopback().latency(Duration::from_millis(2));
+    let watched = session.clone();
+    let addr = start_tunnel(tunnel_to(session, echo_server().await)).await;
+    let churn = tokio::spawn(churn(addr));
+    while !churn.is_finished() {
+        assert_eq!(watched.timeout_ms(), CHANNEL_POLL_MS);
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    churn.await.unwrap();
+}
+
+/// Each finished connection closes its channel, once
+#[tokio::test(flavor = "multi_thread")]
+async fn every_finished_connection_closes_its_channel() {
+    let session = loopback();
+    let watched = session.clone();
+    soak(start_tunnel(tunnel_to(session, echo_server().await)).await).await;
+    // The channel is closed after the client saw its connection end
+    within(async {
+        while watched.closed_channels() < u64::from(SOAK_CONNECTIONS) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(watched.closed_channels(), u64::from(SOAK_CONNECTIONS));
+}
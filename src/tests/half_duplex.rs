@@ -0,0 +1,56 @@
+use super::*;
+use crate::transport::SshTransport;
+use crate::HalfDuplex;
+
+/// Only the answer is forwarded, the server still learns the client has
+/// nothing to send
+async fn remote_to_local_sends_eof<T: SshTransport>(session: T) {
+    let target = target(|mut socket| async move {
+        let mut request = Vec::new();
+        socket.read_to_end(&mut request).await.unwrap();
+        socket.write_all(b"got EOF").await.unwrap();
+    })
+    .await;
+    let tunnel = Tunnel {
+        half_duplex: Some(HalfDuplex::RemoteToLocal),
+        ..tunnel_to(session, target)
+    };
+    let addr = start_tunnel(tunnel).await;
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let mut received = Vec::new();
+    within(socket.read_to_end(&mut received)).await.unwrap();
+    assert_eq!(received, b"got EOF");
+}
+
+/// Only the client's data is forwarded, the client isn't left waiting for
+/// an answer that never comes
+async fn local_to_remote_ends_the_client_read<T: SshTransport>(session: T) {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let tx = std::sync::Mutex::new(Some(tx));
+    let target = target(move |mut socket| {
+        let tx = tx.lock().unwrap().take();
+        async move {
+            let mut logs = Vec::new();
+            socket.read_to_end(&mut logs).await.unwrap();
+            let _ = tx.unwrap().send(logs);
+        }
+    })
+    .await;
+    let tunnel = Tunnel {
+        half_duplex: Some(HalfDuplex::LocalToRemote),
+        ..tunnel_to(session, target)
+    };
+    let addr = start_tunnel(tunnel).await;
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let mut nothing = Vec::new();
+    within(socket.read_to_end(&mut nothing)).await.unwrap();
+    assert!(nothing.is_empty());
+    socket.write_all(b"log line\n").await.unwrap();
+    socket.shutdown().await.unwrap();
+    assert_eq!(within(rx).await.unwrap(), b"log line\n");
+}
+
+both_backends!(
+    remote_to_local_sends_eof,
+    local_to_remote_ends_the_client_read
+);
@@ -0,0 +1,53 @@
+use crate::{io_error_message, report_io_error, Direction};
+use std::io::{Error, ErrorKind};
+
+#[test]
+fn message_names_the_connection_the_operation_and_the_error() {
+    let e = Error::other("no route to host");
+    assert_eq!(
+        io_error_message(7, Direction::LocalRead, &e),
+        "Connection #7: reading from the local client failed: no route to host. Closing"
+    );
+    assert_eq!(
+        io_error_message(8, Direction::ChannelRead, &e),
+        "Connection #8: reading from the SSH channel failed: no route to host. Closing"
+    );
+}
+
+#[test]
+fn message_of_a_write_has_its_size() {
+    let e = Error::from(ErrorKind::BrokenPipe);
+    assert_eq!(
+        io_error_message(1, Direction::LocalWrite(4096), &e),
+        format!(
+            "Connection #1: writing 4096 bytes to the local client failed: {}. Closing",
+            e
+        )
+    );
+    assert_eq!(
+        io_error_message(2, Direction::ChannelWrite(1), &e),
+        format!(
+            "Connection #2: writing 1 bytes to the SSH channel failed: {}. Closing",
+            e
+        )
+    );
+}
+
+#[test]
+fn reset_and_error_close_reasons_per_direction() {
+    let reset = Error::from(ErrorKind::ConnectionReset);
+    let other = Error::other("boom");
+    let cases = [
+        (Direction::LocalRead, &reset, "local reset"),
+        (Direction::LocalWrite(1), &reset, "local reset"),
+        (Direction::ChannelRead, &reset, "remote reset"),
+        (Direction::ChannelWrite(1), &reset, "remote reset"),
+        (Direction::LocalRead, &other, "local read error"),
+        (Direction::LocalWrite(1), &other, "local write error"),
+        (Direction::ChannelRead, &other, "remote read error"),
+        (Direction::ChannelWrite(1), &other, "channel write error"),
+    ];
+    for (direction, e, reason) in cases {
+        assert_eq!(report_io_error(3, direction, e), reason, "{}", e);
+    }
+}
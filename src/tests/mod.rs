@@ -0,0 +1,219 @@
+//! Forwarding tests run against every backend of `SshTransport`. The
+//! loopback ones always run, the ssh2 ones are ignored unless asked for with
+//! `--ignored`: they need SSH2FWD_TEST_SSH to name an SSH server on this
+//! machine as user@host:port that takes the keys of the ssh-agent. The
+//! targets listen on 127.0.0.1, so the server must be able to reach them
+//! there. Tests of refused forwarding use the server SSH2FWD_TEST_SSH_NO_FORWARD
+//! names the same way, one with `AllowTcpForwarding no`.
+
+use crate::local::{LocalStream, PeerAddr};
+use crate::loopback::LoopbackTransport;
+use crate::multi_path::MultiPath;
+use crate::transport::{HandshakeOptions, Ssh2Transport, SshTransport};
+use crate::{forward_connection, Tunnel, CHANNEL_POLL_MS, NEXT_CONNECTION_ID};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Where the ssh2 backend connects, user@host:port
+const SSH_ENV: &str = "SSH2FWD_TEST_SSH";
+
+/// Like SSH_ENV, a server that doesn't allow forwarding
+const SSH_NO_FORWARD_ENV: &str = "SSH2FWD_TEST_SSH_NO_FORWARD";
+
+/// Set in the process `in_child` starts
+const CHILD_ENV: &str = "SSH2FWD_TEST_CHILD";
+
+/// Longest a test step may take before the test fails instead of hanging
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Longest a client may wait to see its connection closed once the other
+/// end closed or never opened
+pub const PROMPT_CLOSE: Duration = Duration::from_secs(2);
+
+/// Run each listed `async fn case<T: SshTransport>(session: T)` of the
+/// calling module once per backend
+macro_rules! both_backends {
+    ($($case:ident),* $(,)?) => {
+        mod loopback_backend {
+            $(
+                #[tokio::test(flavor = "multi_thread")]
+                async fn $case() {
+                    super::$case(crate::tests::loopback()).await
+                }
+            )*
+        }
+
+        mod ssh2_backend {
+            $(
+                #[tokio::test(flavor = "multi_thread")]
+                #[ignore = "needs SSH2FWD_TEST_SSH"]
+                async fn $case() {
+                    super::$case(crate::tests::ssh2().await).await
+                }
+            )*
+        }
+    };
+}
+
+mod failures;
+mod forwarding;
+mod half_duplex;
+mod io_errors;
+mod receive_window;
+mod write_channel;
+
+/// Run `test`, given by its path, again in a new process of the test
+/// binary, for tests that change the whole process. True in that process,
+/// which then runs the test body.
+pub fn in_child(test: &str) -> bool {
+    if std::env::var_os(CHILD_ENV).is_some() {
+        return true;
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", test, "--nocapture", "--test-threads=1"])
+        .env(CHILD_ENV, "1")
+        .output()
+        .unwrap();
+    let out = String::from_utf8_lossy(&output.stdout);
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}\n{}", out, err);
+    assert!(out.contains("1 passed"), "the case didn't run\n{}", out);
+    false
+}
+
+pub fn loopback() -> LoopbackTransport {
+    LoopbackTransport::new(Duration::from_secs(5))
+}
+
+/// A session to the server of SSH2FWD_TEST_SSH
+pub async fn ssh2() -> Ssh2Transport {
+    ssh2_from(SSH_ENV).await
+}
+
+/// A session to the server of SSH2FWD_TEST_SSH_NO_FORWARD
+pub async fn ssh2_without_forwarding() -> Ssh2Transport {
+    ssh2_from(SSH_NO_FORWARD_ENV).await
+}
+
+/// A test asked for with `--ignored` fails when its server isn't set,
+/// instead of passing without running
+async fn ssh2_from(env: &str) -> Ssh2Transport {
+    let spec = std::env::var(env).unwrap_or_else(|_| panic!("{} is not set", env));
+    let (user, addr) = spec
+        .split_once('@')
+        .unwrap_or_else(|| panic!("{} must be user@host:port", env));
+    let tcp = TcpStream::connect(addr)
+        .await
+        .expect("connecting to the SSH server");
+    let options = HandshakeOptions {
+        timeout: Duration::from_secs(10),
+        channel_open_timeout: Duration::from_secs(5),
+        compress: false,
+        ip_tos: None,
+        via_proxy: false,
+        kex: None,
+        host_keys: None,
+        ciphers: None,
+        macs: None,
+    };
+    let session = tokio::task::spawn_blocking(move || Ssh2Transport::handshake(tcp, &options))
+        .await
+        .unwrap()
+        .expect("SSH handshake");
+    session
+        .userauth_agent(user, None)
+        .expect("authenticating with the ssh-agent");
+    session
+}
+
+/// Fail the test instead of hanging when `step` takes too long
+pub async fn within<F: Future>(step: F) -> F::Output {
+    tokio::time::timeout(STEP_TIMEOUT, step)
+        .await
+        .expect("test step timed out")
+}
+
+/// Plain forwarding to `target` over `session`
+pub fn tunnel_to<T: SshTransport>(session: T, target: SocketAddr) -> Tunnel<T> {
+    let paths = vec![(IpAddr::from(Ipv4Addr::LOCALHOST), Some(session))];
+    Tunnel::new(
+        MultiPath::new(paths, CHANNEL_POLL_MS),
+        target.ip().to_string(),
+        target.port(),
+    )
+}
+
+/// Accept local connections for `tunnel` like the accept loop of `run`,
+/// returns the address to connect to
+pub async fn start_tunnel<T: SshTransport>(tunnel: Tunnel<T>) -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tunnel = Arc::new(tunnel);
+    tokio::spawn(async move {
+        while let Ok((socket, peer)) = listener.accept().await {
+            let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(forward_connection(
+                tunnel.clone(),
+                LocalStream::Tcp(socket),
+                PeerAddr::Tcp(peer),
+                conn_id,
+            ));
+        }
+    });
+    addr
+}
+
+/// A target server that runs `serve` for each connection
+pub async fn target<F, Fut>(serve: F) -> SocketAddr
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(serve(socket));
+        }
+    });
+    addr
+}
+
+/// A target that sends back what it gets and closes after the client did
+pub async fn echo_server() -> SocketAddr {
+    target(|mut socket| async move {
+        let (mut rd, mut wr) = socket.split();
+        let _ = tokio::io::copy(&mut rd, &mut wr).await;
+        let _ = wr.shutdown().await;
+    })
+    .await
+}
+
+/// Send `data` through a fresh connection to `addr`, close the sending side
+/// and return everything that comes back until the other end closes
+pub async fn round_trip(addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let (mut rd, mut wr) = socket.split();
+    let mut received = Vec::new();
+    let (written, read) = tokio::join!(
+        async {
+            wr.write_all(data).await?;
+            wr.shutdown().await
+        },
+        rd.read_to_end(&mut received)
+    );
+    written.unwrap();
+    read.unwrap();
+    received
+}
+
+/// `len` bytes that don't repeat within a buffer, so a dropped or
+/// reordered chunk shows
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
@@ -0,0 +1,56 @@
+use super::*;
+use crate::transport::SshTransport;
+use std::sync::atomic::AtomicUsize;
+
+/// More than the kernel buffers of all hops hold together
+const ANSWER: usize = 64 << 20;
+
+/// A client that stops reading holds the server back, and gets everything
+/// once it reads again
+async fn paused_client_holds_back_the_server<T: SshTransport>(session: T) {
+    let sent = Arc::new(AtomicUsize::new(0));
+    let target = target({
+        let sent = sent.clone();
+        move |mut socket| {
+            let sent = sent.clone();
+            async move {
+                for chunk in pattern(ANSWER).chunks(64 << 10) {
+                    socket.write_all(chunk).await.unwrap();
+                    sent.fetch_add(chunk.len(), Ordering::Relaxed);
+                }
+            }
+        }
+    })
+    .await;
+    let tunnel = Tunnel {
+        receive_window_size: Some(64 << 10),
+        ..tunnel_to(session, target)
+    };
+    let addr = start_tunnel(tunnel).await;
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    // The kernel buffers of the hops take a while to grow, until then the
+    // server still gets some data out
+    let held = within(async {
+        let mut held = 0;
+        loop {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let now = sent.load(Ordering::Relaxed);
+            if now == held {
+                return held;
+            }
+            held = now;
+        }
+    })
+    .await;
+    assert!(held < ANSWER, "the server sent everything");
+    let mut received = Vec::new();
+    within(socket.read_to_end(&mut received)).await.unwrap();
+    assert!(
+        received == pattern(ANSWER),
+        "got {} of {} bytes",
+        received.len(),
+        ANSWER
+    );
+}
+
+both_backends!(paused_client_holds_back_the_server);
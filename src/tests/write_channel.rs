@@ -0,0 +1,123 @@
+use crate::transport::DataWait;
+use crate::{classify_io_error, write_channel, ErrorAction};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+/// A session that never has data, counts how often a writer waited
+#[derive(Default)]
+struct Waits(Cell<usize>);
+
+impl DataWait for Waits {
+    fn data_generation(&self) -> u64 {
+        0
+    }
+
+    fn wait_for_data(&self, _seen: u64, _timeout: Duration) {
+        self.0.set(self.0.get() + 1);
+    }
+
+    fn data_read(&self) {}
+}
+
+/// A channel whose writes take at most what `script` allows in turn, or
+/// fail with its error; once it ran out each write takes everything
+struct Channel {
+    script: VecDeque<io::Result<usize>>,
+    written: Vec<u8>,
+}
+
+impl Channel {
+    fn new(script: impl IntoIterator<Item = io::Result<usize>>) -> Self {
+        Channel {
+            script: script.into_iter().collect(),
+            written: Vec::new(),
+        }
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match self.script.pop_front() {
+            Some(step) => step?.min(buf.len()),
+            None => buf.len(),
+        };
+        self.written.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn full() -> io::Result<usize> {
+    Err(ErrorKind::WouldBlock.into())
+}
+
+fn timed_out() -> io::Result<usize> {
+    Err(ErrorKind::TimedOut.into())
+}
+
+#[test]
+fn short_writes_send_the_rest() {
+    let mut channel = Channel::new([Ok(3), Ok(1), Ok(5)]);
+    write_channel(&Waits::default(), &mut channel, b"hello, world", None, None).unwrap();
+    assert_eq!(channel.written, b"hello, world");
+}
+
+#[test]
+fn a_full_window_waits_and_loses_nothing() {
+    let waits = Waits::default();
+    let mut channel = Channel::new([Ok(4), full(), timed_out(), Ok(2), full()]);
+    write_channel(&waits, &mut channel, b"hello, world", None, None).unwrap();
+    assert_eq!(channel.written, b"hello, world");
+    assert_eq!(waits.0.get(), 3);
+}
+
+#[test]
+fn interrupted_writes_are_retried_without_waiting() {
+    let waits = Waits::default();
+    let mut channel = Channel::new([Err(ErrorKind::Interrupted.into())]);
+    write_channel(&waits, &mut channel, b"hello", None, None).unwrap();
+    assert_eq!(channel.written, b"hello");
+    assert_eq!(waits.0.get(), 0);
+}
+
+#[test]
+fn real_errors_stop_the_write() {
+    let mut channel = Channel::new([Ok(2), Err(ErrorKind::BrokenPipe.into())]);
+    let e = write_channel(&Waits::default(), &mut channel, b"hello", None, None).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::BrokenPipe);
+    assert_eq!(channel.written, b"he");
+}
+
+#[test]
+fn a_channel_taking_nothing_fails() {
+    let mut channel = Channel::new([Ok(0)]);
+    let e = write_channel(&Waits::default(), &mut channel, b"hello", None, None).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::WriteZero);
+}
+
+#[test]
+fn a_window_that_stays_full_stalls_after_the_timeout() {
+    let stall = Duration::from_millis(50);
+    let mut channel = Channel::new(std::iter::repeat_with(full).take(1_000_000));
+    let started = Instant::now();
+    let e =
+        write_channel(&Waits::default(), &mut channel, b"hello", None, Some(stall)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+    assert!(started.elapsed() >= stall);
+    assert!(channel.written.is_empty());
+}
+
+#[test]
+fn reads_that_would_block_are_retried() {
+    for kind in [ErrorKind::WouldBlock, ErrorKind::TimedOut] {
+        assert!(matches!(
+            classify_io_error(&kind.into()),
+            ErrorAction::Continue
+        ));
+    }
+}
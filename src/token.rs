@@ -0,0 +1,106 @@
+use crate::secret::{zeroize, zeroize_array, Secret};
+use anyhow::bail;
+use rand::RngCore;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{sleep, timeout, Duration};
+
+pub const TOKEN_LEN: usize = 32;
+/// How long a client gets to send the token after connecting
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Environment variable to hand a pre-shared token to the tunnel instead of
+/// generating a new one on every start
+pub const TOKEN_ENV: &str = "SSH2FWD_TUNNEL_TOKEN";
+
+/// 256 bit secret local clients must send as their first 32 bytes
+pub struct TunnelToken([u8; TOKEN_LEN]);
+
+impl TunnelToken {
+    pub fn generate() -> Self {
+        let mut token = [0; TOKEN_LEN];
+        rand::thread_rng().fill_bytes(&mut token);
+        TunnelToken(token)
+    }
+
+    pub fn from_hex(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.len() != TOKEN_LEN * 2 || !s.is_ascii() {
+            bail!("tunnel token must be {} hex digits", TOKEN_LEN * 2);
+        }
+        let mut token = [0; TOKEN_LEN];
+        for (i, b) in token.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(TunnelToken(token))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Read the token from a freshly accepted connection. Anything other than
+    /// the exact token within the timeout fails.
+    pub async fn verify<S: AsyncRead + Unpin>(&self, socket: &mut S) -> bool {
+        let mut presented = [0; TOKEN_LEN];
+        let ok = match timeout(TOKEN_TIMEOUT, socket.read_exact(&mut presented)).await {
+            Ok(Ok(_)) => constant_time_eq(&presented, &self.0),
+            _ => false,
+        };
+        zeroize_array(&mut presented);
+        ok
+    }
+}
+
+impl Drop for TunnelToken {
+    fn drop(&mut self) {
+        zeroize_array(&mut self.0);
+    }
+}
+
+/// Compare without bailing out on the first difference so the time taken
+/// doesn't tell how much of a guess was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Shared secret local clients send as a `<secret>\n` line before their
+/// own data. The line is consumed, the remote side never sees it.
+pub struct ClientToken(Secret);
+
+/// Pause before closing a client with a wrong token to slow down guessing
+const CLIENT_TOKEN_FAIL_DELAY: Duration = Duration::from_secs(1);
+
+impl ClientToken {
+    pub fn new(secret: Secret) -> anyhow::Result<Self> {
+        if secret.expose().is_empty() || secret.expose().contains('\n') {
+            bail!("client token must be a non-empty single line");
+        }
+        Ok(ClientToken(secret))
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = Secret::new(std::fs::read_to_string(path)?);
+        ClientToken::new(Secret::new(content.expose().trim_end().to_string()))
+    }
+
+    /// Read and check the preamble line. On failure the connection should be
+    /// dropped, which happens after a short delay.
+    pub async fn verify<S: AsyncRead + Unpin>(&self, socket: &mut S) -> bool {
+        let expected = self.0.expose().as_bytes();
+        let mut line = vec![0; expected.len() + 1];
+        let ok = match timeout(TOKEN_TIMEOUT, socket.read_exact(&mut line)).await {
+            Ok(Ok(_)) => {
+                line[expected.len()] == b'\n' && constant_time_eq(&line[..expected.len()], expected)
+            }
+            _ => false,
+        };
+        zeroize(&mut line);
+        if !ok {
+            sleep(CLIENT_TOKEN_FAIL_DELAY).await;
+        }
+        ok
+    }
+}
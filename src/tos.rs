@@ -0,0 +1,27 @@
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+
+/// --ip-tos value, decimal or hex with a leading 0x, e.g. 0x28 for DSCP AF11
+pub fn parse(s: &str) -> Result<u8, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("{} is not a TOS byte, use 0-255 or 0x00-0xff", s))
+}
+
+/// Mark the packets of a connected socket: the TOS byte of IPv4, the
+/// traffic class of IPv6
+pub fn set(socket: SockRef, tos: u8) -> io::Result<()> {
+    match socket.local_addr()?.as_socket() {
+        #[cfg(unix)]
+        Some(SocketAddr::V6(_)) => socket.set_tclass_v6(tos.into()),
+        #[cfg(not(unix))]
+        Some(SocketAddr::V6(_)) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the traffic class of IPv6 sockets can only be set on Unix",
+        )),
+        _ => socket.set_tos_v4(tos.into()),
+    }
+}
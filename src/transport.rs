@@ -0,0 +1,1477 @@
+use crate::activity::Activity;
+use crate::fingerprint::{key_type, sha256, sha256_fingerprint};
+use crate::session_lost::SessionLost;
+use anyhow::{anyhow, bail};
+use log::{debug, info, warn};
+use socket2::SockRef;
+use ssh2::{Channel, FileStat, Listener, OpenFlags, OpenType, Sftp, Stream};
+use ssh2::{
+    DisconnectCode, ErrorCode, HashType, KeyboardInteractivePrompt, MethodType, Prompt, Session,
+};
+use std::io::{ErrorKind, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::fd::AsFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// How a command run on the server ended, with at most `EXEC_OUTPUT_LIMIT`
+/// bytes of each output
+pub struct ExecOutput {
+    pub exit_status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Most bytes of stdout and of stderr an `exec` keeps, the rest is read and
+/// dropped so the command can finish
+pub const EXEC_OUTPUT_LIMIT: usize = 1 << 20;
+
+impl ExecOutput {
+    /// Output of a command as it came, cut to the limit and with what isn't
+    /// UTF-8 replaced
+    pub fn from_bytes(exit_status: i32, stdout: &[u8], stderr: &[u8]) -> Self {
+        let text = |bytes: &[u8]| {
+            String::from_utf8_lossy(&bytes[..bytes.len().min(EXEC_OUTPUT_LIMIT)]).into_owned()
+        };
+        ExecOutput {
+            exit_status,
+            stdout: text(stdout),
+            stderr: text(stderr),
+        }
+    }
+}
+
+/// Size, permissions and times of a file on the server as SFTP reports
+/// them, each only when the server did
+#[derive(Clone, Copy, Default)]
+pub struct FileAttrs {
+    pub size: Option<u64>,
+    /// Permission bits together with the file type bits
+    pub mode: Option<u32>,
+    /// Seconds since the epoch
+    pub atime: Option<u64>,
+    pub mtime: Option<u64>,
+}
+
+impl FileAttrs {
+    pub fn is_dir(&self) -> bool {
+        self.mode.is_some_and(|m| m & 0o170000 == 0o040000)
+    }
+}
+
+/// Why an SFTP operation failed, the first two are worth telling apart
+#[derive(Debug)]
+pub enum SftpError {
+    NoSuchFile,
+    PermissionDenied,
+    Other(String),
+}
+
+impl SftpError {
+    fn from_ssh2(e: ssh2::Error) -> Self {
+        match e.code() {
+            ErrorCode::SFTP(LIBSSH2_FX_NO_SUCH_FILE | LIBSSH2_FX_NO_SUCH_PATH) => {
+                SftpError::NoSuchFile
+            }
+            ErrorCode::SFTP(LIBSSH2_FX_PERMISSION_DENIED) => SftpError::PermissionDenied,
+            _ => SftpError::Other(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for SftpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SftpError::NoSuchFile => write!(f, "no such file or directory on the SSH server"),
+            SftpError::PermissionDenied => write!(f, "permission denied on the SSH server"),
+            SftpError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SftpError {}
+
+/// `userauth_key_file` failed because the key couldn't be loaded, as with an
+/// encrypted key and no or a wrong passphrase. libssh2 can't tell those apart.
+pub fn key_unreadable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ssh2::Error>()
+        .is_some_and(|e| e.code() == ErrorCode::Session(LIBSSH2_ERROR_FILE))
+}
+
+/// Answers keyboard-interactive prompts: gets the server's instructions and
+/// the prompts, each with whether the answer may be echoed
+pub type AnswerPrompts = dyn FnMut(&str, &[(String, bool)]) -> Vec<String>;
+
+/// A key loaded in the ssh-agent
+pub struct AgentIdentity {
+    pub key_type: String,
+    pub fingerprint: String,
+    pub comment: String,
+}
+
+/// What the server answered when asked to connect somewhere for
+/// `probe_direct_tcpip`
+#[derive(Debug)]
+pub enum ProbeOutcome {
+    /// The channel opened, something accepts connections there
+    Open,
+    /// The server tried and could not connect
+    ConnectFailed,
+    /// The server doesn't forward there
+    Prohibited,
+    /// No answer from the server in time
+    TimedOut,
+    Failed(String),
+}
+
+/// Why the server opened no channel for `open_direct_tcpip`, from the reason
+/// code of its answer
+#[derive(Debug)]
+pub enum ChannelOpenError {
+    /// The server tried and could not connect to the target
+    ConnectFailed,
+    /// The server's configuration doesn't allow forwarding there
+    Prohibited,
+    /// The server has no room for another channel
+    ResourceShortage,
+    /// No answer from the server in time
+    TimedOut,
+    /// Anything else, the session itself failing included
+    Other(String),
+}
+
+impl ChannelOpenError {
+    fn from_ssh2(e: ssh2::Error) -> Self {
+        // The reason code of the open failure only shows in the message,
+        // "Channel open failure (<reason>)" of libssh2's channel.c
+        match e.code() {
+            ErrorCode::Session(LIBSSH2_ERROR_CHANNEL_FAILURE)
+                if e.message().contains("connect failed") =>
+            {
+                ChannelOpenError::ConnectFailed
+            }
+            ErrorCode::Session(LIBSSH2_ERROR_CHANNEL_FAILURE)
+                if e.message().contains("prohibited") =>
+            {
+                ChannelOpenError::Prohibited
+            }
+            ErrorCode::Session(LIBSSH2_ERROR_CHANNEL_FAILURE)
+                if e.message().contains("resource shortage") =>
+            {
+                ChannelOpenError::ResourceShortage
+            }
+            ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT) => ChannelOpenError::TimedOut,
+            _ => ChannelOpenError::Other(e.to_string()),
+        }
+    }
+
+    /// Close reason of the connection in the audit log and the API
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ChannelOpenError::ConnectFailed => "target connect failed",
+            ChannelOpenError::Prohibited => "forward prohibited",
+            ChannelOpenError::ResourceShortage => "server resource shortage",
+            ChannelOpenError::TimedOut => "channel open timeout",
+            ChannelOpenError::Other(_) => "channel open failed",
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelOpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelOpenError::ConnectFailed => write!(
+                f,
+                "the SSH server could not connect there, is it running and reachable from the SSH server?"
+            ),
+            ChannelOpenError::Prohibited => write!(
+                f,
+                "the SSH server rejected the forward, check AllowTcpForwarding and PermitOpen in its sshd_config"
+            ),
+            ChannelOpenError::ResourceShortage => {
+                write!(f, "the SSH server has no room for another channel")
+            }
+            ChannelOpenError::TimedOut => write!(
+                f,
+                "no answer from the SSH server within --channel-open-timeout-ms"
+            ),
+            ChannelOpenError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChannelOpenError {}
+
+/// How to run the SSH handshake, and settings of the session it starts
+#[derive(Clone)]
+pub struct HandshakeOptions {
+    /// Connecting and the handshake together may take this long
+    pub timeout: Duration,
+    /// How long opening a channel may take in total, however short the
+    /// session timeout is
+    pub channel_open_timeout: Duration,
+    /// Ask for zlib compression of the session
+    pub compress: bool,
+    /// TOS byte for the packets of a TCP connection to the server
+    pub ip_tos: Option<u8>,
+    /// The TCP connection goes to a proxy, its peer is not the server
+    pub via_proxy: bool,
+    /// Comma separated algorithms to offer instead of libssh2's defaults,
+    /// in order of preference
+    pub kex: Option<String>,
+    pub host_keys: Option<String>,
+    pub ciphers: Option<String>,
+    pub macs: Option<String>,
+}
+
+impl HandshakeOptions {
+    fn preference(&self, kind: Algorithms) -> Option<&str> {
+        match kind {
+            Algorithms::Kex => self.kex.as_deref(),
+            Algorithms::HostKey => self.host_keys.as_deref(),
+            Algorithms::Cipher => self.ciphers.as_deref(),
+            Algorithms::Mac => self.macs.as_deref(),
+        }
+    }
+}
+
+/// The kinds of algorithms whose preference can be set for the handshake
+#[derive(Clone, Copy)]
+pub enum Algorithms {
+    Kex,
+    HostKey,
+    Cipher,
+    Mac,
+}
+
+impl Algorithms {
+    const ALL: [Algorithms; 4] = [
+        Algorithms::Kex,
+        Algorithms::HostKey,
+        Algorithms::Cipher,
+        Algorithms::Mac,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithms::Kex => "kex",
+            Algorithms::HostKey => "host key",
+            Algorithms::Cipher => "cipher",
+            Algorithms::Mac => "mac",
+        }
+    }
+
+    /// The option that sets the preference
+    pub fn flag(self) -> &'static str {
+        match self {
+            Algorithms::Kex => "--kex",
+            Algorithms::HostKey => "--hostkey-algorithms",
+            Algorithms::Cipher => "--cipher",
+            Algorithms::Mac => "--mac",
+        }
+    }
+
+    /// Both directions where there are two
+    fn method_types(self) -> &'static [MethodType] {
+        match self {
+            Algorithms::Kex => &[MethodType::Kex],
+            Algorithms::HostKey => &[MethodType::HostKey],
+            Algorithms::Cipher => &[MethodType::CryptCs, MethodType::CryptSc],
+            Algorithms::Mac => &[MethodType::MacCs, MethodType::MacSc],
+        }
+    }
+
+    /// What libssh2 can do of this kind, in its default order
+    pub fn supported(self) -> anyhow::Result<Vec<&'static str>> {
+        Ok(Session::new()?.supported_algs(self.method_types()[0])?)
+    }
+}
+
+/// Server software and algorithms negotiated for a session. Directions
+/// that ended up with different algorithms show both, client to server first.
+pub struct SessionInfo {
+    pub server_version: String,
+    pub kex: String,
+    pub host_key: String,
+    pub cipher: String,
+    pub mac: String,
+    pub compression: String,
+}
+
+/// How channel readers and writers that got nothing done wait for the
+/// session instead of trying again in a loop. Apart from `SshTransport` so
+/// the copy helpers can be tested without a session.
+pub trait DataWait {
+    /// Counter that moves on whenever a channel of the session may have new
+    /// data, for `wait_for_data`
+    fn data_generation(&self) -> u64;
+
+    /// Sleep until `data_generation` moves past `seen`, or `timeout`. A
+    /// reader that found nothing waits here instead of reading in a loop.
+    fn wait_for_data(&self, seen: u64, timeout: Duration);
+
+    /// A read of a channel returned data, libssh2 may have buffered some for
+    /// other channels at the same time
+    fn data_read(&self);
+}
+
+/// Operations the forwarder needs from an SSH client implementation.
+///
+/// Everything outside this module talks to the SSH server through this trait
+/// so that a different backend can be dropped in without touching the
+/// forwarding code.
+pub trait SshTransport: DataWait + Clone + Send + Sync + 'static {
+    /// One direction of an opened direct-tcpip channel
+    type Stream: Read + Write + Send + 'static;
+
+    /// An open channel, it stays open until passed to `close_channel` even
+    /// when its streams are gone
+    type Channel: Send + 'static;
+
+    /// A file on the server opened through SFTP
+    type RemoteFile: Read + Write + Send + 'static;
+    /// A port the server listens on for us, it stops listening when this is
+    /// dropped
+    type Listener: Send + 'static;
+
+    /// Take over an already connected TCP stream and run the SSH handshake on
+    /// it, giving up after the timeout of `options`
+    fn handshake(tcp: TcpStream, options: &HandshakeOptions) -> anyhow::Result<Self>;
+
+    /// Run the SSH handshake over a connected Unix socket, the end of a
+    /// socket pair a proxy command relays
+    #[cfg(unix)]
+    fn handshake_socket(socket: UnixStream, options: &HandshakeOptions) -> anyhow::Result<Self>;
+
+    /// Try to authenticate with the identities offered by the ssh-agent,
+    /// `socket` overrides the agent location taken from SSH_AUTH_SOCK
+    fn userauth_agent(&self, user: &str, socket: Option<&Path>) -> anyhow::Result<()>;
+
+    /// Keys the ssh-agent offers, what `userauth_agent` will try, in order
+    fn agent_identities(socket: Option<&Path>) -> anyhow::Result<Vec<AgentIdentity>>;
+
+    /// Try to authenticate with a password
+    fn userauth_password(&self, user: &str, password: &str) -> anyhow::Result<()>;
+
+    /// Try to authenticate with a private key file, the public key is
+    /// derived from it
+    fn userauth_key_file(
+        &self,
+        user: &str,
+        key: &Path,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<()>;
+
+    /// Try keyboard-interactive authentication, `answer` returns one answer
+    /// per prompt
+    fn userauth_keyboard_interactive(
+        &self,
+        user: &str,
+        answer: &mut AnswerPrompts,
+    ) -> anyhow::Result<()>;
+
+    fn authenticated(&self) -> bool;
+
+    /// Open a direct-tcpip channel to host:port as seen from the SSH server,
+    /// returning it with a (reader, writer) pair for it. Why the server
+    /// refused shows in the `ChannelOpenError` of a failure.
+    fn open_direct_tcpip(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> anyhow::Result<(Self::Channel, Self::Stream, Self::Stream)>;
+
+    /// Open a direct-tcpip channel to host:port only to see whether the server
+    /// can connect there, and close it again. Also returns how long the
+    /// server took to answer, not counting the wait for other opens.
+    fn probe_direct_tcpip(&self, host: &str, port: u16) -> (ProbeOutcome, Duration);
+
+    /// Whether the server sent EOF on the channel or closed it. A read that
+    /// returns nothing only ends the data when this says so.
+    fn channel_eof(&self, channel: &Self::Channel) -> bool;
+
+    /// Tell the server nothing more comes from the local side. The channel
+    /// stays open for what the server still has to send.
+    fn send_eof(&self, channel: &mut Self::Channel) -> anyhow::Result<()>;
+
+    /// Close the channel, sending EOF first unless `send_eof` did, then wait
+    /// briefly for the server to close its end so it can free the channel
+    fn close_channel(&self, channel: Self::Channel) -> anyhow::Result<()>;
+
+    /// Timeout in milliseconds for blocking operations, 0 means no timeout.
+    /// It applies to the whole session, every channel of it included
+    fn set_timeout(&self, timeout_ms: u32);
+
+    /// SHA256 fingerprint of the server host key, once the handshake is done
+    fn host_key_fingerprint(&self) -> Option<String>;
+
+    /// Server host key in SSH wire format, once the handshake is done
+    fn host_key(&self) -> Option<Vec<u8>>;
+
+    /// What the handshake negotiated
+    fn session_info(&self) -> SessionInfo;
+
+    /// Whether the server still answers, sends a keepalive
+    fn alive(&self) -> bool;
+
+    /// Set once the connection to the server is known to be gone
+    fn lost(&self) -> &SessionLost;
+
+    /// End the session with a disconnect message giving `description`, so
+    /// the server logs an orderly logout and drops the channels with it
+    /// instead of waiting for its own timeouts. Nothing is sent once the
+    /// session is lost or was disconnected before.
+    fn disconnect(&self, description: &str);
+
+    /// After an operation of the session or one of its channels failed:
+    /// whether it was the connection to the server that failed
+    fn connection_failed(&self) -> bool;
+
+    /// Run a command on the server and wait for it to finish
+    fn exec(&self, command: &str) -> anyhow::Result<ExecOutput>;
+
+    /// Open a file on the server through SFTP, for reading, or with `create`
+    /// for writing: created with that mode when it doesn't exist, emptied
+    /// when it does. Why it failed shows in the `SftpError` of a failure.
+    fn sftp_open(&self, path: &Path, create: Option<u32>) -> anyhow::Result<Self::RemoteFile>;
+
+    /// What SFTP knows about a file on the server, symlinks followed
+    fn sftp_stat(&self, path: &Path) -> anyhow::Result<FileAttrs>;
+
+    /// Change the permissions and times of a file on the server to the ones
+    /// set in `attrs`
+    fn sftp_setstat(&self, path: &Path, attrs: FileAttrs) -> anyhow::Result<()>;
+
+    /// Have the server listen on a free port on all its addresses and pass
+    /// the connections to it on to us, returns the port it got
+    fn forward_listen(&self) -> anyhow::Result<(Self::Listener, u16)>;
+
+    /// Wait up to `timeout` for the next connection to `listener`
+    fn forward_accept(
+        &self,
+        listener: &mut Self::Listener,
+        timeout: Duration,
+    ) -> anyhow::Result<(Self::Channel, Self::Stream, Self::Stream)>;
+
+    /// Address of the SSH server, None when the session doesn't run over a
+    /// TCP connection of ours
+    fn server_ip(&self) -> Option<IpAddr>;
+}
+
+/// Longest wait for the disconnect message to go out on exit
+const DISCONNECT_TIMEOUT_MS: u32 = 1000;
+/// libssh2 reports a blocking call that ran into the session timeout with this
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+/// and a channel open the server refused with this
+const LIBSSH2_ERROR_CHANNEL_FAILURE: i32 = -21;
+/// and a key file it couldn't load, which includes an encrypted key tried
+/// without or with a wrong passphrase
+const LIBSSH2_ERROR_FILE: i32 = -16;
+/// and a global request such as tcpip-forward the server turned down with this
+const LIBSSH2_ERROR_REQUEST_DENIED: i32 = -32;
+/// SFTP status codes of a missing file or directory and a denied access
+const LIBSSH2_FX_NO_SUCH_FILE: i32 = 2;
+const LIBSSH2_FX_PERMISSION_DENIED: i32 = 3;
+const LIBSSH2_FX_NO_SUCH_PATH: i32 = 10;
+/// Handshake failures worth a hint
+const LIBSSH2_ERROR_SOCKET_NONE: i32 = -1;
+const LIBSSH2_ERROR_KEX_FAILURE: i32 = -5;
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_KEY_EXCHANGE_FAILURE: i32 = -8;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
+/// Session timeout while `exec` reads, so waiting on one output gives way to
+/// the other
+const EXEC_POLL_MS: u32 = 20;
+/// How often the socket is looked at while waiting for the server's banner
+const GREETING_POLL: Duration = Duration::from_millis(10);
+/// How long closing a channel waits for the server to close its end
+const CHANNEL_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Named pipe of the OpenSSH agent shipped with Windows
+#[cfg(windows)]
+const OPENSSH_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+/// Read stdout and stderr of a command by turns until both ended, keeping up
+/// to `limit` bytes of each. Reading one to its end first would leave the
+/// other to fill the channel window, and the command would never finish. A
+/// read that finds nothing yet times out or would block; `eof` tells a read
+/// of nothing at the end from one that came back empty.
+fn read_exec_output(
+    stdout: &mut impl Read,
+    stderr: &mut impl Read,
+    eof: impl Fn() -> bool,
+    limit: usize,
+) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut outputs = [(Vec::new(), false), (Vec::new(), false)];
+    let mut streams: [&mut dyn Read; 2] = [stdout, stderr];
+    let mut buf = [0u8; 16384];
+    while outputs.iter().any(|(_, ended)| !ended) {
+        for (stream, (output, ended)) in streams.iter_mut().zip(outputs.iter_mut()) {
+            if *ended {
+                continue;
+            }
+            match stream.read(&mut buf) {
+                Ok(0) => *ended = eof(),
+                Ok(n) => {
+                    let keep = n.min(limit - output.len());
+                    output.extend_from_slice(&buf[..keep]);
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    let [(stdout, _), (stderr, _)] = outputs;
+    Ok((stdout, stderr))
+}
+
+/// Repeat `op` as long as it runs into the session timeout, until `deadline`
+fn retry_timeouts<R>(
+    deadline: Instant,
+    mut op: impl FnMut() -> Result<R, ssh2::Error>,
+) -> Result<R, ssh2::Error> {
+    loop {
+        match op() {
+            Err(e)
+                if e.code() == ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT)
+                    && Instant::now() < deadline => {}
+            result => return result,
+        }
+    }
+}
+
+/// What the server sent before the handshake, looked at without taking it
+/// off the socket so a failed handshake can tell what answered
+struct Greeting {
+    data: Vec<u8>,
+    /// The connection ended before a complete line came in
+    closed: bool,
+}
+
+impl Greeting {
+    /// Wait for the first line from the server until `deadline`
+    fn peek(socket: SockRef, deadline: Instant) -> Greeting {
+        let mut buf = [MaybeUninit::<u8>::uninit(); 256];
+        // The socket may be blocking, don't let a peek outlast the deadline
+        let _ = socket.set_read_timeout(Some(GREETING_POLL));
+        let mut greeting = Greeting {
+            data: vec![],
+            closed: false,
+        };
+        loop {
+            match socket.peek(&mut buf) {
+                Ok(0) => greeting.closed = true,
+                Ok(n) => {
+                    // peek initialized the first n bytes
+                    let data = unsafe { std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), n) };
+                    greeting.data = data.to_vec();
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => greeting.closed = true,
+            }
+            if greeting.closed
+                || greeting.data.contains(&b'\n')
+                || greeting.data.len() == buf.len()
+                || Instant::now() >= deadline
+            {
+                break;
+            }
+            std::thread::sleep(GREETING_POLL);
+        }
+        let _ = socket.set_read_timeout(None);
+        greeting
+    }
+
+    /// The identification line, what follows "SSH-2.0-" names the software.
+    /// Servers may send other lines before it.
+    fn banner(&self) -> Option<String> {
+        String::from_utf8_lossy(&self.data)
+            .lines()
+            .find(|l| l.starts_with("SSH-"))
+            .map(|l| l.trim_end().to_string())
+    }
+
+    /// What was received, printable for an error message
+    fn describe(&self) -> String {
+        let text: String = String::from_utf8_lossy(&self.data)
+            .chars()
+            .take(60)
+            .flat_map(char::escape_default)
+            .collect();
+        format!("\"{}\"", text)
+    }
+}
+
+/// Run the SSH handshake and explain a failure with what the server sent
+/// and what the common causes are
+fn run_handshake(
+    session: &mut Session,
+    greeting: &Greeting,
+    deadline: Instant,
+    options: &HandshakeOptions,
+) -> anyhow::Result<()> {
+    if greeting.data.is_empty() {
+        if greeting.closed {
+            bail!("the remote end closed the connection without presenting an SSH banner");
+        }
+        bail!(
+            "the remote end did not present an SSH banner within the handshake timeout. Is it \
+             really an SSH port? Servers of protocols where the client speaks first, such as \
+             HTTPS, wait silently, as does a connection a firewall drops"
+        );
+    }
+    let banner = match greeting.banner() {
+        Some(banner) => banner,
+        None => bail!(
+            "the remote end did not present an SSH banner, it sent {}. Is it really an SSH port?",
+            greeting.describe()
+        ),
+    };
+    session.set_compress(options.compress);
+    for kind in Algorithms::ALL {
+        let Some(list) = options.preference(kind) else {
+            continue;
+        };
+        for &t in kind.method_types() {
+            session.method_pref(t, list).map_err(|e| {
+                anyhow!(
+                    "none of the {} algorithms {} can be used: {}",
+                    kind.name(),
+                    list,
+                    e
+                )
+            })?;
+        }
+    }
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    session.set_timeout(remaining.as_millis().clamp(1, u32::MAX as u128) as u32);
+    let result = session.handshake();
+    session.set_timeout(0);
+    let e = match result {
+        Ok(()) => {
+            if options.compress {
+                report_compression(session);
+            }
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+    let hint = match e.code() {
+        ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT) => {
+            ", the handshake did not finish within the handshake timeout".to_string()
+        }
+        ErrorCode::Session(LIBSSH2_ERROR_KEX_FAILURE | LIBSSH2_ERROR_KEY_EXCHANGE_FAILURE) => {
+            format!(
+                ". The server offers no key exchange, host key, cipher or MAC algorithm of ours \
+                 ({}); old servers may only offer legacy ones such as diffie-hellman-group1-sha1 \
+                 or ssh-dss. `ssh -vv` or `nmap --script ssh2-enum-algos` list what the server \
+                 offers, --kex, --hostkey-algorithms, --cipher and --mac choose what we offer",
+                offered(options)
+            )
+        }
+        // libssh2 reports a connection lost during the key exchange as
+        // "no socket" too
+        ErrorCode::Session(
+            LIBSSH2_ERROR_SOCKET_NONE
+            | LIBSSH2_ERROR_SOCKET_SEND
+            | LIBSSH2_ERROR_SOCKET_DISCONNECT
+            | LIBSSH2_ERROR_SOCKET_RECV,
+        ) => ". The connection was closed or reset during the handshake: old servers close it \
+              when they have no algorithm in common with libssh2, middleboxes may reset it"
+            .to_string(),
+        _ => String::new(),
+    };
+    let negotiated: Vec<String> = [
+        ("kex", MethodType::Kex),
+        ("host key", MethodType::HostKey),
+        ("cipher", MethodType::CryptCs),
+    ]
+    .into_iter()
+    .filter_map(|(name, t)| session.methods(t).map(|m| format!("{} {}", name, m)))
+    .collect();
+    let negotiated = match negotiated.is_empty() {
+        true => String::new(),
+        false => format!(" (negotiated so far: {})", negotiated.join(", ")),
+    };
+    Err(anyhow!(
+        "SSH handshake with {} failed{}: {}{}",
+        banner,
+        negotiated,
+        e,
+        hint
+    ))
+}
+
+/// The algorithms the handshake offered, for a failed negotiation
+fn offered(options: &HandshakeOptions) -> String {
+    let list = |kind: Algorithms| match options.preference(kind) {
+        Some(list) => format!("{} {}", kind.name(), list),
+        None => match kind.supported() {
+            Ok(algs) => format!("{} {}", kind.name(), algs.join(",")),
+            Err(_) => format!("{} libssh2's defaults", kind.name()),
+        },
+    };
+    let lists: Vec<String> = Algorithms::ALL.into_iter().map(list).collect();
+    lists.join("; ")
+}
+
+/// Whether the server went along with compression, it may only offer none
+fn report_compression(session: &Session) {
+    match session.methods(MethodType::CompCs) {
+        Some("none") | None => {
+            warn!("The server does not support compression, the session is uncompressed")
+        }
+        Some(method) => info!("Compression negotiated: {}", method),
+    }
+}
+
+/// Hands the keyboard-interactive prompts of libssh2 to a closure
+struct Prompter<'a>(&'a mut AnswerPrompts);
+
+impl KeyboardInteractivePrompt for Prompter<'_> {
+    fn prompt<'b>(
+        &mut self,
+        _user: &str,
+        instructions: &str,
+        prompts: &[Prompt<'b>],
+    ) -> Vec<String> {
+        let prompts: Vec<(String, bool)> = prompts
+            .iter()
+            .map(|p| (p.text.to_string(), p.echo))
+            .collect();
+        (self.0)(instructions, &prompts)
+    }
+}
+
+/// libssh2 backend via the ssh2 crate
+#[derive(Clone)]
+pub struct Ssh2Transport {
+    session: Session,
+    /// libssh2 keeps the progress of a channel open that timed out in the
+    /// session and picks it up on the next open, whatever that one asked for.
+    /// Opens therefore run one at a time.
+    open_lock: Arc<Mutex<()>>,
+    channel_open_timeout: Duration,
+    activity: Arc<Activity>,
+    lost: Arc<SessionLost>,
+    /// The SFTP subsystem, started on first use
+    sftp: Arc<Mutex<Option<Arc<Sftp>>>>,
+    server_ip: Option<IpAddr>,
+}
+
+impl Ssh2Transport {
+    fn sftp(&self) -> anyhow::Result<Arc<Sftp>> {
+        let mut sftp = self.sftp.lock().unwrap();
+        if let Some(sftp) = &*sftp {
+            return Ok(sftp.clone());
+        }
+        let started = {
+            let _open = self.open_lock.lock().unwrap();
+            self.session.sftp()
+        };
+        let started = Arc::new(started.map_err(|e| anyhow!("starting SFTP: {}", e))?);
+        *sftp = Some(started.clone());
+        Ok(started)
+    }
+}
+
+impl DataWait for Ssh2Transport {
+    fn data_generation(&self) -> u64 {
+        self.activity.generation()
+    }
+
+    fn wait_for_data(&self, seen: u64, timeout: Duration) {
+        self.activity.wait(seen, timeout)
+    }
+
+    fn data_read(&self) {
+        self.activity.note()
+    }
+}
+
+impl SshTransport for Ssh2Transport {
+    type Stream = Stream;
+    type Channel = Channel;
+    type RemoteFile = ssh2::File;
+    type Listener = Listener;
+
+    fn handshake(tcp: TcpStream, options: &HandshakeOptions) -> anyhow::Result<Self> {
+        let mut session = Session::new()?;
+        #[cfg(unix)]
+        let activity = Activity::new(tcp.as_fd())?;
+        #[cfg(not(unix))]
+        let activity = Activity::new();
+        if let Some(tos) = options.ip_tos {
+            if let Err(e) = crate::tos::set(SockRef::from(&tcp), tos) {
+                warn!("Setting the IP TOS of the SSH connection failed: {}", e);
+            }
+        }
+        let server_ip = tcp
+            .peer_addr()
+            .ok()
+            .filter(|_| !options.via_proxy)
+            .map(|a| a.ip());
+        let deadline = Instant::now() + options.timeout;
+        let greeting = Greeting::peek(SockRef::from(&tcp), deadline);
+        session.set_tcp_stream(tcp);
+        run_handshake(&mut session, &greeting, deadline, options)?;
+        Ok(Ssh2Transport {
+            session,
+            open_lock: Arc::new(Mutex::new(())),
+            channel_open_timeout: options.channel_open_timeout,
+            activity,
+            lost: SessionLost::new(),
+            sftp: Arc::default(),
+            server_ip,
+        })
+    }
+
+    #[cfg(unix)]
+    fn handshake_socket(socket: UnixStream, options: &HandshakeOptions) -> anyhow::Result<Self> {
+        let mut session = Session::new()?;
+        let activity = Activity::new(socket.as_fd())?;
+        let deadline = Instant::now() + options.timeout;
+        let greeting = Greeting::peek(SockRef::from(&socket), deadline);
+        session.set_tcp_stream(socket);
+        run_handshake(&mut session, &greeting, deadline, options)?;
+        Ok(Ssh2Transport {
+            session,
+            open_lock: Arc::new(Mutex::new(())),
+            channel_open_timeout: options.channel_open_timeout,
+            activity,
+            lost: SessionLost::new(),
+            sftp: Arc::default(),
+            server_ip: None,
+        })
+    }
+
+    fn userauth_agent(&self, user: &str, socket: Option<&Path>) -> anyhow::Result<()> {
+        let socket = match socket {
+            Some(s) => s,
+            // The OpenSSH agent that comes with Windows listens on a named
+            // pipe, libssh2 on its own may only look for Pageant
+            #[cfg(windows)]
+            None => {
+                return match self.userauth_agent(user, Some(Path::new(OPENSSH_AGENT_PIPE))) {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        debug!("OpenSSH agent: {}, trying Pageant", e);
+                        Ok(self.session.userauth_agent(user)?)
+                    }
+                };
+            }
+            #[cfg(not(windows))]
+            None => return Ok(self.session.userauth_agent(user)?),
+        };
+        let mut agent = self.session.agent()?;
+        agent.set_identity_path(socket)?;
+        agent.connect()?;
+        agent.list_identities()?;
+        let mut result = Err(anyhow!("no identities in agent at {}", socket.display()));
+        for identity in agent.identities()? {
+            match agent.userauth(user, &identity) {
+                Ok(_) => {
+                    result = Ok(());
+                    break;
+                }
+                Err(e) => {
+                    debug!("Agent identity {} rejected: {}", identity.comment(), e);
+                    result = Err(e.into());
+                }
+            }
+        }
+        agent.disconnect()?;
+        result
+    }
+
+    fn agent_identities(socket: Option<&Path>) -> anyhow::Result<Vec<AgentIdentity>> {
+        // The agent is independent of any server, an unconnected session will do
+        let session = Session::new()?;
+        let mut agent = session.agent()?;
+        #[cfg(windows)]
+        let socket = socket.or(Some(Path::new(OPENSSH_AGENT_PIPE)));
+        if let Some(socket) = socket {
+            agent.set_identity_path(socket)?;
+        }
+        agent.connect()?;
+        agent.list_identities()?;
+        let identities = agent
+            .identities()?
+            .iter()
+            .map(|identity| AgentIdentity {
+                key_type: key_type(identity.blob()).unwrap_or("unknown").to_string(),
+                fingerprint: sha256_fingerprint(&sha256(identity.blob())),
+                comment: identity.comment().to_string(),
+            })
+            .collect();
+        agent.disconnect()?;
+        Ok(identities)
+    }
+
+    fn userauth_password(&self, user: &str, password: &str) -> anyhow::Result<()> {
+        Ok(self.session.userauth_password(user, password)?)
+    }
+
+    fn userauth_key_file(
+        &self,
+        user: &str,
+        key: &Path,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<()> {
+        Ok(self
+            .session
+            .userauth_pubkey_file(user, None, key, passphrase)?)
+    }
+
+    fn userauth_keyboard_interactive(
+        &self,
+        user: &str,
+        answer: &mut AnswerPrompts,
+    ) -> anyhow::Result<()> {
+        Ok(self
+            .session
+            .userauth_keyboard_interactive(user, &mut Prompter(answer))?)
+    }
+
+    fn authenticated(&self) -> bool {
+        self.session.authenticated()
+    }
+
+    fn open_direct_tcpip(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> anyhow::Result<(Self::Channel, Self::Stream, Self::Stream)> {
+        let _open = self.open_lock.lock().unwrap();
+        // The session timeout is kept short for the copy loops, an open that
+        // runs into it is resumed until the deadline
+        let c = retry_timeouts(Instant::now() + self.channel_open_timeout, || {
+            self.session
+                .channel_direct_tcpip(host, port, Some((host, port)))
+        })
+        .map_err(ChannelOpenError::from_ssh2)?;
+        // Every direct-tcpip channel carries its data on stream 0, other
+        // stream ids are extended data such as stderr of a command
+        let writer_stream = c.stream(0);
+        let reader_stream = c.stream(0);
+        Ok((c, reader_stream, writer_stream))
+    }
+
+    fn probe_direct_tcpip(&self, host: &str, port: u16) -> (ProbeOutcome, Duration) {
+        let open = self.open_lock.lock().unwrap();
+        let started = Instant::now();
+        let opened = retry_timeouts(started + self.channel_open_timeout, || {
+            self.session
+                .channel_direct_tcpip(host, port, Some((host, port)))
+        });
+        let elapsed = started.elapsed();
+        drop(open);
+        let outcome = match opened {
+            Ok(channel) => {
+                if let Err(e) = self.close_channel(channel) {
+                    debug!("Closing the channel to {}:{}: {}", host, port, e);
+                }
+                ProbeOutcome::Open
+            }
+            Err(e) => match ChannelOpenError::from_ssh2(e) {
+                ChannelOpenError::ConnectFailed => ProbeOutcome::ConnectFailed,
+                ChannelOpenError::Prohibited => ProbeOutcome::Prohibited,
+                ChannelOpenError::TimedOut => ProbeOutcome::TimedOut,
+                e => ProbeOutcome::Failed(e.to_string()),
+            },
+        };
+        (outcome, elapsed)
+    }
+
+    fn channel_eof(&self, channel: &Channel) -> bool {
+        channel.eof()
+    }
+
+    fn send_eof(&self, channel: &mut Channel) -> anyhow::Result<()> {
+        let deadline = Instant::now() + CHANNEL_CLOSE_TIMEOUT;
+        retry_timeouts(deadline, || channel.send_eof())?;
+        Ok(())
+    }
+
+    fn close_channel(&self, mut channel: Channel) -> anyhow::Result<()> {
+        let deadline = Instant::now() + CHANNEL_CLOSE_TIMEOUT;
+        // libssh2 sends the EOF itself when it wasn't sent yet
+        retry_timeouts(deadline, || channel.close())?;
+        retry_timeouts(deadline, || channel.wait_close())?;
+        Ok(())
+    }
+
+    fn set_timeout(&self, timeout_ms: u32) {
+        self.session.set_timeout(timeout_ms)
+    }
+
+    fn host_key_fingerprint(&self) -> Option<String> {
+        self.session
+            .host_key_hash(HashType::Sha256)
+            .map(sha256_fingerprint)
+    }
+
+    fn host_key(&self) -> Option<Vec<u8>> {
+        self.session.host_key().map(|(key, _)| key.to_vec())
+    }
+
+    fn session_info(&self) -> SessionInfo {
+        let method = |t| self.session.methods(t).unwrap_or("-").to_string();
+        let pair = |cs, sc| {
+            let (cs, sc) = (method(cs), method(sc));
+            if cs == sc {
+                cs
+            } else {
+                format!("{} / {}", cs, sc)
+            }
+        };
+        SessionInfo {
+            server_version: self.session.banner().unwrap_or("-").to_string(),
+            kex: method(MethodType::Kex),
+            host_key: method(MethodType::HostKey),
+            cipher: pair(MethodType::CryptCs, MethodType::CryptSc),
+            mac: pair(MethodType::MacCs, MethodType::MacSc),
+            compression: pair(MethodType::CompCs, MethodType::CompSc),
+        }
+    }
+
+    fn alive(&self) -> bool {
+        // libssh2 only sends keepalives once an interval is set
+        self.session.set_keepalive(true, 1);
+        self.session.keepalive_send().is_ok()
+    }
+
+    fn lost(&self) -> &SessionLost {
+        &self.lost
+    }
+
+    fn disconnect(&self, description: &str) {
+        if !self.lost.close() {
+            return;
+        }
+        // The process is about to end, it can't wait for a stuck socket
+        self.session.set_timeout(DISCONNECT_TIMEOUT_MS);
+        match self
+            .session
+            .disconnect(Some(DisconnectCode::ByApplication), description, None)
+        {
+            Ok(()) => debug!("Disconnected from the SSH server"),
+            Err(e) => debug!("Disconnecting from the SSH server: {}", e),
+        }
+    }
+
+    fn connection_failed(&self) -> bool {
+        ssh2::Error::last_session_error(&self.session).is_some_and(|e| {
+            matches!(
+                e.code(),
+                ErrorCode::Session(
+                    LIBSSH2_ERROR_SOCKET_NONE
+                        | LIBSSH2_ERROR_SOCKET_SEND
+                        | LIBSSH2_ERROR_SOCKET_DISCONNECT
+                        | LIBSSH2_ERROR_SOCKET_RECV
+                )
+            )
+        })
+    }
+
+    fn exec(&self, command: &str) -> anyhow::Result<ExecOutput> {
+        let mut channel = {
+            let _open = self.open_lock.lock().unwrap();
+            self.session.channel_session()?
+        };
+        channel.exec(command)?;
+        let timeout = self.session.timeout();
+        if timeout == 0 || timeout > EXEC_POLL_MS {
+            self.session.set_timeout(EXEC_POLL_MS);
+        }
+        let read = read_exec_output(
+            &mut channel.stream(0),
+            &mut channel.stderr(),
+            || channel.eof(),
+            EXEC_OUTPUT_LIMIT,
+        )
+        .map_err(anyhow::Error::from)
+        .and_then(|output| {
+            retry_timeouts(Instant::now() + CHANNEL_CLOSE_TIMEOUT, || {
+                channel.wait_close()
+            })?;
+            Ok(output)
+        });
+        self.session.set_timeout(timeout);
+        let (stdout, stderr) = read?;
+        Ok(ExecOutput::from_bytes(
+            channel.exit_status()?,
+            &stdout,
+            &stderr,
+        ))
+    }
+
+    fn sftp_open(&self, path: &Path, create: Option<u32>) -> anyhow::Result<ssh2::File> {
+        let (flags, mode) = match create {
+            Some(mode) => (
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                mode,
+            ),
+            None => (OpenFlags::READ, 0),
+        };
+        Ok(self
+            .sftp()?
+            .open_mode(path, flags, mode as i32, OpenType::File)
+            .map_err(SftpError::from_ssh2)?)
+    }
+
+    fn sftp_stat(&self, path: &Path) -> anyhow::Result<FileAttrs> {
+        let stat = self.sftp()?.stat(path).map_err(SftpError::from_ssh2)?;
+        Ok(FileAttrs {
+            size: stat.size,
+            mode: stat.perm,
+            atime: stat.atime,
+            mtime: stat.mtime,
+        })
+    }
+
+    fn sftp_setstat(&self, path: &Path, attrs: FileAttrs) -> anyhow::Result<()> {
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: attrs.mode,
+            atime: attrs.atime,
+            mtime: attrs.mtime,
+        };
+        Ok(self
+            .sftp()?
+            .setstat(path, stat)
+            .map_err(SftpError::from_ssh2)?)
+    }
+
+    fn forward_listen(&self) -> anyhow::Result<(Listener, u16)> {
+        let _open = self.open_lock.lock().unwrap();
+        let listened = retry_timeouts(Instant::now() + self.channel_open_timeout, || {
+            self.session.channel_forward_listen(0, None, None)
+        });
+        match listened {
+            Ok(listening) => Ok(listening),
+            Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_REQUEST_DENIED) => bail!(
+                "the SSH server refused to listen for us, check AllowTcpForwarding in its \
+                 sshd_config"
+            ),
+            Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT) => {
+                bail!("no answer from the SSH server within --channel-open-timeout-ms")
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn forward_accept(
+        &self,
+        listener: &mut Listener,
+        timeout: Duration,
+    ) -> anyhow::Result<(Channel, Stream, Stream)> {
+        match retry_timeouts(Instant::now() + timeout, || listener.accept()) {
+            Ok(c) => {
+                let (reader_stream, writer_stream) = (c.stream(0), c.stream(0));
+                Ok((c, reader_stream, writer_stream))
+            }
+            Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT) => {
+                bail!("nobody connected within {:?}", timeout)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn server_ip(&self) -> Option<IpAddr> {
+        self.server_ip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io;
+
+    /// The two outputs of a command over a channel with a window: stdout
+    /// has nothing to read until the reader took in all but `window` bytes
+    /// of stderr, as with a server that can't send while the window is full
+    struct Command {
+        stdout: Cell<usize>,
+        stderr: Cell<usize>,
+        window: usize,
+    }
+
+    struct Output<'a> {
+        command: &'a Command,
+        stderr: bool,
+    }
+
+    impl Read for Output<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let c = self.command;
+            let (left, byte) = match self.stderr {
+                true => (&c.stderr, b'e'),
+                false if c.stderr.get() > c.window => {
+                    return Err(io::Error::from(ErrorKind::TimedOut))
+                }
+                false => (&c.stdout, b'o'),
+            };
+            let n = left.get().min(buf.len()).min(1000);
+            left.set(left.get() - n);
+            buf[..n].fill(byte);
+            Ok(n)
+        }
+    }
+
+    fn run(stdout: usize, stderr: usize, limit: usize) -> (Vec<u8>, Vec<u8>) {
+        let command = Command {
+            stdout: Cell::new(stdout),
+            stderr: Cell::new(stderr),
+            window: 2000,
+        };
+        let output = |stderr| Output {
+            command: &command,
+            stderr,
+        };
+        let eof = || command.stdout.get() == 0 && command.stderr.get() == 0;
+        read_exec_output(&mut output(false), &mut output(true), eof, limit).unwrap()
+    }
+
+    #[test]
+    fn stderr_beyond_the_window_does_not_stall_stdout() {
+        let (stdout, stderr) = run(5000, 50_000, EXEC_OUTPUT_LIMIT);
+        assert_eq!(stdout, vec![b'o'; 5000]);
+        assert_eq!(stderr, vec![b'e'; 50_000]);
+    }
+
+    #[test]
+    fn output_beyond_the_limit_is_read_and_dropped() {
+        let (stdout, stderr) = run(30_000, 20_000, 10_000);
+        assert_eq!(stdout, vec![b'o'; 10_000]);
+        assert_eq!(stderr, vec![b'e'; 10_000]);
+    }
+
+    /// Hands out `reads` in turn, then reads nothing; `left` counts the
+    /// ones still to come
+    struct Reads<'a> {
+        reads: Vec<&'static [u8]>,
+        left: &'a Cell<usize>,
+    }
+
+    impl Read for Reads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.reads.is_empty() {
+                return Ok(0);
+            }
+            let read = self.reads.remove(0);
+            self.left.set(self.reads.len());
+            buf[..read.len()].copy_from_slice(read);
+            Ok(read.len())
+        }
+    }
+
+    #[test]
+    fn empty_reads_before_eof_do_not_end_the_output() {
+        let left = Cell::new(3);
+        let mut stderr = Reads {
+            reads: vec![b"", b"", b"late"],
+            left: &left,
+        };
+        let eof = || left.get() == 0;
+        let (stdout, stderr) = read_exec_output(&mut io::empty(), &mut stderr, eof, 10).unwrap();
+        assert!(stdout.is_empty());
+        assert_eq!(stderr, b"late");
+    }
+
+    #[test]
+    fn output_that_is_not_utf8_is_replaced() {
+        let output = ExecOutput::from_bytes(0, b"ok \xff\xfe", b"\xc3");
+        assert_eq!(output.stdout, "ok \u{fffd}\u{fffd}");
+        assert_eq!(output.stderr, "\u{fffd}");
+    }
+
+    /// What a handshake with a server that runs `serve` fails with, and
+    /// how long it took
+    async fn handshake_error<F, Fut>(timeout: Duration, serve: F) -> (String, Duration)
+    where
+        F: FnOnce(TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            serve(socket).await
+        });
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let options = HandshakeOptions {
+            timeout,
+            channel_open_timeout: Duration::from_secs(5),
+            compress: false,
+            ip_tos: None,
+            via_proxy: false,
+            kex: None,
+            host_keys: None,
+            ciphers: None,
+            macs: None,
+        };
+        let started = Instant::now();
+        let result = tokio::task::spawn_blocking(move || Ssh2Transport::handshake(tcp, &options))
+            .await
+            .unwrap();
+        match result {
+            Ok(_) => panic!("the handshake went through"),
+            Err(e) => (e.to_string(), started.elapsed()),
+        }
+    }
+
+    #[tokio::test]
+    async fn an_echo_server_times_out_without_a_banner() {
+        let timeout = Duration::from_millis(500);
+        let (e, took) = handshake_error(timeout, |mut socket| async move {
+            let (mut rd, mut wr) = socket.split();
+            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+        })
+        .await;
+        assert!(
+            e.contains("did not present an SSH banner within the handshake timeout"),
+            "{}",
+            e
+        );
+        assert!(took < timeout * 4, "took {:?}", took);
+    }
+
+    #[tokio::test]
+    async fn garbage_is_shown() {
+        let (e, _) = handshake_error(Duration::from_secs(5), |mut socket| async move {
+            use tokio::io::AsyncWriteExt;
+            let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        })
+        .await;
+        assert!(
+            e.contains("did not present an SSH banner, it sent"),
+            "{}",
+            e
+        );
+        assert!(e.contains("HTTP/1.1 400 Bad Request"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn a_close_before_the_banner_says_so() {
+        let (e, _) = handshake_error(Duration::from_secs(5), |socket| async move {
+            drop(socket);
+        })
+        .await;
+        assert!(
+            e.contains("closed the connection without presenting an SSH banner"),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn a_close_after_the_banner_names_the_server() {
+        let (e, _) = handshake_error(Duration::from_secs(5), |mut socket| async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let _ = socket.write_all(b"SSH-2.0-Ancient_1.0\r\n").await;
+            // Take the client's banner, then go away mid-handshake
+            let mut line = [0u8; 64];
+            let _ = socket.read(&mut line).await;
+        })
+        .await;
+        assert!(
+            e.contains("SSH handshake with SSH-2.0-Ancient_1.0 failed"),
+            "{}",
+            e
+        );
+        assert!(e.contains("closed or reset during the handshake"), "{}", e);
+    }
+
+    fn open_failure(code: i32, message: &'static str) -> ChannelOpenError {
+        ChannelOpenError::from_ssh2(ssh2::Error::new(ErrorCode::Session(code), message))
+    }
+
+    /// The messages of libssh2's channel.c for each reason code
+    #[test]
+    fn channel_open_failures_by_reason() {
+        let failure = |message| open_failure(LIBSSH2_ERROR_CHANNEL_FAILURE, message);
+        assert!(matches!(
+            failure("Channel open failure (connect failed)"),
+            ChannelOpenError::ConnectFailed
+        ));
+        assert!(matches!(
+            failure("Channel open failure (administratively prohibited)"),
+            ChannelOpenError::Prohibited
+        ));
+        assert!(matches!(
+            failure("Channel open failure (resource shortage)"),
+            ChannelOpenError::ResourceShortage
+        ));
+        assert!(matches!(
+            failure("Channel open failure (unknown channel type)"),
+            ChannelOpenError::Other(_)
+        ));
+        assert!(matches!(
+            failure("Channel open failure"),
+            ChannelOpenError::Other(_)
+        ));
+    }
+
+    #[test]
+    fn channel_open_timeout_and_other_errors() {
+        assert!(matches!(
+            open_failure(LIBSSH2_ERROR_TIMEOUT, "Would block waiting for channel"),
+            ChannelOpenError::TimedOut
+        ));
+        // The reason text alone doesn't make a refused open
+        assert!(matches!(
+            open_failure(LIBSSH2_ERROR_SOCKET_DISCONNECT, "connect failed"),
+            ChannelOpenError::Other(_)
+        ));
+    }
+
+    #[test]
+    fn sftp_errors_by_status() {
+        let status = |code| SftpError::from_ssh2(ssh2::Error::new(ErrorCode::SFTP(code), "sftp"));
+        assert!(matches!(
+            status(LIBSSH2_FX_NO_SUCH_FILE),
+            SftpError::NoSuchFile
+        ));
+        assert!(matches!(
+            status(LIBSSH2_FX_NO_SUCH_PATH),
+            SftpError::NoSuchFile
+        ));
+        assert!(matches!(
+            status(LIBSSH2_FX_PERMISSION_DENIED),
+            SftpError::PermissionDenied
+        ));
+        assert!(matches!(status(4), SftpError::Other(_)));
+    }
+
+    #[test]
+    fn unreadable_key_by_error_code() {
+        let error = |code, message| {
+            anyhow::Error::from(ssh2::Error::new(ErrorCode::Session(code), message))
+        };
+        assert!(key_unreadable(&error(
+            LIBSSH2_ERROR_FILE,
+            "Unable to extract public key from private key file"
+        )));
+        assert!(!key_unreadable(&error(
+            -19,
+            "Wrong passphrase or invalid/unrecognized private key file format"
+        )));
+        assert!(!key_unreadable(&anyhow!("passphrase")));
+    }
+}
@@ -0,0 +1,279 @@
+use crate::addr::{split_host_port, valid_host};
+use crate::config::AppConfig;
+use crate::transport::Algorithms;
+
+/// Exit status when the options don't make sense, the same clap uses for
+/// command line errors
+pub const EXIT_INVALID_CONFIG: i32 = 2;
+
+/// Check the effective settings before anything touches the network, so a
+/// typo is reported with the flag it came from instead of failing deep in a
+/// connect or channel open. Returns every problem found.
+pub fn validate(config: &AppConfig) -> Vec<String> {
+    let mut problems = vec![];
+    if let Some(addr) = &config.sshaddress {
+        check_address("--sshaddress", addr, false, &mut problems);
+    }
+    if !config.dynamic {
+        if config.remote_srv.is_empty() {
+            problems.push("--remote-srv is empty".to_string());
+        } else if !valid_host(&config.remote_srv) {
+            problems.push(format!(
+                "--remote-srv '{}' is not a valid host name or address",
+                config.remote_srv
+            ));
+        }
+        if config.remote_port == 0 {
+            problems.push("--remote-port 0 is not a port, use 1-65535".to_string());
+        }
+        if !config.dest_allow.is_empty() || config.dest_allow_file.is_some() {
+            problems.push("--dest-allow and --dest-allow-file only apply with -D".to_string());
+        }
+    }
+    let local = &config.local_srv_address;
+    match local.strip_prefix("unix:") {
+        Some("") => problems.push("--local-srv-address 'unix:' needs a socket path".to_string()),
+        Some(_) => {}
+        None if local.starts_with(crate::local::PIPE_PREFIX) => {}
+        None => {
+            check_address("--local-srv-address", local, true, &mut problems);
+            if config.unix_mode.is_some() || config.unix_owner.is_some() {
+                problems.push(format!(
+                    "--unix-mode and --unix-owner need a unix: --local-srv-address, not '{}'",
+                    local
+                ));
+            }
+        }
+    }
+    if let Some(addr) = config.api_addr {
+        if !addr.ip().is_loopback() && config.api_token.is_none() {
+            problems.push(format!(
+                "--api-addr {} is not loopback, set --api-token",
+                addr
+            ));
+        }
+    }
+    if let Some(server) = &config.dns_server {
+        check_address("--dns-server", server, false, &mut problems);
+    }
+    if config.auth_order.is_empty() {
+        problems.push("--auth-order names no method".to_string());
+    }
+    for (kind, algs) in [
+        (Algorithms::Kex, &config.kex),
+        (Algorithms::HostKey, &config.hostkey_algorithms),
+        (Algorithms::Cipher, &config.cipher),
+        (Algorithms::Mac, &config.mac),
+    ] {
+        if let Some(algs) = algs {
+            check_algorithms(kind, algs, &mut problems);
+        }
+    }
+    problems
+}
+
+/// Every name of a comma separated list is one libssh2 knows; it ignores
+/// unknown ones silently, so a typo would just narrow down what is offered
+fn check_algorithms(kind: Algorithms, algs: &str, problems: &mut Vec<String>) {
+    let supported = match kind.supported() {
+        Ok(supported) => supported,
+        Err(e) => {
+            problems.push(format!(
+                "{}: can't ask libssh2 what it supports: {}",
+                kind.flag(),
+                e
+            ));
+            return;
+        }
+    };
+    let unknown: Vec<&str> = algs
+        .split(',')
+        .filter(|alg| !supported.contains(alg))
+        .collect();
+    if !unknown.is_empty() {
+        problems.push(format!(
+            "{}: libssh2 doesn't support {}, it supports {}",
+            kind.flag(),
+            unknown.join(","),
+            supported.join(",")
+        ));
+    }
+}
+
+/// host[:port] with a usable host, and a port other than 0. A local address
+/// to bind needs the port.
+fn check_address(flag: &str, addr: &str, needs_port: bool, problems: &mut Vec<String>) {
+    match split_host_port(addr) {
+        Err(e) => problems.push(format!("{} '{}': {}", flag, addr, e)),
+        Ok((host, port)) => {
+            if !valid_host(&host) {
+                problems.push(format!(
+                    "{} '{}': '{}' is not a valid host name or address",
+                    flag, addr, host
+                ));
+            }
+            match port {
+                Some(0) => problems.push(format!("{} '{}': port 0 is not a port", flag, addr)),
+                None if needs_port => problems.push(format!(
+                    "{} '{}' has no port, e.g. 127.0.0.1:8080",
+                    flag, addr
+                )),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// What `validate` finds in the settings of a command line
+    fn problems(args: &[impl AsRef<str>]) -> Vec<String> {
+        let args = args.iter().map(AsRef::as_ref);
+        let matches = crate::Opts::command()
+            .try_get_matches_from(std::iter::once("ssh2fwd").chain(args))
+            .unwrap();
+        let opts = crate::Opts::from_arg_matches(&matches).unwrap();
+        validate(&AppConfig::from_args(opts, &matches).unwrap())
+    }
+
+    /// The one problem of a command line, which names the flag and `value`
+    fn problem(args: &[String], flag: &str, value: &str) -> String {
+        let problems = problems(args);
+        assert_eq!(problems.len(), 1, "{:?}: {:?}", args, problems);
+        let problem = problems.into_iter().next().unwrap();
+        assert!(problem.contains(flag), "{:?}: {}", args, problem);
+        assert!(problem.contains(value), "{:?}: {}", args, problem);
+        problem
+    }
+
+    /// A command line with nothing wrong
+    const GOOD: [&str; 8] = [
+        "-s",
+        "bastion:22",
+        "-r",
+        "db.internal",
+        "-p",
+        "5432",
+        "-l",
+        "127.0.0.1:5432",
+    ];
+
+    /// GOOD with the value of each flag in `changes` replaced, or the flag
+    /// added
+    fn with(changes: &[&str]) -> Vec<String> {
+        let mut args: Vec<String> = GOOD.iter().map(|a| a.to_string()).collect();
+        for pair in changes.chunks(2) {
+            match args.iter().position(|a| a == pair[0]) {
+                Some(i) => args[i + 1] = pair[1].to_string(),
+                None => args.extend([pair[0].to_string(), pair[1].to_string()]),
+            }
+        }
+        args
+    }
+
+    #[test]
+    fn a_sound_command_line_passes() {
+        assert_eq!(problems(&GOOD), Vec::<String>::new());
+        assert_eq!(
+            problems(&with(&["-s", "[2001:db8::10]:2222"])),
+            Vec::<String>::new()
+        );
+        assert_eq!(problems(&with(&["-l", "[::1]:5432"])), Vec::<String>::new());
+        assert_eq!(
+            problems(&with(&["-l", "unix:/run/db.sock"])),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn ports_must_be_ports() {
+        problem(&with(&["-p", "0"]), "--remote-port", "0");
+        problem(
+            &with(&["-l", "127.0.0.1:0"]),
+            "--local-srv-address",
+            "127.0.0.1:0",
+        );
+        problem(&with(&["-s", "bastion:0"]), "--sshaddress", "bastion:0");
+        problem(
+            &with(&["-l", "127.0.0.1:99999"]),
+            "--local-srv-address",
+            "invalid port",
+        );
+    }
+
+    #[test]
+    fn hosts_must_be_usable() {
+        problem(&with(&["-r", ""]), "--remote-srv", "empty");
+        problem(
+            &with(&["-r", "db internal"]),
+            "--remote-srv",
+            "'db internal'",
+        );
+        problem(&with(&["-s", "bas/tion"]), "--sshaddress", "'bas/tion'");
+        problem(
+            &with(&["-s", "[2001:db8::10"]),
+            "--sshaddress",
+            "missing ']'",
+        );
+        problem(
+            &with(&[
+                "--dns-server",
+                "1.1.1.1:x",
+                "--dns-forward",
+                "127.0.0.1:5353",
+            ]),
+            "--dns-server",
+            "invalid port",
+        );
+    }
+
+    #[test]
+    fn a_local_address_needs_a_port() {
+        problem(
+            &with(&["-l", "127.0.0.1"]),
+            "--local-srv-address",
+            "has no port",
+        );
+        problem(
+            &with(&["-l", "unix:"]),
+            "--local-srv-address",
+            "needs a socket path",
+        );
+    }
+
+    #[test]
+    fn options_that_only_go_with_others() {
+        problem(
+            &with(&["--unix-mode", "600"]),
+            "--unix-mode",
+            "127.0.0.1:5432",
+        );
+        problem(&with(&["--dest-allow", "10.0.0.0/8"]), "--dest-allow", "-D");
+        problem(
+            &with(&["--api-addr", "0.0.0.0:9000"]),
+            "--api-token",
+            "0.0.0.0:9000",
+        );
+        assert!(problems(&with(&["--api-addr", "127.0.0.1:9000"])).is_empty());
+        assert!(problems(&with(&["--api-addr", "0.0.0.0:9000", "--api-token", "t"])).is_empty());
+    }
+
+    #[test]
+    fn unknown_algorithms_are_named() {
+        let e = problem(
+            &with(&["--cipher", "aes128-ctr,rot13"]),
+            "--cipher",
+            "rot13",
+        );
+        assert!(!e.contains("doesn't support aes128-ctr"), "{}", e);
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let problems = problems(&with(&["-p", "0", "-l", "127.0.0.1", "-r", ""]));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+    }
+}